@@ -1,4 +1,4 @@
-use std::io::{Read, Result};
+use std::io::{Read, Result, Seek};
 
 /// Extension trait for `std::io::Read` to provide convenient binary reading methods.
 pub trait BinaryReader: Read {
@@ -120,6 +120,16 @@ impl<R: Read> BinaryReader for R {
     }
 }
 
+/// Extension trait for `std::io::Seek`, mostly for pointing at the offending byte in error messages.
+pub trait BinarySeek: Seek {
+    /// Returns the current offset, or 0 if the stream can't tell.
+    fn offset(&mut self) -> u64 {
+        self.stream_position().unwrap_or(0)
+    }
+}
+
+impl<S: Seek> BinarySeek for S {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -188,6 +198,14 @@ mod tests {
         assert!(BinaryReader::read_u32(&mut rdr).is_err());
     }
 
+    #[test]
+    fn test_offset() {
+        let mut rdr = Cursor::new(vec![1, 2, 3]);
+        assert_eq!(rdr.offset(), 0);
+        rdr.read_u16().unwrap();
+        assert_eq!(rdr.offset(), 2);
+    }
+
     #[test]
     fn test_zero_length_reads() {
         let data = vec![1, 2, 3];
@@ -137,7 +137,7 @@ impl Display {
                 Self::render_pattern(grid, play_data, instruments, patterns, order, &theme, x_offset, 0, platform, visualizer_mode, theme_id, pat_max_y);
             },
             ViewMode::Instruments => Self::render_instruments(grid, instruments, 0, &theme),
-            ViewMode::Message => Self::render_message(grid, &play_data.song_message, 0, &theme),
+            ViewMode::Message => Self::render_message(grid, &play_data.song_message, &play_data.load_report, 0, &theme),
             ViewMode::Help => Self::render_help(grid, 0, &theme),
         }
 
@@ -576,12 +576,14 @@ impl Display {
                         
                         let p = &pattern.rows[row_idx].channels[actual_ch];
                         
-                        let note = if p.note == 0 { "---".to_string() } else if p.note == 97 { "OFF".to_string() } else {
+                        let note = if p.note == 0 { "---".to_string() } else if p.note == 97 { "OFF".to_string() }
+                            else if p.note == xmplayer::pattern::Pattern::NOTE_CUT { "CUT".to_string() } else if p.note == xmplayer::pattern::Pattern::NOTE_FADE { "FAD".to_string() } else {
                             format!("{}{}", xmplayer::pattern::Pattern::NOTES[((p.note - 1) % 12) as usize], (p.note - 1) / 12)
                         };
                         let inst = if p.instrument == 0 { "..".to_string() } else { format!("{:02X}", p.instrument) };
                         let vol = if p.volume == 0 { "..".to_string() } else { format!("{:02X}", p.volume) };
-                        let effect = if p.effect == 0 && p.effect_param == 0 { "...".to_string() } else { format!("{:01X}{:02X}", p.effect, p.effect_param) };
+                        let (effect, effect_param) = p.shown_effect();
+                        let effect = if effect == 0 && effect_param == 0 { "...".to_string() } else { format!("{:01X}{:02X}", effect, effect_param) };
                         
                         let is_muted = actual_ch < play_data.channel_status.len()
                             && play_data.channel_status[actual_ch].force_off;
@@ -815,12 +817,23 @@ impl Display {
         }
     }
 
-    fn render_message(grid: &mut Grid, message: &str, y_offset: isize, theme: &Theme) {
+    fn render_message(grid: &mut Grid, message: &str, load_report: &str, y_offset: isize, theme: &Theme) {
         let start_y = (y_offset.max(0) as usize) + 2;
-        for (i, line) in message.lines().enumerate() {
-            let draw_y = start_y + i;
-            if draw_y >= grid.height { break; }
+        let mut draw_y = start_y;
+        for line in message.lines() {
+            if draw_y >= grid.height { return; }
             grid.print(2, draw_y, line, theme.pat_note_fg, theme.row_bg_even);
+            draw_y += 1;
+        }
+
+        if load_report.is_empty() { return; }
+        if draw_y > start_y { draw_y += 1; }
+        if draw_y >= grid.height { return; }
+        grid.print(2, draw_y, "--- LOAD REPORT ---", theme.accent_fg, theme.row_bg_even);
+        for line in load_report.lines() {
+            draw_y += 1;
+            if draw_y >= grid.height { return; }
+            grid.print(2, draw_y, line, theme.col_note, theme.row_bg_odd);
         }
     }
 
@@ -833,7 +846,7 @@ impl Display {
         grid.print(c1, start_y,     "--- VIEW MODES ---", theme.accent_fg, theme.row_bg_even);
         grid.print(c1, start_y + 1, "F1: Pattern View", theme.col_note, theme.row_bg_odd);
         grid.print(c1, start_y + 2, "F2: Instrument View", theme.col_note, theme.row_bg_odd);
-        grid.print(c1, start_y + 3, "F3: Message View", theme.col_note, theme.row_bg_odd);
+        grid.print(c1, start_y + 3, "F3: Message / Load Report", theme.col_note, theme.row_bg_odd);
         grid.print(c1, start_y + 4, "F4: Help View", theme.col_note, theme.row_bg_odd);
 
         grid.print(c2, start_y,     "--- NAVIGATION ---", theme.accent_fg, theme.row_bg_even);
@@ -1,5 +1,5 @@
-use xmplayer::song::{PlaybackCmd, UserData};
-use xmplayer::module_reader::print_module;
+use xmplayer::song::{CompatProfile, FilterType, PanLaw, PlaybackCmd, UserData};
+use xmplayer::module_reader::{list_archive, print_module, LoadOptions};
 use std::env;
 use std::time::{Duration, SystemTime};
 use std::io::{stdout, Write};
@@ -22,23 +22,98 @@ fn main() {
 
 	let _ = dbg!(env::args());
 
-    let path = env::args().nth(1).unwrap();
+    // --filter=none|linear|cubic|sinc|a500|a1200, --compat=ft2|protracker|st3|it|modern, --ramp=<ms>,
+    // --separation=<percent>, --panlaw=ft2|linear|power, --crossfeed, --gain=<dB>, --limiter, --normalize, --lenient,
+    // --member=<name> and --list can go anywhere after the program name
+    let (options, args): (Vec<String>, Vec<String>) = env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let mut filter = None;
+    let mut ramp = None;
+    let mut commands = vec![];
+    let mut load_options = LoadOptions::default();
+    let mut list = false;
+    for option in options {
+        let parsed = if let Some(name) = option.strip_prefix("--filter=") {
+            name.parse::<FilterType>().map(|f| filter = Some(f))
+        } else if let Some(name) = option.strip_prefix("--compat=") {
+            name.parse::<CompatProfile>().map(|p| load_options.compat_profile = Some(p))
+        } else if let Some(ms) = option.strip_prefix("--ramp=") {
+            ms.parse::<f32>().map(|ms| ramp = Some(ms)).map_err(|e| format!("bad ramp '{}': {}", ms, e))
+        } else if let Some(percent) = option.strip_prefix("--separation=") {
+            percent.parse::<u32>().map(|percent| commands.push(PlaybackCmd::SetStereoSeparation(percent)))
+                .map_err(|e| format!("bad separation '{}': {}", percent, e))
+        } else if let Some(name) = option.strip_prefix("--panlaw=") {
+            name.parse::<PanLaw>().map(|law| commands.push(PlaybackCmd::SetPanLaw(law)))
+        } else if option == "--crossfeed" {
+            commands.push(PlaybackCmd::SetCrossfeed(true));
+            Ok(())
+        } else if let Some(db) = option.strip_prefix("--gain=") {
+            db.parse::<f32>().map(|db| commands.push(PlaybackCmd::SetMasterGain(db))).map_err(|e| format!("bad gain '{}': {}", db, e))
+        } else if option == "--limiter" {
+            commands.push(PlaybackCmd::SetLimiter(true));
+            Ok(())
+        } else if option == "--normalize" {
+            load_options.normalize_loudness = true;
+            Ok(())
+        } else if option == "--lenient" {
+            load_options.lenient = true;
+            Ok(())
+        } else if let Some(name) = option.strip_prefix("--member=") {
+            load_options.archive_member = Some(name.to_string());
+            Ok(())
+        } else if option == "--list" {
+            list = true;
+            Ok(())
+        } else {
+            Err(format!("unknown option {}", option))
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    let Some(path) = args.first() else { return; };
+    if list {
+        list_members(path);
+        return;
+    }
     //let file = File::open(path).expect("failed to open the file");
 
    // let data = read_module(path.as_str()).unwrap();
 
-    let (mut song, consumer) = match SongState::new(&path) {
+    // the load report is in the message view
+    let (mut song, consumer) = match SongState::new_with_options(path, &load_options) {
         Ok(s) => {s}
         Err(e) => {dbg!(e);return;}
     };
+    if let Some(filter) = filter {
+        let _ = song.get_sender().send(PlaybackCmd::SetFilter(filter));
+    }
+    if let Some(ms) = ramp {
+        let _ = song.get_sender().send(PlaybackCmd::SetVolumeRamp(ms));
+    }
+    for command in commands {
+        let _ = song.get_sender().send(command);
+    }
 
-    if env::args().len() > 2 {
-        print_module(&song, env::args().skip(2));
+    if args.len() > 1 {
+        print_module(&song, args.into_iter().skip(1));
     } else {
         run(&mut song, consumer);
     }
 }
 
+// what's in a zip, and which members are modules
+fn list_members(path: &str) {
+    let members = match std::fs::read(path).map_err(|e| e.to_string()).and_then(|data| list_archive(&data).map_err(|e| e.to_string())) {
+        Ok(members) => members,
+        Err(e) => {eprintln!("{}", e); return;}
+    };
+    for member in members {
+        let format = member.format.map_or("-".to_string(), |format| format!("{:?}", format));
+        println!("{:>10}  {:<4}  {}", member.size, format, member.name);
+    }
+}
+
 struct TerminalModeSetter {
 }
 
@@ -52,6 +52,9 @@ pub(crate) enum WaveControl {
     SIN,
     RAMP,
     SQUARE,
+    // ST3 and IT, a new value from -255 to 255 on every tick
+    #[allow(clippy::upper_case_acronyms)]
+    RANDOM(i32),
 }
 
 impl WaveControl {
@@ -62,6 +65,25 @@ impl WaveControl {
             _ => WaveControl::SQUARE
         }
     }
+
+    // FT2 has no random waveform and plays it as a square
+    pub(crate) fn ft2_control(control: u8) -> u8 {
+        if control & 3 == 3 { control ^ 1 } else { control }
+    }
+}
+
+// IT's slides: x0 goes up and 0x down on every tick but the first, xF and Fx are the fine versions for the first
+// tick only. Anything else does nothing.
+pub(crate) fn it_slide(first_tick: bool, param: u8) -> i32 {
+    let (x, y) = ((param >> 4) as i32, (param & 0xF) as i32);
+    match (x, y) {
+        (1..=0xF, 0xF) if first_tick => x,
+        (0xF, 1..=0xF) if first_tick => -y,
+        (1..=0xF, 0xF) | (0xF, 1..=0xF) => 0,
+        (_, 0) if !first_tick => x,
+        (0, _) if !first_tick => -y,
+        _ => 0,
+    }
 }
 
 const SIN_TABLE: [i32; 32] =
@@ -75,6 +97,8 @@ pub(crate) struct VibratoState {
     speed:  i8,
     depth:  i8,
     pos:    i8,
+    // Uxy, the fine vibrato of ST3 and IT, goes four times less deep
+    pub(crate) fine: bool,
 }
 
 impl VibratoState {
@@ -83,10 +107,15 @@ impl VibratoState {
         VibratoState {
             speed: 0,
             depth: 0,
-            pos: 0
+            pos: 0,
+            fine: false,
         }
     }
 
+    fn depth_shift(&self) -> i32 {
+        if self.fine { 7 } else { 5 }
+    }
+
     pub(crate) fn set_speed(&mut self, speed: i8) {
         if speed != 0 {
             self.speed = speed;
@@ -107,14 +136,16 @@ impl VibratoState {
         let delta;
         let vibrato_pos = (self.pos >> 2) & 31;
         match wave_control {
-            WaveControl::SIN => { delta = SIN_TABLE[vibrato_pos as usize] * self.depth as i32; }
+            WaveControl::SIN => { delta = SIN_TABLE[vibrato_pos as usize]; }
             WaveControl::RAMP => {
                 let temp:i32 = (vibrato_pos * 8) as i32;
                 delta = if self.pos < 0 { 255 - temp } else { temp } as i32
             }
             WaveControl::SQUARE => { delta = 255; }
+            WaveControl::RANDOM(value) => { return (value * self.depth as i32) >> self.depth_shift(); }
         }
-        ((delta >> 5) * (if self.pos < 0 { -1 } else { 1 })) as i32
+        // every waveform goes as deep as the depth, not only the sine
+        (((delta * self.depth as i32) >> self.depth_shift()) * (if self.pos < 0 { -1 } else { 1 })) as i32
     }
 
     pub(crate) fn next_tick(&mut self) {
@@ -167,6 +198,7 @@ impl TremoloState {
                 delta = if self.pos < 0 { 255 - temp } else { temp } as i32
             }
             WaveControl::SQUARE => { delta = 255; }
+            WaveControl::RANDOM(value) => { return (value * self.depth as i32) >> 6; }
         }
         (((delta * self.depth as i32) >> 6) * (if self.pos < 0 { -1 } else { 1 })) as i32
     }
@@ -177,6 +209,42 @@ impl TremoloState {
     }
 }
 
+// IT's panbrello goes through its wave in 256 steps, and moves the panning by up to 8 * depth either way
+#[derive(Clone,Copy,Debug)]
+pub(crate) struct PanbrelloState {
+    speed:                  u8,
+    depth:                  u8,
+    pos:                    u8,
+    pub(crate) waveform:    u8,
+}
+
+impl PanbrelloState {
+    pub(crate) fn new() -> PanbrelloState {
+        PanbrelloState { speed: 0, depth: 0, pos: 0, waveform: 0 }
+    }
+
+    pub(crate) fn set(&mut self, speed: u8, depth: u8) {
+        if speed != 0 { self.speed = speed; }
+        if depth != 0 { self.depth = depth; }
+    }
+
+    // random_value goes from -255 to 255, for the random waveform
+    pub(crate) fn get_panning_shift(&self, random_value: i32) -> i32 {
+        let sign = if self.pos < 128 { 1 } else { -1 };
+        let delta = match self.waveform & 3 {
+            0 => SIN_TABLE[((self.pos >> 2) & 31) as usize] * sign,
+            1 => (128 - self.pos as i32) * 2,
+            2 => 255 * sign,
+            _ => random_value,
+        };
+        (delta * self.depth as i32) >> 5
+    }
+
+    pub(crate) fn next_tick(&mut self) {
+        self.pos = self.pos.wrapping_add(self.speed);
+    }
+}
+
 #[derive(Clone,Copy,Debug)]
 pub(crate) struct EnvelopeState {
     pub(crate) frame:      u16,
@@ -192,7 +260,7 @@ impl EnvelopeState {
     }
 
     pub(crate) fn key_off(&mut self, env: &Envelope) {
-        if env.sustain && env.has_loop && env.loop_end_point == env.sustain_point {
+        if !env.separate_sustain_loop && env.sustain && env.has_loop && env.loop_end_point == env.sustain_point {
             self.idx = env.loop_start_point as usize;
             self.frame = env.points[self.idx].frame;
         }
@@ -205,6 +273,10 @@ impl EnvelopeState {
             return env.points[0].value * 256
         }
 
+        if env.separate_sustain_loop {
+            return self.handle_loops(env, channel_sustained);
+        }
+
         // loop
         if  (!env.sustain || channel_sustained) && env.has_loop && self.frame == env.points[env.loop_end_point as usize].frame {
             // self.looped = true;
@@ -242,6 +314,41 @@ impl EnvelopeState {
         retval
     }
 
+    // IT envelopes: the sustain loop runs while the key is held, the normal loop takes over after key off. Both play
+    // their end point before going back to the start, a loop of a single point holds it.
+    fn handle_loops(&mut self, env: &Envelope, channel_sustained: bool) -> u16 {
+        let active_loop = if env.sustain && channel_sustained {
+            Some((env.sustain_point as usize, env.sustain_end_point as usize))
+        } else if env.has_loop {
+            Some((env.loop_start_point as usize, env.loop_end_point as usize))
+        } else {
+            None
+        };
+
+        if let Some((start, end)) = active_loop && self.frame > env.points[end].frame {
+            self.idx = start;
+            self.frame = env.points[start].frame;
+        }
+
+        let last = (env.size - 1) as usize;
+        if self.idx > last - 1 { self.idx = last - 1; }
+        while self.idx < last - 1 && self.frame >= env.points[self.idx + 1].frame {
+            self.idx += 1;
+        }
+
+        let retval = if self.frame >= env.points[last].frame {
+            env.points[last].value * 256
+        } else {
+            EnvelopeState::lerp(self.frame, &env.points[self.idx], &env.points[self.idx + 1])
+        };
+
+        if active_loop.is_some() || self.frame < env.points[last].frame {
+            self.frame = self.frame.saturating_add(1);
+        }
+
+        retval
+    }
+
     pub(crate) fn set_position(&mut self, env: &Envelope, pos: u8) {
         // // pre: envelope exists and should be set
         //
@@ -441,6 +548,10 @@ impl Note {
     }
 
 
+    pub(crate) fn original_note(&self) -> u8 {
+        self.original_note
+    }
+
     pub(crate) fn get_tone(note: u8, relative_note: i8) -> Result<u8, bool> {
         let tone = note as i8 + relative_note;
         if tone > 12 * 10 || tone < 0 {
@@ -630,7 +741,8 @@ impl Note {
 
 #[cfg(test)]
 mod tests {
-    use crate::channel_state::channel_state::Note;
+    use crate::channel_state::channel_state::{it_slide, EnvelopeState, Note, PanbrelloState, ResonantFilter, VolumeRamp};
+    use crate::envelope::{Envelope, EnvelopePoint};
     use crate::tables::{TableType, AudioTables};
 
     #[test]
@@ -660,6 +772,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_resonant_filter() {
+        let mut filter = ResonantFilter::new();
+        filter.update(256, 48000.0);
+        assert_eq!(filter.process(0.5), 0.5);
+
+        // lets DC through and takes the Nyquist frequency down a lot
+        filter.cutoff = 40;
+        filter.update(256, 48000.0);
+        let dc = (0..4000).map(|_| filter.process(0.5)).last().unwrap();
+        assert!((dc - 0.5).abs() < 1e-3, "{}", dc);
+
+        filter.reset();
+        let nyquist = (0..4000).map(|i| filter.process(if i % 2 == 0 { 0.5 } else { -0.5 }).abs()).skip(3000).fold(0.0f32, f32::max);
+        assert!(nyquist < 0.01, "{}", nyquist);
+
+        // resonance peaks around the cutoff
+        filter.resonance = 127;
+        filter.update(256, 48000.0);
+        filter.reset();
+        let peak = (0..4000).map(|_| filter.process(0.5)).fold(0.0f32, f32::max);
+        assert!(peak > 0.75, "{}", peak);
+    }
+
+    #[test]
+    fn test_sustain_loop_and_loop() {
+        // 0..4 ramps up, the sustain loop runs 4..6, and after key off the loop runs 6..8 from where it was
+        let mut envelope = Envelope::new();
+        for (idx, (frame, value)) in [(0, 0), (4, 16), (6, 32), (8, 48), (10, 64)].into_iter().enumerate() {
+            envelope.points[idx] = EnvelopePoint { frame, value };
+        }
+        envelope.size = 5;
+        envelope.on = true;
+        envelope.separate_sustain_loop = true;
+        envelope.sustain = true;
+        envelope.sustain_point = 1;
+        envelope.sustain_end_point = 2;
+        envelope.has_loop = true;
+        envelope.loop_start_point = 2;
+        envelope.loop_end_point = 3;
+
+        let mut state = EnvelopeState::new();
+        let held: Vec<u16> = (0..10).map(|_| state.handle(&envelope, true, 64, false) / 256).collect();
+        assert_eq!(held, vec![0, 4, 8, 12, 16, 24, 32, 16, 24, 32]);
+
+        state.key_off(&envelope);
+        let released: Vec<u16> = (0..6).map(|_| state.handle(&envelope, false, 64, false) / 256).collect();
+        assert_eq!(released, vec![40, 48, 32, 40, 48, 32]);
+
+        // without loops it stays at the end
+        envelope.has_loop = false;
+        let end: Vec<u16> = (0..8).map(|_| state.handle(&envelope, false, 64, false) / 256).collect();
+        assert_eq!(end, vec![40, 48, 56, 64, 64, 64, 64, 64]);
+    }
+
     fn tremor_state(x: u8, y: u8, tick: i8) -> bool {
         let tremor_pos = 0u8;
 
@@ -695,6 +862,30 @@ mod tests {
         tremor_count <  x + 1
 
     }
+    #[test]
+    fn test_it_slide() {
+        // (param, on the first tick, on the others)
+        for (param, first, other) in [(0x40, 0, 4), (0x03, 0, -3), (0x4F, 4, 0), (0xF3, -3, 0), (0xFF, 15, 0), (0x43, 0, 0), (0x00, 0, 0)] {
+            assert_eq!((it_slide(true, param), it_slide(false, param)), (first, other), "param {:02x}", param);
+        }
+    }
+
+    #[test]
+    fn test_panbrello() {
+        let mut panbrello = PanbrelloState::new();
+        panbrello.set(64, 8);
+        let shifts: Vec<i32> = (0..4).map(|_| { let shift = panbrello.get_panning_shift(0); panbrello.next_tick(); shift }).collect();
+        assert_eq!(shifts, vec![0, (255 * 8) >> 5, 0, (-255 * 8) >> 5]);
+
+        // the square wave, and zero keeps the last speed and depth
+        panbrello.waveform = 2;
+        panbrello.set(0, 0);
+        assert_eq!(panbrello.get_panning_shift(0), (255 * 8) >> 5);
+        panbrello.next_tick();
+        panbrello.next_tick();
+        assert_eq!(panbrello.get_panning_shift(0), (-255 * 8) >> 5);
+    }
+
     #[test]
     fn test_tremor() {
             for tick in 0i8..30i8 {
@@ -711,6 +902,31 @@ mod tests {
             }
         assert!(true)
     }
+
+    #[test]
+    fn test_volume_ramp() {
+        // a new note comes in from silence and gets to its gains after the ramp
+        let mut ramp = VolumeRamp::new();
+        ramp.set_target([1.0, 0.5], 1, 4);
+        let gains: Vec<[f32; 2]> = (0..5).map(|_| ramp.next()).collect();
+        assert_eq!(gains, vec![[0.25, 0.125], [0.5, 0.25], [0.75, 0.375], [1.0, 0.5], [1.0, 0.5]]);
+
+        // the same gains on the next tick don't start it again
+        ramp.set_target([1.0, 0.5], 1, 4);
+        assert_eq!(ramp.next(), [1.0, 0.5]);
+
+        // without ramping the gains change at once
+        ramp.set_target([0.2, 0.2], 2, 0);
+        assert_eq!(ramp.next(), [0.2, 0.2]);
+
+        // a cut note goes to silence whatever its volume
+        ramp.fading_out = true;
+        ramp.set_target([1.0, 1.0], 2, 2);
+        assert!(!ramp.is_silent());
+        ramp.next();
+        assert_eq!(ramp.next(), [0.0, 0.0]);
+        assert!(ramp.is_silent());
+    }
 }
 
 #[derive(Clone,Copy,Debug)]
@@ -753,11 +969,69 @@ impl Volume {
     }
 }
 
+// The gains of both sides as they move to a new volume or panning over a few milliseconds, instead of at once
+#[derive(Clone,Copy,Debug)]
+pub(crate) struct VolumeRamp {
+    gains:                  [f32; 2],
+    target:                 [f32; 2],
+    step:                   [f32; 2],
+    frames:                 usize,
+    // the note the gains are for, a new one starts from silence
+    note_count:             u32,
+    // a note cut or replaced by a new one on the channel, on its way to silence
+    pub(crate) fading_out:  bool,
+}
+
+impl VolumeRamp {
+    pub(crate) fn new() -> VolumeRamp {
+        VolumeRamp {
+            gains: [0.0; 2],
+            target: [0.0; 2],
+            step: [0.0; 2],
+            frames: 0,
+            note_count: 0,
+            fading_out: false,
+        }
+    }
+
+    // Heads for the gains of the tick over the ramp's length, or goes straight there when ramping is off
+    pub(crate) fn set_target(&mut self, target: [f32; 2], note_count: u32, length: usize) {
+        let target = if self.fading_out { [0.0; 2] } else { target };
+        if length == 0 {
+            (self.gains, self.target, self.frames, self.note_count) = (target, target, 0, note_count);
+            return;
+        }
+        if note_count != self.note_count {
+            self.note_count = note_count;
+            self.gains = [0.0; 2];
+        } else if target == self.target && (self.frames > 0 || self.gains == target) {
+            return;
+        }
+        self.target = target;
+        self.frames = length;
+        self.step = [(target[0] - self.gains[0]) / length as f32, (target[1] - self.gains[1]) / length as f32];
+    }
+
+    pub(crate) fn next(&mut self) -> [f32; 2] {
+        if self.frames > 0 {
+            self.frames -= 1;
+            self.gains = if self.frames == 0 { self.target } else { [self.gains[0] + self.step[0], self.gains[1] + self.step[1]] };
+        }
+        self.gains
+    }
+
+    pub(crate) fn is_silent(&self) -> bool {
+        self.frames == 0 && self.gains == [0.0; 2]
+    }
+}
+
 
 #[derive(Clone,Copy,Debug)]
 pub(crate) struct Panning {
     pub(crate) panning:               u8,
     pub(crate) final_panning:         u8,
+    // what panbrello adds for the tick
+    pub(crate) offset:                i32,
 }
 
 impl Panning {
@@ -765,6 +1039,7 @@ impl Panning {
         Panning {
             panning: 0x80,
             final_panning: 128,
+            offset: 0,
         }
     }
 
@@ -779,7 +1054,80 @@ impl Panning {
     }
 
     pub(crate) fn update_envelope_panning(&mut self, envelope_panning: u16) {
-        self.final_panning = clamp(self.panning as i32 + (envelope_panning as i32-32*256)*(128 - (self.panning as i32 - 128).abs()) / (32i32 * 128i32), 0 ,255) as u8;
+        let panning = clamp(self.panning as i32 + self.offset, 0, 255);
+        self.final_panning = clamp(panning + (envelope_panning as i32-32*256)*(128 - (panning - 128).abs()) / (32i32 * 128i32), 0 ,255) as u8;
         // self.panning = clamp(self.panning , 0, 255) as u8;
     }
 }
+
+// Impulse Tracker's two pole resonant low-pass filter, with the coefficients OpenMPT uses in IT mode.
+// The cutoff and resonance go from 0 to 127, a cutoff of 127 without resonance leaves the voice unfiltered.
+#[derive(Clone,Copy,Debug)]
+pub(crate) struct ResonantFilter {
+    pub(crate) cutoff:      u8,
+    pub(crate) resonance:   u8,
+    active:                 bool,
+    a0:                     f32,
+    b0:                     f32,
+    b1:                     f32,
+    y1:                     f32,
+    y2:                     f32,
+}
+
+impl ResonantFilter {
+    pub(crate) fn new() -> ResonantFilter {
+        ResonantFilter {
+            cutoff: 127,
+            resonance: 0,
+            active: false,
+            a0: 1.0,
+            b0: 0.0,
+            b1: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.y1 = 0.0;
+        self.y2 = 0.0;
+    }
+
+    // envelope_modifier goes from -256 to 256 with the filter envelope, it's 256 without one
+    pub(crate) fn update(&mut self, envelope_modifier: i32, rate: f32) {
+        let scaled_cutoff = self.cutoff as i32 * (envelope_modifier + 256);
+        if scaled_cutoff / 256 >= 254 && self.resonance == 0 {
+            self.active = false;
+            return;
+        }
+
+        let frequency = 110.0 * 2.0f32.powf(0.25 + scaled_cutoff as f32 / (24.0 * 512.0));
+        let frequency = frequency.clamp(120.0, 20000.0f32.min(rate / 2.0));
+        let damping = 10.0f32.powf(-(self.resonance as f32) * ((24.0 / 128.0) / 20.0));
+
+        let r = rate / (2.0 * std::f32::consts::PI * frequency);
+        let d = damping * r + damping - 1.0;
+        let e = r * r;
+
+        self.a0 = 1.0 / (1.0 + d + e);
+        self.b0 = (d + e + e) / (1.0 + d + e);
+        self.b1 = -e / (1.0 + d + e);
+
+        if !self.active {
+            self.reset();
+            self.active = true;
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn process(&mut self, input: f32) -> f32 {
+        if !self.active {
+            return input;
+        }
+        let output = self.a0 * input + self.b0 * self.y1 + self.b1 * self.y2;
+        self.y2 = self.y1;
+        // the history is clipped like the mixer's
+        self.y1 = output.clamp(-2.0, 2.0);
+        output
+    }
+}
@@ -1,6 +1,8 @@
-use crate::channel_state::channel_state::{clamp, EnvelopeState, Note, Panning, PortaToNoteState, TremoloState, VibratoState, Volume, VibratoEnvelopeState};
-use crate::instrument::Instruments;
+use crate::channel_state::channel_state::{clamp, it_slide, EnvelopeState, Note, PanbrelloState, Panning, PortaToNoteState, ResonantFilter, TremoloState, VibratoState, Volume, VolumeRamp, VibratoEnvelopeState, WaveControl};
+use crate::instrument::{Instrument, Instruments, LoopType, NewNoteAction, Sample};
 use crate::tables::AudioTables;
+use crate::paula::Blep;
+use crate::song::CompatProfile;
 use crate::module_reader::is_note_valid;
 use std::num::Wrapping;
 use std::cmp::{min, max};
@@ -58,6 +60,11 @@ pub(crate) struct Voice {
     pub(crate) loop_started:                   bool,
     pub(crate) ping:                           bool,
     pub(crate) sustained:                      bool,
+    pub(crate) fading:                         bool,
+    // S9F plays the sample backwards
+    pub(crate) reverse:                        bool,
+    // counts the notes, an AdLib instrument keys on when it changes
+    pub(crate) note_count:                     u32,
     pub(crate) spline_data:                    SplineData,
 }
 
@@ -73,6 +80,9 @@ impl Voice {
             loop_started: false,
             ping: true,
             sustained: false,
+            fading: false,
+            reverse: false,
+            note_count: 0,
             spline_data: SplineData::new(),
         }
     }
@@ -80,6 +90,11 @@ impl Voice {
 
     pub(crate) fn key_off(&mut self, instruments: &Instruments, is_note_delay: bool) -> bool {
         let instrument = &instruments[self.instrument];
+        // the note leaves the sample's sustain loop for the rest of the sample
+        if let Some(sample) = instrument.samples.get(self.sample) && self.sustained && sample.sustain_loop.sample.is_some() {
+            self.sample_position = sample.release_position(self.sample_position);
+            self.loop_started = false;
+        }
         self.sustained = false;
         if !instrument.volume_envelope.on {
             // self.on = false;
@@ -100,6 +115,17 @@ impl Voice {
         return true;
     }
 
+    // a note fade lets the note fade out without leaving the sustain
+    pub(crate) fn fade_out(&mut self) {
+        if !self.sustained || self.fading {
+            if self.volume.fadeout_vol - self.volume.fadeout_speed * 2 < 0 {
+                self.volume.fadeout_vol = 0;
+            } else {
+                self.volume.fadeout_vol -= self.volume.fadeout_speed * 2;
+            }
+        }
+    }
+
     pub(crate) fn set_frequency(&mut self, frequency: f32, rate: f32) {
         self.frequency = frequency;
         self.du = self.frequency / rate;
@@ -110,6 +136,9 @@ impl Voice {
         self.loop_started = false;
         self.ping = true;
         self.sustained = true;
+        self.fading = false;
+        self.reverse = false;
+        self.note_count = self.note_count.wrapping_add(1);
     }
 
 }
@@ -120,6 +149,7 @@ pub(crate) struct ChannelState {
     pub(crate) frequency:                      f32,
     pub(crate) volume_envelope_state:          EnvelopeState,
     pub(crate) panning_envelope_state:         EnvelopeState,
+    pub(crate) pitch_envelope_state:           EnvelopeState,
     #[allow(dead_code)]
     pub(crate) vibrato_envelope_state:         VibratoEnvelopeState,
     pub(crate) vibrato_state:                  VibratoState,
@@ -153,9 +183,102 @@ pub(crate) struct ChannelState {
     pub(crate) last_samples_pos:               usize,
     pub(crate) loop_row:                       u8,
     pub(crate) loop_count:                     u8,
+    // S73-S76 override the new note action of the instrument until the next note
+    pub(crate) new_note_action:                Option<NewNoteAction>,
+    pub(crate) filter:                         ResonantFilter,
+    // what the pitch envelope does to the frequency, in IT it goes up to 16 semitones each way
+    pub(crate) pitch_envelope_factor:          f32,
+    // the instrument the envelopes run for, envelope carry only continues them for the same one
+    pub(crate) envelope_instrument:            usize,
+    pub(crate) random_seed:                    u32,
+    // IT's channel volume, Mxx/Nxy, scales everything on the channel
+    pub(crate) channel_volume:                 u8,
+    pub(crate) last_channel_volume_slide:      u8,
+    pub(crate) last_tempo_slide:               u8,
+    // SAx, added to the sample offset in 64k steps
+    pub(crate) high_offset:                    u32,
+    // S91 plays the right side with the phase inverted
+    pub(crate) surround:                       bool,
+    pub(crate) panbrello_state:                PanbrelloState,
+    // ST3's one effect memory for D, E, F, I, J, K, L, Q, R and S
+    pub(crate) shared_effect_memory:           u8,
+    // ProTracker's EFx, the speed, the counter that inverts a byte when it passes 128 and the last byte inverted
+    pub(crate) funk_speed:                     u8,
+    pub(crate) funk_counter:                   u8,
+    pub(crate) funk_position:                  u32,
+    // ProTracker plays an instrument without a note once the sample playing ends
+    pub(crate) queued_instrument:              Option<usize>,
+    // where ProTracker's 9xx moved the start of the sample to, until the next instrument number
+    pub(crate) sample_start:                   u32,
+    // the steps Paula's output is still settling from
+    pub(crate) blep:                           Blep,
+    pub(crate) ramp:                           VolumeRamp,
 }
 
+// How much each EFx speed adds to the funk counter on every tick
+const FUNK_TABLE: [u8; 16] = [0, 5, 6, 7, 8, 10, 11, 13, 16, 19, 22, 26, 32, 43, 64, 128];
+
 impl ChannelState {
+    pub(crate) fn new() -> Self {
+        Self {
+            voice: Voice::new(),
+            note: Note::new(),
+            frequency: 0.0,
+            volume_envelope_state: EnvelopeState::new(),
+            panning_envelope_state: EnvelopeState::new(),
+            pitch_envelope_state: EnvelopeState::new(),
+            vibrato_envelope_state: VibratoEnvelopeState::new(),
+            vibrato_state: VibratoState::new(),
+            tremolo_state: TremoloState::new(),
+            frequency_shift: 0.0,
+            period_shift: 0,
+            on: false,
+            last_porta_up: 0,
+            last_porta_down: 0,
+            last_fine_porta_up: 0,
+            last_fine_porta_down: 0,
+            last_volume_slide: 0,
+            last_fine_volume_slide_up: 0,
+            last_fine_volume_slide_down: 0,
+            porta_to_note: PortaToNoteState::new(),
+            last_sample_offset: 0,
+            last_panning_speed: 0,
+            panning: Panning::new(),
+            force_off: false,
+            glissando: false,
+            vibrato_control: 0,
+            tremolo_control: 0,
+            tremor: 0,
+            tremor_count: 0,
+            multi_retrig_count: 0,
+            multi_retrig_volume: 0,
+            last_played_note: 0,
+            last_samples: [0.0; 512],
+            last_samples_pos: 0,
+            loop_row: 0,
+            loop_count: 0,
+            new_note_action: None,
+            filter: ResonantFilter::new(),
+            pitch_envelope_factor: 1.0,
+            envelope_instrument: 0,
+            random_seed: 0x1234,
+            channel_volume: 64,
+            last_channel_volume_slide: 0,
+            last_tempo_slide: 0,
+            high_offset: 0,
+            surround: false,
+            panbrello_state: PanbrelloState::new(),
+            shared_effect_memory: 0,
+            funk_speed: 0,
+            funk_counter: 0,
+            funk_position: 0,
+            queued_instrument: None,
+            sample_start: 0,
+            blep: Blep::new(),
+            ramp: VolumeRamp::new(),
+        }
+    }
+
     fn set_note(&mut self, note: u8, fine_tune: i8, original_note: u8, frequency_tables: &AudioTables) {
         self.note.set_note(note, fine_tune, original_note, frequency_tables);
         self.frequency_shift = 0.0;
@@ -176,19 +299,97 @@ impl ChannelState {
         let instrument = &instruments[self.voice.instrument];
         self.volume_envelope_state.key_off(&instrument.volume_envelope);
         self.panning_envelope_state.key_off(&instrument.panning_envelope);
+        self.pitch_envelope_state.key_off(&instrument.pitch_envelope);
         self.voice.key_off(instruments, is_note_delay)
     }
 
     pub(crate) fn update_frequency(&mut self, rate: f32, semitone: bool, frequency_tables: &AudioTables) {
-        self.voice.set_frequency(self.note.frequency(self.period_shift, semitone, frequency_tables) + self.frequency_shift, rate)
+        self.voice.set_frequency((self.note.frequency(self.period_shift, semitone, frequency_tables) + self.frequency_shift) * self.pitch_envelope_factor, rate)
+    }
+
+    // A copy of the note that goes on playing while it ramps down to silence, for when it stops at once
+    pub(crate) fn fading_out(&self) -> ChannelState {
+        let mut state = *self;
+        state.ramp.fading_out = true;
+        state.queued_instrument = None;
+        state
+    }
+
+    // What the note gets when a new note moves it to the background, or on a duplicate check or a past note action
+    pub(crate) fn apply_note_action(&mut self, instruments: &Instruments, action: NewNoteAction) {
+        match action {
+            NewNoteAction::Cut => { self.on = false; }
+            NewNoteAction::Continue => {}
+            NewNoteAction::NoteOff => { self.key_off(instruments, false); }
+            NewNoteAction::NoteFade => {
+                self.voice.fading = true;
+                self.voice.volume.fadeout_speed = instruments[self.voice.instrument].volume_fadeout as i32;
+            }
+        }
+    }
+
+    // FinalVol = (FadeOutVol/65536)*(EnvelopeVol/64)*(GlobalVol/64)*(Vol/64)*Scale;
+    // global_volume is already scaled to 0..1, IT's goes up to 128 and the others to 64
+    pub(crate) fn update_output_volume(&mut self, instrument: &Instrument, global_volume: f32) {
+        let envelope_volume = self.volume_envelope_state.handle(&instrument.volume_envelope, self.voice.sustained, 64, false);
+
+        let mut envelope_panning = self.panning_envelope_state.handle(&instrument.panning_envelope, self.voice.sustained, 32, true);
+        envelope_panning = clamp(envelope_panning, 0, 64 * 256);
+        self.panning.update_envelope_panning(envelope_panning);
+
+        self.voice.volume.envelope_vol = envelope_volume as i32;
+        self.voice.volume.global_vol = (global_volume * 64.0) as i32;
+        self.voice.volume.output_volume = (self.voice.volume.fadeout_vol as f32 / 65536.0) * (envelope_volume as f32 / 16384.0) * (self.voice.volume.get_volume() as f32 / 64.0) * global_volume * (instrument.global_volume as f32 / 128.0) * (self.channel_volume as f32 / 64.0);
+    }
+
+    // Impulse Tracker's swing is different on every note, a small LCG keeps playback repeatable
+    fn random(&mut self, range: i32) -> i32 {
+        self.random_seed = self.random_seed.wrapping_mul(1103515245).wrapping_add(12345);
+        ((self.random_seed >> 16) as i32 % (2 * range + 1)) - range
+    }
+
+    // IT instruments pan with the pitch of the note, and swing the volume and panning of a new note at random
+    pub(crate) fn apply_instrument_variation(&mut self, instrument: &Instrument, note: u8) {
+        if instrument.pitch_pan_separation != 0 {
+            let offset = (note as i32 - instrument.pitch_pan_center as i32) * instrument.pitch_pan_separation as i32 * 255 / (8 * 64);
+            self.panning.set_panning(self.panning.panning as i32 + offset);
+        }
+        if instrument.random_panning != 0 {
+            let swing = self.random(instrument.random_panning as i32) * 255 / 64;
+            self.panning.set_panning(self.panning.panning as i32 + swing);
+        }
+        if instrument.random_volume != 0 {
+            let volume = self.voice.volume.volume as i32;
+            let swing = volume * self.random(instrument.random_volume as i32) / 100;
+            self.voice.volume.set_volume(volume + swing);
+        }
+    }
+
+    // The pitch envelope either bends the note by up to 16 semitones, in half semitone steps, or drives the filter.
+    // The filter envelope sits in the middle of its range without changing the cutoff, and doubles it at the top
+    pub(crate) fn update_pitch_envelope(&mut self, instrument: &Instrument, rate: f32) {
+        let mut envelope_modifier = 256;
+        self.pitch_envelope_factor = 1.0;
+        if instrument.pitch_envelope.on {
+            let envelope_value = self.pitch_envelope_state.handle(&instrument.pitch_envelope, self.voice.sustained, 32, false);
+            if instrument.filter_envelope {
+                envelope_modifier = envelope_value as i32 / 32 - 256;
+            } else {
+                self.pitch_envelope_factor = 2.0f32.powf((envelope_value as f32 / 256.0 - 32.0) / 24.0);
+            }
+        }
+        self.filter.update(envelope_modifier, rate);
     }
 
     pub(crate) fn reset_envelopes(&mut self, instruments: &Instruments) {
         let instrument = &instruments[self.voice.instrument];
         self.voice.volume.fadeout_vol = 65536;
         self.voice.volume.fadeout_speed = 0;//self.instrument.volume_fadeout as i32;
-        self.volume_envelope_state.reset(0, &instrument.volume_envelope);
-        self.panning_envelope_state.reset(0, &instrument.panning_envelope);
+        let carry = self.on && self.envelope_instrument == self.voice.instrument;
+        self.envelope_instrument = self.voice.instrument;
+        if !(carry && instrument.volume_envelope.carry) { self.volume_envelope_state.reset(0, &instrument.volume_envelope); }
+        if !(carry && instrument.panning_envelope.carry) { self.panning_envelope_state.reset(0, &instrument.panning_envelope); }
+        if !(carry && instrument.pitch_envelope.carry) { self.pitch_envelope_state.reset(0, &instrument.pitch_envelope); }
         if self.vibrato_control & 0x4 != 4 { self.vibrato_state.set_pos(0); }
         if self.tremolo_control & 0x4 != 4 { self.tremolo_state.set_pos(0); }
     }
@@ -199,16 +400,19 @@ impl ChannelState {
             let instrument = &instruments[self.voice.instrument];
             let sample = &instrument.samples[self.voice.sample];
 
-            let tone = match Note::get_tone(note, sample.relative_note) {
+            let tone = match Note::get_tone(instrument.mapped_note(note), sample.relative_note) {
                 Ok(p) => p,
                 Err(_e) => return,
             };
 
-            self.on = true;
             self.voice.trigger_note();
             self.frequency_shift = 0.0;
             self.period_shift = 0;
             self.tremor_count = 0;
+
+            if let Some(cutoff) = instrument.filter_cutoff { self.filter.cutoff = cutoff; }
+            if let Some(resonance) = instrument.filter_resonance { self.filter.resonance = resonance; }
+            self.filter.reset();
             // self.last_sample = 0;
             // self.last_sample_pos = 0.0;
 
@@ -218,9 +422,61 @@ impl ChannelState {
             self.update_frequency(rate, false, frequency_tables);
             // self.voice.sustained = true;
             self.reset_envelopes(instruments);
+            self.on = true;
         }
     }
 
+    // ProTracker's 9xx moves the start of the sample, and adds up until an instrument number resets it
+    pub(crate) fn protracker_offset(&mut self, param: u8) {
+        if param != 0 {
+            self.last_sample_offset = param as u32 * 256;
+        }
+        self.sample_start += self.last_sample_offset;
+    }
+
+    // A note starts where 9xx left the start. Past the end there's only the loop to play, if the sample has one
+    pub(crate) fn start_protracker_sample(&mut self, sample: &Sample) {
+        if self.sample_start == 0 {
+            return;
+        }
+        if self.sample_start < sample.length.saturating_sub(4) {
+            self.voice.sample_position = self.sample_start as f32 + 4.0;
+        } else if sample.loop_type != LoopType::NoLoop {
+            self.voice.sample_position = sample.loop_start as f32;
+            self.voice.loop_started = true;
+        } else {
+            self.on = false;
+        }
+    }
+
+    // The queued instrument takes over from its loop start, or goes quiet if it has no loop
+    pub(crate) fn swap_instrument(&mut self, instruments: &Instruments) {
+        let Some(instrument) = self.queued_instrument.take() else { return; };
+        self.voice.instrument = instrument;
+        self.voice.sample = 0;
+        self.funk_position = 0;
+        let sample = &instruments[instrument].samples[0];
+        if sample.loop_type == LoopType::NoLoop {
+            self.on = false;
+        } else {
+            self.voice.sample_position = sample.loop_start as f32;
+            self.voice.loop_started = true;
+        }
+    }
+
+    // EFx on the first tick, and every tick after it until EF0: true when it's time to invert the next byte
+    pub(crate) fn update_funk(&mut self) -> bool {
+        if self.funk_speed == 0 {
+            return false;
+        }
+        self.funk_counter = self.funk_counter.wrapping_add(FUNK_TABLE[self.funk_speed as usize]);
+        if self.funk_counter & 0x80 == 0 {
+            return false;
+        }
+        self.funk_counter = 0;
+        true
+    }
+
     pub(crate) fn vibrato(&mut self, first_tick: bool, speed: u8, depth: u8) {
         if first_tick {
             self.vibrato_state.set_speed(speed as i8);
@@ -230,6 +486,21 @@ impl ChannelState {
         }
     }
 
+    // waveform 3 is random in ST3 and IT, FT2's controls come here without it
+    fn wave_control(&mut self, control: u8) -> WaveControl {
+        if control & 3 == 3 { WaveControl::RANDOM(self.random(255)) } else { WaveControl::from(control) }
+    }
+
+    pub(crate) fn update_vibrato(&mut self) {
+        let wave_control = self.wave_control(self.vibrato_control);
+        self.frequency_shift = self.vibrato_state.get_frequency_shift(wave_control) as f32;
+    }
+
+    pub(crate) fn update_tremolo(&mut self) {
+        let wave_control = self.wave_control(self.tremolo_control);
+        self.voice.volume.volume_shift = self.tremolo_state.get_volume_shift(wave_control);
+    }
+
     pub(crate) fn tremolo(&mut self, first_tick: bool, speed: u8, depth: u8) {
         if first_tick {
             self.tremolo_state.set_speed(speed as i8);
@@ -265,6 +536,31 @@ impl ChannelState {
         }
     }
 
+    // IT's Pxy, P0x slides right and Px0 left, PFx and PxF are the fine slides
+    pub(crate) fn it_panning_slide(&mut self, first_tick: bool, param: u8) {
+        if param != 0 { self.last_panning_speed = param; }
+        let slide = it_slide(first_tick, self.last_panning_speed);
+        if slide != 0 {
+            self.panning.set_panning(self.panning.panning as i32 - slide * 4);
+        }
+    }
+
+    pub(crate) fn channel_volume_slide(&mut self, first_tick: bool, param: u8) {
+        if param != 0 { self.last_channel_volume_slide = param; }
+        let slide = it_slide(first_tick, self.last_channel_volume_slide);
+        self.channel_volume = clamp(self.channel_volume as i32 + slide, 0, 64) as u8;
+    }
+
+    pub(crate) fn panbrello(&mut self, first_tick: bool, speed: u8, depth: u8) {
+        if first_tick {
+            self.panbrello_state.set(speed, depth);
+        } else {
+            self.panbrello_state.next_tick();
+        }
+        let random_value = self.random(255);
+        self.panning.offset = self.panbrello_state.get_panning_shift(random_value);
+    }
+
     pub(crate) fn retrig_note(&mut self, instruments: &Instruments, first_tick: bool, tick: u32, param: u8, note: u8, rate: f32, frequency_tables: &AudioTables) {
         if !first_tick && param != 0 && (tick % param as u32 == 0) {
             self.trigger_note(instruments, note, rate, frequency_tables);
@@ -419,8 +715,9 @@ impl ChannelState {
             }
 
             if is_note_valid(note) {
-                let sample = &instruments[self.voice.instrument].samples[self.voice.sample];
-                self.porta_to_note.target_note.set_note(clamp(note as i16 + sample.relative_note as i16, 0, 119) as u8, sample.finetune, note, frequency_tables);
+                let instrument = &instruments[self.voice.instrument];
+                let sample = &instrument.samples[self.voice.sample];
+                self.porta_to_note.target_note.set_note(clamp(instrument.mapped_note(note) as i16 + sample.relative_note as i16, 0, 119) as u8, sample.finetune, note, frequency_tables);
             }
         } else {
             let mut up = true;
@@ -448,53 +745,61 @@ impl ChannelState {
         }
     }
 
-    pub(crate) fn porta_up(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables) {
+    pub(crate) fn porta_up(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables, compat: CompatProfile) {
         if first_tick {
             if amount != 0 {
                 self.last_porta_up = (amount as u16) * 4;
             }
         } else {
-            self.note.period = (Wrapping(self.note.period) - Wrapping(self.last_porta_up)).0;
-            if (self.note.period as i16) < 1 {
-                self.note.period = 1;
-            }
+            let (lowest, _) = compat.period_limits();
+            self.note.period = if compat == CompatProfile::FT2 {
+                let period = (Wrapping(self.note.period) - Wrapping(self.last_porta_up)).0;
+                if (period as i16) < lowest as i16 { lowest } else { period }
+            } else {
+                max(self.note.period.saturating_sub(self.last_porta_up), lowest)
+            };
             self.update_frequency(rate, false, frequency_tables);
         }
     }
 
-    pub(crate) fn porta_down(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables) {
+    pub(crate) fn porta_down(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables, compat: CompatProfile) {
         if first_tick {
             if amount != 0 {
                 self.last_porta_down = (amount as u16) * 4;
             }
         } else {
-            self.note.period += self.last_porta_down;
-            if (self.note.period as i16) > 31999 { // FT2 bug
-                self.note.period = 31999;
-            }
+            let (_, highest) = compat.period_limits();
+            self.note.period = if compat == CompatProfile::FT2 { // FT2 bug, past 32767 the period looks negative
+                let period = (Wrapping(self.note.period) + Wrapping(self.last_porta_down)).0;
+                if (period as i16) > highest as i16 { highest } else { period }
+            } else {
+                min(self.note.period.saturating_add(self.last_porta_down), highest)
+            };
             self.update_frequency(rate, false, frequency_tables);
         }
     }
 
-    pub(crate) fn fine_porta_up(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables) {
+    pub(crate) fn fine_porta_up(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables, compat: CompatProfile) {
         if first_tick {
             if amount != 0 {
                 self.last_fine_porta_up = (amount as u16) * 4;
             }
-            self.note.period = (Wrapping(self.note.period) - Wrapping(self.last_fine_porta_up)).0;
+            // FT2 doesn't check the fine slides up at all
+            self.note.period = if compat == CompatProfile::FT2 {
+                (Wrapping(self.note.period) - Wrapping(self.last_fine_porta_up)).0
+            } else {
+                max(self.note.period.saturating_sub(self.last_fine_porta_up), compat.period_limits().0)
+            };
             self.update_frequency(rate, false, frequency_tables);
         }
     }
 
-    pub(crate) fn fine_porta_down(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables) {
+    pub(crate) fn fine_porta_down(&mut self, first_tick: bool, amount: u8, rate: f32, frequency_tables: &AudioTables, compat: CompatProfile) {
         if first_tick {
             if amount != 0 {
                 self.last_fine_porta_down = (amount as u16) * 4;
             }
-            self.note.period += self.last_fine_porta_down;
-            if self.note.period > 31999 {
-                self.note.period = 31999;
-            }
+            self.note.period = min(self.note.period.saturating_add(self.last_fine_porta_down), compat.period_limits().1);
             self.update_frequency(rate, false, frequency_tables);
         }
     }
@@ -11,47 +11,60 @@ impl EnvelopePoint {
     }
 }
 
+/// XM envelopes use the first 12 points, IT envelopes all 25
+pub const MAX_ENVELOPE_POINTS: usize = 25;
 
-pub type EnvelopePoints = [EnvelopePoint;12];
+pub type EnvelopePoints = [EnvelopePoint; MAX_ENVELOPE_POINTS];
 
 #[derive(Debug, Copy, Clone)]
 pub struct Envelope {
     pub(crate) points:             EnvelopePoints,
     pub(crate) size:               u8,
     pub(crate) sustain_point:      u8,
+    // the end of the sustain loop, XM only has a sustain point so it's the same point
+    pub(crate) sustain_end_point:  u8,
     pub(crate) loop_start_point:   u8,
     pub(crate) loop_end_point:     u8,
     pub(crate) on:                 bool,
     pub(crate) sustain:            bool,
     pub(crate) has_loop:           bool,
+    // IT: the sustain loop runs until key off, and only then the normal loop takes over
+    pub(crate) separate_sustain_loop: bool,
+    // IT: a new note of the same instrument picks up the envelope where the last one left it
+    pub(crate) carry:              bool,
 }
 
 impl Envelope {
     pub(crate) fn new() -> Self {
         Envelope{
-            points: [EnvelopePoint { frame: 0, value: 0 }; 12],
+            points: [EnvelopePoint::new(); MAX_ENVELOPE_POINTS],
             size: 0,
             sustain_point: 0,
+            sustain_end_point: 0,
             loop_start_point: 0,
             loop_end_point: 0,
             on: false,
             sustain: false,
-            has_loop: false
+            has_loop: false,
+            separate_sustain_loop: false,
+            carry: false,
         }
     }
 
-    pub(crate) fn create(points: [EnvelopePoint; 12], size: u8, sustain_point: u8, loop_start_point: u8, loop_end_point: u8, env_type: u8) -> Self {
+    pub(crate) fn create(points: EnvelopePoints, size: u8, sustain_point: u8, loop_start_point: u8, loop_end_point: u8, env_type: u8) -> Self {
         Envelope {
             points,
             size,
             sustain_point,
+            sustain_end_point: sustain_point,
             loop_start_point,
             loop_end_point,
             on: (env_type & 1) == 1,
             sustain: (env_type & 2) == 2,
             has_loop: (env_type & 4) == 4,
+            separate_sustain_loop: false,
+            carry: false,
         }
     }
 
 }
-
@@ -3,7 +3,9 @@ use std::num::Wrapping;
 
 use crate::envelope::Envelope;
 use binary_reader_io::BinaryReader;
-use crate::{SimpleError, SimpleResult};
+use crate::module_reader::{LoadError, LoadResult, Section};
+use crate::module_reader::unpack::BitReader;
+use crate::opl::AdlibPatch;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LoopType {
@@ -23,6 +25,91 @@ impl LoopType {
     }
 }
 
+/// What happens to the note playing on a channel when a new one starts there. Anything but a cut moves the
+/// old note to the background, where it plays on until it ends or fades out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NewNoteAction {
+    Cut = 0,
+    Continue = 1,
+    NoteOff = 2,
+    NoteFade = 3,
+}
+
+impl NewNoteAction {
+    pub(crate) fn from_it(value: u8) -> NewNoteAction {
+        match value {
+            1 => NewNoteAction::Continue,
+            2 => NewNoteAction::NoteOff,
+            3 => NewNoteAction::NoteFade,
+            _ => NewNoteAction::Cut
+        }
+    }
+}
+
+/// Which of the background notes of a channel count as duplicates of a new note of the same instrument
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateCheckType {
+    Off = 0,
+    Note = 1,
+    Sample = 2,
+    Instrument = 3,
+}
+
+impl DuplicateCheckType {
+    pub(crate) fn from_it(value: u8) -> DuplicateCheckType {
+        match value {
+            1 => DuplicateCheckType::Note,
+            2 => DuplicateCheckType::Sample,
+            3 => DuplicateCheckType::Instrument,
+            _ => DuplicateCheckType::Off
+        }
+    }
+}
+
+/// What happens to the duplicates, and to the background notes of a channel on S70-S72
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateCheckAction {
+    Cut = 0,
+    NoteOff = 1,
+    NoteFade = 2,
+}
+
+impl DuplicateCheckAction {
+    pub(crate) fn from_it(value: u8) -> DuplicateCheckAction {
+        match value {
+            1 => DuplicateCheckAction::NoteOff,
+            2 => DuplicateCheckAction::NoteFade,
+            _ => DuplicateCheckAction::Cut
+        }
+    }
+}
+
+impl From<DuplicateCheckAction> for NewNoteAction {
+    fn from(action: DuplicateCheckAction) -> NewNoteAction {
+        match action {
+            DuplicateCheckAction::Cut => NewNoteAction::Cut,
+            DuplicateCheckAction::NoteOff => NewNoteAction::NoteOff,
+            DuplicateCheckAction::NoteFade => NewNoteAction::NoteFade,
+        }
+    }
+}
+
+/// A loop that only plays until key off, the normal loop takes over from there. The sample gets a copy of itself
+/// with the sustain loop as its loop, prepared and padded like any other, which plays while the note is held.
+#[derive(Clone, Debug)]
+pub struct SustainLoop {
+    pub start: u32,
+    pub end: u32,
+    pub loop_type: LoopType,
+    pub(crate) sample: Option<Box<Sample>>,
+}
+
+impl SustainLoop {
+    pub(crate) fn new() -> SustainLoop {
+        SustainLoop { start: 0, end: 0, loop_type: LoopType::NoLoop, sample: None }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Sample {
     pub length: u32,
@@ -38,11 +125,12 @@ pub struct Sample {
     pub name: String,
     pub is_ping_pong: bool,
     pub original_loop_end: u32,
+    pub sustain_loop: SustainLoop,
     pub data: Vec<f32>
 }
 
 impl Sample {
-    fn new() -> Sample {
+    pub(crate) fn new() -> Sample {
         Sample {
             length: 0,
             loop_start: 0,
@@ -57,10 +145,50 @@ impl Sample {
             name: "".to_string(),
             is_ping_pong: false,
             original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
             data: vec![]
         }
     }
 
+    /// The sample that plays, the sustain loop copy until key off and the sample itself from there
+    pub(crate) fn playing(&self, sustained: bool) -> &Sample {
+        match &self.sustain_loop.sample {
+            Some(sample) if sustained => sample,
+            _ => self,
+        }
+    }
+
+    /// Where a note leaving the sustain loop on key off goes on in the sample itself
+    pub(crate) fn release_position(&self, position: f32) -> f32 {
+        let Some(sustained) = &self.sustain_loop.sample else { return position; };
+
+        // the second half of an unrolled ping-pong loop is the loop backwards
+        let sustain_end = sustained.original_loop_end as f32 + 4.0;
+        let mut position = position;
+        if sustained.is_ping_pong && position >= sustain_end {
+            position = (2.0 * sustain_end - position - 1.0).max(sustained.loop_start as f32);
+        }
+
+        if self.loop_type != LoopType::NoLoop && self.loop_len > 0 && position >= self.loop_end as f32 {
+            position = self.loop_start as f32 + (position - self.loop_start as f32) % self.loop_len as f32;
+        }
+        position
+    }
+
+    /// EFx flips every bit of a byte in the loop, which turns v into -1 - v. Its copies in the padding flip with it
+    pub(crate) fn invert(&mut self, position: usize) {
+        let value = -self.data[position] - 1.0 / 32768.0;
+        self.data[position] = value;
+        let (loop_start, loop_end) = (self.loop_start as usize, self.loop_end as usize);
+        let suffix = self.length as usize + position - loop_start;
+        if position < loop_start + 4 && suffix < self.data.len() {
+            self.data[suffix] = value;
+        }
+        if position + 4 >= loop_end {
+            self.data[position + 4 - loop_end] = value;
+        }
+    }
+
     fn unpack_i16(mut data: Vec<i16>) -> Vec<i16> {
         for i in 1..data.len() {
             data[i] = (Wrapping(data[i - 1]) + Wrapping(data[i])).0;
@@ -94,6 +222,10 @@ impl Sample {
     }
 
 
+    fn signed_u16(data: Vec<u16>) -> Vec<i16> {
+        data.into_iter().map(|s| (s ^ 0x8000) as i16).collect()
+    }
+
     fn upsamplei16(data: Vec<i16>) -> Vec<f32> {
         let mut result = vec!(0.0f32; data.len());
         result.reserve_exact(data.len() as usize);
@@ -103,20 +235,20 @@ impl Sample {
         result
     }
 
-    pub(crate) fn read_s3m_sample_data<R: Read + Seek>(&mut self, file: &mut R, sample_ptr: u32) -> SimpleResult<()> {
+    pub(crate) fn read_s3m_sample_data<R: Read + Seek>(&mut self, file: &mut R, sample_ptr: u32) -> LoadResult<()> {
         if self.length == 0 { return Ok(()); }
         file.seek(SeekFrom::Start((sample_ptr as u64)  * 16))?;
 
         if self.bitness == 8 {
             self.data = Sample::upsamplei16(Sample::upsampleu8(file.read_bytes(self.length as usize)?));
         } else {
-            return Err(SimpleError::new("Unknown S3M sample format"));
+            return Err(LoadError::invalid("Unknown S3M sample format").at(Section::Sample, sample_ptr as u64 * 16));
         }
         self.setup_loops_and_padding();
         Ok(())
     }
 
-    pub(crate) fn read_non_packed_data<R: Read>(&mut self, file: &mut R) -> SimpleResult<()> {
+    pub(crate) fn read_non_packed_data<R: Read>(&mut self, file: &mut R) -> LoadResult<()> {
         if self.length == 0 { return Ok(()); }
         if self.bitness == 8 {
             self.data = Sample::upsamplei16(Sample::upsamplei8(file.read_i8_vec(self.length as usize)?));
@@ -127,7 +259,30 @@ impl Sample {
         Ok(())
     }
 
-    pub(crate) fn read_data<R: Read>(&mut self, file: &mut R) -> SimpleResult<()> {
+    pub(crate) fn read_unsigned_data<R: Read>(&mut self, file: &mut R) -> LoadResult<()> {
+        if self.length == 0 { return Ok(()); }
+        if self.bitness == 8 {
+            self.data = Sample::upsamplei16(Sample::upsampleu8(file.read_bytes(self.length as usize)?));
+        } else {
+            self.data = Sample::upsamplei16(Sample::signed_u16(file.read_u16_vec(self.length as usize)?));
+        }
+        self.setup_loops_and_padding();
+        Ok(())
+    }
+
+    pub(crate) fn read_big_endian_data<R: Read>(&mut self, file: &mut R) -> LoadResult<()> {
+        if self.length == 0 { return Ok(()); }
+        if self.bitness == 8 {
+            self.data = Sample::upsamplei16(Sample::upsamplei8(file.read_i8_vec(self.length as usize)?));
+        } else {
+            let bytes = file.read_bytes(self.length as usize * 2)?;
+            self.data = Sample::upsamplei16(bytes.chunks_exact(2).map(|b| i16::from_be_bytes([b[0], b[1]])).collect());
+        }
+        self.setup_loops_and_padding();
+        Ok(())
+    }
+
+    pub(crate) fn read_data<R: Read>(&mut self, file: &mut R) -> LoadResult<()> {
         if self.length == 0 { return Ok(()); }
         if self.bitness == 8 {
             self.data = Sample::upsamplei16(Sample::upsamplei8(Sample::unpack_i8(file.read_i8_vec(self.length as usize)?)));
@@ -138,9 +293,82 @@ impl Sample {
         Ok(())
     }
 
+    // Impulse Tracker 2.14 compression: blocks of up to 0x8000 bytes worth of samples, each with its own
+    // bit stream of deltas. 2.15 packs the deltas of the deltas instead.
+    pub(crate) fn read_it_compressed_data<R: Read>(&mut self, file: &mut R, it215: bool) -> LoadResult<()> {
+        if self.length == 0 { return Ok(()); }
+        let block_length = if self.bitness == 8 { 0x8000 } else { 0x4000 };
+
+        let mut data: Vec<i16> = Vec::with_capacity(self.length as usize);
+        while data.len() < self.length as usize {
+            let packed_length = file.read_u16()?;
+            let block = file.read_bytes(packed_length as usize)?;
+            let count = (self.length as usize - data.len()).min(block_length);
+            if self.bitness == 8 {
+                data.extend(Sample::upsamplei8(unpack_it_block_8(&block, count, it215)));
+            } else {
+                data.extend(unpack_it_block_16(&block, count, it215));
+            }
+        }
+        self.data = Sample::upsamplei16(data);
+        self.setup_loops_and_padding();
+        Ok(())
+    }
+
+    // Cuts a length that runs past the bytes the file has left down to them. Says if it had to.
+    pub(crate) fn fit_length(&mut self, bytes_left: u64) -> bool {
+        let bytes_per_sample = if self.bitness == 16 { 2 } else { 1 };
+        let available = (bytes_left / bytes_per_sample).min(u32::MAX as u64) as u32;
+        if self.length <= available {
+            return false;
+        }
+        self.length = available;
+        true
+    }
+
+    // Whether the loops stay inside the sample. The padding set up after reading indexes the data by them.
+    pub(crate) fn loops_fit(&self) -> bool {
+        (self.loop_type == LoopType::NoLoop || (self.loop_start <= self.loop_end && self.loop_end <= self.length))
+            && (self.sustain_loop.loop_type == LoopType::NoLoop || (self.sustain_loop.start <= self.sustain_loop.end && self.sustain_loop.end <= self.length))
+    }
+
+    // Pulls loops that reach past the end of the sample back into it, dropping those that end up empty. Says if it had to.
+    pub(crate) fn fit_loops(&mut self) -> bool {
+        let (loop_start, loop_end) = (self.loop_start, self.loop_end);
+        self.loop_end = self.loop_end.min(self.length);
+        self.loop_start = self.loop_start.min(self.loop_end);
+        self.loop_len = self.loop_end - self.loop_start;
+        let mut fitted = self.loop_type != LoopType::NoLoop && (loop_start, loop_end) != (self.loop_start, self.loop_end);
+        if self.loop_len == 0 && self.loop_type != LoopType::NoLoop {
+            self.loop_type = LoopType::NoLoop;
+            fitted = true;
+        }
+
+        let (sustain_start, sustain_end) = (self.sustain_loop.start, self.sustain_loop.end);
+        self.sustain_loop.end = self.sustain_loop.end.min(self.length);
+        self.sustain_loop.start = self.sustain_loop.start.min(self.sustain_loop.end);
+        fitted |= self.sustain_loop.loop_type != LoopType::NoLoop && (sustain_start, sustain_end) != (self.sustain_loop.start, self.sustain_loop.end);
+        if self.sustain_loop.start == self.sustain_loop.end && self.sustain_loop.loop_type != LoopType::NoLoop {
+            self.sustain_loop.loop_type = LoopType::NoLoop;
+            fitted = true;
+        }
+        fitted
+    }
+
     pub(crate) fn setup_loops_and_padding(&mut self) {
         if self.length == 0 || self.data.is_empty() { return; }
 
+        if self.sustain_loop.loop_type != LoopType::NoLoop && self.sustain_loop.sample.is_none() {
+            let mut sustained = self.clone();
+            sustained.loop_start = self.sustain_loop.start;
+            sustained.loop_end = self.sustain_loop.end;
+            sustained.loop_len = self.sustain_loop.end - self.sustain_loop.start;
+            sustained.loop_type = self.sustain_loop.loop_type;
+            sustained.sustain_loop = SustainLoop::new();
+            sustained.setup_loops_and_padding();
+            self.sustain_loop.sample = Some(Box::new(sustained));
+        }
+
         self.original_loop_end = self.loop_end;
         if self.loop_type == LoopType::PingPongLoop {
             self.is_ping_pong = true;
@@ -191,6 +419,87 @@ impl Sample {
     }
 }
 
+// The width starts at 9 bits. Below 7 bits, the one value with only the top bit set announces a new width
+// in the next 3 bits. At 7 and 8 bits, the 8 values around the middle are new widths. At 9 bits, the top bit
+// set means the rest is the new width. Whatever doesn't change the width is a delta. A block running out
+// of bits early is played as silence from there, like Impulse Tracker does.
+fn unpack_it_block_8(block: &[u8], count: usize, it215: bool) -> Vec<i8> {
+    let mut reader = BitReader::new(block);
+    let mut result = Vec::with_capacity(count);
+    let mut width = 9;
+    let (mut delta, mut delta2) = (0i8, 0i8);
+
+    while result.len() < count {
+        let Ok(mut value) = reader.read(width) else { break };
+
+        if width < 7 {
+            if value == 1 << (width - 1) {
+                let Ok(new_width) = reader.read(3) else { break };
+                width = if new_width + 1 < width { new_width + 1 } else { new_width + 2 };
+                continue;
+            }
+        } else if width < 9 {
+            let border = (0xFF >> (9 - width)) - 4;
+            if value > border && value <= border + 8 {
+                value -= border;
+                width = if value < width { value } else { value + 1 };
+                continue;
+            }
+        } else if value & 0x100 != 0 {
+            width = (value + 1) & 0xFF;
+            if width == 0 || width > 9 { break; }
+            continue;
+        }
+
+        let shift = 8 - width.min(8);
+        delta = delta.wrapping_add(((value as u8) << shift) as i8 >> shift);
+        delta2 = delta2.wrapping_add(delta);
+        result.push(if it215 { delta2 } else { delta });
+    }
+
+    result.resize(count, 0);
+    result
+}
+
+// Same as the 8 bit version, with 4 bits for the new width below 7 bits and 16 values around the middle
+fn unpack_it_block_16(block: &[u8], count: usize, it215: bool) -> Vec<i16> {
+    let mut reader = BitReader::new(block);
+    let mut result = Vec::with_capacity(count);
+    let mut width = 17;
+    let (mut delta, mut delta2) = (0i16, 0i16);
+
+    while result.len() < count {
+        let Ok(mut value) = reader.read(width) else { break };
+
+        if width < 7 {
+            if value == 1 << (width - 1) {
+                let Ok(new_width) = reader.read(4) else { break };
+                width = if new_width + 1 < width { new_width + 1 } else { new_width + 2 };
+                continue;
+            }
+        } else if width < 17 {
+            let border = (0xFFFF >> (17 - width)) - 8;
+            if value > border && value <= border + 16 {
+                value -= border;
+                width = if value < width { value } else { value + 1 };
+                continue;
+            }
+        } else if value & 0x10000 != 0 {
+            width = (value + 1) & 0xFF;
+            if width == 0 || width > 17 { break; }
+            continue;
+        }
+
+        let shift = 16 - width.min(16);
+        delta = delta.wrapping_add(((value as u16) << shift) as i16 >> shift);
+        delta2 = delta2.wrapping_add(delta);
+        result.push(if it215 { delta2 } else { delta });
+    }
+
+    result.resize(count, 0);
+    result
+}
+
 #[derive(Debug, Clone)]
 pub struct VibratoEnvelope {
     pub vibrato_type: u8,
@@ -224,10 +533,32 @@ pub struct Instrument {
     pub name: String,
     pub idx: u8,
     pub sample_indexes: Vec<u8>,
+    /// The note each note plays as, IT keyboards can transpose every note on its own. Empty when notes play as they are
+    pub note_map: Vec<u8>,
     pub volume_envelope: Envelope,
     pub panning_envelope: Envelope,
     pub vibrato_envelope: VibratoEnvelope,
+    pub pitch_envelope: Envelope,
+    /// The pitch envelope moves the filter cutoff instead of the pitch
+    pub filter_envelope: bool,
+    /// Cutoff and resonance the filter starts with on a new note, 0-127
+    pub filter_cutoff: Option<u8>,
+    pub filter_resonance: Option<u8>,
     pub volume_fadeout: u16,
+    pub new_note_action: NewNoteAction,
+    pub duplicate_check_type: DuplicateCheckType,
+    pub duplicate_check_action: DuplicateCheckAction,
+    /// 0-128, scales everything the instrument plays
+    pub global_volume: u8,
+    /// Notes above the center note pan to the right, and notes below it to the left, by separation/8 per note
+    pub pitch_pan_separation: i8,
+    pub pitch_pan_center: u8,
+    /// How far a new note's volume may randomly stray, in percent
+    pub random_volume: u8,
+    /// How far a new note's panning may randomly stray, 0-64
+    pub random_panning: u8,
+    /// S3M AdLib instruments play on the OPL2 instead of a sample, which only keeps the volume and tuning
+    pub adlib: Option<AdlibPatch>,
 
     pub samples: Vec<Sample>,
 }
@@ -238,13 +569,34 @@ impl Instrument {
             name: "".to_string(),
             idx: 0,
             sample_indexes: vec![0u8; 96],
+            note_map: vec![],
             volume_envelope: Envelope::new(),
             panning_envelope: Envelope::new(),
             vibrato_envelope: VibratoEnvelope::new(),
+            pitch_envelope: Envelope::new(),
+            filter_envelope: false,
+            filter_cutoff: None,
+            filter_resonance: None,
             volume_fadeout: 0,
+            new_note_action: NewNoteAction::Cut,
+            duplicate_check_type: DuplicateCheckType::Off,
+            duplicate_check_action: DuplicateCheckAction::Cut,
+            global_volume: 128,
+            pitch_pan_separation: 0,
+            pitch_pan_center: 0,
+            random_volume: 0,
+            random_panning: 0,
+            adlib: None,
             samples: vec![Sample::new(); 1]
         }
     }
+
+    pub(crate) fn mapped_note(&self, note: u8) -> u8 {
+        match self.note_map.get(note as usize - 1) {
+            Some(mapped) => *mapped,
+            None => note,
+        }
+    }
 }
 
 pub (crate) type Instruments = Vec<Instrument>;
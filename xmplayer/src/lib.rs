@@ -63,6 +63,8 @@ pub mod pattern;
 pub mod song;
 pub mod tables;
 pub mod song_state;
+pub mod opl;
+pub mod paula;
 
 
 #[cfg(test)]
@@ -0,0 +1,430 @@
+    use crate::module_reader::{SongData, Patterns, SongType, FrequencyType};
+    use std::io::{Read, Seek, SeekFrom};
+    use binary_reader_io::{BinaryReader, BinarySeek};
+    use crate::pattern::Pattern;
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, ModuleMetadata};
+    use crate::module_reader::metadata::instrument_names;
+
+    const NUM_CHANNELS: usize = 8;
+    const ROW_COUNT: usize = 64;
+    const SAMPLE_HEADER_SIZE: u64 = 25;
+    const PATTERN_SIZE: u64 = (ROW_COUNT * NUM_CHANNELS * 3) as u64;
+
+    pub fn read_669<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < 0x1F1 {
+            return Err(LoadError::truncated().in_format(SongType::C669).at(Section::Header, 0));
+        }
+
+        read_669_header(&mut file, file_len).map_err(|e| e.in_format(SongType::C669).at(Section::Header, 0))
+    }
+
+    fn read_669_header<R: Read + Seek>(file: &mut R, file_len: u64) -> LoadResult<SongData>
+    {
+        let id = file.read_bytes(2)?;
+        let tracker_name = if id == "if".as_bytes() {
+            "Composer 669"
+        } else if id == "JN".as_bytes() {
+            "UNIS 669"
+        } else {
+            return Err(LoadError::wrong_format(SongType::C669));
+        };
+
+        // 3 lines of 36 characters
+        let song_message = file.read_bytes(108)?;
+        let lines: Vec<String> = song_message.chunks(36)
+            .map(|line| String::from_utf8_lossy(line).replace('\0', " ").trim_end().to_string())
+            .collect();
+
+        let sample_count = file.read_u8()?;
+        let pattern_count = file.read_u8()?;
+        let restart_position = file.read_u8()?;
+
+        if sample_count > 64 || pattern_count > 128 || restart_position >= 128 {
+            return Err(LoadError::invalid("Unknown 669 format"));
+        }
+
+        let orders = file.read_bytes(128)?;
+        let tempos = file.read_bytes(128)?;
+        let breaks = file.read_bytes(128)?;
+
+        let song_length = orders.iter().position(|x| *x == 0xFF).unwrap_or(orders.len());
+        if song_length == 0 {
+            return Err(LoadError::invalid("Unknown 669 format - song length"));
+        }
+        let pattern_order = orders[0..song_length].to_vec();
+        if pattern_order.iter().any(|x| *x >= pattern_count) {
+            return Err(LoadError::invalid("Unknown 669 format - order list"));
+        }
+        if breaks[0..pattern_count as usize].iter().any(|x| *x as usize >= ROW_COUNT) {
+            return Err(LoadError::invalid("Unknown 669 format - break list"));
+        }
+
+        let patterns_pos = 0x1F1 + sample_count as u64 * SAMPLE_HEADER_SIZE;
+        if file_len < patterns_pos {
+            return Err(LoadError::truncated().at(Section::Instrument, 0x1F1));
+        }
+        if file_len < patterns_pos + pattern_count as u64 * PATTERN_SIZE {
+            return Err(LoadError::truncated().at(Section::Pattern, patterns_pos));
+        }
+
+        let instruments_pos = file.offset();
+        let mut instruments = read_instruments(file, sample_count as usize).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+        let mut report = LoadReport::default();
+        let mut patterns = read_patterns(file, pattern_count as usize, &tempos, &breaks, &mut report).map_err(|e| e.at(Section::Pattern, patterns_pos))?;
+
+        read_sample_data(file, &mut instruments)?;
+
+        patterns.push(Patterns::new(64, NUM_CHANNELS));
+
+        Ok(SongData {
+            id: String::from_utf8_lossy(&id).to_string(),
+            name: lines[0].trim().to_string(),
+            song_type: SongType::C669,
+            tracker_name: tracker_name.to_string(),
+            song_length: song_length as u16,
+            restart_position: restart_position as u16,
+            channel_count: NUM_CHANNELS as u16,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: tempos[pattern_order[0] as usize].max(1) as u16,
+            bpm: 78, // 669 ticks run at ~32Hz, not the 50Hz of 125 BPM
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
+            song_message: lines.join("\n").trim_end().to_string(),
+            report,
+        })
+    }
+
+    pub(crate) fn read_669_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(0))?;
+        let id = file.read_bytes(2)?;
+        let tracker_name = if id == "if".as_bytes() {
+            "Composer 669"
+        } else if id == "JN".as_bytes() {
+            "UNIS 669"
+        } else {
+            return Err(LoadError::wrong_format(SongType::C669));
+        };
+
+        let song_message = file.read_bytes(108)?;
+        let lines: Vec<String> = song_message.chunks(36)
+            .map(|line| String::from_utf8_lossy(line).replace('\0', " ").trim_end().to_string())
+            .collect();
+
+        let sample_count = file.read_u8()?;
+        let pattern_count = file.read_u8()?;
+        let restart_position = file.read_u8()?;
+
+        if sample_count > 64 || pattern_count > 128 || restart_position >= 128 {
+            return Err(LoadError::invalid("Unknown 669 format"));
+        }
+
+        let orders = file.read_bytes(128)?;
+        let song_length = orders.iter().position(|x| *x == 0xFF).unwrap_or(orders.len());
+        if song_length == 0 {
+            return Err(LoadError::invalid("Unknown 669 format - song length"));
+        }
+
+        // tempo and break lists
+        file.seek(SeekFrom::Current(256))?;
+        let instruments_pos = file.offset();
+        let instruments = read_instruments(file, sample_count as usize).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+
+        let names = instrument_names(&instruments);
+        Ok(ModuleMetadata {
+            title: lines[0].trim().to_string(),
+            format: SongType::C669,
+            tracker_name: tracker_name.to_string(),
+            channel_count: NUM_CHANNELS as u16,
+            pattern_count: pattern_count as u16,
+            order_count: song_length as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message: lines.join("\n").trim_end().to_string(),
+        })
+    }
+
+    fn read_sample_data<R: Read + Seek>(f: &mut R, instruments: &mut [Instrument]) -> LoadResult<()> {
+        for instrument in instruments.iter_mut().skip(1) {
+            let sample_pos = f.offset();
+            instrument.samples[0].read_unsigned_data(f).map_err(|e| e.at(Section::Sample, sample_pos))?;
+        }
+        Ok(())
+    }
+
+    fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, tempos: &[u8], breaks: &[u8], report: &mut LoadReport) -> LoadResult<Vec<Patterns>> {
+        let mut patterns: Vec<Patterns> = vec![];
+        patterns.reserve_exact(pattern_count);
+
+        for pattern_idx in 0..pattern_count {
+            let mut pattern = Patterns::new(ROW_COUNT, NUM_CHANNELS);
+
+            // 669 effects keep running on the following rows until the channel gets a new note or a new effect
+            let mut running_effect = [None::<(u8, u8)>; NUM_CHANNELS];
+
+            for row in pattern.rows.iter_mut() {
+                for (channel_idx, channel) in row.channels.iter_mut().enumerate() {
+                    let data = file.read_bytes(3)?;
+                    *channel = read_note(&data, &mut running_effect[channel_idx]);
+                }
+            }
+
+            // the break list holds the last row played, so the pattern is simply shorter
+            pattern.rows.truncate(breaks[pattern_idx] as usize + 1);
+
+            // the tempo list sets the speed at the start of each pattern
+            let speed = tempos[pattern_idx];
+            if speed != 0 {
+                let first_row = &mut pattern.rows[0].channels;
+                match first_row.iter_mut().find(|p| p.effect == 0 && p.effect_param == 0) {
+                    Some(slot) => {
+                        slot.effect = 0xF;
+                        slot.effect_param = speed;
+                    }
+                    None => report.warn(format!("Pattern {} has an effect on every channel of its first row, leaving out its speed of {}", pattern_idx, speed)),
+                }
+            }
+
+            patterns.push(pattern);
+        }
+
+        Ok(patterns)
+    }
+
+    // 669 volumes go from 0 to 15, spread over 0 to 64 and rounded like OpenMPT does
+    fn volume(volume: u8) -> u8 {
+        ((volume as u16 * 64 + 8) / 15) as u8
+    }
+
+    //  BYTE 0   BYTE 1   BYTE 2
+    // nnnnnnii iiiivvvv eeeepppp
+    fn read_note(data: &[u8], running_effect: &mut Option<(u8, u8)>) -> Pattern {
+        let mut pattern = Pattern::new();
+
+        if data[0] < 0xFE {
+            pattern.note = (data[0] >> 2) + 25;
+            pattern.instrument = (((data[0] & 0x3) << 4) | (data[1] >> 4)) + 1;
+            *running_effect = None;
+        }
+
+        // 0xFE means volume only, 0xFF means no note, instrument or volume
+        if data[0] <= 0xFE {
+            pattern.volume = 0x10 + volume(data[1] & 0xF);
+        }
+
+        if data[2] != 0xFF {
+            let param = data[2] & 0xF;
+            *running_effect = match data[2] >> 4 {
+                0x0 => Some((0x1, param)),                          // portamento up
+                0x1 => Some((0x2, param)),                          // portamento down
+                0x2 => Some((0x3, param)),                          // slot-based tone portamento
+                0x3 => {                                            // frequency adjust - a one-shot fine slide
+                    pattern.effect = 0xE;
+                    pattern.effect_param = 0x10 | param.max(1);
+                    None
+                }
+                0x4 => Some((0x4, 0x80 | param)),                   // frequency vibrato
+                0x5 => {                                            // set tempo
+                    if param != 0 {
+                        pattern.effect = 0xF;
+                        pattern.effect_param = param;
+                    }
+                    None
+                }
+                0x6 => Some((0x19, if param == 0 { 0x04 } else { 0x40 })), // UNIS 669 balance slide, 0 = left
+                0x7 => {                                            // UNIS 669 slot retrig
+                    pattern.effect = 0xE;
+                    pattern.effect_param = 0x90 | param;
+                    None
+                }
+                _ => None,
+            };
+        }
+
+        if let Some((effect, effect_param)) = *running_effect {
+            pattern.effect = effect;
+            pattern.effect_param = effect_param;
+        }
+
+        pattern
+    }
+
+    fn read_sample<R: Read>(file: &mut R) -> LoadResult<Sample> {
+        let name = file.read_string(13);
+        let length = file.read_u32()?;
+        let mut loop_start = file.read_u32()?;
+        let mut loop_end = file.read_u32()?;
+
+        // no loop is usually marked by a loop end of 0xFFFFF
+        let loop_type = if loop_end <= length && loop_start.saturating_add(2) < loop_end { LoopType::ForwardLoop } else { LoopType::NoLoop };
+        if loop_type == LoopType::NoLoop {
+            loop_start = 0;
+            loop_end = 0;
+        }
+
+        Ok(Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: 64,
+            finetune: 0,
+            loop_type,
+            bitness: 8,
+            panning: 128,
+            relative_note: 0,
+            name,
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![],
+        })
+    }
+
+    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize) -> LoadResult<Vec<Instrument>> {
+        let mut instruments: Vec<Instrument> = vec![];
+
+        // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
+        instruments.reserve_exact(instrument_count + 1);
+
+        instruments.push(Instrument::new());
+
+        for instrument_idx in 1..instrument_count + 1 {
+            let mut instrument = Instrument::new();
+            let sample = read_sample(file)?;
+            instrument.name = sample.name.trim().to_string();
+            instrument.idx = instrument_idx as u8;
+            instrument.samples = vec![sample];
+            instruments.push(instrument);
+        }
+        Ok(instruments)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+
+        fn build_669(id: &[u8; 2]) -> Vec<u8> {
+            let mut data = vec![];
+            data.extend_from_slice(id);
+            let mut message = [b' '; 108];
+            message[..5].copy_from_slice(b"title");
+            data.extend_from_slice(&message);
+            data.push(1);                                       // samples
+            data.push(1);                                       // patterns
+            data.push(0);                                       // loop order
+
+            let mut orders = [0xFFu8; 128];
+            orders[0] = 0;
+            data.extend_from_slice(&orders);
+            let mut tempos = [0u8; 128];
+            tempos[0] = 4;
+            data.extend_from_slice(&tempos);
+            let mut breaks = [0u8; 128];
+            breaks[0] = 31;
+            data.extend_from_slice(&breaks);
+
+            data.extend_from_slice(&[b's'; 13]);
+            data.extend_from_slice(&16u32.to_le_bytes());       // length
+            data.extend_from_slice(&0u32.to_le_bytes());        // loop start
+            data.extend_from_slice(&0xFFFFFu32.to_le_bytes());  // loop end - no loop
+
+            let mut pattern = vec![0xFFu8; PATTERN_SIZE as usize];
+            // row 0, channel 0: note 24, sample 0, volume 15, porta up 3
+            pattern[0] = 24 << 2;
+            pattern[1] = 0x0F;
+            pattern[2] = 0x03;
+            data.extend_from_slice(&pattern);
+
+            data.extend_from_slice(&[0x80u8; 16]);
+            data
+        }
+
+        #[test]
+        fn test_read_669() {
+            for id in [b"if", b"JN"] {
+                let song = read_669(&mut Cursor::new(build_669(id))).unwrap();
+                assert_eq!(song.channel_count, 8);
+                assert_eq!(song.name, "title");
+                assert_eq!(song.tempo, 4);
+                assert_eq!(song.pattern_order, vec![0]);
+                assert_eq!(song.patterns[0].rows.len(), 32);
+
+                let first = &song.patterns[0].rows[0].channels;
+                assert_eq!(first[0].note, 49);
+                assert_eq!(first[0].instrument, 1);
+                assert_eq!(first[0].volume, 0x10 + 64);
+                assert_eq!((first[0].effect, first[0].effect_param), (0x1, 0x3));
+                assert_eq!((first[1].effect, first[1].effect_param), (0xF, 4));
+
+                // portamento keeps sliding on the following rows
+                let second = &song.patterns[0].rows[1].channels;
+                assert_eq!((second[0].effect, second[0].effect_param), (0x1, 0x3));
+                assert_eq!(second[0].note, 0);
+
+                assert_eq!(song.instruments[1].samples[0].loop_type, LoopType::NoLoop);
+            }
+        }
+
+        #[test]
+        fn test_volume() {
+            assert_eq!(volume(0), 0);
+            assert_eq!(volume(8), 34);
+            assert_eq!(volume(15), 64);
+        }
+
+        #[test]
+        fn test_speed_without_a_free_slot() {
+            let mut data = build_669(b"if");
+            let pattern_pos = 0x1F1 + SAMPLE_HEADER_SIZE as usize;
+            for channel in 0..NUM_CHANNELS {
+                data[pattern_pos + channel * 3 + 2] = 0x12;     // porta down 2
+            }
+            let song = read_669(&mut Cursor::new(data)).unwrap();
+            let first = &song.patterns[0].rows[0].channels;
+            assert!(first.iter().all(|p| (p.effect, p.effect_param) == (0x2, 0x2)));
+            assert_eq!(song.get_load_report().warnings().next(), Some("Pattern 0 has an effect on every channel of its first row, leaving out its speed of 4"));
+        }
+
+        #[test]
+        fn test_bogus_loop_start() {
+            let mut data = build_669(b"if");
+            data[0x1F1 + 17..0x1F1 + 21].copy_from_slice(&u32::MAX.to_le_bytes());
+            data[0x1F1 + 21..0x1F1 + 25].copy_from_slice(&8u32.to_le_bytes());
+            let song = read_669(&mut Cursor::new(data)).unwrap();
+            assert_eq!(song.instruments[1].samples[0].loop_type, LoopType::NoLoop);
+        }
+
+        #[test]
+        fn test_truncated_pattern() {
+            let mut data = build_669(b"if");
+            let patterns_pos = 0x1F1 + SAMPLE_HEADER_SIZE;
+            data.truncate(patterns_pos as usize + 100);
+            let error = read_669(&mut Cursor::new(data)).err().unwrap();
+            assert_eq!(error.to_string(), format!("Truncated pattern data (C669 pattern at offset 0x{:X})", patterns_pos));
+        }
+
+        #[test]
+        fn test_metadata() {
+            crate::module_reader::metadata::assert_metadata_matches_song(&build_669(b"if"));
+        }
+
+        #[test]
+        fn test_reject_bad_break_row() {
+            let mut data = build_669(b"if");
+            data[0x71 + 256] = 64;
+            assert!(read_669(&mut Cursor::new(data)).is_err());
+        }
+    }
@@ -0,0 +1,174 @@
+    use std::{fmt, io};
+    use crate::module_reader::SongType;
+    use crate::SimpleError;
+
+    /// The part of the module a reader was in when it gave up
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Section {
+        Header,
+        Pattern,
+        Instrument,
+        Sample,
+        /// The song message or comment
+        Message,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum LoadErrorKind {
+        /// Nothing recognized the data
+        UnknownFormat,
+        /// The signature doesn't match, so the data is in some other format
+        WrongFormat,
+        /// The data ends before the module does
+        Truncated,
+        /// The data is in the right format but broken, or uses something the reader doesn't know
+        Invalid(String),
+        /// Anything else the io layer complained about
+        Io(String),
+    }
+
+    /// A module loading error. Readers fill in the kind, and the format, section and offset get
+    /// added on the way out as far as they are known.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct LoadError {
+        kind:       LoadErrorKind,
+        format:     Option<SongType>,
+        section:    Option<Section>,
+        offset:     Option<u64>,
+    }
+
+    pub type LoadResult<T> = Result<T, LoadError>;
+
+    impl LoadError {
+        pub fn new(kind: LoadErrorKind) -> Self {
+            Self { kind, format: None, section: None, offset: None }
+        }
+
+        pub fn unknown_format() -> Self {
+            Self::new(LoadErrorKind::UnknownFormat)
+        }
+
+        pub fn wrong_format(format: SongType) -> Self {
+            Self::new(LoadErrorKind::WrongFormat).in_format(format)
+        }
+
+        pub fn truncated() -> Self {
+            Self::new(LoadErrorKind::Truncated)
+        }
+
+        pub fn invalid(message: &str) -> Self {
+            Self::new(LoadErrorKind::Invalid(message.to_string()))
+        }
+
+        /// Sets the format, unless it's already known
+        pub fn in_format(mut self, format: SongType) -> Self {
+            self.format.get_or_insert(format);
+            self
+        }
+
+        /// Sets the section and offset, unless they are already known. The innermost caller knows best.
+        pub fn at(mut self, section: Section, offset: u64) -> Self {
+            if self.section.is_none() {
+                self.section = Some(section);
+                self.offset = Some(offset);
+            }
+            self
+        }
+
+        pub fn kind(&self) -> &LoadErrorKind { &self.kind }
+        pub fn format(&self) -> Option<SongType> { self.format }
+        pub fn section(&self) -> Option<Section> { self.section }
+        pub fn offset(&self) -> Option<u64> { self.offset }
+
+        /// True if the data just isn't in the format that was tried, as opposed to being in it but broken
+        pub fn is_wrong_format(&self) -> bool {
+            matches!(self.kind, LoadErrorKind::WrongFormat | LoadErrorKind::UnknownFormat)
+        }
+    }
+
+    impl fmt::Display for Section {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let name = match self {
+                Section::Header => "header",
+                Section::Pattern => "pattern",
+                Section::Instrument => "instrument",
+                Section::Sample => "sample",
+                Section::Message => "song message",
+            };
+            write!(f, "{}", name)
+        }
+    }
+
+    impl fmt::Display for LoadError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.kind {
+                LoadErrorKind::UnknownFormat => write!(f, "Unknown module format")?,
+                LoadErrorKind::WrongFormat => match self.format {
+                    Some(format) => write!(f, "Not a {:?} module", format)?,
+                    None => write!(f, "Wrong module format")?,
+                },
+                LoadErrorKind::Truncated => match self.section {
+                    Some(section) => write!(f, "Truncated {} data", section)?,
+                    None => write!(f, "Truncated data")?,
+                },
+                LoadErrorKind::Invalid(message) | LoadErrorKind::Io(message) => write!(f, "{}", message)?,
+            }
+
+            if self.kind == LoadErrorKind::WrongFormat || self.kind == LoadErrorKind::UnknownFormat {
+                return Ok(());
+            }
+
+            match (self.format, self.section, self.offset) {
+                (Some(format), Some(section), Some(offset)) => write!(f, " ({:?} {} at offset 0x{:X})", format, section, offset),
+                (Some(format), _, _) => write!(f, " ({:?})", format),
+                (None, Some(section), Some(offset)) => write!(f, " ({} at offset 0x{:X})", section, offset),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl std::error::Error for LoadError {}
+
+    impl From<io::Error> for LoadError {
+        fn from(e: io::Error) -> Self {
+            match e.kind() {
+                io::ErrorKind::UnexpectedEof => Self::truncated(),
+                _ => Self::new(LoadErrorKind::Io(e.to_string())),
+            }
+        }
+    }
+
+    impl From<LoadError> for SimpleError {
+        fn from(e: LoadError) -> Self {
+            SimpleError::from(e.to_string())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::module_reader::open_module;
+
+        #[test]
+        fn test_truncated_sample_data() {
+            let mut data = std::fs::read("test_data/test.far").unwrap();
+            data.truncate(data.len() - 100);
+
+            let error = open_module(&data).err().unwrap();
+            assert_eq!(error.kind(), &LoadErrorKind::Truncated);
+            assert_eq!(error.format(), Some(SongType::FAR));
+            assert_eq!(error.section(), Some(Section::Sample));
+            assert!(error.offset().unwrap() < data.len() as u64);
+            assert!(error.to_string().starts_with("Truncated sample data (FAR sample at offset 0x"));
+        }
+
+        #[test]
+        fn test_simple_error_conversion() {
+            let error = LoadError::invalid("Bad pattern").in_format(SongType::XM).at(Section::Pattern, 0x1A3F);
+            assert_eq!(SimpleError::from(error).to_string(), "Bad pattern (XM pattern at offset 0x1A3F)");
+
+            // the innermost section and format win
+            let error = LoadError::truncated().at(Section::Sample, 10).at(Section::Header, 0).in_format(SongType::MOD).in_format(SongType::XM);
+            assert_eq!((error.format(), error.section(), error.offset()), (Some(SongType::MOD), Some(Section::Sample), Some(10)));
+        }
+    }
@@ -0,0 +1,343 @@
+    use crate::module_reader::{SongData, Patterns, SongType, FrequencyType};
+    use std::io::{Read, Seek, SeekFrom};
+    use binary_reader_io::{BinaryReader, BinarySeek};
+    use crate::pattern::Pattern;
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::channel_state::channel_state::clamp;
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+
+    const NUM_CHANNELS: usize = 16;
+    const HEADER_SIZE: u64 = 98;
+    const ORDERS_SIZE: u64 = 256 + 3 + 256 * 2;
+    const MAX_SAMPLES: usize = 64;
+
+    pub fn read_far<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < HEADER_SIZE + ORDERS_SIZE {
+            return Err(LoadError::truncated().in_format(SongType::FAR).at(Section::Header, 0));
+        }
+
+        read_far_header(&mut file, file_len).map_err(|e| e.in_format(SongType::FAR).at(Section::Header, 0))
+    }
+
+    fn read_far_header<R: Read + Seek>(file: &mut R, file_len: u64) -> LoadResult<SongData>
+    {
+        let id = file.read_bytes(4)?;
+        if id != [b'F', b'A', b'R', 0xFE] {
+            return Err(LoadError::wrong_format(SongType::FAR));
+        }
+
+        let name = file.read_string(40);
+        let eof = file.read_bytes(3)?;
+        if eof != [b'\r', b'\n', 0x1A] {
+            return Err(LoadError::wrong_format(SongType::FAR));
+        }
+
+        let header_len = file.read_u16()? as u64;
+        let version = file.read_u8()?;
+        let _channels_on = file.read_bytes(16)?;
+        let _editing_state = file.read_bytes(9)?;
+        let default_speed = file.read_u8()?;
+        let _panning = file.read_bytes(16)?; // no per-channel default panning in SongData (yet)
+        let _pattern_state = file.read_bytes(4)?;
+        let message_len = file.read_u16()?;
+        let message = file.read_bytes(message_len as usize)?;
+        let song_message = message.chunks(132)
+            .map(|line| String::from_utf8_lossy(line).replace('\0', " ").trim_end().to_string())
+            .collect::<Vec<String>>()
+            .join("\n").trim_end().to_string();
+
+        let orders = file.read_bytes(256)?;
+        let _stored_patterns = file.read_u8()?;
+        let song_length = file.read_u8()? as usize;
+        let restart_position = file.read_u8()?;
+        let pattern_sizes = file.read_u16_vec(256)?;
+
+        if song_length == 0 || header_len < HEADER_SIZE + message_len as u64 + ORDERS_SIZE || header_len > file_len {
+            return Err(LoadError::invalid("Unknown FAR format"));
+        }
+
+        let mut pattern_order = orders[0..song_length].to_vec();
+        let pattern_count = pattern_order.iter().cloned().max().unwrap_or(0) as usize + 1;
+
+        file.seek(SeekFrom::Start(header_len))?;
+        let patterns_pos = file.offset();
+        let mut patterns = read_patterns(file, &pattern_sizes[..pattern_count]).map_err(|e| e.at(Section::Pattern, patterns_pos))?;
+
+        // patterns that aren't in the order list are still stored before the samples
+        let unused_len: i64 = pattern_sizes[pattern_count..].iter().map(|s| *s as i64).sum();
+        file.seek(SeekFrom::Current(unused_len))?;
+
+        let instruments_pos = file.offset();
+        let instruments = read_instruments(file).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+
+        // fix empty patterns at end
+        let mut report = LoadReport::default();
+        fix_pattern_order(&mut pattern_order, song_length, patterns.len(), &mut report);
+
+        patterns.push(Patterns::new(64, NUM_CHANNELS));
+
+        Ok(SongData {
+            id: "FAR".to_string(),
+            name: name.trim().to_string(),
+            song_type: SongType::FAR,
+            tracker_name: format!("Farandole Composer {}.{}", version >> 4, version & 0xF),
+            song_length: song_length as u16,
+            restart_position: restart_position as u16,
+            channel_count: NUM_CHANNELS as u16,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: if default_speed == 0 { 6 } else { clamp(default_speed, 1, 0x1F) as u16 },
+            bpm: 80, // Farandole's default tempo is slower than the usual 50Hz tick
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
+            song_message,
+            report,
+        })
+    }
+
+    pub(crate) fn read_far_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file.read_bytes(4)? != [b'F', b'A', b'R', 0xFE] {
+            return Err(LoadError::wrong_format(SongType::FAR));
+        }
+
+        let title = file.read_string(40);
+        if file.read_bytes(3)? != [b'\r', b'\n', 0x1A] {
+            return Err(LoadError::wrong_format(SongType::FAR));
+        }
+
+        let header_len = file.read_u16()? as u64;
+        let version = file.read_u8()?;
+        file.seek(SeekFrom::Current(16 + 9 + 1 + 16 + 4))?;
+        let message_len = file.read_u16()?;
+        let message = file.read_bytes(message_len as usize)?;
+        let song_message = message.chunks(132)
+            .map(|line| String::from_utf8_lossy(line).replace('\0', " ").trim_end().to_string())
+            .collect::<Vec<String>>()
+            .join("\n").trim_end().to_string();
+
+        let orders = file.read_bytes(256)?;
+        let _stored_patterns = file.read_u8()?;
+        let song_length = file.read_u8()? as usize;
+        let _restart_position = file.read_u8()?;
+        let pattern_sizes = file.read_u16_vec(256)?;
+
+        if song_length == 0 || header_len < HEADER_SIZE + message_len as u64 + ORDERS_SIZE || header_len > file_len {
+            return Err(LoadError::invalid("Unknown FAR format"));
+        }
+
+        let pattern_count = orders[0..song_length].iter().cloned().max().unwrap_or(0) as u16 + 1;
+
+        // every stored pattern comes before the samples, used or not
+        let patterns_len: u64 = pattern_sizes.iter().map(|s| *s as u64).sum();
+        file.seek(SeekFrom::Start(header_len + patterns_len))?;
+
+        let instruments_pos = file.offset();
+        let names = read_sample_names(file).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::FAR,
+            tracker_name: format!("Farandole Composer {}.{}", version >> 4, version & 0xF),
+            channel_count: NUM_CHANNELS as u16,
+            pattern_count,
+            order_count: song_length as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message,
+        })
+    }
+
+    // read_instruments without the sample data
+    fn read_sample_names<R: Read + Seek>(file: &mut R) -> LoadResult<Vec<String>> {
+        let sample_map = file.read_bytes(MAX_SAMPLES / 8)?;
+        let sample_count = (0..MAX_SAMPLES).rev().find(|i| sample_map[i / 8] & (1 << (i % 8)) != 0).map_or(0, |i| i + 1);
+
+        let mut names = vec![];
+        for i in 0..sample_count {
+            if sample_map[i / 8] & (1 << (i % 8)) != 0 {
+                let sample = read_sample(file)?;
+                file.seek(SeekFrom::Current(sample.length as i64 * sample.bitness as i64 / 8))?;
+                names.push(sample.name.trim().to_string());
+            } else {
+                names.push("".to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn read_patterns<R: Read + Seek>(file: &mut R, sizes: &[u16]) -> LoadResult<Vec<Patterns>> {
+        let mut patterns: Vec<Patterns> = vec![];
+        patterns.reserve_exact(sizes.len() + 1);
+
+        for size in sizes {
+            let row_count = (*size as usize).saturating_sub(2) / (NUM_CHANNELS * 4);
+            if row_count == 0 {
+                patterns.push(Patterns::new(64, NUM_CHANNELS));
+                file.seek(SeekFrom::Current(*size as i64))?;
+                continue;
+            }
+
+            let break_row = file.read_u8()? as usize;
+            let _tempo = file.read_u8()?;
+
+            let mut pattern = Patterns::new(row_count, NUM_CHANNELS);
+            let data = file.read_bytes(row_count * NUM_CHANNELS * 4)?;
+            for (note, channel) in data.chunks_exact(4).zip(pattern.rows.iter_mut().flat_map(|r| r.channels.iter_mut())) {
+                *channel = read_note(note);
+            }
+
+            // skip whatever is left over
+            file.seek(SeekFrom::Current((*size as usize - 2 - row_count * NUM_CHANNELS * 4) as i64))?;
+
+            // the break row is one less than the last row played
+            if break_row > 0 && break_row + 2 < row_count {
+                pattern.rows.truncate(break_row + 2);
+            }
+
+            patterns.push(pattern);
+        }
+
+        Ok(patterns)
+    }
+
+    // note, instrument, volume, eeeepppp
+    fn read_note(data: &[u8]) -> Pattern {
+        let mut pattern = Pattern::new();
+
+        if (1..=72).contains(&data[0]) {
+            pattern.note = data[0] + 24;
+            // a sample past the last one leaves the channel's sample as it is
+            if (data[1] as usize) < MAX_SAMPLES {
+                pattern.instrument = data[1] + 1;
+            }
+        }
+
+        // 1-16
+        if data[2] > 0 {
+            pattern.volume = 0x10 + ((clamp(data[2], 1, 16) - 1) as u16 * 64 / 15) as u8;
+        }
+
+        let param = data[3] & 0xF;
+        let (effect, effect_param) = match data[3] >> 4 {
+            0x1 => (0x1, param << 2),                   // pitch adjust up
+            0x2 => (0x2, param << 2),                   // pitch adjust down
+            0x3 => (0x3, param << 2),                   // portamento to note
+            0x4 => (0xE, 0x90 | param),                 // retrigger
+            0x5 => (0x4, param),                        // set vibrato depth
+            0x6 | 0x9 => (0x4, param << 4),             // vibrato, sustained vibrato
+            0x7 => (0xA, param << 4),                   // volume slide up
+            0x8 => (0xA, param),                        // volume slide down
+            0xB => (0x8, param * 0x11),                 // balance
+            0xC => (0xE, 0xD0 | param),                 // note offset
+            0xF if param != 0 => (0xF, param),          // speed
+            _ => (0, 0),                                // volume portamento and fine tempo
+        };
+        pattern.effect = effect;
+        pattern.effect_param = effect_param;
+
+        pattern
+    }
+
+    fn read_sample<R: Read>(file: &mut R) -> LoadResult<Sample> {
+        let name = file.read_string(32);
+        let mut length = file.read_u32()?;
+        let _finetune = file.read_u8()?;
+        let volume = file.read_u8()?;
+        let mut loop_start = file.read_u32()?;
+        let mut loop_end = file.read_u32()?;
+        let sample_type = file.read_u8()?;
+        let loop_mode = file.read_u8()?;
+
+        let bitness = if sample_type & 1 != 0 { 16 } else { 8 };
+        if bitness == 16 { // length is in bytes
+            length /= 2;
+            loop_start /= 2;
+            loop_end /= 2;
+        }
+
+        // fix overflown loop
+        if loop_end > length {
+            loop_end = length;
+        }
+
+        let loop_type = if loop_mode & 8 != 0 && loop_start.saturating_add(2) < loop_end { LoopType::ForwardLoop } else { LoopType::NoLoop };
+        if loop_type == LoopType::NoLoop {
+            loop_start = 0;
+            loop_end = 0;
+        }
+
+        Ok(Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: clamp(volume.saturating_mul(4), 0, 64),
+            finetune: 0,
+            loop_type,
+            bitness,
+            panning: 128,
+            relative_note: 0,
+            name,
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![],
+        })
+    }
+
+    // A bit map of the stored samples, each one followed by its data
+    fn read_instruments<R: Read + Seek>(file: &mut R) -> LoadResult<Vec<Instrument>> {
+        let sample_map = file.read_bytes(MAX_SAMPLES / 8)?;
+        let sample_count = (0..MAX_SAMPLES).rev().find(|i| sample_map[i / 8] & (1 << (i % 8)) != 0).map_or(0, |i| i + 1);
+
+        let mut instruments: Vec<Instrument> = vec![];
+
+        // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
+        instruments.reserve_exact(sample_count + 1);
+
+        instruments.push(Instrument::new());
+
+        for instrument_idx in 1..sample_count + 1 {
+            let mut instrument = Instrument::new();
+            instrument.idx = instrument_idx as u8;
+
+            let i = instrument_idx - 1;
+            if sample_map[i / 8] & (1 << (i % 8)) != 0 {
+                let mut sample = read_sample(file)?;
+                let sample_pos = file.offset();
+                sample.read_non_packed_data(file).map_err(|e| e.at(Section::Sample, sample_pos))?;
+                instrument.name = sample.name.trim().to_string();
+                instrument.samples = vec![sample];
+            }
+            instruments.push(instrument);
+        }
+        Ok(instruments)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_notes_and_samples_out_of_range() {
+            let note = read_note(&[72, 63, 0, 0]);
+            assert_eq!((note.note, note.instrument), (96, 64));
+            assert_eq!(read_note(&[73, 0, 0, 0]).note, 0);
+            assert_eq!(read_note(&[250, 0, 0, 0]).note, 0);
+            let note = read_note(&[1, 255, 0, 0]);
+            assert_eq!((note.note, note.instrument), (25, 0));
+        }
+    }
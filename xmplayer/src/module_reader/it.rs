@@ -1,216 +1,433 @@
 use std::io::{Read, Seek, SeekFrom};
-use std::io;
-use crate::{SimpleResult, SimpleError};
+use crate::module_reader::{LoadError, LoadResult, LoadReport, ModuleMetadata, Section, SongType, bytes_left};
 
-use crate::envelope::{EnvelopePoint, EnvelopePoints};
-use crate::instrument::{Instrument, LoopType, Sample};
+use crate::envelope::{Envelope, EnvelopePoint, MAX_ENVELOPE_POINTS};
+use crate::instrument::{DuplicateCheckAction, DuplicateCheckType, Instrument, LoopType, NewNoteAction, Sample, SustainLoop};
 use binary_reader_io::BinaryReader;
-use crate::module_reader::{Patterns, Row, SongData};
+use crate::module_reader::{Patterns, SongData};
 use crate::pattern::Pattern;
+use crate::module_reader;
+use crate::module_reader::s3m::fix_effects;
+
+    const NOTE_COUNT: usize = 120;
+    const USE_INSTRUMENTS: u16 = 0x04;
+    const FILTER_ENVELOPE: u8 = 0x80;
+
+    // Rows are lists of channel entries ending with a zero. An entry can reuse the mask of the previous entry on its
+    // channel, and through the mask its note, instrument, volume and command.
+    fn read_pattern<R: Read>(file: &mut R, channel_count: usize) -> LoadResult<Patterns> {
+        let _length = file.read_u16()?;
+        let row_count = file.read_u16()?;
+        let _ = file.read_u32()?;
 
-    fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, channel_count: usize) -> SimpleResult<Vec<Patterns>> {
-        let mut patterns: Vec<Patterns> = vec![];
-        patterns.reserve_exact(pattern_count as usize);
-
-        for _pattern_idx in 0..pattern_count {
-            let _pattern_header_size = file.read_u32()?;
-            let _pattern_type = file.read_u8()?;
-            let row_count = file.read_u16()?;
-            let pattern_size = file.read_u16()?;
-
-            let mut pos = 0usize;
-            if pattern_size == 0 {
-                patterns.push(Patterns {
-                    rows: vec![Row {
-                        channels: vec![Pattern {
-                            note: 0,
-                            instrument: 0,
-                            volume: 0,
-                            effect: 0,
-                            effect_param: 0
-                        }; channel_count]
-                    }; 64]
-                });
-                continue;
-            }
+        let mut pattern = Patterns::new(row_count as usize, channel_count);
+        let mut masks = [0u8; 64];
+        let mut last_values = [Pattern::new(); 64];
+
+        let mut last_effect_param   = [0u8; 64];
+        let mut last_effect         = [0u8; 64];
+        let mut last_vibrato_param  = [0u8; 64];
+        let mut last_instrument     = [0u8; 64];
+
+        for row in pattern.rows.iter_mut() {
+            loop {
+                let channel_variable = file.read_u8()?;
+                if channel_variable == 0 { break; }
 
-            let mut rows: Vec<Row> = vec![];
-            rows.reserve_exact(row_count as usize);
-            for _row_idx in 0..row_count {
-                let mut channels: Vec<Pattern> = vec![];
-                channels.reserve_exact(channel_count);
-                for _channel_idx in 0..channel_count {
-                    let flags = file.read_u8()?;
-                    channels.push(if flags & 0x80 == 0x80 {
-                        pos += 1;
-                        let note = if flags & 1 == 1 {
-                            pos += 1;
-                            file.read_u8()?
-                        } else { 0 };
-                        let instrument = if flags & 2 == 2 {
-                            pos += 1;
-                            file.read_u8()?
-                        } else { 0 };
-                        let volume = if flags & 4 == 4 {
-                            pos += 1;
-                            file.read_u8()?
-                        } else { 0 };
-                        let effect = if flags & 8 == 8 {
-                            pos += 1;
-                            file.read_u8()?
-                        } else { 0 };
-                        let effect_param = if flags & 16 == 16 {
-                            pos += 1;
-                            file.read_u8()?
-                        } else { 0 };
-                        Pattern {
-                            note,
-                            instrument,
-                            volume,
-                            effect,
-                            effect_param
+                let channel_id = ((channel_variable - 1) & 63) as usize;
+                if channel_variable & 0x80 == 0x80 {
+                    masks[channel_id] = file.read_u8()?;
+                }
+                let mask = masks[channel_id];
+                let last = &mut last_values[channel_id];
+
+                if mask & 0x01 == 0x01 { last.note = file.read_u8()?; }
+                if mask & 0x02 == 0x02 { last.instrument = file.read_u8()?; }
+                if mask & 0x04 == 0x04 { last.volume = file.read_u8()?; }
+                if mask & 0x08 == 0x08 {
+                    last.effect = file.read_u8()?;
+                    last.effect_param = file.read_u8()?;
+                }
+
+                let mut entry = Pattern::new();
+                if mask & 0x11 != 0 { entry.note = convert_note(last.note); }
+                if mask & 0x22 != 0 { entry.instrument = last.instrument; }
+                if mask & 0x44 != 0 { entry.volume = convert_volume(last.volume); }
+
+                if mask & 0x88 != 0 {
+                    entry.effect = last.effect;
+                    entry.effect_param = last.effect_param;
+                    // the effects IT does its own way get codes of their own, the rest work like in S3M
+                    match (entry.effect, entry.get_x()) {
+                        (19, 0x5..=0x7 | 0x9 | 0xA | 0xF) => { entry.effect = 0x1C; } // S, for the S commands XM has nothing like
+                        (19, 0x8) => { // S8x, set panning
+                            entry.effect = 0xE;
+                            entry.effect_param = 0x80 | entry.get_y();
+                        }
+                        (13, _) => { entry.effect = 0x16; } // M, set channel volume
+                        (14, _) => { entry.effect = 0x17; } // N, channel volume slide
+                        (16, _) => { entry.effect = 0x19; } // P, panning slide
+                        (20, 0x0 | 0x1) => { entry.effect = 0x24; } // T0x/T1x, tempo slide, past Z as it has no letter of its own
+                        (22, _) => { // V, set global volume
+                            entry.effect = 0x10;
+                            entry.effect_param = entry.effect_param.min(0x80);
                         }
-                    } else {
-                        let note = flags;
-                        let instrument = file.read_u8()?;
-                        let volume = file.read_u8()?;
-                        let effect = file.read_u8()?;
-                        let effect_param = file.read_u8()?;
-                        pos += 5;
-
-                        Pattern {
-                            note,
-                            instrument,
-                            volume,
-                            effect,
-                            effect_param
+                        (23, _) => { entry.effect = 0x20; } // W, global volume slide
+                        (24, _) => { entry.effect = 0x8; }  // X, set panning
+                        (25, _) => { entry.effect = 0x22; } // Y, panbrello
+                        (26, _) => { entry.effect = 0x23; } // Z, the filter macros
+                        _ => {
+                            fix_effects(
+                                &mut entry,
+                                &mut last_effect[channel_id],
+                                &mut last_effect_param[channel_id],
+                                &mut last_vibrato_param[channel_id],
+                                &mut last_instrument[channel_id]
+                            );
                         }
-                    });
+                    }
+                }
+
+                if entry.instrument != 0 && entry.effect != 0x3 {
+                    last_instrument[channel_id] = entry.instrument;
+                }
+
+                if channel_id < channel_count {
+                    row.channels[channel_id] = entry;
                 }
-                rows.push(Row { channels });
-            }
-            if pattern_size as usize != pos {
-                return Err(SimpleError::from(format!("size {} != pos {}", pattern_size, pos)));
             }
-            patterns.push(Patterns { rows })
         }
 
-        Ok(patterns)
+        Ok(pattern)
+    }
+
+    // Notes go from C-0 up to B-9, the samples are tuned an octave down to make up for XM's C-0 being note 1.
+    // Everything between the last note and the note cut is a note fade.
+    fn convert_note(note: u8) -> u8 {
+        match note {
+            0..=95 => note + 1,
+            96..=119 => 0,
+            255 => 97,
+            254 => Pattern::NOTE_CUT,
+            _ => Pattern::NOTE_FADE,
+        }
     }
 
-    #[allow(dead_code)]
-    fn read_envelope<R: Read>(file: &mut R) -> EnvelopePoints {
-        let mut result = [EnvelopePoint::new(); 12];
+    // The pitch slides go where XM's volume column has nothing, 0x01-0x0A and 0x51-0x5A, which the player only reads
+    // as slides in IT songs
+    fn convert_volume(volume: u8) -> u8 {
+        const PORTA_SPEEDS: [u8; 10] = [0, 1, 4, 8, 16, 32, 64, 96, 128, 255];
+        match volume {
+            0..=64 => 0x10 + volume,
+            105..=114 => 0x01 + (volume - 105), // pitch slide down, with the parameter one up so 0 stays empty
+            115..=124 => 0x51 + (volume - 115), // pitch slide up
+            65..=74 => 0x90 | (volume - 65),    // fine volume up
+            75..=84 => 0x80 | (volume - 75),    // fine volume down
+            85..=94 => 0x70 | (volume - 85),    // volume slide up
+            95..=104 => 0x60 | (volume - 95),   // volume slide down
+            128..=192 => 0xC0 | ((volume - 128) as u16 * 15 / 64) as u8,
+            193..=202 => match PORTA_SPEEDS[(volume - 193) as usize] {
+                0 => 0xF0,
+                speed => 0xF0 | (speed / 16).clamp(1, 15),
+            },
+            203..=212 => 0xB0 | (volume - 203), // vibrato depth
+            _ => 0,
+        }
+    }
 
-        for point in &mut result {
-            point.frame = file.read_u16().unwrap();
-            point.value = file.read_u16().unwrap();
+    // IT envelopes have up to 25 nodes, a loop and a sustain loop, both with a start and an end node.
+    // value_offset moves the signed panning and pitch values up to where the XM ones are, centered around 32
+    fn read_envelope<R: Read>(file: &mut R, value_offset: i16) -> LoadResult<(Envelope, u8)> {
+        const ON: u8 = 0x01;
+        const LOOP: u8 = 0x02;
+        const SUSTAIN_LOOP: u8 = 0x04;
+        const CARRY: u8 = 0x08;
+
+        let flags = file.read_u8()?;
+        let node_count = file.read_u8()?;
+        let loop_start = file.read_u8()?;
+        let loop_end = file.read_u8()?;
+        let sustain_start = file.read_u8()?;
+        let sustain_end = file.read_u8()?;
+
+        let mut points = [EnvelopePoint::new(); MAX_ENVELOPE_POINTS];
+        for point in &mut points {
+            let value = file.read_i8()?;
+            let frame = file.read_u16()?;
+            *point = EnvelopePoint { frame, value: (value as i16 + value_offset).clamp(0, 64) as u16 };
         }
-        result
+        let _ = file.read_u8()?;
+
+        let size = node_count.min(MAX_ENVELOPE_POINTS as u8);
+        let last = size.saturating_sub(1);
+        let envelope = Envelope {
+            points,
+            size,
+            sustain_point: sustain_start.min(last),
+            sustain_end_point: sustain_end.clamp(sustain_start, last),
+            loop_start_point: loop_start.min(last),
+            loop_end_point: loop_end.clamp(loop_start, last),
+            on: flags & ON == ON,
+            sustain: flags & SUSTAIN_LOOP == SUSTAIN_LOOP,
+            has_loop: flags & LOOP == LOOP,
+            separate_sustain_loop: true,
+            carry: flags & CARRY == CARRY,
+        };
+        Ok((envelope, flags))
     }
 
-    #[allow(dead_code)]
-    fn read_samples<R: Read>(file: &mut R, sample_count: usize) -> SimpleResult<Vec<Sample>> {
+    // Along with the samples, whether they set their own panning. Those that don't take it from the instrument.
+    fn read_samples<R: Read + Seek>(file: &mut R, sample_ptrs: &[u32], lenient: bool, report: &mut LoadReport) -> LoadResult<(Vec<Sample>, Vec<bool>)> {
         let mut samples: Vec<Sample> = vec![];
-        samples.reserve_exact(sample_count as usize);
-
-        for _sample_idx in 0..sample_count {
-            let mut length = file.read_u32()?;
-            let mut loop_start = file.read_u32()?;
-            let mut loop_len = file.read_u32()?;
-            let volume = file.read_u8()?;
-            let finetune = file.read_i8()?;
-            let flags = file.read_u8()?;
-            let panning = file.read_u8()?;
-            let relative_note = file.read_i8()?;
-            let _reserved = file.read_u8()?;
-            let name = file.read_string(22);
-
-            let bitness = if (flags & 16) == 16 { 16 } else { 8 };
-            if bitness == 16 { // length is in bits
-                length /= 2;
-                loop_start /= 2;
-                loop_len /= 2;
-            }
+        let mut pannings: Vec<bool> = vec![];
+        samples.reserve_exact(sample_ptrs.len());
+        pannings.reserve_exact(sample_ptrs.len());
+
+        for (sample_idx, sample_ptr) in sample_ptrs.iter().enumerate() {
+            let (sample, has_panning) = read_sample(file, *sample_ptr, sample_idx + 1, lenient, report).map_err(|e| e.at(Section::Sample, *sample_ptr as u64))?;
+            samples.push(sample);
+            pannings.push(has_panning);
+        }
+        Ok((samples, pannings))
+    }
 
-            let loop_type = LoopType::from_flags(flags);
-            match loop_type {
-                LoopType::NoLoop => {
-                    loop_start = 0;
-                    loop_len = length;
-                }
-                _ => {}
+    fn read_sample<R: Read + Seek>(file: &mut R, sample_ptr: u32, sample_idx: usize, lenient: bool, report: &mut LoadReport) -> LoadResult<(Sample, bool)> {
+        const HAS_DATA: u8 = 0x01;
+        const SIXTEEN_BIT: u8 = 0x02;
+        const COMPRESSED: u8 = 0x08;
+        const LOOP: u8 = 0x10;
+        const SUSTAIN_LOOP: u8 = 0x20;
+        const PING_PONG: u8 = 0x40;
+        const PING_PONG_SUSTAIN: u8 = 0x80;
+
+        const SIGNED: u8 = 0x01;
+        const IT215: u8 = 0x04;
+
+        file.seek(SeekFrom::Start(sample_ptr as u64))?;
+        let id = file.read_string(4);
+        if id != "IMPS" {
+            return Err(LoadError::invalid("Error in reading IT sample - wrong ID"));
+        }
+
+        let _dos_name = file.read_string(12);
+        let _zero = file.read_u8()?;
+        let _global_volume = file.read_u8()?;
+        let flags = file.read_u8()?;
+        let volume = file.read_u8()?;
+        let name = file.read_string(26);
+        let convert = file.read_u8()?;
+        let default_pan = file.read_u8()?;
+        let length = file.read_u32()?;
+        let loop_start = file.read_u32()?;
+        let loop_end = file.read_u32()?;
+        let c5speed = file.read_u32()?;
+        let sustain_loop_start = file.read_u32()?;
+        let sustain_loop_end = file.read_u32()?;
+        let data_ptr = file.read_u32()?;
+        let _vibrato = file.read_bytes(4)?;
+
+        let length = if flags & HAS_DATA == HAS_DATA { length } else { 0 };
+        // the top bit says the default panning is used at all
+        let has_panning = default_pan & 0x80 == 0x80;
+
+        // Impulse Tracker ignores loops that don't fit in the sample
+        let has_loop = flags & LOOP == LOOP && loop_start < loop_end && loop_end <= length;
+        let (loop_start, loop_end) = if has_loop { (loop_start, loop_end) } else { (0, length) };
+        let loop_type = match (has_loop, flags & PING_PONG == PING_PONG) {
+            (false, _) => LoopType::NoLoop,
+            (true, false) => LoopType::ForwardLoop,
+            (true, true) => LoopType::PingPongLoop,
+        };
+
+        let has_sustain_loop = flags & SUSTAIN_LOOP == SUSTAIN_LOOP && sustain_loop_start < sustain_loop_end && sustain_loop_end <= length;
+        let sustain_loop = SustainLoop {
+            start: sustain_loop_start,
+            end: sustain_loop_end,
+            loop_type: match (has_sustain_loop, flags & PING_PONG_SUSTAIN == PING_PONG_SUSTAIN) {
+                (false, _) => LoopType::NoLoop,
+                (true, false) => LoopType::ForwardLoop,
+                (true, true) => LoopType::PingPongLoop,
+            },
+            sample: None,
+        };
+
+        let (finetune, relative_note) = module_reader::c2spd_to_finetune_relnote(if c5speed == 0 { 8363 } else { c5speed });
+        let relative_note = relative_note - 12;
+
+        let mut sample = Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: volume.min(64),
+            finetune,
+            loop_type,
+            bitness: if flags & SIXTEEN_BIT == SIXTEEN_BIT { 16 } else { 8 },
+            panning: if has_panning { it_panning(default_pan & 0x7F) } else { 128 },
+            relative_note,
+            name,
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop,
+            data: vec![],
+        };
+
+        if sample.length == 0 {
+            return Ok((sample, has_panning));
+        }
+
+        // stereo samples have the right channel after the left one, only the left one is played
+        file.seek(SeekFrom::Start(data_ptr as u64))?;
+        let compressed = flags & COMPRESSED == COMPRESSED;
+        // the length of a compressed sample doesn't say how many bytes it takes
+        if lenient && !compressed && sample.fit_length(bytes_left(file)?) {
+            report.repair(format!("Sample {} runs past the end of the file, cut to {} samples", sample_idx, sample.length));
+            if sample.fit_loops() {
+                report.repair(format!("Sample {} loops past its end, the loop is cut to {}-{}", sample_idx, sample.loop_start, sample.loop_end));
             }
+        }
+        let result = if compressed {
+            sample.read_it_compressed_data(file, convert & IT215 == IT215)
+        } else if convert & SIGNED == SIGNED {
+            sample.read_non_packed_data(file)
+        } else {
+            sample.read_unsigned_data(file)
+        };
+        match result {
+            Err(e) if lenient && compressed => {
+                report.repair(format!("Sample {} can't be unpacked ({}), left silent", sample_idx, e));
+                sample.fit_length(0);
+                sample.fit_loops();
+            }
+            result => result.map_err(|e| e.at(Section::Sample, data_ptr as u64))?,
+        }
 
-            samples.push(Sample {
-                length,
-                loop_start,
-                loop_end: loop_start + loop_len,
-                loop_len,
-                volume,
-                finetune,
-                loop_type,
-                bitness,
-                panning,
-                relative_note,
-                name,
-                is_ping_pong: false,
-                original_loop_end: 0,
-                data: vec![],
-            })
-        }
-
-        for sample in &mut samples {
-            sample.read_data(file)?;
-        }
-
-        Ok(samples)
+        Ok((sample, has_panning))
     }
 
-    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_ptrs: &Vec<u32>) -> SimpleResult<Vec<Instrument>> {
-        let mut instruments: Vec<Instrument> = vec![];
-        let instrument_count = instrument_ptrs.len();
+    // IT pans from 0 to 64
+    fn it_panning(panning: u8) -> u8 {
+        (panning.min(64) as u32 * 255 / 64) as u8
+    }
 
-        instruments.reserve_exact(instrument_count + 1);
+    // Without instruments, each sample plays as an instrument of its own
+    fn sample_instruments(samples: Vec<Sample>) -> Vec<Instrument> {
+        let mut instruments: Vec<Instrument> = vec![];
+        instruments.reserve_exact(samples.len() + 1);
         instruments.push(Instrument::new());
 
-        for instrument_ptr in instrument_ptrs {
+        for (idx, sample) in samples.into_iter().enumerate() {
             let mut instrument = Instrument::new();
-            file.seek(SeekFrom::Start(*instrument_ptr as u64))?;
-            let id = file.read_string(4);
-            if id != "IMPI" {
-                return Err(SimpleError::new("Error in reading IT instrument - wrong ID"));
+            instrument.name = sample.name.trim().to_string();
+            instrument.idx = (idx + 1) as u8;
+            instrument.sample_indexes = vec![0u8; NOTE_COUNT];
+            instrument.samples = vec![sample];
+            instruments.push(instrument);
+        }
+        instruments
+    }
+
+    // The keyboard table has a note to play and a sample for each of the 120 notes. The instrument gets its own
+    // copy of the samples it uses, after an empty one at index 0 for the notes without a sample. Samples without a
+    // panning of their own get the instrument's.
+    fn map_samples(instrument: &mut Instrument, keyboard: &[u8], samples: &[Sample], sample_pannings: &[bool], panning: Option<u8>) {
+        instrument.note_map = keyboard.chunks_exact(2).map(|entry| entry[0].min(119) + 1).collect();
+
+        let mut used: Vec<usize> = vec![];
+        instrument.sample_indexes = keyboard.chunks_exact(2).map(|entry| {
+            let sample = entry[1] as usize;
+            if sample == 0 || sample > samples.len() {
+                return 0;
             }
+            let idx = used.iter().position(|s| *s == sample).unwrap_or_else(|| {
+                used.push(sample);
+                used.len() - 1
+            });
+            (idx + 1) as u8
+        }).collect();
+
+        instrument.samples = std::iter::once(Sample::new()).chain(used.iter().map(|sample| {
+            let mut copy = samples[sample - 1].clone();
+            if let Some(panning) = panning && !sample_pannings[sample - 1] {
+                copy.panning = panning;
+            }
+            copy
+        })).collect();
+    }
 
-            let _dos_name = file.read_string(12);
-            let _zero = file.read_u8()?;
-            let _nna = file.read_u8()?;
-            let _dct = file.read_u8()?;
-            let _dca = file.read_u8()?;
-            let _fade_out = file.read_u16()?;
-            let _pps = file.read_i8()?;
-            let _ppc = file.read_u8()?;
-            let _gv = file.read_u8()?;
-            let _dfp = file.read_u8()?;
-            let _rvv = file.read_u8()?;
-            let _rpv = file.read_u8()?;
-            let _tv = file.read_u16()?;
-            let _nos = file.read_u8()?;
-            let _x = file.read_u8()?;
-            let name = file.read_string(26);
-            let _ifc = file.read_u8()?;
-            let _ifr = file.read_u8()?;
-            let _mc = file.read_u8()?;
-            let _mp = file.read_u8()?;
-            let _mb = file.read_u16()?;
-            let _nsi = file.read_bytes(240)?;
-
-            instrument.name = name.trim().to_string();
+    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_ptrs: &Vec<u32>, samples: &[Sample], sample_pannings: &[bool]) -> LoadResult<Vec<Instrument>> {
+        let mut instruments: Vec<Instrument> = vec![];
+        let instrument_count = instrument_ptrs.len();
+
+        instruments.reserve_exact(instrument_count + 1);
+        instruments.push(Instrument::new());
+
+        for (idx, instrument_ptr) in instrument_ptrs.iter().enumerate() {
+            let mut instrument = read_instrument(file, *instrument_ptr, samples, sample_pannings).map_err(|e| e.at(Section::Instrument, *instrument_ptr as u64))?;
+            instrument.idx = (idx + 1) as u8;
             instruments.push(instrument);
         }
         Ok(instruments)
     }
 
+    fn read_instrument<R: Read + Seek>(file: &mut R, instrument_ptr: u32, samples: &[Sample], sample_pannings: &[bool]) -> LoadResult<Instrument> {
+        let mut instrument = Instrument::new();
+        file.seek(SeekFrom::Start(instrument_ptr as u64))?;
+        let id = file.read_string(4);
+        if id != "IMPI" {
+            return Err(LoadError::invalid("Error in reading IT instrument - wrong ID"));
+        }
+
+        let _dos_name = file.read_string(12);
+        let _zero = file.read_u8()?;
+        let nna = file.read_u8()?;
+        let dct = file.read_u8()?;
+        let dca = file.read_u8()?;
+        let fade_out = file.read_u16()?;
+        let pps = file.read_i8()?;
+        let ppc = file.read_u8()?;
+        let gv = file.read_u8()?;
+        let dfp = file.read_u8()?;
+        let rvv = file.read_u8()?;
+        let rpv = file.read_u8()?;
+        let _tv = file.read_u16()?;
+        let _nos = file.read_u8()?;
+        let _x = file.read_u8()?;
+        let name = file.read_string(26);
+        let ifc = file.read_u8()?;
+        let ifr = file.read_u8()?;
+        let _mc = file.read_u8()?;
+        let _mp = file.read_u8()?;
+        let _mb = file.read_u16()?;
+        let keyboard = file.read_bytes(NOTE_COUNT * 2)?;
+        let (volume_envelope, _) = read_envelope(file, 0)?;
+        let (panning_envelope, _) = read_envelope(file, 32)?;
+        let (pitch_envelope, pitch_flags) = read_envelope(file, 32)?;
+
+        instrument.name = name.trim().to_string();
+        instrument.volume_envelope = volume_envelope;
+        instrument.panning_envelope = panning_envelope;
+        instrument.pitch_envelope = pitch_envelope;
+        instrument.filter_envelope = pitch_flags & FILTER_ENVELOPE == FILTER_ENVELOPE;
+        // the top bit says they're used at all
+        instrument.filter_cutoff = if ifc & 0x80 == 0x80 { Some(ifc & 0x7F) } else { None };
+        instrument.filter_resonance = if ifr & 0x80 == 0x80 { Some(ifr & 0x7F) } else { None };
+        instrument.new_note_action = NewNoteAction::from_it(nna);
+        instrument.duplicate_check_type = DuplicateCheckType::from_it(dct);
+        instrument.duplicate_check_action = DuplicateCheckAction::from_it(dca);
+        // IT takes the fade out from 1024 every tick, XM takes twice as much from 65536
+        instrument.volume_fadeout = fade_out.min(256) * 32;
+        instrument.global_volume = gv.min(128);
+        instrument.pitch_pan_separation = pps.clamp(-32, 32);
+        instrument.pitch_pan_center = ppc.min(119) + 1;
+        instrument.random_volume = rvv.min(100);
+        instrument.random_panning = rpv.min(64);
+        // unlike the samples, the top bit says the instrument's panning is not used
+        let panning = if dfp & 0x80 == 0 { Some(it_panning(dfp)) } else { None };
+        map_samples(&mut instrument, &keyboard, samples, sample_pannings, panning);
+        Ok(instrument)
+    }
+
     fn truncate_patterns(pattern_order: &mut Vec<u8>) {
         let mut write_pos = 0;
         for i in 0..pattern_order.len() {
@@ -224,11 +441,11 @@ use crate::pattern::Pattern;
         pattern_order.truncate(write_pos);
     }
 
-    fn read_it_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
+    fn read_it_header<R: Read + Seek>(file: &mut R, lenient: bool) -> LoadResult<SongData>
     {
         let id = file.read_string(4);
         if id != "IMPM" {
-            return Err(SimpleError::from(io::Error::new(io::ErrorKind::Other, "Not an IT module")));
+            return Err(LoadError::wrong_format(SongType::IT));
         }
 
         let name = file.read_string(26);
@@ -241,14 +458,14 @@ use crate::pattern::Pattern;
         let compatible_with_version = file.read_u16()?;
 
         if compatible_with_version < 0x200 {
-            return Err(SimpleError::from(io::Error::new(io::ErrorKind::Other, "IT module is not in a compatible format")));
+            return Err(LoadError::invalid("IT module is not in a compatible format").at(Section::Header, 0x28));
         }
 
         let flags = file.read_u16()?;
         let special = file.read_u16()?;
+        let global_volume = file.read_u8()?;
         let _ = file.read_u8()?;
-        let _ = file.read_u8()?;
-        let _speed = file.read_u8()?;
+        let speed = file.read_u8()?;
         let tempo = file.read_u8()?;
         let _ = file.read_u8()?;
         let _ = file.read_u8()?;
@@ -262,10 +479,16 @@ use crate::pattern::Pattern;
         truncate_patterns(&mut pattern_order);
 
         let instrument_ptrs = file.read_u32_vec(instrument_count as usize)?;
-        let _sample_ptrs = file.read_u32_vec(sample_count as usize)?; // samples not fully implemented yet
+        let sample_ptrs = file.read_u32_vec(sample_count as usize)?;
         let pattern_ptrs = file.read_u32_vec(pattern_count as usize)?;
 
-        let instruments = read_instruments(file, &instrument_ptrs)?;
+        let mut report = LoadReport::default();
+        let (samples, sample_pannings) = read_samples(file, &sample_ptrs, lenient, &mut report)?;
+        let instruments = if flags & USE_INSTRUMENTS == USE_INSTRUMENTS {
+            read_instruments(file, &instrument_ptrs, &samples, &sample_pannings)?
+        } else {
+            sample_instruments(samples)
+        };
 
         let mut patterns: Vec<Patterns> = vec![];
         for ptr in pattern_ptrs {
@@ -273,7 +496,7 @@ use crate::pattern::Pattern;
                 patterns.push(Patterns::new(64, 64));
             } else {
                 file.seek(SeekFrom::Start(ptr as u64))?;
-                patterns.extend(read_patterns(file, 1, 64)?);
+                patterns.push(read_pattern(file, 64).map_err(|e| e.at(Section::Pattern, ptr as u64))?);
             }
         }
 
@@ -286,24 +509,387 @@ use crate::pattern::Pattern;
         Ok(SongData {
             id: id.trim().to_string(),
             name: name.trim().to_string(),
-            song_type: crate::module_reader::SongType::IT,
+            song_type: SongType::IT,
             tracker_name: "Impulse Tracker".to_string(),
             song_length: pattern_order.len() as u16,
             restart_position: 0,
             channel_count: 64,
             patterns,
-            instrument_count,
+            instrument_count: (instruments.len() - 1) as u16,
             frequency_type: if (flags & 1) == 1 { crate::module_reader::FrequencyType::LINEAR } else { crate::module_reader::FrequencyType::AMIGA },
-            tempo: tempo as u16,
+            tempo: speed as u16,
             bpm: tempo as u16,
             pattern_order,
             instruments,
             use_amiga: (flags & 1) != 1,
+            global_volume: global_volume.min(128),
+            fast_volume_slides: false,
+            vblank: false,
             song_message,
+            report,
         })
     }
 
-    pub(crate) fn read_it<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData> {
+    // Instrument and sample names sit at the same offset in the old and new instrument formats,
+    // so unlike read_it this doesn't care about the compatible version
+    pub(crate) fn read_it_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
         file.seek(SeekFrom::Start(0))?;
-        read_it_header(file)
+        if file.read_string(4) != "IMPM" {
+            return Err(LoadError::wrong_format(SongType::IT));
+        }
+
+        let title = file.read_string(26);
+        let _ = file.read_u16()?;
+        let order_count = file.read_u16()?;
+        let instrument_count = file.read_u16()?;
+        let sample_count = file.read_u16()?;
+        let pattern_count = file.read_u16()?;
+
+        file.seek(SeekFrom::Start(0x2C))?;
+        let flags = file.read_u16()?;
+        let special = file.read_u16()?;
+        file.seek(SeekFrom::Start(0x36))?;
+        let message_length = file.read_u16()?;
+        let message_offset = file.read_u32()?;
+
+        // disabled channels have the top bit of their panning set
+        file.seek(SeekFrom::Start(0x40))?;
+        let channel_count = file.read_bytes(64)?.iter().filter(|pan| **pan < 128).count();
+
+        file.seek(SeekFrom::Start(0xC0))?;
+        let mut pattern_order = file.read_bytes(order_count as usize)?;
+        truncate_patterns(&mut pattern_order);
+        let instrument_ptrs = file.read_u32_vec(instrument_count as usize)?;
+        let sample_ptrs = file.read_u32_vec(sample_count as usize)?;
+
+        let mut instrument_names = vec![];
+        for ptr in instrument_ptrs {
+            file.seek(SeekFrom::Start(ptr as u64 + 0x20))?;
+            instrument_names.push(file.read_string(26).trim().to_string());
+        }
+
+        let mut sample_names = vec![];
+        for ptr in sample_ptrs {
+            file.seek(SeekFrom::Start(ptr as u64 + 0x14))?;
+            sample_names.push(file.read_string(26).trim().to_string());
+        }
+
+        // the samples are the instruments when there are no instruments
+        if flags & USE_INSTRUMENTS != USE_INSTRUMENTS {
+            instrument_names = sample_names.clone();
+        }
+
+        // lines end with a CR
+        let mut song_message = String::new();
+        if (special & 1) == 1 && message_offset > 0 {
+            file.seek(SeekFrom::Start(message_offset as u64))?;
+            let message = file.read_bytes(message_length as usize)?;
+            song_message = String::from_utf8_lossy(&message).replace('\r', "\n").replace('\0', "").trim_end().to_string();
+        }
+
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::IT,
+            tracker_name: "Impulse Tracker".to_string(),
+            channel_count: channel_count as u16,
+            pattern_count,
+            order_count: pattern_order.len() as u16,
+            instrument_names,
+            sample_names,
+            song_message,
+        })
+    }
+
+    pub(crate) fn read_it<R: Read + Seek>(file: &mut R, lenient: bool) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+        read_it_header(file, lenient).map_err(|e| e.in_format(SongType::IT).at(Section::Header, 0))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const ENVELOPE_SIZE: usize = 82;
+        use std::io::Cursor;
+
+        // lowest bits first, like the IT unpacker reads them
+        fn pack_bits(values: &[(u32, u32)]) -> Vec<u8> {
+            let mut bytes = vec![];
+            let (mut buffer, mut count) = (0u64, 0);
+            for (value, width) in values {
+                buffer |= (*value as u64) << count;
+                count += width;
+                while count >= 8 {
+                    bytes.push(buffer as u8);
+                    buffer >>= 8;
+                    count -= 8;
+                }
+            }
+            if count > 0 {
+                bytes.push(buffer as u8);
+            }
+            bytes
+        }
+
+        fn sample_header(flags: u8, convert: u8, length: u32, data_ptr: u32) -> Vec<u8> {
+            let mut header = vec![0u8; 0x50];
+            header[0..4].copy_from_slice(b"IMPS");
+            header[0x11] = 64;
+            header[0x12] = flags;
+            header[0x13] = 64;
+            header[0x14..0x18].copy_from_slice(b"smpl");
+            header[0x2E] = convert;
+            header[0x30..0x34].copy_from_slice(&length.to_le_bytes());
+            header[0x3C..0x40].copy_from_slice(&8363u32.to_le_bytes());
+            header[0x48..0x4C].copy_from_slice(&data_ptr.to_le_bytes());
+            header
+        }
+
+        // Every way the width can change: a 9 bit switch down to 3, a 3 bit switch up to 8, an 8 bit switch back to 9
+        fn it214_8bit_block() -> Vec<u8> {
+            let bits = pack_bits(&[(5, 9), (0x102, 9), (3, 3), (7, 3), (4, 3), (6, 3), (0xFE, 8), (0x83, 8), (0xF6, 9)]);
+            let mut block = (bits.len() as u16).to_le_bytes().to_vec();
+            block.extend_from_slice(&bits);
+            block
+        }
+
+        fn it215_16bit_block() -> Vec<u8> {
+            let bits = pack_bits(&[(1000, 17), (0x10003, 17), (0xF, 4), (8, 4), (15, 4), (0xFFFF, 17)]);
+            let mut block = (bits.len() as u16).to_le_bytes().to_vec();
+            block.extend_from_slice(&bits);
+            block
+        }
+
+        fn build_it() -> Vec<u8> {
+            let mut data = vec![0u8; 0xC0];
+            data[0..4].copy_from_slice(b"IMPM");
+            data[4..8].copy_from_slice(b"song");
+            data[0x20..0x22].copy_from_slice(&1u16.to_le_bytes());     // orders
+            data[0x24..0x26].copy_from_slice(&3u16.to_le_bytes());     // samples
+            data[0x28..0x2A].copy_from_slice(&0x214u16.to_le_bytes());
+            data[0x2A..0x2C].copy_from_slice(&0x214u16.to_le_bytes());
+            data.push(255);
+            for ptr in [0x100u32, 0x150, 0x1A0] {
+                data.extend_from_slice(&ptr.to_le_bytes());
+            }
+            data.resize(0x100, 0);
+
+            data.extend(sample_header(0x01 | 0x08, 0x01, 5, 0x200));                  // IT214, 8 bit
+            data.extend(sample_header(0x01 | 0x02 | 0x08, 0x01 | 0x04, 3, 0x280));     // IT215, 16 bit
+            data.extend(sample_header(0x01, 0x00, 4, 0x300));                         // unsigned, not compressed
+            data.resize(0x200, 0);
+            data.extend(it214_8bit_block());
+            data.resize(0x280, 0);
+            data.extend(it215_16bit_block());
+            data.resize(0x300, 0);
+            data.extend_from_slice(&[0x80, 0x90, 0x70, 0x80]);
+            data
+        }
+
+        fn sample_data(sample: &Sample, count: usize) -> Vec<f32> {
+            sample.data[4..4 + count].to_vec()
+        }
+
+        #[test]
+        fn test_compressed_samples() {
+            let song = read_it(&mut Cursor::new(build_it()), false).unwrap();
+            assert_eq!(song.instrument_count, 3);
+
+            let upsample = |value: i32| (value * 257 + 128) as f32 / 32768.0;
+            let it214 = &song.instruments[1].samples[0];
+            assert_eq!(it214.bitness, 8);
+            assert_eq!(sample_data(it214, 5), [5, 8, 7, 5, -5].map(upsample));
+
+            // the deltas of deltas add up twice
+            let it215 = &song.instruments[2].samples[0];
+            assert_eq!(it215.bitness, 16);
+            assert_eq!(sample_data(it215, 3), [1000, 1000 + 999, 1000 + 999 + 998].map(|v: i32| v as i16 as f32 / 32768.0));
+
+            assert_eq!(sample_data(&song.instruments[3].samples[0], 4), [0, 16, -16, 0].map(upsample));
+        }
+
+        #[test]
+        fn test_sustain_loop() {
+            // a ping-pong sustain loop over 2..6, and a normal loop over 8..12
+            let mut data = sample_header(0x01 | 0x10 | 0x20 | 0x80, 0x01, 12, 0x50);
+            data[0x34..0x38].copy_from_slice(&8u32.to_le_bytes());
+            data[0x38..0x3C].copy_from_slice(&12u32.to_le_bytes());
+            data[0x40..0x44].copy_from_slice(&2u32.to_le_bytes());
+            data[0x44..0x48].copy_from_slice(&6u32.to_le_bytes());
+            data.extend((0..12).map(|value| value * 8));
+
+            let (sample, _) = read_sample(&mut Cursor::new(data), 0, 1, false, &mut LoadReport::default()).unwrap();
+            assert_eq!((sample.loop_start, sample.loop_end, sample.loop_type), (12, 16, LoopType::ForwardLoop));
+            assert_eq!(sample.playing(false).data.len(), sample.data.len());
+
+            let sustained = sample.playing(true);
+            assert_eq!((sustained.loop_start, sustained.loop_end, sustained.loop_len), (6, 14, 8));
+            let value = |idx: usize| sample.data[idx + 4];
+            assert_eq!(sample_data(sustained, 10), [0, 1, 2, 3, 4, 5, 5, 4, 3, 2].map(value));
+
+            // on the way back through the sustain loop, and on the way forward
+            assert_eq!(sample.release_position(11.0), 8.0);
+            assert_eq!(sample.release_position(5.5), 5.5);
+        }
+
+        #[test]
+        fn test_truncated_compressed_sample() {
+            let mut data = build_it();
+            data.truncate(0x200 + 4);
+            let error = read_it(&mut Cursor::new(data), false).err().unwrap();
+            assert_eq!((error.section(), error.offset()), (Some(Section::Sample), Some(0x200)));
+        }
+
+        fn build_nna_it() -> Vec<u8> {
+            let mut data = vec![0u8; 0xC0];
+            data[0..4].copy_from_slice(b"IMPM");
+            data[0x20..0x22].copy_from_slice(&2u16.to_le_bytes());     // orders
+            data[0x22..0x24].copy_from_slice(&1u16.to_le_bytes());     // instruments
+            data[0x24..0x26].copy_from_slice(&1u16.to_le_bytes());     // samples
+            data[0x26..0x28].copy_from_slice(&1u16.to_le_bytes());     // patterns
+            data[0x28..0x2A].copy_from_slice(&0x214u16.to_le_bytes());
+            data[0x2A..0x2C].copy_from_slice(&0x214u16.to_le_bytes());
+            data[0x2C..0x2E].copy_from_slice(&(USE_INSTRUMENTS | 1).to_le_bytes());
+            data[0x32] = 6;
+            data[0x33] = 125;
+            data.extend_from_slice(&[0, 255]);
+            for ptr in [0x100u32, 0x400, 0x480] {
+                data.extend_from_slice(&ptr.to_le_bytes());
+            }
+            data.resize(0x100, 0);
+
+            let mut instrument = vec![0u8; 0x40];
+            instrument[0..4].copy_from_slice(b"IMPI");
+            instrument[0x11] = 1;   // continue
+            instrument[0x12] = 1;   // duplicate notes
+            instrument[0x13] = 2;   // fade them
+            instrument[0x14..0x16].copy_from_slice(&16u16.to_le_bytes());
+            instrument[0x16] = (-8i8) as u8;    // pitch pan separation
+            instrument[0x17] = 60;              // around C-5
+            instrument[0x18] = 96;              // global volume
+            instrument[0x19] = 0x80 | 16;       // no default panning
+            instrument[0x1A] = 25;              // random volume
+            instrument[0x1B] = 8;               // random panning
+            instrument[0x3A] = 0x80 | 40;   // cutoff
+            instrument[0x3B] = 0x80 | 100;  // resonance
+            for note in 0..NOTE_COUNT as u8 {
+                instrument.extend_from_slice(&[note, 1]);
+            }
+            // a volume envelope with a sustain loop over its last two points, and carry
+            let mut envelope = vec![0x0F, 3, 0, 2, 1, 2, 64, 0, 0, 32, 4, 0, 0, 8, 0];
+            envelope.resize(ENVELOPE_SIZE, 0);
+            instrument.extend(envelope);
+            instrument.extend_from_slice(&[0; ENVELOPE_SIZE]);
+            // a filter envelope that opens up over ten ticks
+            let mut envelope = vec![0x80 | 0x01, 2, 0, 0, 0, 0, (-32i8) as u8, 0, 0, 32, 10, 0];
+            envelope.resize(ENVELOPE_SIZE, 0);
+            instrument.extend(envelope);
+            data.extend(instrument);
+            data.resize(0x400, 0);
+
+            data.extend(sample_header(0x01, 0x01, 4, 0x450));
+            data.resize(0x450, 0);
+            data.extend_from_slice(&[0, 64, 0, 192]);
+            data.resize(0x480, 0);
+
+            let rows: &[u8] = &[
+                0x81, 0x0F, 60, 1, 32, 19, 0x75,    // C-5, instrument, volume and S75 on channel 1
+                0x82, 0x01, 254,                    // note cut on channel 2
+                0,
+                0x81, 0x39, 62, 26, 0x40,           // D-5 with the last instrument and Z40
+                0x02, 120,                          // the last mask, note fade
+                0,
+            ];
+            data.extend_from_slice(&(rows.len() as u16).to_le_bytes());
+            data.extend_from_slice(&2u16.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(rows);
+            data
+        }
+
+        #[test]
+        fn test_patterns_and_instrument_settings() {
+            let song = read_it(&mut Cursor::new(build_nna_it()), false).unwrap();
+            assert_eq!((song.tempo, song.bpm), (6, 125));
+
+            let instrument = &song.instruments[1];
+            assert_eq!(instrument.new_note_action, NewNoteAction::Continue);
+            assert_eq!(instrument.duplicate_check_type, DuplicateCheckType::Note);
+            assert_eq!(instrument.duplicate_check_action, DuplicateCheckAction::NoteFade);
+            assert_eq!(instrument.volume_fadeout, 16 * 32);
+            assert_eq!(instrument.samples[1].relative_note, -12);
+            assert_eq!((instrument.pitch_pan_separation, instrument.pitch_pan_center, instrument.global_volume), (-8, 61, 96));
+            assert_eq!((instrument.random_volume, instrument.random_panning, instrument.samples[1].panning), (25, 8, 128));
+            assert_eq!((instrument.filter_cutoff, instrument.filter_resonance), (Some(40), Some(100)));
+            assert!(instrument.filter_envelope && instrument.pitch_envelope.on);
+            assert_eq!(instrument.pitch_envelope.size, 2);
+            assert_eq!((instrument.pitch_envelope.points[0].value, instrument.pitch_envelope.points[1].value), (0, 64));
+            assert_eq!(instrument.pitch_envelope.points[1].frame, 10);
+
+            let volume_envelope = &instrument.volume_envelope;
+            assert!(volume_envelope.on && volume_envelope.has_loop && volume_envelope.sustain && volume_envelope.carry);
+            assert_eq!((volume_envelope.sustain_point, volume_envelope.sustain_end_point), (1, 2));
+            assert_eq!((volume_envelope.loop_start_point, volume_envelope.loop_end_point), (0, 2));
+            assert_eq!((volume_envelope.points[1].value, volume_envelope.points[2].frame), (32, 8));
+            assert!(!instrument.panning_envelope.on);
+
+            let rows = &song.patterns[0].rows;
+            assert_eq!(rows.len(), 2);
+            let first = &rows[0].channels[0];
+            assert_eq!((first.note, first.instrument, first.volume, first.effect, first.effect_param), (61, 1, 0x30, 0x1C, 0x75));
+            assert_eq!(rows[0].channels[1].note, Pattern::NOTE_CUT);
+
+            let second = &rows[1].channels[0];
+            assert_eq!((second.note, second.instrument, second.volume, second.effect, second.effect_param), (63, 1, 0, 0x23, 0x40));
+            assert_eq!(rows[1].channels[1].note, Pattern::NOTE_FADE);
+        }
+
+        #[test]
+        fn test_it_effects() {
+            let rows: &[u8] = &[
+                0x81, 0x0C, 107, 13, 0x30,  // pitch slide down by 2 in the volume column, M30
+                0x82, 0x0C, 118, 20, 0x12,  // pitch slide up by 3, T12
+                0x83, 0x08, 19, 0x91,       // S91
+                0x84, 0x08, 19, 0x84,       // S84
+                0x85, 0x08, 22, 0xA0,       // VA0
+                0x86, 0x08, 25, 0x48,       // Y48
+                0x87, 0x08, 20, 0x80,       // T80
+                0,
+            ];
+            let mut data = (rows.len() as u16).to_le_bytes().to_vec();
+            data.extend_from_slice(&1u16.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(rows);
+
+            let patterns = read_pattern(&mut Cursor::new(data), 64).unwrap();
+            let converted: Vec<(u8, u8, u8)> = patterns.rows[0].channels[..7].iter().map(|p| (p.volume, p.effect, p.effect_param)).collect();
+            assert_eq!(converted, vec![
+                (0x03, 0x16, 0x30),
+                (0x54, 0x24, 0x12),
+                (0, 0x1C, 0x91),
+                (0, 0xE, 0x84),
+                (0, 0x10, 0x80),
+                (0, 0x22, 0x48),
+                (0, 0xF, 0x80),
+            ]);
+        }
+
+        #[test]
+        fn test_keyboard_table() {
+            let samples: Vec<Sample> = (0..3).map(|idx| Sample { volume: idx, panning: 64, ..Sample::new() }).collect();
+            let mut keyboard = vec![0u8; NOTE_COUNT * 2];
+            for note in 0..NOTE_COUNT {
+                // the low notes play an octave up
+                keyboard[note * 2] = if note < 60 { note as u8 + 12 } else { note as u8 };
+                keyboard[note * 2 + 1] = if note < 60 { 3 } else if note < 100 { 1 } else { 0 };
+            }
+
+            let mut instrument = Instrument::new();
+            map_samples(&mut instrument, &keyboard, &samples, &[false, false, true], Some(192));
+            assert_eq!(instrument.samples.iter().map(|s| s.volume).collect::<Vec<u8>>(), vec![0, 2, 0]);
+            assert_eq!(instrument.samples.iter().map(|s| s.panning).collect::<Vec<u8>>(), vec![0, 64, 192]);
+            assert_eq!((instrument.sample_indexes[0], instrument.sample_indexes[60], instrument.sample_indexes[119]), (1, 2, 0));
+            assert_eq!(instrument.sample_indexes.len(), NOTE_COUNT);
+            assert_eq!((instrument.mapped_note(1), instrument.mapped_note(60), instrument.mapped_note(61)), (13, 72, 61));
+        }
     }
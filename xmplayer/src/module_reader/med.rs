@@ -0,0 +1,700 @@
+    use crate::module_reader::{SongData, Patterns, SongType, FrequencyType};
+    use std::io::{Read, Seek, SeekFrom};
+    use binary_reader_io::{BinaryReader, BinarySeek};
+    use crate::pattern::Pattern;
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::channel_state::channel_state::clamp;
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+
+    const MAX_SAMPLES: usize = 63;
+    const MAX_CHANNELS: usize = 64;
+    const SONG_HEADER_TEMPO_OFFSET: u64 = 764;
+
+    // song flags
+    const FLAG_VOLUME_HEX: u8 = 0x10;
+    const FLAG_EIGHT_CHANNEL: u8 = 0x40;
+    // song flags2
+    const FLAG2_BPM: u8 = 0x20;
+
+    // the tempos MED Soundstudio uses when importing 8 channel (OctaMED 1-4) songs
+    const EIGHT_CHANNEL_TEMPOS: [u16; 10] = [179, 164, 152, 141, 131, 123, 116, 110, 104, 98];
+
+    // number of octaves in the IFF multi-octave sample types 1-6
+    const IFF_OCTAVES: [u32; 7] = [1, 5, 3, 2, 4, 6, 7];
+
+    struct MedSample {
+        repeat: u16,
+        repeat_length: u16,
+        volume: u8,
+        transpose: i8,
+    }
+
+    #[derive(Default)]
+    struct MedExpansion {
+        finetunes: Vec<i8>,
+        names: Vec<String>,
+        song_name: String,
+        annotation: String,
+    }
+
+    // What the note and command translation needs to know about the song
+    struct MedSettings {
+        eight_channel: bool,
+        bpm_mode: bool,
+        rows_per_beat: u16,
+        volume_hex: bool,
+        transpose: i8,
+    }
+
+    impl MedSettings {
+        // MED tempo is a BPM value in BPM mode, otherwise either an old SoundTracker tempo (1-10) or a CIA timer value
+        fn tempo_to_bpm(&self, tempo: u16) -> u16 {
+            let bpm = if self.bpm_mode && !self.eight_channel {
+                tempo as f32 * self.rows_per_beat as f32 / 4.0
+            } else if self.eight_channel && tempo > 0 {
+                EIGHT_CHANNEL_TEMPOS[tempo.min(10) as usize - 1] as f32
+            } else if tempo > 0 && tempo <= 10 {
+                (6.0 * 1773447.0 / 14500.0) / tempo as f32
+            } else {
+                tempo as f32 / 0.264
+            };
+            (bpm.round() as u16).clamp(32, 255)
+        }
+
+        // Volumes are decimal unless the song says otherwise
+        fn volume(&self, param: u8) -> u8 {
+            let volume = if self.volume_hex { param & 0x7F } else { (param >> 4) * 10 + (param & 0xF) };
+            clamp(volume, 0, 64)
+        }
+    }
+
+    pub fn read_med<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < 52 {
+            return Err(LoadError::truncated().in_format(SongType::MED).at(Section::Header, 0));
+        }
+
+        read_med_header(&mut file, file_len).map_err(|e| e.in_format(SongType::MED).at(Section::Header, 0))
+    }
+
+    // Offsets add up in u64, so pointers near the end of the u32 range land past the end of the file instead of wrapping
+    fn seek_to<R: Seek>(file: &mut R, ptr: u64, file_len: u64) -> LoadResult<()> {
+        if ptr == 0 || ptr >= file_len {
+            return Err(LoadError::invalid("Bad MED offset"));
+        }
+        file.seek(SeekFrom::Start(ptr))?;
+        Ok(())
+    }
+
+    fn read_med_header<R: Read + Seek>(file: &mut R, file_len: u64) -> LoadResult<SongData>
+    {
+        let id = file.read_bytes(4)?;
+        let version = match &id[..] {
+            b"MMD0" => 0,
+            b"MMD1" => 1,
+            b"MMD2" => 2,
+            b"MMD3" => 3,
+            _ => return Err(LoadError::wrong_format(SongType::MED)),
+        };
+
+        let _module_length = file.read_u32_be()?;
+        let song_ptr = file.read_u32_be()?;
+        let _play_sequence_state = file.read_u32_be()?;
+        let blocks_ptr = file.read_u32_be()?;
+        let _flags = file.read_u32_be()?;
+        let samples_ptr = file.read_u32_be()?;
+        let _reserved = file.read_u32_be()?;
+        let expansion_ptr = file.read_u32_be()?;
+        file.seek(SeekFrom::Start(51))?;
+        let extra_songs = file.read_u8()?;
+
+        let mut report = LoadReport::default();
+        if extra_songs > 0 {
+            report.warn(format!("{} additional songs in the module are ignored", extra_songs));
+        }
+
+        seek_to(file, song_ptr as u64, file_len)?;
+        let mut med_samples = vec![];
+        for _ in 0..MAX_SAMPLES {
+            let repeat = file.read_u16_be()?;
+            let repeat_length = file.read_u16_be()?;
+            let _midi_channel = file.read_u8()?;
+            let _midi_preset = file.read_u8()?;
+            let volume = file.read_u8()?;
+            let transpose = file.read_i8()?;
+            med_samples.push(MedSample { repeat, repeat_length, volume, transpose });
+        }
+
+        let block_count = file.read_u16_be()?;
+        let song_length = file.read_u16_be()?;
+
+        let mut sequence = vec![];
+        let mut sections = (0, 0, 0);
+        if version < 2 {
+            let play_sequence = file.read_bytes(256)?;
+            sequence = play_sequence[..(song_length as usize).min(256)].iter().map(|b| *b as u16).collect();
+        } else {
+            let play_sequences_ptr = file.read_u32_be()?;
+            let sections_ptr = file.read_u32_be()?;
+            let _track_volumes_ptr = file.read_u32_be()?;
+            let _track_count = file.read_u16_be()?;
+            let play_sequence_count = file.read_u16_be()?;
+            sections = (play_sequences_ptr, play_sequence_count, sections_ptr);
+        }
+
+        file.seek(SeekFrom::Start(song_ptr as u64 + SONG_HEADER_TEMPO_OFFSET))?;
+        let default_tempo = file.read_u16_be()?;
+        let transpose = file.read_i8()?;
+        let flags = file.read_u8()?;
+        let flags2 = file.read_u8()?;
+        let speed = file.read_u8()?;
+        let _track_volumes = file.read_bytes(16)?;
+        let _master_volume = file.read_u8()?;
+        let sample_count = (file.read_u8()? as usize).min(MAX_SAMPLES);
+
+        if version >= 2 {
+            let (play_sequences_ptr, play_sequence_count, sections_ptr) = sections;
+            sequence = read_sections(file, play_sequences_ptr, play_sequence_count, sections_ptr, song_length, file_len)?;
+        }
+
+        if block_count == 0 || block_count > 255 || sequence.is_empty() {
+            return Err(LoadError::invalid("Unsupported MED song layout"));
+        }
+
+        let settings = MedSettings {
+            eight_channel: flags & FLAG_EIGHT_CHANNEL != 0,
+            bpm_mode: flags2 & FLAG2_BPM != 0,
+            rows_per_beat: (flags2 & 0x1F) as u16 + 1,
+            volume_hex: flags & FLAG_VOLUME_HEX != 0,
+            transpose,
+        };
+
+        let expansion = read_expansion(file, expansion_ptr, file_len)?;
+
+        let mut patterns = read_blocks(file, blocks_ptr, block_count as usize, version, &settings, file_len).map_err(|e| e.at(Section::Pattern, blocks_ptr as u64))?;
+        let channel_count = patterns.iter().filter_map(|p| p.rows.first()).map(|r| r.channels.len()).max().unwrap_or(4);
+
+        // blocks can have different track counts
+        for pattern in patterns.iter_mut() {
+            for row in pattern.rows.iter_mut() {
+                row.channels.resize(channel_count, Pattern::new());
+            }
+        }
+
+        let instruments = read_instruments(file, samples_ptr, &med_samples[..sample_count], &expansion, file_len, &mut report).map_err(|e| e.at(Section::Instrument, samples_ptr as u64))?;
+
+        let mut pattern_order: Vec<u8> = sequence.iter().map(|b| (*b).min(block_count) as u8).collect();
+
+        // fix empty patterns at end
+        fix_pattern_order(&mut pattern_order, sequence.len(), patterns.len(), &mut report);
+
+        patterns.push(Patterns::new(64, channel_count));
+
+        Ok(SongData {
+            id: String::from_utf8_lossy(&id).to_string(),
+            name: expansion.song_name,
+            song_type: SongType::MED,
+            tracker_name: if version < 2 { "MED/OctaMED".to_string() } else { "OctaMED Soundstudio".to_string() },
+            song_length: pattern_order.len() as u16,
+            restart_position: 0,
+            channel_count: channel_count as u16,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: if speed == 0 { 6 } else { clamp(speed, 1, 0x1F) as u16 },
+            bpm: settings.tempo_to_bpm(default_tempo),
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
+            song_message: expansion.annotation,
+            report,
+        })
+    }
+
+    // The channel count comes from the block headers, the names and the song message from the expansion
+    pub(crate) fn read_med_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let id = file.read_bytes(4)?;
+        let version = match &id[..] {
+            b"MMD0" => 0,
+            b"MMD1" => 1,
+            b"MMD2" => 2,
+            b"MMD3" => 3,
+            _ => return Err(LoadError::wrong_format(SongType::MED)),
+        };
+
+        let _module_length = file.read_u32_be()?;
+        let song_ptr = file.read_u32_be()?;
+        let _play_sequence_state = file.read_u32_be()?;
+        let blocks_ptr = file.read_u32_be()?;
+        file.seek(SeekFrom::Start(32))?;
+        let expansion_ptr = file.read_u32_be()?;
+
+        seek_to(file, song_ptr as u64 + MAX_SAMPLES as u64 * 8, file_len)?;
+        let block_count = file.read_u16_be()?;
+        let song_length = file.read_u16_be()?;
+        let order_count = if version < 2 {
+            (song_length as usize).min(256)
+        } else {
+            let play_sequences_ptr = file.read_u32_be()?;
+            let sections_ptr = file.read_u32_be()?;
+            let _track_volumes_ptr = file.read_u32_be()?;
+            let _track_count = file.read_u16_be()?;
+            let play_sequence_count = file.read_u16_be()?;
+            read_sections(file, play_sequences_ptr, play_sequence_count, sections_ptr, song_length, file_len)?.len()
+        };
+
+        file.seek(SeekFrom::Start(song_ptr as u64 + SONG_HEADER_TEMPO_OFFSET + 23))?;
+        let sample_count = (file.read_u8()? as usize).min(MAX_SAMPLES);
+
+        if block_count == 0 || block_count > 255 || order_count == 0 {
+            return Err(LoadError::invalid("Unsupported MED song layout"));
+        }
+
+        seek_to(file, blocks_ptr as u64, file_len)?;
+        let mut block_ptrs = vec![];
+        for _ in 0..block_count {
+            block_ptrs.push(file.read_u32_be()?);
+        }
+
+        let mut channel_count = 0;
+        for ptr in block_ptrs {
+            seek_to(file, ptr as u64, file_len).map_err(|e| e.at(Section::Pattern, ptr as u64))?;
+            let track_count = if version == 0 { file.read_u8()? as usize } else { file.read_u16_be()? as usize };
+            if track_count == 0 || track_count > MAX_CHANNELS {
+                return Err(LoadError::invalid("Bad MED block track count").at(Section::Pattern, ptr as u64));
+            }
+            channel_count = channel_count.max(track_count);
+        }
+
+        let expansion = read_expansion(file, expansion_ptr, file_len)?;
+        let names: Vec<String> = (0..sample_count).map(|idx| expansion.names.get(idx).cloned().unwrap_or_default()).collect();
+
+        Ok(ModuleMetadata {
+            title: expansion.song_name,
+            format: SongType::MED,
+            tracker_name: if version < 2 { "MED/OctaMED".to_string() } else { "OctaMED Soundstudio".to_string() },
+            channel_count: channel_count as u16,
+            pattern_count: block_count,
+            order_count: order_count as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message: expansion.annotation,
+        })
+    }
+
+    // MMD2+ songs are a list of sections, each playing one of the play sequences
+    fn read_sections<R: Read + Seek>(file: &mut R, play_sequences_ptr: u32, play_sequence_count: u16, sections_ptr: u32, section_count: u16, file_len: u64) -> LoadResult<Vec<u16>> {
+        seek_to(file, play_sequences_ptr as u64, file_len)?;
+        let mut play_sequences = vec![];
+        for _ in 0..play_sequence_count {
+            play_sequences.push(file.read_u32_be()?);
+        }
+
+        let mut sections = vec![];
+        if sections_ptr == 0 {
+            sections.push(0);
+        } else {
+            seek_to(file, sections_ptr as u64, file_len)?;
+            for _ in 0..section_count {
+                sections.push(file.read_u16_be()? as usize);
+            }
+        }
+
+        let mut sequence = vec![];
+        for section in sections {
+            let Some(&ptr) = play_sequences.get(section) else { continue; };
+
+            // skip the name and the reserved fields
+            seek_to(file, ptr as u64 + 40, file_len)?;
+            let length = file.read_u16_be()?;
+            for _ in 0..length {
+                let entry = file.read_u16_be()?;
+                // 0x8000 and up are sequence commands (stop, jump) which we don't follow
+                if entry < 0x8000 {
+                    sequence.push(entry);
+                }
+            }
+        }
+        Ok(sequence)
+    }
+
+    fn read_expansion<R: Read + Seek>(file: &mut R, ptr: u32, file_len: u64) -> LoadResult<MedExpansion> {
+        let mut expansion = MedExpansion::default();
+        if ptr == 0 { return Ok(expansion); }
+
+        seek_to(file, ptr as u64 + 4, file_len)?;
+        let sample_ext_ptr = file.read_u32_be()?;
+        let sample_ext_count = file.read_u16_be()?;
+        let sample_ext_size = file.read_u16_be()?;
+        let annotation_ptr = file.read_u32_be()?;
+        let annotation_length = file.read_u32_be()?;
+        let info_ptr = file.read_u32_be()?;
+        let info_count = file.read_u16_be()?;
+        let info_size = file.read_u16_be()?;
+        file.seek(SeekFrom::Start(ptr as u64 + 44))?;
+        let song_name_ptr = file.read_u32_be()?;
+        let song_name_length = file.read_u32_be()?;
+
+        // hold, decay, suppress MIDI off, finetune
+        if sample_ext_ptr != 0 && sample_ext_size >= 4 {
+            for i in 0..sample_ext_count as u64 {
+                seek_to(file, sample_ext_ptr as u64 + i * sample_ext_size as u64 + 3, file_len)?;
+                expansion.finetunes.push(file.read_i8()?);
+            }
+        }
+
+        if info_ptr != 0 && info_size >= 40 {
+            for i in 0..info_count as u64 {
+                seek_to(file, info_ptr as u64 + i * info_size as u64, file_len)?;
+                expansion.names.push(file.read_string(40).trim().to_string());
+            }
+        }
+
+        if annotation_ptr != 0 {
+            seek_to(file, annotation_ptr as u64, file_len)?;
+            expansion.annotation = read_text(file, annotation_length as u64, file_len)?;
+        }
+
+        if song_name_ptr != 0 {
+            seek_to(file, song_name_ptr as u64, file_len)?;
+            expansion.song_name = read_text(file, song_name_length as u64, file_len)?.trim().to_string();
+        }
+
+        Ok(expansion)
+    }
+
+    fn read_text<R: Read + Seek>(file: &mut R, length: u64, file_len: u64) -> LoadResult<String> {
+        let position = file.stream_position()?;
+        let text = file.read_bytes(length.min(file_len - position) as usize)?;
+        Ok(String::from_utf8_lossy(&text).trim_end_matches('\0').trim_end().to_string())
+    }
+
+    fn read_blocks<R: Read + Seek>(file: &mut R, blocks_ptr: u32, block_count: usize, version: u8, settings: &MedSettings, file_len: u64) -> LoadResult<Vec<Patterns>> {
+        seek_to(file, blocks_ptr as u64, file_len)?;
+        let mut block_ptrs = vec![];
+        for _ in 0..block_count {
+            block_ptrs.push(file.read_u32_be()?);
+        }
+
+        let mut patterns: Vec<Patterns> = vec![];
+        patterns.reserve_exact(block_count + 1);
+
+        for ptr in block_ptrs {
+            seek_to(file, ptr as u64, file_len)?;
+
+            let (track_count, row_count) = if version == 0 {
+                let tracks = file.read_u8()? as usize;
+                let rows = file.read_u8()? as usize + 1;
+                (tracks, rows)
+            } else {
+                let tracks = file.read_u16_be()? as usize;
+                let rows = file.read_u16_be()? as usize + 1;
+                let _block_info_ptr = file.read_u32_be()?;
+                (tracks, rows)
+            };
+
+            if track_count == 0 || track_count > MAX_CHANNELS {
+                return Err(LoadError::invalid("Bad MED block track count").at(Section::Pattern, ptr as u64));
+            }
+
+            let note_size = if version == 0 { 3 } else { 4 };
+            let data = file.read_bytes(track_count * row_count * note_size).map_err(|e| LoadError::from(e).at(Section::Pattern, ptr as u64))?;
+
+            let mut pattern = Patterns::new(row_count, track_count);
+            for (note, channel) in data.chunks_exact(note_size).zip(pattern.rows.iter_mut().flat_map(|r| r.channels.iter_mut())) {
+                *channel = if version == 0 { read_mmd0_note(note, settings) } else { read_mmd1_note(note, settings) };
+            }
+            patterns.push(pattern);
+        }
+
+        Ok(patterns)
+    }
+
+    // xynnnnnn iiiicccc pppppppp - x and y are instrument bits 4 and 5
+    fn read_mmd0_note(data: &[u8], settings: &MedSettings) -> Pattern {
+        let instrument = (data[1] >> 4) | ((data[0] & 0x80) >> 3) | ((data[0] & 0x40) >> 1);
+        let (effect, effect_param) = convert_effect(data[1] & 0xF, data[2], settings);
+        Pattern { note: convert_note(data[0] & 0x3F, settings.transpose), instrument, volume: 0, effect, effect_param }
+    }
+
+    // nnnnnnnn xxiiiiii cccccccc pppppppp
+    fn read_mmd1_note(data: &[u8], settings: &MedSettings) -> Pattern {
+        let (effect, effect_param) = convert_effect(data[2], data[3], settings);
+        Pattern { note: convert_note(data[0], settings.transpose), instrument: data[1] & 0x3F, volume: 0, effect, effect_param }
+    }
+
+    // MED's C-1 is ProTracker's C-1 (period 856)
+    fn convert_note(note: u8, transpose: i8) -> u8 {
+        if note == 0 || note > 0x7F { return 0; }
+        let mut note = note as i16 + 36 + transpose as i16;
+        while note > 96 { note -= 12; }
+        while note < 1 { note += 12; }
+        note as u8
+    }
+
+    fn convert_effect(command: u8, param: u8, settings: &MedSettings) -> (u8, u8) {
+        match command {
+            0x00..=0x07 => (command, param),            // arpeggio, slides, portamento, vibrato, tremolo - as in MOD
+            0x09 => {                                   // secondary tempo - ticks per line
+                if param == 0 { (0, 0) } else { (0xF, clamp(param, 1, 0x1F)) }
+            }
+            0x0A | 0x0D => (0xA, param),                // volume slide
+            0x0B => (0xB, param),                       // position jump
+            0x0C => (0xC, settings.volume(param)),      // set volume
+            0x0F => convert_misc(param, settings),
+            0x11 => (0xE, 0x10 | (param & 0xF)),        // fine slide up
+            0x12 => (0xE, 0x20 | (param & 0xF)),        // fine slide down
+            0x14 => (0x4, param),                       // ProTracker compatible vibrato
+            0x15 => (0xE, 0x50 | (param & 0xF)),        // set finetune
+            0x16 => (0xE, 0x60 | (param & 0xF)),        // loop
+            0x18 => (0xE, 0xC0 | (param & 0xF)),        // cut note
+            0x19 => (0x9, param),                       // sample offset
+            0x1A => (0xE, 0xA0 | (param & 0xF)),        // fine volume up
+            0x1B => (0xE, 0xB0 | (param & 0xF)),        // fine volume down
+            0x1D if param < 100 => (0xD, ((param / 10) << 4) | (param % 10)), // next pattern, hex row
+            0x1E => (0xE, 0xE0 | (param & 0xF)),        // pattern delay
+            0x1F => {                                   // note delay / retrigger
+                if param >> 4 != 0 { (0xE, 0xD0 | (param >> 4)) } else if param != 0 { (0xE, 0x90 | param) } else { (0, 0) }
+            }
+            _ => (0, 0),                                // hold/decay, synth jumps, filter and MIDI commands
+        }
+    }
+
+    fn convert_misc(param: u8, settings: &MedSettings) -> (u8, u8) {
+        match param {
+            0x00 => (0xD, 0),                                           // pattern break
+            0x01..=0xF0 => (0xF, settings.tempo_to_bpm(param as u16) as u8), // set tempo
+            0xF1 => (0xE, 0x93),                                        // play note twice
+            0xF2 => (0xE, 0xD3),                                        // delay note
+            0xF3 => (0xE, 0x92),                                        // play note three times
+            0xFF => (0xE, 0xC0),                                        // note off
+            _ => (0, 0),
+        }
+    }
+
+    fn read_instruments<R: Read + Seek>(file: &mut R, samples_ptr: u32, med_samples: &[MedSample], expansion: &MedExpansion, file_len: u64, report: &mut LoadReport) -> LoadResult<Vec<Instrument>> {
+        let mut instruments: Vec<Instrument> = vec![];
+
+        // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
+        instruments.reserve_exact(med_samples.len() + 1);
+
+        instruments.push(Instrument::new());
+
+        if med_samples.is_empty() { return Ok(instruments); }
+
+        seek_to(file, samples_ptr as u64, file_len)?;
+        let mut sample_ptrs = vec![];
+        for _ in 0..med_samples.len() {
+            sample_ptrs.push(file.read_u32_be()?);
+        }
+
+        for (idx, (ptr, header)) in sample_ptrs.into_iter().zip(med_samples).enumerate() {
+            let mut instrument = Instrument::new();
+            instrument.idx = (idx + 1) as u8;
+            instrument.name = expansion.names.get(idx).cloned().unwrap_or_default();
+
+            if ptr != 0 {
+                seek_to(file, ptr as u64, file_len)?;
+                let length = file.read_u32_be()?;
+                let sample_type = file.read_u16_be()? as i16;
+
+                if sample_type < 0 {
+                    let kind = if sample_type == -1 { "synth" } else { "hybrid" };
+                    report.warn(format!("Instrument {}: {} instruments are not supported, playing silence", idx + 1, kind));
+                } else {
+                    let octaves = IFF_OCTAVES.get((sample_type & 0xF) as usize).cloned().unwrap_or(1);
+                    if octaves > 1 {
+                        report.warn(format!("Instrument {}: only the first octave of a multi-octave sample is played", idx + 1));
+                    }
+                    if sample_type & 0x20 != 0 {
+                        report.warn(format!("Instrument {}: stereo sample is played as mono", idx + 1));
+                    }
+
+                    let finetune = expansion.finetunes.get(idx).cloned().unwrap_or(0);
+                    let mut sample = create_sample(header, length / ((1 << octaves) - 1), sample_type, finetune);
+                    sample.name = instrument.name.clone();
+                    let sample_pos = file.offset();
+                    sample.read_big_endian_data(file).map_err(|e| e.at(Section::Sample, sample_pos))?;
+                    instrument.samples = vec![sample];
+                }
+            }
+            instruments.push(instrument);
+        }
+        Ok(instruments)
+    }
+
+    fn create_sample(header: &MedSample, mut length: u32, sample_type: i16, finetune: i8) -> Sample {
+        let bitness = if sample_type & 0x10 != 0 { 16 } else { 8 };
+
+        // repeat is in words
+        let mut loop_start = header.repeat as u32 * 2;
+        let mut loop_len = header.repeat_length as u32 * 2;
+        if bitness == 16 { // length is in bytes
+            length /= 2;
+            loop_start /= 2;
+            loop_len /= 2;
+        }
+
+        let mut loop_end = (loop_start + loop_len).min(length);
+        let loop_type = if header.repeat_length > 1 && loop_start + 2 < loop_end { LoopType::ForwardLoop } else { LoopType::NoLoop };
+        if loop_type == LoopType::NoLoop {
+            loop_start = 0;
+            loop_end = 0;
+        }
+
+        Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: clamp(header.volume, 0, 64),
+            finetune: finetune.clamp(-8, 7) * 16,
+            loop_type,
+            bitness,
+            panning: 128,
+            relative_note: header.transpose,
+            name: "".to_string(),
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![],
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+        use crate::module_reader::LoadErrorKind;
+
+        fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+            data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        }
+
+        // one 2 track block, one 8-bit sample and one synth instrument
+        fn build_med(version: u8) -> Vec<u8> {
+            let mut data = vec![0u8; 52];
+            data[..4].copy_from_slice(&[b'M', b'M', b'D', b'0' + version]);
+
+            let song_ptr = data.len();
+            put_u32(&mut data, 8, song_ptr as u32);
+            let mut song = vec![0u8; 788];
+            song[0..2].copy_from_slice(&2u16.to_be_bytes());    // repeat
+            song[2..4].copy_from_slice(&4u16.to_be_bytes());    // repeat length
+            song[6] = 40;                                       // volume
+            song[504..506].copy_from_slice(&1u16.to_be_bytes()); // blocks
+            song[506..508].copy_from_slice(&1u16.to_be_bytes()); // song length / sections
+            song[764..766].copy_from_slice(&33u16.to_be_bytes()); // CIA tempo - 125 BPM
+            song[769] = 3;                                      // ticks per line
+            song[787] = 2;                                      // samples
+            data.extend_from_slice(&song);
+
+            if version >= 2 {
+                let play_sequences_ptr = data.len();
+                data.extend_from_slice(&(play_sequences_ptr as u32 + 4).to_be_bytes());
+                data.extend_from_slice(&[0u8; 40]);
+                data.extend_from_slice(&2u16.to_be_bytes());
+                data.extend_from_slice(&0u16.to_be_bytes());
+                data.extend_from_slice(&0x8001u16.to_be_bytes()); // stop command
+                let sections_ptr = data.len();
+                data.extend_from_slice(&0u16.to_be_bytes());
+                put_u32(&mut data, song_ptr + 508, play_sequences_ptr as u32);
+                put_u32(&mut data, song_ptr + 512, sections_ptr as u32);
+                data[song_ptr + 523] = 1;                       // play sequences
+            }
+
+            let blocks_ptr = data.len();
+            let block_ptr = blocks_ptr + 4;
+            put_u32(&mut data, 16, blocks_ptr as u32);
+            data.extend_from_slice(&(block_ptr as u32).to_be_bytes());
+            if version == 0 {
+                data.extend_from_slice(&[2, 1]);                // 2 tracks, 2 lines
+                data.extend_from_slice(&[13, 0x1C, 0x20]);      // C-2, instrument 1, volume 20 (decimal)
+                data.extend_from_slice(&[0, 0x0F, 0x00]);       // pattern break
+                data.extend_from_slice(&[0, 0x00, 0x00]);
+                data.extend_from_slice(&[0, 0x09, 0x04]);       // 4 ticks per line
+            } else {
+                data.extend_from_slice(&2u16.to_be_bytes());
+                data.extend_from_slice(&1u16.to_be_bytes());
+                data.extend_from_slice(&0u32.to_be_bytes());
+                data.extend_from_slice(&[13, 1, 0x0C, 0x20]);
+                data.extend_from_slice(&[0, 0, 0x0F, 0x00]);
+                data.extend_from_slice(&[0, 0, 0x00, 0x00]);
+                data.extend_from_slice(&[0, 0, 0x09, 0x04]);
+            }
+
+            let samples_ptr = data.len();
+            put_u32(&mut data, 24, samples_ptr as u32);
+            data.extend_from_slice(&(samples_ptr as u32 + 8).to_be_bytes());
+            data.extend_from_slice(&(samples_ptr as u32 + 8 + 6 + 16).to_be_bytes());
+            data.extend_from_slice(&16u32.to_be_bytes());
+            data.extend_from_slice(&0u16.to_be_bytes());
+            data.extend((0..16u8).map(|i| i.wrapping_mul(8)));
+            data.extend_from_slice(&8u32.to_be_bytes());
+            data.extend_from_slice(&0xFFFFu16.to_be_bytes());   // synth
+            data.extend_from_slice(&[0u8; 8]);
+            data
+        }
+
+        #[test]
+        fn test_read_med() {
+            for version in 0..4 {
+                let song = read_med(&mut Cursor::new(build_med(version))).unwrap();
+                assert_eq!(song.channel_count, 2);
+                assert_eq!(song.pattern_order, vec![0]);
+                assert_eq!(song.bpm, 125);
+                assert_eq!(song.tempo, 3);
+                assert_eq!(song.patterns[0].rows.len(), 2);
+
+                let first = &song.patterns[0].rows[0].channels;
+                assert_eq!(first[0].note, 49);
+                assert_eq!(first[0].instrument, 1);
+                assert_eq!((first[0].effect, first[0].effect_param), (0xC, 20));
+                assert_eq!((first[1].effect, first[1].effect_param), (0xD, 0));
+                assert_eq!((song.patterns[0].rows[1].channels[1].effect, song.patterns[0].rows[1].channels[1].effect_param), (0xF, 4));
+
+                let sample = &song.instruments[1].samples[0];
+                assert_eq!(sample.volume, 40);
+                assert_eq!(sample.loop_type, LoopType::ForwardLoop);
+                assert_eq!((sample.loop_start, sample.loop_end), (4 + 4, 12 + 4));
+
+                assert!(song.instruments[2].samples[0].data.is_empty());
+                assert_eq!(song.report.warnings().count(), 1);
+            }
+        }
+
+        #[test]
+        fn test_metadata() {
+            for version in 0..4 {
+                crate::module_reader::metadata::assert_metadata_matches_song(&build_med(version));
+            }
+        }
+
+        #[test]
+        fn test_pointer_near_the_end_of_u32() {
+            let mut data = build_med(0);
+            put_u32(&mut data, 32, u32::MAX - 1);              // expansion
+            let error = read_med(&mut Cursor::new(data)).err().unwrap();
+            assert_eq!(error.kind(), &LoadErrorKind::Invalid("Bad MED offset".to_string()));
+        }
+
+        #[test]
+        fn test_med_tempo() {
+            let mut settings = MedSettings { eight_channel: false, bpm_mode: true, rows_per_beat: 4, volume_hex: true, transpose: 0 };
+            assert_eq!(settings.tempo_to_bpm(140), 140);
+            settings.bpm_mode = false;
+            assert_eq!(settings.tempo_to_bpm(6), 122);
+            settings.eight_channel = true;
+            assert_eq!(settings.tempo_to_bpm(1), 179);
+        }
+    }
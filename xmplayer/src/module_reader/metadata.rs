@@ -0,0 +1,140 @@
+    use std::io::Cursor;
+    use crate::instrument::Instrument;
+    use crate::module_reader::{probe_format, unpack, LoadError, LoadResult, Section, SongType};
+    use crate::module_reader::module::{read_mod_metadata, read_soundtracker_metadata};
+    use crate::module_reader::xm::read_xm_metadata;
+    use crate::module_reader::s3m::read_s3m_metadata;
+    use crate::module_reader::stm::read_stm_metadata;
+    use crate::module_reader::it::read_it_metadata;
+    use crate::module_reader::mtm::read_mtm_metadata;
+    use crate::module_reader::composer_669::read_669_metadata;
+    use crate::module_reader::med::read_med_metadata;
+    use crate::module_reader::okt::read_okt_metadata;
+    use crate::module_reader::ptm::read_ptm_metadata;
+    use crate::module_reader::ult::read_ult_metadata;
+    use crate::module_reader::far::read_far_metadata;
+    use crate::module_reader::probe::has_mod_signature;
+
+    /// What's in a module, as far as the headers tell. Formats without instruments list their samples as instruments too.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct ModuleMetadata {
+        pub title:              String,
+        pub format:             SongType,
+        pub tracker_name:       String,
+        pub channel_count:      u16,
+        pub pattern_count:      u16,
+        pub order_count:        u16,
+        pub instrument_names:   Vec<String>,
+        pub sample_names:       Vec<String>,
+        pub song_message:       String,
+    }
+
+    impl ModuleMetadata {
+        pub fn instrument_count(&self) -> usize { self.instrument_names.len() }
+        pub fn sample_count(&self) -> usize { self.sample_names.len() }
+    }
+
+    /// Reads the metadata of a module without loading it. Patterns and sample data are skipped, not decoded,
+    /// so this is cheap enough for indexing a whole archive. Packed modules still have to be unpacked.
+    pub fn probe_metadata(data: &[u8]) -> LoadResult<ModuleMetadata> {
+        let data = &*unpack(data, None)?;
+        let candidates = probe_format(data);
+        let (best, _) = *candidates.first().ok_or(LoadError::unknown_format())?;
+
+        let error = match read_metadata_as(data, best) {
+            Ok(metadata) => {return Ok(metadata)},
+            Err(e) => e,
+        };
+
+        for (song_type, _) in candidates.iter().skip(1) {
+            if let Ok(metadata) = read_metadata_as(data, *song_type) {
+                return Ok(metadata);
+            }
+        }
+
+        Err(error)
+    }
+
+    fn read_metadata_as(data: &[u8], song_type: SongType) -> LoadResult<ModuleMetadata> {
+        let mut buf = Cursor::new(data);
+
+        match song_type {
+            SongType::XM => read_xm_metadata(&mut buf),
+            SongType::MOD => if has_mod_signature(data) { read_mod_metadata(&mut buf) } else { read_soundtracker_metadata(&mut buf) },
+            SongType::S3M => read_s3m_metadata(&mut buf),
+            SongType::STM => read_stm_metadata(&mut buf),
+            SongType::IT => read_it_metadata(&mut buf),
+            SongType::MTM => read_mtm_metadata(&mut buf),
+            SongType::C669 => read_669_metadata(&mut buf),
+            SongType::MED => read_med_metadata(&mut buf),
+            SongType::OKT => read_okt_metadata(&mut buf),
+            SongType::PTM => read_ptm_metadata(&mut buf),
+            SongType::ULT => read_ult_metadata(&mut buf),
+            SongType::FAR => read_far_metadata(&mut buf),
+        }.map_err(|e| e.in_format(song_type).at(Section::Header, 0))
+    }
+
+    /// The names of the instruments a reader read the headers of, without the dummy at index 0
+    pub(crate) fn instrument_names(instruments: &[Instrument]) -> Vec<String> {
+        instruments.iter().skip(1).map(|i| i.name.trim().to_string()).collect()
+    }
+
+    /// Checks probe_metadata against what the full reader makes of the same data
+    #[cfg(test)]
+    pub(crate) fn assert_metadata_matches_song(data: &[u8]) {
+        use crate::module_reader::open_module;
+
+        let metadata = probe_metadata(data).unwrap();
+        let song = open_module(data).unwrap();
+
+        assert_eq!(metadata.format, song.song_type);
+        assert_eq!(metadata.title, song.name);
+        assert_eq!(metadata.tracker_name, song.tracker_name);
+        assert_eq!(metadata.channel_count, song.channel_count);
+        assert_eq!(metadata.pattern_count as usize, song.patterns.len() - 1); // the readers add an empty pattern
+        assert_eq!(metadata.order_count, song.song_length);
+        assert_eq!(metadata.instrument_names, instrument_names(&song.instruments));
+        assert_eq!(metadata.song_message, song.song_message);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_metadata_matches_song() {
+            for path in ["test_data/test.xm", "test_data/milky.xm", "test_data/test.mod", "test_data/spacedeb.mod",
+                         "test_data/AmigaLimitsFinetune.mod", "test_data/test.ptm", "test_data/test.ult", "test_data/test.far"] {
+                assert_metadata_matches_song(&std::fs::read(path).unwrap());
+            }
+        }
+
+        #[test]
+        fn test_xm_metadata() {
+            let data = std::fs::read("test_data/test.xm").unwrap();
+            let metadata = probe_metadata(&data).unwrap();
+            let song = crate::module_reader::open_module(&data).unwrap();
+
+            let sample_names: Vec<String> = song.instruments.iter().skip(1).flat_map(|i| i.samples.iter().map(|s| s.name.trim().to_string())).collect();
+            assert_eq!(metadata.sample_names, sample_names);
+            assert_eq!(metadata.instrument_count(), song.instrument_count as usize);
+        }
+
+        // too short for read_s3m, but the headers are all there
+        #[test]
+        fn test_s3m_metadata() {
+            let metadata = probe_metadata(&std::fs::read("test_data/test.s3m").unwrap()).unwrap();
+            assert_eq!(metadata.format, SongType::S3M);
+            assert!(metadata.channel_count > 0);
+            assert_eq!(metadata.instrument_count(), metadata.sample_count());
+        }
+
+        #[test]
+        fn test_truncated_sample_data_is_not_read() {
+            let mut data = std::fs::read("test_data/test.far").unwrap();
+            let metadata = probe_metadata(&data).unwrap();
+            data.truncate(data.len() - 100);
+            assert_eq!(probe_metadata(&data).unwrap(), metadata);
+            assert!(probe_metadata(&[0u8; 16]).is_err());
+        }
+    }
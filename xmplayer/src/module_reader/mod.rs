@@ -1,13 +1,19 @@
 use std::{fmt, fs};
-use crate::{SimpleResult};
 use crate::instrument::{Instrument, Sample};
-use crate::module_reader::module::read_mod;
+use crate::module_reader::module::{read_mod, read_soundtracker};
 use crate::module_reader::s3m::read_s3m;
 use crate::module_reader::xm::read_xm;
 use crate::pattern::Pattern;
 use crate::channel_state::channel_state::clamp;
 use crate::module_reader::stm::read_stm;
 use crate::module_reader::it::read_it;
+use crate::module_reader::mtm::read_mtm;
+use crate::module_reader::composer_669::read_669;
+use crate::module_reader::med::read_med;
+use crate::module_reader::okt::read_okt;
+use crate::module_reader::ptm::read_ptm;
+use crate::module_reader::ult::read_ult;
+use crate::module_reader::far::read_far;
 use crate::channel_state::ChannelState;
 use crate::song_state::SongHandle;
 use std::io::{Cursor, Seek, SeekFrom};
@@ -17,14 +23,40 @@ mod module;
 mod s3m;
 mod stm;
 mod it;
-
-#[derive(Debug, Copy, Clone)]
-pub(crate) enum SongType {
+mod mtm;
+mod composer_669;
+mod med;
+mod okt;
+mod ptm;
+mod ult;
+mod far;
+mod probe;
+mod error;
+mod report;
+mod metadata;
+pub(crate) mod unpack;
+
+pub use probe::probe_format;
+pub use error::{LoadError, LoadErrorKind, LoadResult, Section};
+pub use report::{LoadNote, LoadOptions, LoadReport};
+pub use metadata::{probe_metadata, ModuleMetadata};
+pub use unpack::{detect_container, list_archive, unpack, ArchiveMember, Container};
+pub(crate) use s3m::recall_shared_effect;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SongType {
     XM,
     MOD,
     S3M,
     STM,
     IT,
+    MTM,
+    C669,
+    MED,
+    OKT,
+    PTM,
+    ULT,
+    FAR,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -102,7 +134,14 @@ pub struct SongData {
     pub(crate)      pattern_order:      Vec<u8>,
     pub(crate)      instruments:        Vec<Instrument>,
     pub(crate)      use_amiga:          bool,
+    // the global volume the song starts with, IT's goes up to 128 and the others to 64
+    pub(crate)      global_volume:      u8,
+    // ST3 3.00 slides the volume on the first tick of the row too
+    pub(crate)      fast_volume_slides: bool,
+    // old Soundtrackers play at 50 ticks a second, Fxx only sets the speed
+    pub(crate)      vblank:             bool,
     pub(crate)      song_message:       String,
+    pub(crate)      report:             LoadReport,
 }
 
 impl Default for SongData {
@@ -123,7 +162,11 @@ impl Default for SongData {
             pattern_order: vec![],
             instruments: vec![],
             use_amiga: false,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
             song_message: "".to_string(),
+            report: LoadReport::default(),
         }
     }
 }
@@ -144,49 +187,136 @@ impl SongData {
     pub(crate) fn get_instrument(&self, channel: &ChannelState) -> &Instrument {
         &self.instruments[channel.voice.instrument]
     }
+
+    /// What had to be repaired or left out while loading, e.g. unsupported instrument types
+    pub fn get_load_report(&self) -> &LoadReport {
+        &self.report
+    }
+}
+
+/// Points orders past the last pattern at the empty pattern the readers add after the real ones.
+/// Only the orders that actually get played are worth a note.
+pub(crate) fn fix_pattern_order(pattern_order: &mut [u8], song_length: usize, pattern_count: usize, report: &mut LoadReport) {
+    for (idx, order) in pattern_order.iter_mut().enumerate() {
+        if *order as usize >= pattern_count {
+            if idx < song_length {
+                report.repair(format!("Order {} points past the last pattern ({}), playing an empty pattern instead", idx, order));
+            }
+            *order = pattern_count as u8;
+        }
+    }
 }
 
 
-pub fn read_module(path: &str) -> SimpleResult<SongData> {
+pub fn read_module(path: &str) -> LoadResult<SongData> {
+    read_module_with_options(path, &LoadOptions::default())
+}
+
+pub fn read_module_with_options(path: &str, options: &LoadOptions) -> LoadResult<SongData> {
     let data = fs::read(path)?;
-    open_module(data.as_slice())
+    open_module_with_options(data.as_slice(), options)
 }
 
-pub fn open_module(data: &[u8]) -> SimpleResult<SongData> {
-    let mut buf = Cursor::new(data);
+pub fn open_module(data: &[u8]) -> LoadResult<SongData> {
+    open_module_with_options(data, &LoadOptions::default())
+}
 
-    let _ = buf.seek(SeekFrom::Start(0));
-    match read_xm(&mut buf) {
+/// Loads the module with the reader of the best matching format, or of the format in the options. If that fails,
+/// a lenient load cuts bogus sample and instrument sizes down to the file, pads truncated data with silence, then
+/// gives the other plausible formats a go. The error returned is always the one from the best match. Packed and
+/// archived modules are unpacked first. What got repaired is in the song's load report.
+pub fn open_module_with_options(data: &[u8], options: &LoadOptions) -> LoadResult<SongData> {
+    let data = &*unpack(data, options.archive_member.as_deref())?;
+    let candidates: Vec<SongType> = match options.format {
+        Some(format) => vec![format],
+        None => probe_format(data).into_iter().map(|(song_type, _)| song_type).collect(),
+    };
+    let best = *candidates.first().ok_or(LoadError::unknown_format())?;
+
+    let error = match open_module_as(data, best) {
         Ok(module) => {return Ok(module)},
-        Err(_) => {},
+        Err(e) => e,
+    };
+
+    if !options.lenient {
+        return Err(error);
     }
 
-    let _ = buf.seek(SeekFrom::Start(0));
-    match read_mod(&mut buf) {
-        Ok(module) => {return Ok(module)},
-        Err(_) => {},
+    if let Ok(module) = read_as(data, best, true) {
+        return Ok(module);
     }
 
-    let _ = buf.seek(SeekFrom::Start(0));
-    match read_stm(&mut buf) {
-        Ok(module) => {return Ok(module)},
-        Err(_) => {},
+    // a file cut short in the header isn't worth saving
+    if error.kind() == &LoadErrorKind::Truncated && error.section() != Some(Section::Header)
+        && let Some(mut module) = open_padded(data, best) {
+        module.report.repair(format!("{}, the file ends at offset 0x{:X} and the rest plays as silence", error, data.len()));
+        return Ok(module);
     }
 
-    let _ = buf.seek(SeekFrom::Start(0));
-    match read_s3m(&mut buf) {
-        Ok(module) => {return Ok(module)},
-        Err(_) => {},
+    for song_type in candidates.iter().skip(1) {
+        if let Ok(mut module) = open_module_as(data, *song_type) {
+            module.report.warn(format!("Loaded as {:?} after failing as {:?}: {}", song_type, best, error));
+            return Ok(module);
+        }
+    }
+
+    Err(error)
+}
+
+// Grows the zero padding until the reader is happy. Sample data running way past the end of the file is
+// more likely a bogus length than a truncated file, so there's a limit.
+fn open_padded(data: &[u8], song_type: SongType) -> Option<SongData> {
+    let mut padding = 0x1000;
+    while padding <= data.len().max(0x100000) {
+        let mut padded = data.to_vec();
+        padded.resize(data.len() + padding, 0);
+        if let Ok(module) = open_module_as(&padded, song_type) {
+            return Some(module);
+        }
+        padding *= 4;
+    }
+    None
+}
+
+/// Loads the module as the given format, skipping detection
+pub fn open_module_as(data: &[u8], song_type: SongType) -> LoadResult<SongData> {
+    read_as(data, song_type, false)
+}
+
+// Lenient XM, S3M and IT readers fit sample and instrument sizes into what's left of the file
+fn read_as(data: &[u8], song_type: SongType, lenient: bool) -> LoadResult<SongData> {
+    let mut buf = Cursor::new(data);
+
+    match song_type {
+        SongType::XM => read_xm(&mut buf, lenient),
+        SongType::MOD => if probe::has_mod_signature(data) { read_mod(&mut buf) } else { read_soundtracker(&mut buf) },
+        SongType::S3M => read_s3m(&mut buf, lenient),
+        SongType::STM => read_stm(&mut buf),
+        SongType::IT => read_it(&mut buf, lenient),
+        SongType::MTM => read_mtm(&mut buf),
+        SongType::C669 => read_669(&mut buf),
+        SongType::MED => read_med(&mut buf),
+        SongType::OKT => read_okt(&mut buf),
+        SongType::PTM => read_ptm(&mut buf),
+        SongType::ULT => read_ult(&mut buf),
+        SongType::FAR => read_far(&mut buf),
     }
+}
 
-    let _ = buf.seek(SeekFrom::Start(0));
-    read_it(&mut buf)
+// How much of the file is left after where the reader is
+pub(crate) fn bytes_left<R: Seek>(file: &mut R) -> LoadResult<u64> {
+    let position = file.stream_position()?;
+    let end = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(position))?;
+    Ok(end.saturating_sub(position))
 }
 
 
 pub fn print_module(handle: &SongHandle, patterns: impl Iterator<Item = String>) {
     let _data = &handle.song_data;
 
+    print!("{}", _data.report);
+
     for pattern in patterns {
         match pattern.parse::<usize>() {
             Ok(idx) => {
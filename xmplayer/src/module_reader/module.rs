@@ -1,73 +1,74 @@
     use crate::module_reader::{SongData, Patterns, Row, SongType, FrequencyType};
     use std::io::{Read, Seek, SeekFrom};
-    use binary_reader_io::BinaryReader;
+    use binary_reader_io::{BinaryReader, BinarySeek};
     use std::iter::FromIterator;
     use crate::pattern::Pattern;
-    use crate::instrument::{Instrument, Sample, LoopType};
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
     use crate::channel_state::channel_state::{clamp};
     use crate::tables::AMIGA_PERIOD;
-    use crate::{SimpleError, SimpleResult};
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+    use crate::module_reader::metadata::instrument_names;
 
-    pub fn read_mod<R: Read + Seek>(mut file: &mut R) -> SimpleResult<SongData> {
+    pub fn read_mod<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
         file.seek(SeekFrom::Start(0))?;
 
         let file_len = file.seek(SeekFrom::End(0))?;
         file.seek(SeekFrom::Start(0))?;
 
         if file_len < 1084 {
-            return Err(SimpleError::new("File is too small!"));
+            return Err(LoadError::truncated().in_format(SongType::MOD).at(Section::Header, 0));
         }
 
-        read_mod_header(&mut file)
+        read_mod_header(&mut file).map_err(|e| e.in_format(SongType::MOD).at(Section::Header, 0))
     }
 
-    fn read_mod_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
-    {
-        file.seek(SeekFrom::Start(1080))?;
-
-        let id = file.read_bytes(4)?;
-
-        let num_channels = if id == "M.K.".as_bytes() {
+    fn read_channel_count(id: &[u8]) -> LoadResult<usize> {
+        Ok(if id == "M.K.".as_bytes() {
             4
         } else if id == "6CHN".as_bytes() {
             6
         } else if id == "8CHN".as_bytes() {
             8
         } else if id[2] == 'C' as u8 && id[3] == 'H' as u8 {
-            let n: usize = String::from_utf8(id[0..2].to_vec()).map_err(|_e| SimpleError::new("Invalid channel count"))?.parse().map_err(|_e| SimpleError::new("Invalid channel count"))?;
+            let n: usize = String::from_utf8(id[0..2].to_vec()).map_err(|_e| LoadError::invalid("Invalid channel count"))?.parse().map_err(|_e| LoadError::invalid("Invalid channel count"))?;
             if n < 10 || n > 32 {
-                return Err(SimpleError::new("Unknown mod format"));
+                return Err(LoadError::invalid("Unknown mod format"));
             }
             n
         } else {
-            return Err(SimpleError::new("Not a mod file"));
-        };
+            return Err(LoadError::wrong_format(SongType::MOD));
+        })
+    }
+
+    fn read_mod_header<R: Read + Seek>(file: &mut R) -> LoadResult<SongData>
+    {
+        file.seek(SeekFrom::Start(1080))?;
+
+        let id = file.read_bytes(4)?;
+        let num_channels = read_channel_count(&id)?;
 
         file.seek(SeekFrom::Start(0))?;
 
         let name = file.read_string(20);
 
-        let mut instruments = read_instruments(file)?;
+        let mut instruments = read_instruments(file, 31, false).map_err(|e| e.at(Section::Instrument, 20))?;
 
         let song_length = file.read_u8()?;
 
         let _restart_position = file.read_u8()?; // unused
 
         let mut pattern_order = file.read_bytes(128)?;
-        let pattern_count = pattern_order.iter().cloned().max().ok_or(SimpleError::new("Unknown pattern count"))? + 1;
+        let pattern_count = pattern_order.iter().cloned().max().ok_or(LoadError::invalid("Unknown pattern count"))? as usize + 1;
 
         let id_str = file.read_string(4);
 
-        let mut patterns = read_patterns(file, pattern_count as usize, num_channels as usize)?;
+        let mut patterns = read_patterns(file, pattern_count, num_channels as usize).map_err(|e| e.at(Section::Pattern, 1084))?;
 
         read_sample_data(file, &mut instruments)?;
 
         // fix empty patterns at end
-        for idx in 0..pattern_order.len() {
-            if pattern_order[idx] >= patterns.len() as u8 {
-                pattern_order[idx] = patterns.len() as u8;
-            }
-        }
+        let mut report = LoadReport::default();
+        fix_pattern_order(&mut pattern_order, song_length as usize, patterns.len(), &mut report);
 
         patterns.push(Patterns {
             rows: vec![Row {
@@ -98,18 +99,214 @@
             pattern_order: Vec::from_iter(pattern_order.iter().cloned()),
             instruments,
             use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
             song_message: "".to_string(),
+            report,
         })
     }
 
-    fn read_sample_data<R: Read + Seek>(f: &mut R, instruments: &mut Vec<Instrument>) -> SimpleResult<()> {
+    // 15 sample (Ultimate) Soundtracker modules have no signature, so the header and patterns have to look sane
+    pub fn read_soundtracker<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < SOUNDTRACKER_HEADER_SIZE + 1024 {
+            return Err(LoadError::truncated().in_format(SongType::MOD).at(Section::Header, 0));
+        }
+
+        read_soundtracker_header(&mut file, file_len).map_err(|e| e.in_format(SongType::MOD).at(Section::Header, 0))
+    }
+
+    const SOUNDTRACKER_HEADER_SIZE: u64 = 20 + 15 * 30 + 2 + 128;
+
+    fn is_text(data: &[u8]) -> bool {
+        data.iter().all(|c| *c == 0 || *c >= 32)
+    }
+
+    // Checks the 15 sample headers for what Soundtracker could have written, and adds up their lengths in bytes
+    fn check_soundtracker_samples<R: Read>(file: &mut R) -> LoadResult<u64> {
+        let mut samples_len = 0u64;
+        for _ in 0..15 {
+            let sample = file.read_bytes(30)?;
+            let length = u16::from_be_bytes([sample[22], sample[23]]);
+            let loop_start = u16::from_be_bytes([sample[26], sample[27]]);
+            let loop_len = u16::from_be_bytes([sample[28], sample[29]]);
+            if !is_text(&sample[0..22]) || sample[24] > 0xF || sample[25] > 64
+                || length > 0x7FFF || loop_start as u32 + loop_len as u32 > 0x7FFF {
+                return Err(LoadError::wrong_format(SongType::MOD));
+            }
+            samples_len += length as u64 * 2;
+        }
+        Ok(samples_len)
+    }
+
+    fn read_soundtracker_header<R: Read + Seek>(file: &mut R, file_len: u64) -> LoadResult<SongData>
+    {
+        let name = file.read_bytes(20)?;
+        if !is_text(&name) {
+            return Err(LoadError::wrong_format(SongType::MOD));
+        }
+
+        let samples_len = check_soundtracker_samples(file)?;
+
+        let song_length = file.read_u8()?;
+        // Ultimate Soundtracker keeps the CIA tempo where later trackers keep the restart position
+        let tempo = file.read_u8()?;
+        let pattern_order = file.read_bytes(128)?;
+        let pattern_count = *pattern_order.iter().max().unwrap() as u64 + 1;
+
+        if song_length == 0 || song_length > 128 || pattern_count > 64
+            || file_len < SOUNDTRACKER_HEADER_SIZE + pattern_count * 1024 {
+            return Err(LoadError::wrong_format(SongType::MOD));
+        }
+
+        // at most a little trailing garbage
+        if file_len > SOUNDTRACKER_HEADER_SIZE + pattern_count * 1024 + samples_len + 4096 {
+            return Err(LoadError::wrong_format(SongType::MOD));
+        }
+
+        for _ in 0..pattern_count * 256 {
+            let data = file.read_u32_be()?;
+            let sample = ((data & 0xF0000000) >> 24) | ((data & 0xF000) >> 12);
+            let period = (data >> 16) & 0x0FFF;
+            if sample > 15 || (period != 0 && !(113..=856).contains(&period)) {
+                return Err(LoadError::wrong_format(SongType::MOD));
+            }
+        }
+
+        file.seek(SeekFrom::Start(20))?;
+
+        let mut instruments = read_instruments(file, 15, true).map_err(|e| e.at(Section::Instrument, 20))?;
+        file.seek(SeekFrom::Start(SOUNDTRACKER_HEADER_SIZE))?;
+
+        let mut patterns = read_patterns(file, pattern_count as usize, 4).map_err(|e| e.at(Section::Pattern, SOUNDTRACKER_HEADER_SIZE))?;
+
+        let mut report = LoadReport::default();
+
+        // the last sample is often cut short, drop it rather than the whole module
+        if let Err(e) = read_sample_data(file, &mut instruments) {
+            if instruments[1..15].iter().any(|i| i.samples[0].length > 0 && i.samples[0].data.is_empty()) {
+                return Err(e);
+            }
+            let last = &mut instruments[15].samples[0];
+            last.length = 0;
+            last.loop_start = 0;
+            last.loop_end = 0;
+            last.loop_len = 0;
+            last.loop_type = LoopType::NoLoop;
+            report.repair(format!("{}, dropped sample 15", e));
+        }
+
+        let mut pattern_order = pattern_order;
+        fix_pattern_order(&mut pattern_order, song_length as usize, patterns.len(), &mut report);
+
+        patterns.push(Patterns::new(64, 4));
+
+        // without a CIA tempo the song is timed by the vertical blank
+        let vblank = !(tempo != 0 && tempo != 0x78 && tempo < 240);
+        let bpm = if !vblank {
+            clamp(((709379.0 * 125.0 / 50.0) / ((240 - tempo as u32) * 122) as f32).round() as u16, 32, 255)
+        } else {
+            125
+        };
+
+        Ok(SongData {
+            id: "".to_string(),
+            name: String::from_utf8_lossy(&name).replace('\0', " ").trim().to_string(),
+            song_type: SongType::MOD,
+            tracker_name: "Soundtracker".to_string(),
+            song_length: song_length as u16,
+            restart_position: 0,
+            channel_count: 4,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: 6,
+            bpm,
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank,
+            song_message: "".to_string(),
+            report,
+        })
+    }
+
+    pub(crate) fn read_mod_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(1080))?;
+        let num_channels = read_channel_count(&file.read_bytes(4)?)?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let title = file.read_string(20);
+        let instruments = read_instruments(file, 31, false).map_err(|e| e.at(Section::Instrument, 20))?;
+        let song_length = file.read_u8()?;
+        let _restart_position = file.read_u8()?;
+        let pattern_order = file.read_bytes(128)?;
+        let pattern_count = *pattern_order.iter().max().unwrap() as u16 + 1;
+
+        let names = instrument_names(&instruments);
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::MOD,
+            tracker_name: "Unknown".to_string(),
+            channel_count: num_channels as u16,
+            pattern_count,
+            order_count: song_length as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message: "".to_string(),
+        })
+    }
+
+    // only the header checks, the pattern scan of read_soundtracker would mean reading the patterns
+    pub(crate) fn read_soundtracker_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(0))?;
+        let title = file.read_bytes(20)?;
+        if !is_text(&title) {
+            return Err(LoadError::wrong_format(SongType::MOD));
+        }
+
+        check_soundtracker_samples(file)?;
+        file.seek(SeekFrom::Start(20))?;
+        let instruments = read_instruments(file, 15, true).map_err(|e| e.at(Section::Instrument, 20))?;
+        let song_length = file.read_u8()?;
+        let _tempo = file.read_u8()?;
+        let pattern_order = file.read_bytes(128)?;
+        let pattern_count = *pattern_order.iter().max().unwrap() as u16 + 1;
+
+        if song_length == 0 || song_length > 128 || pattern_count > 64 {
+            return Err(LoadError::wrong_format(SongType::MOD));
+        }
+
+        let names = instrument_names(&instruments);
+        Ok(ModuleMetadata {
+            title: String::from_utf8_lossy(&title).replace('\0', " ").trim().to_string(),
+            format: SongType::MOD,
+            tracker_name: "Soundtracker".to_string(),
+            channel_count: 4,
+            pattern_count,
+            order_count: song_length as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message: "".to_string(),
+        })
+    }
+
+    fn read_sample_data<R: Read + Seek>(f: &mut R, instruments: &mut Vec<Instrument>) -> LoadResult<()> {
         for i in 1..instruments.len() {
-            instruments[i].samples[0].read_non_packed_data(f)?;
+            let sample_pos = f.offset();
+            instruments[i].samples[0].read_non_packed_data(f).map_err(|e| e.at(Section::Sample, sample_pos))?;
         }
         Ok(())
     }
 
-    fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, channel_count: usize) -> SimpleResult<Vec<Patterns>> {
+    fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, channel_count: usize) -> LoadResult<Vec<Patterns>> {
         let mut patterns: Vec<Patterns> = vec![];
         patterns.reserve_exact(pattern_count as usize);
 
@@ -160,7 +357,7 @@
         Ok(patterns)
     }
 
-    fn fix_effects(e: u8, p: u8) -> (u8, u8) {
+    pub(crate) fn fix_effects(e: u8, p: u8) -> (u8, u8) {
         let mut effect = e;
         let mut effect_param = p;
 
@@ -199,9 +396,9 @@
         return (effect, effect_param)
     }
 
-    fn read_sample<R: Read>(file: &mut R) -> SimpleResult<Sample> {
+    fn read_sample<R: Read>(file: &mut R, soundtracker: bool) -> LoadResult<Sample> {
         let name = file.read_string(22);
-        let length = file.read_u16_be()? * 2;
+        let length = file.read_u16_be()? as u32 * 2;
         let ft = file.read_u8()? & 0xf;
         let sign = ((ft >> 3) * 0xF0) as i8;
         let mut finetune= ft as i8 | sign;
@@ -211,16 +408,21 @@
         let ft_ft = 8 * ((2 * (((ft as i16) & 0xF) ^ 8)) - 16) as i8;
 
         if ft_ft != finetune {
-            return Err(SimpleError::new("Bug in finetune calculation"));
+            return Err(LoadError::invalid("Bug in finetune calculation"));
         }
 
         let volume = file.read_u8()?;
-        let mut loop_start = file.read_u16_be()? * 2;
-        let mut loop_len = file.read_u16_be()? * 2;
+        let mut loop_start = file.read_u16_be()? as u32 * 2;
+        let mut loop_len = file.read_u16_be()? as u32 * 2;
 
         if loop_len < 2 {
             loop_len = 2;
         }
+
+        // Ultimate Soundtracker stored the loop start in bytes
+        if soundtracker && loop_start + loop_len > length && loop_start / 2 + loop_len <= length {
+            loop_start /= 2;
+        }
         // fix overflown loop
         if loop_start+loop_len > length {
             if loop_start >= length {
@@ -237,10 +439,10 @@
         }
 
         Ok(Sample {
-            length: length as u32,
-            loop_start: loop_start as u32,
-            loop_end: (loop_start + loop_len) as u32,
-            loop_len: loop_len as u32,
+            length,
+            loop_start,
+            loop_end: loop_start + loop_len,
+            loop_len,
             volume: clamp(volume, 0, 64),
             finetune,
             loop_type: if loop_len > 2 {LoopType::ForwardLoop} else {LoopType::NoLoop},
@@ -250,13 +452,13 @@
             name,
             is_ping_pong: false,
             original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
             data: vec![],
         })
     }
 
-    fn read_instruments<R: Read + Seek>(file: &mut R) -> SimpleResult<Vec<Instrument>> {
+    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize, soundtracker: bool) -> LoadResult<Vec<Instrument>> {
         let mut instruments: Vec<Instrument> = vec![];
-        let instrument_count = 31;
 
         // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
         instruments.reserve_exact(instrument_count + 1 as usize);
@@ -265,11 +467,89 @@
 
         for instrument_idx in 1..instrument_count +1 {
             let mut instrument = Instrument::new();
-            let sample = read_sample(file)?;
+            let sample = read_sample(file, soundtracker)?;
             instrument.name = sample.name.clone();
             instrument.idx = instrument_idx as u8;
             instrument.samples = vec![sample];
             instruments.push(instrument);
         }
         Ok(instruments)
-    }
\ No newline at end of file
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+        use crate::module_reader::LoadErrorKind;
+
+        fn build_soundtracker() -> Vec<u8> {
+            let mut data = vec![];
+            data.extend_from_slice(b"st-song\0\0\0\0\0\0\0\0\0\0\0\0\0");
+            for i in 0..15 {
+                let mut sample = [0u8; 30];
+                sample[..6].copy_from_slice(b"st-01:");
+                if i == 0 {
+                    sample[22..24].copy_from_slice(&16u16.to_be_bytes()); // length, in words
+                    sample[25] = 64;
+                    sample[26..28].copy_from_slice(&8u16.to_be_bytes());  // loop start, in bytes
+                    sample[28..30].copy_from_slice(&12u16.to_be_bytes()); // loop length, in words
+                }
+                data.extend_from_slice(&sample);
+            }
+            data.push(1);                                               // song length
+            data.push(0x78);                                            // default tempo
+            data.extend_from_slice(&[0u8; 128]);
+
+            let mut pattern = vec![0u8; 1024];
+            pattern[0..4].copy_from_slice(&[0x01, 0xAC, 0x1C, 0x20]);  // period 428, sample 1, C20
+            data.extend_from_slice(&pattern);
+            data.extend((0..32u8).map(|i| i.wrapping_mul(8)));
+            data
+        }
+
+        #[test]
+        fn test_read_soundtracker() {
+            let song = read_soundtracker(&mut Cursor::new(build_soundtracker())).unwrap();
+            assert_eq!(song.channel_count, 4);
+            assert_eq!(song.bpm, 125);
+            assert!(song.vblank);
+            assert_eq!(song.instruments.len(), 16);
+            assert_eq!(song.pattern_order[0], 0);
+
+            let channel = &song.patterns[0].rows[0].channels[0];
+            assert_eq!((channel.note, channel.instrument), (49, 1));
+            assert_eq!((channel.effect, channel.effect_param), (0xC, 0x20));
+
+            // the loop start is in bytes
+            let sample = &song.instruments[1].samples[0];
+            assert_eq!((sample.loop_start, sample.loop_end), (8 + 4, 32 + 4));
+        }
+
+        #[test]
+        fn test_metadata() {
+            crate::module_reader::metadata::assert_metadata_matches_song(&build_soundtracker());
+        }
+
+        #[test]
+        fn test_reject_bad_soundtracker() {
+            let mut data = build_soundtracker();
+            data[600] = 0x11;                                           // sample 17
+            assert!(read_soundtracker(&mut Cursor::new(data)).is_err());
+
+            let mut data = build_soundtracker();
+            data[20 + 25] = 65;                                         // volume
+            assert!(read_soundtracker(&mut Cursor::new(data)).is_err());
+        }
+
+        #[test]
+        fn test_bogus_sample_lengths() {
+            let mut data = build_soundtracker();
+            data[20 + 22..20 + 30].fill(0xFF);                          // length, loop start and loop length
+            assert_eq!(read_soundtracker_metadata(&mut Cursor::new(&data)).err().unwrap().kind(), &LoadErrorKind::WrongFormat);
+
+            let mut data = std::fs::read("test_data/test.mod").unwrap();
+            data[20 + 22..20 + 30].fill(0xFF);
+            let metadata = read_mod_metadata(&mut Cursor::new(&data)).unwrap();
+            assert_eq!(metadata.instrument_names.len(), 31);
+            assert!(read_mod(&mut Cursor::new(&data)).is_err());
+        }
+    }
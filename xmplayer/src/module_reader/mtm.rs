@@ -0,0 +1,389 @@
+    use crate::module_reader::{SongData, Patterns, SongType, FrequencyType};
+    use std::io::{Read, Seek, SeekFrom};
+    use binary_reader_io::{BinaryReader, BinarySeek};
+    use crate::pattern::Pattern;
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::module_reader::module::fix_effects;
+    use crate::channel_state::channel_state::clamp;
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+    use crate::module_reader::metadata::instrument_names;
+
+    const TRACK_SIZE: usize = 192;
+    const MAX_CHANNELS: usize = 32;
+
+    pub fn read_mtm<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < 66 + 128 {
+            return Err(LoadError::truncated().in_format(SongType::MTM).at(Section::Header, 0));
+        }
+
+        read_mtm_header(&mut file).map_err(|e| e.in_format(SongType::MTM).at(Section::Header, 0))
+    }
+
+    fn read_mtm_header<R: Read + Seek>(file: &mut R) -> LoadResult<SongData>
+    {
+        let id = file.read_bytes(3)?;
+        if id != "MTM".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::MTM));
+        }
+
+        let version = file.read_u8()?;
+        if version >= 0x20 {
+            return Err(LoadError::invalid("Unknown mtm version"));
+        }
+
+        let name = file.read_string(20);
+        let track_count = file.read_u16()?;
+        let last_pattern = file.read_u8()?;
+        let last_order = file.read_u8()?;
+        let comment_length = file.read_u16()?;
+        let sample_count = file.read_u8()?;
+        let _attribute = file.read_u8()?;
+        let mut row_count = file.read_u8()?;
+        let num_channels = file.read_u8()?;
+        let _pan_positions = file.read_bytes(32)?; // no per-channel default panning in SongData (yet)
+
+        if last_order >= 128 || row_count > 64 || num_channels == 0 || num_channels as usize > MAX_CHANNELS {
+            return Err(LoadError::invalid("Unknown mtm format"));
+        }
+
+        // 0 means the default 64 rows
+        if row_count == 0 { row_count = 64; }
+
+        let instruments_pos = file.offset();
+        let mut instruments = read_instruments(file, sample_count as usize).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+
+        let mut pattern_order = file.read_bytes(128)?;
+        let song_length = last_order as usize + 1;
+        pattern_order.truncate(song_length);
+
+        let tracks_pos = file.offset();
+        let tracks = file.read_bytes(track_count as usize * TRACK_SIZE).map_err(|e| LoadError::from(e).at(Section::Pattern, tracks_pos))?;
+        let sequence_pos = file.offset();
+        let track_sequence = file.read_u16_vec((last_pattern as usize + 1) * MAX_CHANNELS).map_err(|e| LoadError::from(e).at(Section::Pattern, sequence_pos))?;
+
+        let mut patterns = read_patterns(&tracks, &track_sequence, row_count as usize, num_channels as usize);
+
+        let comment_pos = file.offset();
+        let song_message = read_comment(file, comment_length as usize).map_err(|e| e.at(Section::Message, comment_pos))?;
+
+        read_sample_data(file, &mut instruments)?;
+
+        // fix empty patterns at end
+        let mut report = LoadReport::default();
+        fix_pattern_order(&mut pattern_order, song_length, patterns.len(), &mut report);
+
+        patterns.push(Patterns::new(64, num_channels as usize));
+
+        Ok(SongData {
+            id: "MTM".to_string(),
+            name: name.trim().to_string(),
+            song_type: SongType::MTM,
+            tracker_name: format!("MultiTracker {}.{}", version >> 4, version & 0xf),
+            song_length: song_length as u16,
+            restart_position: 0,
+            channel_count: num_channels as u16,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: 6,
+            bpm: 125,
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
+            song_message,
+            report,
+        })
+    }
+
+    pub(crate) fn read_mtm_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(0))?;
+        if file.read_bytes(3)? != "MTM".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::MTM));
+        }
+
+        let version = file.read_u8()?;
+        if version >= 0x20 {
+            return Err(LoadError::invalid("Unknown mtm version"));
+        }
+
+        let title = file.read_string(20);
+        let track_count = file.read_u16()?;
+        let last_pattern = file.read_u8()?;
+        let last_order = file.read_u8()?;
+        let comment_length = file.read_u16()?;
+        let sample_count = file.read_u8()?;
+        let _attribute = file.read_u8()?;
+        let row_count = file.read_u8()?;
+        let num_channels = file.read_u8()?;
+        let _pan_positions = file.read_bytes(32)?;
+
+        if last_order >= 128 || row_count > 64 || num_channels == 0 || num_channels as usize > MAX_CHANNELS {
+            return Err(LoadError::invalid("Unknown mtm format"));
+        }
+
+        let instruments_pos = file.offset();
+        let instruments = read_instruments(file, sample_count as usize).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+
+        // orders, tracks and the track sequence are in the way of the comment
+        let skip = 128 + track_count as i64 * TRACK_SIZE as i64 + (last_pattern as i64 + 1) * MAX_CHANNELS as i64 * 2;
+        file.seek(SeekFrom::Current(skip))?;
+        let song_message = read_comment(file, comment_length as usize)?;
+
+        let names = instrument_names(&instruments);
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::MTM,
+            tracker_name: format!("MultiTracker {}.{}", version >> 4, version & 0xf),
+            channel_count: num_channels as u16,
+            pattern_count: last_pattern as u16 + 1,
+            order_count: last_order as u16 + 1,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message,
+        })
+    }
+
+    fn read_comment<R: Read>(file: &mut R, length: usize) -> LoadResult<String> {
+        // The comment is stored as 40 character lines, NUL padded
+        let comment = file.read_bytes(length)?;
+        let lines: Vec<String> = comment.chunks(40)
+            .map(|line| String::from_utf8_lossy(line).replace('\0', " ").trim_end().to_string())
+            .collect();
+        Ok(lines.join("\n").trim_end().to_string())
+    }
+
+    fn read_sample_data<R: Read + Seek>(f: &mut R, instruments: &mut [Instrument]) -> LoadResult<()> {
+        for instrument in instruments.iter_mut().skip(1) {
+            let sample_pos = f.offset();
+            instrument.samples[0].read_unsigned_data(f).map_err(|e| e.at(Section::Sample, sample_pos))?;
+        }
+        Ok(())
+    }
+
+    fn read_patterns(tracks: &[u8], track_sequence: &[u16], row_count: usize, channel_count: usize) -> Vec<Patterns> {
+        let track_count = tracks.len() / TRACK_SIZE;
+        let mut patterns: Vec<Patterns> = vec![];
+
+        for sequence in track_sequence.chunks(MAX_CHANNELS) {
+            let mut pattern = Patterns::new(row_count, channel_count);
+
+            for (channel_idx, track) in sequence.iter().cloned().take(channel_count).enumerate() {
+                // Track 0 is never saved - it's always considered empty
+                if track == 0 || track as usize > track_count { continue; }
+
+                let track_data = &tracks[(track as usize - 1) * TRACK_SIZE..track as usize * TRACK_SIZE];
+                for (row_idx, row) in pattern.rows.iter_mut().enumerate() {
+                    row.channels[channel_idx] = read_note(&track_data[row_idx * 3..row_idx * 3 + 3]);
+                }
+            }
+            patterns.push(pattern);
+        }
+
+        patterns
+    }
+
+    // ppppppii iiiieeee aaaaaaaa
+    fn read_note(data: &[u8]) -> Pattern {
+        let pitch = data[0] >> 2;
+        let note = if pitch != 0 { pitch + 25 } else { 0 };
+        let instrument = ((data[0] & 0x3) << 4) | (data[1] >> 4);
+
+        let (effect, effect_param) = fix_effects(data[1] & 0xF, data[2]);
+
+        Pattern {
+            note: if note > 96 { 0 } else { note },
+            instrument,
+            volume: 0,
+            effect,
+            effect_param
+        }
+    }
+
+    fn read_sample<R: Read>(file: &mut R) -> LoadResult<Sample> {
+        let name = file.read_string(22);
+        let mut length = file.read_u32()?;
+        let mut loop_start = file.read_u32()?;
+        let mut loop_end = file.read_u32()?;
+        let ft = file.read_u8()? & 0xf;
+        let volume = file.read_u8()?;
+        let attribute = file.read_u8()?;
+
+        // same nibble encoding as MOD finetune
+        let finetune = 8 * ((2 * (((ft as i16) & 0xF) ^ 8)) - 16) as i8;
+
+        let bitness = if attribute & 1 == 1 { 16 } else { 8 };
+        if bitness == 16 { // length is in bytes
+            length /= 2;
+            loop_start /= 2;
+            loop_end /= 2;
+        }
+
+        // fix overflown loop
+        if loop_end > length {
+            loop_end = length;
+        }
+
+        let loop_type = if loop_start.saturating_add(2) < loop_end { LoopType::ForwardLoop } else { LoopType::NoLoop };
+        if loop_type == LoopType::NoLoop {
+            loop_start = 0;
+            loop_end = 0;
+        }
+
+        Ok(Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: clamp(volume, 0, 64),
+            finetune,
+            loop_type,
+            bitness,
+            panning: 128,
+            relative_note: 0,
+            name,
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![],
+        })
+    }
+
+    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize) -> LoadResult<Vec<Instrument>> {
+        let mut instruments: Vec<Instrument> = vec![];
+
+        // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
+        instruments.reserve_exact(instrument_count + 1);
+
+        instruments.push(Instrument::new());
+
+        for instrument_idx in 1..instrument_count + 1 {
+            let mut instrument = Instrument::new();
+            let sample = read_sample(file)?;
+            instrument.name = sample.name.clone();
+            instrument.idx = instrument_idx as u8;
+            instrument.samples = vec![sample];
+            instruments.push(instrument);
+        }
+        Ok(instruments)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+
+        fn build_mtm() -> Vec<u8> {
+            let mut data = vec![];
+            data.extend_from_slice(b"MTM");
+            data.push(0x10);                                    // version 1.0
+            data.extend_from_slice(&[b'x'; 20]);                // name
+            data.extend_from_slice(&1u16.to_le_bytes());        // tracks
+            data.push(0);                                       // last pattern
+            data.push(1);                                       // last order
+            data.extend_from_slice(&40u16.to_le_bytes());       // comment length
+            data.push(1);                                       // samples
+            data.push(0);                                       // attribute
+            data.push(64);                                      // rows
+            data.push(4);                                       // channels
+            data.extend_from_slice(&[0u8; 32]);                 // panning
+
+            data.extend_from_slice(&[b's'; 22]);                // sample name
+            data.extend_from_slice(&32u32.to_le_bytes());       // length
+            data.extend_from_slice(&8u32.to_le_bytes());        // loop start
+            data.extend_from_slice(&24u32.to_le_bytes());       // loop end
+            data.push(0);                                       // finetune
+            data.push(48);                                      // volume
+            data.push(0);                                       // attribute
+
+            let mut order = [0u8; 128];
+            order[1] = 5;                                       // past the last pattern
+            data.extend_from_slice(&order);
+
+            let mut track = [0u8; TRACK_SIZE];
+            track[0] = 24 << 2;                                 // C-4, instrument 1, effect C20
+            track[1] = (1 << 4) | 0xC;
+            track[2] = 0x20;
+            track[3] = 0;                                       // porta up with 0 param - no memory
+            track[4] = 0x1;
+            track[5] = 0;
+            data.extend_from_slice(&track);
+
+            let mut sequence = [0u16; 32];
+            sequence[2] = 1;
+            for t in sequence.iter() {
+                data.extend_from_slice(&t.to_le_bytes());
+            }
+
+            let mut comment = [0u8; 40];
+            comment[..5].copy_from_slice(b"hello");
+            data.extend_from_slice(&comment);
+
+            data.extend((0..32u8).map(|i| i.wrapping_mul(8)));
+            data
+        }
+
+        #[test]
+        fn test_read_mtm() {
+            let song = read_mtm(&mut Cursor::new(build_mtm())).unwrap();
+            assert_eq!(song.channel_count, 4);
+            assert_eq!(song.song_length, 2);
+            assert_eq!(song.pattern_order, vec![0, 1]);
+            assert_eq!(song.patterns.len(), 2);
+            assert_eq!(song.song_message, "hello");
+
+            let row = &song.patterns[0].rows[0];
+            assert_eq!(row.channels[2].note, 49);
+            assert_eq!(row.channels[2].instrument, 1);
+            assert_eq!(row.channels[2].effect, 0xC);
+            assert_eq!(row.channels[2].effect_param, 0x20);
+            assert_eq!(song.patterns[0].rows[1].channels[2].effect, 0);
+            assert_eq!(row.channels[0].note, 0);
+
+            let sample = &song.instruments[1].samples[0];
+            assert_eq!(sample.loop_type, LoopType::ForwardLoop);
+            assert_eq!(sample.volume, 48);
+            assert_eq!(sample.data.len(), 32 + 8);
+        }
+
+        #[test]
+        fn test_bogus_loop_start() {
+            let mut data = build_mtm();
+            data[92..96].copy_from_slice(&u32::MAX.to_le_bytes());
+            let song = read_mtm(&mut Cursor::new(data)).unwrap();
+            assert_eq!(song.instruments[1].samples[0].loop_type, LoopType::NoLoop);
+        }
+
+        #[test]
+        fn test_truncated_tracks() {
+            let mut data = build_mtm();
+            let tracks_pos = 66 + 37 + 128;
+            data.truncate(tracks_pos + 10);
+            let error = read_mtm(&mut Cursor::new(data.clone())).err().unwrap();
+            assert_eq!(error.to_string(), format!("Truncated pattern data (MTM pattern at offset 0x{:X})", tracks_pos));
+
+            // not being in the header, it's worth padding
+            let song = crate::module_reader::open_module_with_options(&data, &crate::module_reader::LoadOptions::lenient()).unwrap();
+            assert_eq!(song.song_type, SongType::MTM);
+            assert!(song.get_load_report().repairs().any(|repair| repair.starts_with("Truncated pattern data (MTM pattern at offset")));
+        }
+
+        #[test]
+        fn test_metadata() {
+            crate::module_reader::metadata::assert_metadata_matches_song(&build_mtm());
+        }
+
+        #[test]
+        fn test_reject_non_mtm() {
+            let mut data = build_mtm();
+            data[0] = b'X';
+            assert!(read_mtm(&mut Cursor::new(data)).is_err());
+        }
+    }
@@ -0,0 +1,386 @@
+    use crate::module_reader::{SongData, Patterns, SongType, FrequencyType};
+    use std::io::{Read, Seek, SeekFrom};
+    use binary_reader_io::{BinaryReader, BinarySeek};
+    use crate::pattern::Pattern;
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::channel_state::channel_state::clamp;
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+
+    const SAMPLE_COUNT: usize = 36;
+    const SAMPLE_HEADER_SIZE: usize = 32;
+
+    // Oktalyzer modules are IFF-like: an "OKTASONG" tag followed by big endian chunks
+    pub fn read_okt<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < 16 {
+            return Err(LoadError::truncated().in_format(SongType::OKT).at(Section::Header, 0));
+        }
+
+        read_okt_header(&mut file, file_len).map_err(|e| e.in_format(SongType::OKT).at(Section::Header, 0))
+    }
+
+    fn read_okt_header<R: Read + Seek>(file: &mut R, file_len: u64) -> LoadResult<SongData>
+    {
+        let id = file.read_bytes(8)?;
+        if id != "OKTASONG".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::OKT));
+        }
+
+        let mut channel_count = 0usize;
+        let mut sample_headers = vec![];
+        let mut speed = 6u16;
+        let mut song_length = 0usize;
+        let mut pattern_order = vec![];
+        let mut patterns: Vec<Patterns> = vec![];
+        let mut sample_bodies: Vec<Vec<u8>> = vec![];
+        let mut report = LoadReport::default();
+
+        while file.stream_position()? + 8 <= file_len {
+            let chunk_id = file.read_bytes(4)?;
+            let chunk_len = file.read_u32_be()? as u64;
+            let chunk_start = file.offset();
+            if chunk_start + chunk_len > file_len {
+                // a truncated last sample is common enough, take what's there
+                if &chunk_id[..] != b"SBOD" {
+                    let section = match &chunk_id[..] { b"PBOD" => Section::Pattern, b"SAMP" => Section::Instrument, _ => Section::Header };
+                    return Err(LoadError::truncated().at(section, chunk_start - 8));
+                }
+            }
+
+            match &chunk_id[..] {
+                b"CMOD" => {
+                    // each of the 4 Amiga channels can be split into two software mixed channels
+                    for _ in 0..4 {
+                        channel_count += if file.read_u16_be()? != 0 { 2 } else { 1 };
+                    }
+                }
+                b"SAMP" => {
+                    let data = file.read_bytes(chunk_len as usize)?;
+                    sample_headers = data.chunks_exact(SAMPLE_HEADER_SIZE).take(SAMPLE_COUNT).map(|h| h.to_vec()).collect();
+                }
+                b"SPEE" => { speed = file.read_u16_be()?; }
+                b"SLEN" => { let _pattern_count = file.read_u16_be()?; }
+                b"PLEN" => { song_length = file.read_u16_be()? as usize; }
+                b"PATT" => { pattern_order = file.read_bytes(chunk_len.min(128) as usize)?; }
+                b"PBOD" => {
+                    if channel_count == 0 {
+                        return Err(LoadError::invalid("Oktalyzer pattern before channel modes"));
+                    }
+                    patterns.push(read_pattern(file, channel_count).map_err(|e| e.at(Section::Pattern, chunk_start))?);
+                }
+                b"SBOD" => {
+                    let len = chunk_len.min(file_len - chunk_start);
+                    if len < chunk_len {
+                        report.repair(format!("Sample data at offset 0x{:X} is cut short by 0x{:X} bytes", chunk_start, chunk_len - len));
+                    }
+                    sample_bodies.push(file.read_bytes(len as usize)?);
+                }
+                _ => {}
+            }
+
+            file.seek(SeekFrom::Start(chunk_start + chunk_len))?;
+        }
+
+        if channel_count == 0 || patterns.is_empty() || song_length == 0 || song_length > pattern_order.len() {
+            return Err(LoadError::invalid("Unknown Oktalyzer format"));
+        }
+
+        let instruments = read_instruments(&sample_headers, sample_bodies)?;
+
+        pattern_order.truncate(song_length);
+
+        // fix empty patterns at end
+        fix_pattern_order(&mut pattern_order, song_length, patterns.len(), &mut report);
+
+        patterns.push(Patterns::new(64, channel_count));
+
+        Ok(SongData {
+            id: "OKTASONG".to_string(),
+            name: "".to_string(),
+            song_type: SongType::OKT,
+            tracker_name: "Oktalyzer".to_string(),
+            song_length: song_length as u16,
+            restart_position: 0,
+            channel_count: channel_count as u16,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: clamp(speed, 1, 0x1F),
+            bpm: 125,
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
+            song_message: "".to_string(),
+            report,
+        })
+    }
+
+    // walks the chunks like read_okt, but only reads the small ones
+    pub(crate) fn read_okt_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file.read_bytes(8)? != "OKTASONG".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::OKT));
+        }
+
+        let mut channel_count = 0usize;
+        let mut pattern_count = 0u16;
+        let mut song_length = 0u16;
+        let mut names = vec![];
+
+        while file.stream_position()? + 8 <= file_len {
+            let chunk_id = file.read_bytes(4)?;
+            let chunk_len = file.read_u32_be()? as u64;
+            let chunk_start = file.offset();
+
+            match &chunk_id[..] {
+                b"CMOD" => {
+                    for _ in 0..4 {
+                        channel_count += if file.read_u16_be()? != 0 { 2 } else { 1 };
+                    }
+                }
+                b"SAMP" => {
+                    let data = file.read_bytes(chunk_len as usize).map_err(|e| LoadError::from(e).at(Section::Instrument, chunk_start - 8))?;
+                    names = data.chunks_exact(SAMPLE_HEADER_SIZE).take(SAMPLE_COUNT).map(|h| read_sample(h).name).collect();
+                }
+                b"PLEN" => { song_length = file.read_u16_be()?; }
+                b"PBOD" => { pattern_count += 1; }
+                _ => {}
+            }
+
+            file.seek(SeekFrom::Start(chunk_start + chunk_len))?;
+        }
+
+        if channel_count == 0 || pattern_count == 0 || song_length == 0 {
+            return Err(LoadError::invalid("Unknown Oktalyzer format"));
+        }
+
+        Ok(ModuleMetadata {
+            title: "".to_string(),
+            format: SongType::OKT,
+            tracker_name: "Oktalyzer".to_string(),
+            channel_count: channel_count as u16,
+            pattern_count,
+            order_count: song_length,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message: "".to_string(),
+        })
+    }
+
+    fn read_pattern<R: Read>(file: &mut R, channel_count: usize) -> LoadResult<Patterns> {
+        let row_count = file.read_u16_be()? as usize;
+        if row_count == 0 || row_count > 256 {
+            return Err(LoadError::invalid("Bad Oktalyzer pattern length"));
+        }
+
+        let data = file.read_bytes(row_count * channel_count * 4)?;
+        let mut pattern = Patterns::new(row_count, channel_count);
+        for (note, channel) in data.chunks_exact(4).zip(pattern.rows.iter_mut().flat_map(|r| r.channels.iter_mut())) {
+            *channel = read_note(note);
+        }
+        Ok(pattern)
+    }
+
+    // nnnnnnnn iiiiiiii eeeeeeee pppppppp
+    fn read_note(data: &[u8]) -> Pattern {
+        let mut pattern = Pattern::new();
+
+        // Oktalyzer's C-1 is ProTracker's C-1 (period 856). Samples past the last one are left out, the note plays
+        // with the sample the channel has.
+        if data[0] > 0 && data[0] <= 36 {
+            pattern.note = data[0] + 36;
+            if (data[1] as usize) < SAMPLE_COUNT {
+                pattern.instrument = data[1] + 1;
+            }
+        }
+
+        let param = data[3];
+        let (effect, effect_param) = match data[2] {
+            1 if param != 0 => (0x1, param),                           // portamento down (period)
+            2 if param != 0 => (0x2, param),                           // portamento up (period)
+            10..=12 if param != 0 => (0x0, param),                     // arpeggio 1-3
+            17 | 21 => {                                               // H/L - slide up/down once (notes)
+                if pattern.note != 0 {
+                    let shift = (param & 0xF) as i16 * if data[2] == 17 { 1 } else { -1 };
+                    pattern.note = clamp(pattern.note as i16 + shift, 1, 96) as u8;
+                }
+                (0, 0)
+            }
+            25 if param < 0x80 => (0xB, (param >> 4) * 10 + (param & 0xF)), // position jump, decimal
+            27 => {                                                    // release sample
+                pattern.note = 97;
+                (0, 0)
+            }
+            28 if param & 0xF != 0 => (0xF, param & 0xF),              // speed
+            31 => match param {                                        // volume
+                0x00..=0x40 => (0xC, param),
+                0x41..=0x4F => (0xA, param & 0xF),                     // fade down, every tick
+                0x51..=0x5F => (0xA, (param & 0xF) << 4),              // fade up, every tick
+                0x61..=0x6F => (0xE, 0xB0 | (param & 0xF)),            // fade down once
+                0x71..=0x7F => (0xE, 0xA0 | (param & 0xF)),            // fade up once
+                _ => (0, 0),
+            },
+            _ => (0, 0),                                               // filter and continuous note slides
+        };
+        pattern.effect = effect;
+        pattern.effect_param = effect_param;
+
+        pattern
+    }
+
+    fn read_sample(header: &[u8]) -> Sample {
+        let name = String::from_utf8_lossy(&header[0..20]).replace('\0', " ").trim().to_string();
+        let length = u32::from_be_bytes([header[20], header[21], header[22], header[23]]);
+        let repeat = u16::from_be_bytes([header[24], header[25]]) as u32 * 2;
+        let repeat_length = u16::from_be_bytes([header[26], header[27]]) as u32 * 2;
+        let volume = header[29];
+
+        let mut loop_start = repeat;
+        let mut loop_end = (repeat + repeat_length).min(length);
+        let loop_type = if repeat_length > 2 && loop_start + 2 < loop_end { LoopType::ForwardLoop } else { LoopType::NoLoop };
+        if loop_type == LoopType::NoLoop {
+            loop_start = 0;
+            loop_end = 0;
+        }
+
+        Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: clamp(volume, 0, 64),
+            finetune: 0,
+            loop_type,
+            bitness: 8,
+            panning: 128,
+            relative_note: 0,
+            name,
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![],
+        }
+    }
+
+    // Sample bodies are only stored for samples that have a length, in order
+    fn read_instruments(headers: &[Vec<u8>], bodies: Vec<Vec<u8>>) -> LoadResult<Vec<Instrument>> {
+        let mut instruments: Vec<Instrument> = vec![];
+
+        // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
+        instruments.reserve_exact(headers.len() + 1);
+
+        instruments.push(Instrument::new());
+
+        let mut bodies = bodies.into_iter();
+        for (idx, header) in headers.iter().enumerate() {
+            let mut instrument = Instrument::new();
+            let mut sample = read_sample(header);
+            if sample.length > 0 {
+                let body = bodies.next().unwrap_or_default();
+                sample.length = sample.length.min(body.len() as u32);
+                sample.loop_end = sample.loop_end.min(sample.length);
+                if sample.loop_start + 2 >= sample.loop_end {
+                    sample.loop_type = LoopType::NoLoop;
+                    sample.loop_start = 0;
+                    sample.loop_end = 0;
+                }
+                sample.loop_len = sample.loop_end - sample.loop_start;
+                sample.read_non_packed_data(&mut &body[..])?;
+            }
+            instrument.name = sample.name.clone();
+            instrument.idx = (idx + 1) as u8;
+            instrument.samples = vec![sample];
+            instruments.push(instrument);
+        }
+        Ok(instruments)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+
+        fn chunk(data: &mut Vec<u8>, id: &[u8], body: &[u8]) {
+            data.extend_from_slice(id);
+            data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(body);
+        }
+
+        fn build_okt() -> Vec<u8> {
+            let mut data = b"OKTASONG".to_vec();
+            chunk(&mut data, b"CMOD", &[0, 0, 0, 1, 0, 1, 0, 0]);
+
+            let mut samples = vec![0u8; SAMPLE_COUNT * SAMPLE_HEADER_SIZE];
+            samples[0..4].copy_from_slice(b"bass");
+            samples[20..24].copy_from_slice(&32u32.to_be_bytes());
+            samples[24..26].copy_from_slice(&4u16.to_be_bytes());     // repeat, in words
+            samples[26..28].copy_from_slice(&8u16.to_be_bytes());     // repeat length, in words
+            samples[29] = 48;
+            chunk(&mut data, b"SAMP", &samples);
+
+            chunk(&mut data, b"SPEE", &3u16.to_be_bytes());
+            chunk(&mut data, b"SLEN", &1u16.to_be_bytes());
+            chunk(&mut data, b"PLEN", &1u16.to_be_bytes());
+            chunk(&mut data, b"PATT", &[0u8; 128]);
+
+            let mut pattern = 2u16.to_be_bytes().to_vec();
+            let mut rows = vec![0u8; 2 * 6 * 4];
+            rows[0..4].copy_from_slice(&[13, 0, 31, 0x45]);          // C-2, sample 1, fade down 5
+            rows[4..8].copy_from_slice(&[0, 0, 28, 4]);               // speed 4
+            rows[24..28].copy_from_slice(&[0, 0, 27, 0]);             // release
+            pattern.extend_from_slice(&rows);
+            chunk(&mut data, b"PBOD", &pattern);
+
+            chunk(&mut data, b"SBOD", &[0x10u8; 32]);
+            data
+        }
+
+        #[test]
+        fn test_read_okt() {
+            let song = read_okt(&mut Cursor::new(build_okt())).unwrap();
+            assert_eq!(song.channel_count, 6);
+            assert_eq!(song.tempo, 3);
+            assert_eq!(song.pattern_order, vec![0]);
+            assert_eq!(song.patterns[0].rows.len(), 2);
+
+            let first = &song.patterns[0].rows[0].channels;
+            assert_eq!((first[0].note, first[0].instrument), (49, 1));
+            assert_eq!((first[0].effect, first[0].effect_param), (0xA, 0x05));
+            assert_eq!((first[1].effect, first[1].effect_param), (0xF, 4));
+            assert_eq!(song.patterns[0].rows[1].channels[0].note, 97);
+
+            let sample = &song.instruments[1].samples[0];
+            assert_eq!(sample.volume, 48);
+            assert_eq!(sample.loop_type, LoopType::ForwardLoop);
+            assert_eq!((sample.loop_start, sample.loop_end), (8 + 4, 24 + 4));
+            assert_eq!(song.instruments.len(), SAMPLE_COUNT + 1);
+        }
+
+        #[test]
+        fn test_sample_past_the_last_one() {
+            assert_eq!((read_note(&[13, 35, 0, 0]).note, read_note(&[13, 35, 0, 0]).instrument), (49, 36));
+            assert_eq!((read_note(&[13, 36, 0, 0]).note, read_note(&[13, 36, 0, 0]).instrument), (49, 0));
+            assert_eq!(read_note(&[13, 255, 0, 0]).instrument, 0);
+        }
+
+        #[test]
+        fn test_metadata() {
+            crate::module_reader::metadata::assert_metadata_matches_song(&build_okt());
+        }
+
+        #[test]
+        fn test_reject_non_okt() {
+            let mut data = build_okt();
+            data[0] = b'X';
+            assert!(read_okt(&mut Cursor::new(data)).is_err());
+        }
+    }
@@ -0,0 +1,254 @@
+    use crate::module_reader::SongType;
+
+    // Most formats have a magic string, the header sanity checks only nudge the score.
+    // Soundtracker has no magic at all so it never scores high.
+    const MAGIC: u8 = 80;
+    const WEAK_MAGIC: u8 = 50;
+    const NO_MAGIC: u8 = 20;
+    const SANE_HEADER: u8 = 20;
+
+    type Probe = fn(&[u8]) -> u8;
+
+    const SOUNDTRACKER_HEADER_SIZE: usize = 20 + 15 * 30 + 2 + 128;
+
+    /// Guesses the format of a module from its header, without loading it.
+    /// Returns every plausible format with a confidence of 1-100, best match first.
+    pub fn probe_format(data: &[u8]) -> Vec<(SongType, u8)> {
+        let probes: [(SongType, Probe); 12] = [
+            (SongType::XM, probe_xm),
+            (SongType::IT, probe_it),
+            (SongType::S3M, probe_s3m),
+            (SongType::STM, probe_stm),
+            (SongType::MOD, probe_mod),
+            (SongType::MTM, probe_mtm),
+            (SongType::C669, probe_669),
+            (SongType::MED, probe_med),
+            (SongType::OKT, probe_okt),
+            (SongType::PTM, probe_ptm),
+            (SongType::ULT, probe_ult),
+            (SongType::FAR, probe_far),
+        ];
+
+        let mut result: Vec<(SongType, u8)> = probes.iter()
+            .map(|(song_type, probe)| (*song_type, probe(data)))
+            .filter(|(_, confidence)| *confidence > 0)
+            .collect();
+
+        // stable, so ties keep the order above
+        result.sort_by_key(|(_, confidence)| std::cmp::Reverse(*confidence));
+        result
+    }
+
+    /// Does the data have one of the 31 sample MOD signatures? Without one it can only be a 15 sample Soundtracker module.
+    pub(crate) fn has_mod_signature(data: &[u8]) -> bool {
+        match data.get(1080..1084) {
+            Some(b"M.K." | b"6CHN" | b"8CHN") => true,
+            Some(id) if id[2] == b'C' && id[3] == b'H' => {
+                std::str::from_utf8(&id[0..2]).ok().and_then(|n| n.parse::<usize>().ok()).is_some_and(|n| (10..=32).contains(&n))
+            }
+            _ => false,
+        }
+    }
+
+    fn u16_le(data: &[u8], offset: usize) -> Option<u16> {
+        data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32_be(data: &[u8], offset: usize) -> Option<u32> {
+        data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn is_text(data: &[u8]) -> bool {
+        data.iter().all(|c| *c == 0 || *c >= 32)
+    }
+
+    fn score(magic: u8, sane: bool) -> u8 {
+        magic + if sane { SANE_HEADER } else { 0 }
+    }
+
+    fn probe_xm(data: &[u8]) -> u8 {
+        if data.len() < 60 || &data[0..17] != b"Extended Module: " {
+            return 0;
+        }
+        let header_size = data.get(60..64).map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        score(MAGIC, data[37] == 0x1A && header_size >= 20)
+    }
+
+    fn probe_it(data: &[u8]) -> u8 {
+        if data.len() < 0xC0 || &data[0..4] != b"IMPM" {
+            return 0;
+        }
+        let order_count = u16_le(data, 0x20).unwrap_or(0);
+        let instrument_count = u16_le(data, 0x22).unwrap_or(0);
+        let sample_count = u16_le(data, 0x24).unwrap_or(0);
+        let pattern_count = u16_le(data, 0x26).unwrap_or(0);
+        score(MAGIC, order_count <= 256 && instrument_count <= 99 && sample_count <= 99 && pattern_count <= 256)
+    }
+
+    fn probe_s3m(data: &[u8]) -> u8 {
+        if data.len() < 0x60 || &data[44..48] != b"SCRM" {
+            return 0;
+        }
+        score(MAGIC, data[28] == 0x1A && data[29] == 16)
+    }
+
+    fn probe_stm(data: &[u8]) -> u8 {
+        if data.len() < 48 {
+            return 0;
+        }
+        match &data[20..28] {
+            b"!Scream!" | b"BMOD2STM" | b"WUZAMOD!" | b"SWavePro" => {}
+            _ => return 0,
+        }
+        score(MAGIC, data[28] == 0x1A && data[29] == 2 && data[31] > 0)
+    }
+
+    fn probe_mod(data: &[u8]) -> u8 {
+        if has_mod_signature(data) {
+            // four printable bytes at 1080 can happen by chance, so check the order list too
+            let song_length = data[950];
+            return score(MAGIC, song_length > 0 && song_length <= 128 && data[952..1080].iter().all(|p| *p < 128));
+        }
+        probe_soundtracker(data)
+    }
+
+    // header sanity is all there is to go on
+    fn probe_soundtracker(data: &[u8]) -> u8 {
+        if data.len() < SOUNDTRACKER_HEADER_SIZE + 1024 || !is_text(&data[0..20]) {
+            return 0;
+        }
+
+        let mut samples_len = 0usize;
+        for sample in data[20..20 + 15 * 30].chunks_exact(30) {
+            let length = u16::from_be_bytes([sample[22], sample[23]]);
+            if !is_text(&sample[0..22]) || sample[24] > 0xF || sample[25] > 64 || length > 0x7FFF {
+                return 0;
+            }
+            samples_len += length as usize * 2;
+        }
+
+        let song_length = data[470];
+        let pattern_count = *data[472..600].iter().max().unwrap() as usize + 1;
+        if song_length == 0 || song_length > 128 || pattern_count > 64 {
+            return 0;
+        }
+
+        let expected_len = SOUNDTRACKER_HEADER_SIZE + pattern_count * 1024 + samples_len;
+        score(NO_MAGIC, data.len() >= SOUNDTRACKER_HEADER_SIZE + pattern_count * 1024 && data.len() <= expected_len + 4096)
+    }
+
+    fn probe_mtm(data: &[u8]) -> u8 {
+        if data.len() < 66 || &data[0..3] != b"MTM" || data[3] >= 0x20 {
+            return 0;
+        }
+        score(MAGIC, data[27] < 128 && data[32] <= 64 && data[33] > 0 && data[33] <= 32)
+    }
+
+    // "if" and "JN" are common enough at the start of a file to need the header checks to count
+    fn probe_669(data: &[u8]) -> u8 {
+        if data.len() < 0x1F1 || !(&data[0..2] == b"if" || &data[0..2] == b"JN") {
+            return 0;
+        }
+        let sample_count = data[110];
+        let pattern_count = data[111];
+        let loop_order = data[112];
+        let orders = &data[113..241];
+        let song_length = orders.iter().position(|x| *x == 0xFF).unwrap_or(orders.len());
+        let breaks = &data[369..497];
+        let sane = sample_count <= 64 && pattern_count > 0 && pattern_count <= 128 && loop_order < 128 && song_length > 0
+            && orders[0..song_length].iter().all(|o| *o < pattern_count)
+            && breaks[0..pattern_count as usize].iter().all(|b| *b < 64);
+        if sane { score(WEAK_MAGIC, true) } else { 0 }
+    }
+
+    fn probe_med(data: &[u8]) -> u8 {
+        if data.len() < 52 || !matches!(&data[0..4], b"MMD0" | b"MMD1" | b"MMD2" | b"MMD3") {
+            return 0;
+        }
+        let song_ptr = u32_be(data, 8).unwrap_or(0) as usize;
+        let blocks_ptr = u32_be(data, 16).unwrap_or(0) as usize;
+        score(MAGIC, song_ptr > 0 && song_ptr < data.len() && blocks_ptr > 0 && blocks_ptr < data.len())
+    }
+
+    fn probe_okt(data: &[u8]) -> u8 {
+        if data.len() < 16 || &data[0..8] != b"OKTASONG" {
+            return 0;
+        }
+        score(MAGIC, &data[8..12] == b"CMOD")
+    }
+
+    fn probe_ptm(data: &[u8]) -> u8 {
+        if data.len() < 608 || &data[44..48] != b"PTMF" {
+            return 0;
+        }
+        let channel_count = u16_le(data, 38).unwrap_or(0);
+        score(MAGIC, data[28] == 0x1A && channel_count > 0 && channel_count <= 32)
+    }
+
+    fn probe_ult(data: &[u8]) -> u8 {
+        if data.len() < 48 || &data[0..14] != b"MAS_UTrack_V00" {
+            return 0;
+        }
+        score(MAGIC, (b'1'..=b'4').contains(&data[14]))
+    }
+
+    fn probe_far(data: &[u8]) -> u8 {
+        if data.len() < 98 || data[0..4] != [b'F', b'A', b'R', 0xFE] {
+            return 0;
+        }
+        score(MAGIC, data[44..47] == [b'\r', b'\n', 0x1A])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::module_reader::{open_module, open_module_as, LoadErrorKind};
+
+        #[test]
+        fn test_probe_test_data() {
+            let files = [
+                ("test_data/test.xm", SongType::XM),
+                ("test_data/test.mod", SongType::MOD),
+                ("test_data/test.s3m", SongType::S3M),
+                ("test_data/test.ptm", SongType::PTM),
+                ("test_data/test.ult", SongType::ULT),
+                ("test_data/test.far", SongType::FAR),
+            ];
+            for (path, song_type) in files {
+                let data = std::fs::read(path).unwrap();
+                let probed = probe_format(&data);
+                assert_eq!(probed.first(), Some(&(song_type, 100)), "{}", path);
+            }
+        }
+
+        #[test]
+        fn test_probe_garbage() {
+            assert!(probe_format(&[0u8; 16]).is_empty());
+            assert!(probe_format(b"if it isn't a module, it isn't a module").is_empty());
+            assert!(open_module(&[0u8; 16]).is_err());
+        }
+
+        // read_s3m wants at least 1084 bytes, test.s3m is shorter than that
+        fn read_padded_s3m() -> Vec<u8> {
+            let mut data = std::fs::read("test_data/test.s3m").unwrap();
+            data.resize(1084.max(data.len()), 0);
+            data
+        }
+
+        #[test]
+        fn test_corrupt_s3m_reports_s3m_error() {
+            let mut data = read_padded_s3m();
+            data[29] = 0; // file type
+            assert_eq!(probe_format(&data)[0], (SongType::S3M, MAGIC));
+            let error = open_module(&data).err().unwrap();
+            assert_eq!(error.format(), Some(SongType::S3M));
+            assert_eq!(error.kind(), &LoadErrorKind::Invalid("Unknown s3m format".to_string()));
+        }
+
+        #[test]
+        fn test_forced_format() {
+            let data = std::fs::read("test_data/test.xm").unwrap();
+            assert!(open_module_as(&data, SongType::XM).is_ok());
+            assert!(open_module_as(&data, SongType::S3M).err().unwrap().is_wrong_format());
+        }
+    }
@@ -0,0 +1,310 @@
+    use crate::module_reader::{SongData, Patterns, SongType, FrequencyType};
+    use std::io::{Read, Seek, SeekFrom};
+    use binary_reader_io::{BinaryReader, BinarySeek};
+    use crate::pattern::Pattern;
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::channel_state::channel_state::clamp;
+    use crate::module_reader;
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+    use crate::module_reader::metadata::instrument_names;
+
+    const HEADER_SIZE: u64 = 608;
+    const SAMPLE_HEADER_SIZE: u64 = 80;
+    const ROW_COUNT: usize = 64;
+
+    pub fn read_ptm<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < HEADER_SIZE {
+            return Err(LoadError::truncated().in_format(SongType::PTM).at(Section::Header, 0));
+        }
+
+        read_ptm_header(&mut file, file_len).map_err(|e| e.in_format(SongType::PTM).at(Section::Header, 0))
+    }
+
+    fn read_ptm_header<R: Read + Seek>(file: &mut R, file_len: u64) -> LoadResult<SongData>
+    {
+        file.seek(SeekFrom::Start(44))?;
+        let id = file.read_bytes(4)?;
+        if id != "PTMF".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::PTM));
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let name = file.read_string(28);
+        let _eof = file.read_u8()?;
+        let version = file.read_u16()?;
+        let _reserved = file.read_u8()?;
+        let song_length = file.read_u16()?;
+        let sample_count = file.read_u16()?;
+        let pattern_count = file.read_u16()?;
+        let channel_count = file.read_u16()?;
+
+        if song_length == 0 || song_length > 256 || sample_count > 255 || pattern_count == 0 || pattern_count > 128
+            || channel_count == 0 || channel_count > 32 {
+            return Err(LoadError::invalid("Unknown PTM format"));
+        }
+
+        file.seek(SeekFrom::Start(96))?;
+        let mut pattern_order = file.read_bytes(256)?;
+        pattern_order.truncate(song_length as usize);
+        let pattern_offsets = file.read_u16_vec(128)?;
+
+        if file_len < HEADER_SIZE + sample_count as u64 * SAMPLE_HEADER_SIZE {
+            return Err(LoadError::truncated());
+        }
+
+        let instruments_pos = file.offset();
+        let (mut instruments, sample_offsets) = read_instruments(file, sample_count as usize).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+        let mut patterns = read_patterns(file, &pattern_offsets[..pattern_count as usize], channel_count as usize, file_len)?;
+
+        read_sample_data(file, &mut instruments, &sample_offsets, file_len)?;
+
+        // fix empty patterns at end
+        let mut report = LoadReport::default();
+        fix_pattern_order(&mut pattern_order, song_length as usize, patterns.len(), &mut report);
+
+        patterns.push(Patterns::new(64, channel_count as usize));
+
+        Ok(SongData {
+            id: "PTMF".to_string(),
+            name: name.trim().to_string(),
+            song_type: SongType::PTM,
+            tracker_name: format!("PolyTracker {}.{:02x}", version >> 8, version & 0xFF),
+            song_length,
+            restart_position: 0,
+            channel_count,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: 6,
+            bpm: 125,
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
+            song_message: "".to_string(),
+            report,
+        })
+    }
+
+    pub(crate) fn read_ptm_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(44))?;
+        if file.read_bytes(4)? != "PTMF".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::PTM));
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let title = file.read_string(28);
+        let _eof = file.read_u8()?;
+        let version = file.read_u16()?;
+        let _reserved = file.read_u8()?;
+        let song_length = file.read_u16()?;
+        let sample_count = file.read_u16()?;
+        let pattern_count = file.read_u16()?;
+        let channel_count = file.read_u16()?;
+
+        if song_length == 0 || song_length > 256 || sample_count > 255 || pattern_count == 0 || pattern_count > 128
+            || channel_count == 0 || channel_count > 32 {
+            return Err(LoadError::invalid("Unknown PTM format"));
+        }
+
+        let (instruments, _) = read_instruments(file, sample_count as usize).map_err(|e| e.at(Section::Instrument, HEADER_SIZE))?;
+
+        let names = instrument_names(&instruments);
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::PTM,
+            tracker_name: format!("PolyTracker {}.{:02x}", version >> 8, version & 0xFF),
+            channel_count,
+            pattern_count,
+            order_count: song_length,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message: "".to_string(),
+        })
+    }
+
+    // Sample data is delta encoded bytes, even for 16 bit samples
+    fn read_sample_data<R: Read + Seek>(file: &mut R, instruments: &mut [Instrument], offsets: &[u32], file_len: u64) -> LoadResult<()> {
+        for (instrument, offset) in instruments.iter_mut().skip(1).zip(offsets) {
+            let sample = &mut instrument.samples[0];
+            if sample.length == 0 { continue; }
+
+            let byte_len = sample.length as u64 * (sample.bitness as u64 / 8);
+            if *offset as u64 + byte_len > file_len {
+                return Err(LoadError::truncated().at(Section::Sample, *offset as u64));
+            }
+
+            file.seek(SeekFrom::Start(*offset as u64))?;
+            let mut data = file.read_bytes(byte_len as usize)?;
+            let mut acc = 0u8;
+            for b in data.iter_mut() {
+                acc = acc.wrapping_add(*b);
+                *b = acc;
+            }
+            sample.read_non_packed_data(&mut &data[..])?;
+        }
+        Ok(())
+    }
+
+    fn read_patterns<R: Read + Seek>(file: &mut R, offsets: &[u16], channel_count: usize, file_len: u64) -> LoadResult<Vec<Patterns>> {
+        let mut patterns: Vec<Patterns> = vec![];
+        patterns.reserve_exact(offsets.len() + 1);
+
+        for offset in offsets {
+            // offsets are in paragraphs
+            let offset = *offset as u64 * 16;
+            if offset == 0 || offset >= file_len {
+                patterns.push(Patterns::new(ROW_COUNT, channel_count));
+                continue;
+            }
+            patterns.push(read_pattern(file, offset, channel_count).map_err(|e| e.at(Section::Pattern, offset))?);
+        }
+
+        Ok(patterns)
+    }
+
+    fn read_pattern<R: Read + Seek>(file: &mut R, offset: u64, channel_count: usize) -> LoadResult<Patterns> {
+        let mut pattern = Patterns::new(ROW_COUNT, channel_count);
+
+        file.seek(SeekFrom::Start(offset))?;
+
+        for row in pattern.rows.iter_mut() {
+            loop {
+                let flags = file.read_u8()?;
+                if flags == 0 { break; }
+
+                let mut channel = Pattern::new();
+
+                if flags & 0x20 != 0 {
+                    let note = file.read_u8()?;
+                    channel.note = match note {
+                        1..=96 => note,
+                        254 => 97,
+                        _ => 0,
+                    };
+                    channel.instrument = file.read_u8()?;
+                }
+
+                if flags & 0x40 != 0 {
+                    let effect = file.read_u8()?;
+                    let param = file.read_u8()?;
+                    let (effect, effect_param) = convert_effect(effect, param);
+                    channel.effect = effect;
+                    channel.effect_param = effect_param;
+                }
+
+                if flags & 0x80 != 0 {
+                    channel.volume = 0x10 + clamp(file.read_u8()?, 0, 64);
+                }
+
+                let channel_idx = (flags & 0x1F) as usize;
+                if channel_idx < channel_count {
+                    row.channels[channel_idx] = channel;
+                }
+            }
+        }
+
+        Ok(pattern)
+    }
+
+    // 0-F are the ProTracker effects, PolyTracker adds G-N on top
+    fn convert_effect(effect: u8, param: u8) -> (u8, u8) {
+        match effect {
+            0xC => (0xC, clamp(param, 0, 64)),
+            0x0..=0xF => (effect, param),
+            0x10 => (0x10, clamp(param, 0, 64)),                        // G - global volume
+            0x11 => (0x1B, param & 0xF),                                // H - retrigger
+            0x12 => (0x4, (param & 0xF0) | ((param & 0xF) >> 2)),       // I - fine vibrato
+            _ => (0, 0),                                                // note slides and sample reverse
+        }
+    }
+
+    fn read_sample<R: Read>(file: &mut R) -> LoadResult<(Sample, u32)> {
+        let flags = file.read_u8()?;
+        let _file_name = file.read_string(12);
+        let volume = file.read_u8()?;
+        let c4speed = file.read_u16()? as u32;
+        let _segment = file.read_u16()?;
+        let data_offset = file.read_u32()?;
+        let mut length = file.read_u32()?;
+        let mut loop_start = file.read_u32()?;
+        let mut loop_end = file.read_u32()?;
+        let _gus_data = file.read_bytes(14)?;
+        let name = file.read_string(28);
+        let _id = file.read_bytes(4)?;
+
+        // 1 is a PCM sample, OPL and MIDI instruments aren't supported
+        if flags & 3 != 1 {
+            length = 0;
+        }
+
+        let bitness = if flags & 0x10 != 0 { 16 } else { 8 };
+        if bitness == 16 { // length is in bytes
+            length /= 2;
+            loop_start /= 2;
+            loop_end /= 2;
+        }
+
+        // fix overflown loop
+        if loop_end > length {
+            loop_end = length;
+        }
+
+        let mut loop_type = if flags & 4 == 0 { LoopType::NoLoop } else if flags & 8 == 0 { LoopType::ForwardLoop } else { LoopType::PingPongLoop };
+        if loop_end <= loop_start.saturating_add(2) {
+            loop_type = LoopType::NoLoop;
+        }
+        if loop_type == LoopType::NoLoop {
+            loop_start = 0;
+            loop_end = 0;
+        }
+
+        let (finetune, relative_note) = module_reader::c2spd_to_finetune_relnote(if c4speed == 0 { 8363 } else { c4speed });
+
+        Ok((Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: clamp(volume, 0, 64),
+            finetune,
+            loop_type,
+            bitness,
+            panning: 128,
+            relative_note,
+            name,
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![],
+        }, data_offset))
+    }
+
+    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize) -> LoadResult<(Vec<Instrument>, Vec<u32>)> {
+        let mut instruments: Vec<Instrument> = vec![];
+        let mut offsets = vec![];
+
+        // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
+        instruments.reserve_exact(instrument_count + 1);
+
+        instruments.push(Instrument::new());
+
+        file.seek(SeekFrom::Start(HEADER_SIZE))?;
+        for instrument_idx in 1..instrument_count + 1 {
+            let mut instrument = Instrument::new();
+            let (sample, offset) = read_sample(file)?;
+            instrument.name = sample.name.trim().to_string();
+            instrument.idx = instrument_idx as u8;
+            instrument.samples = vec![sample];
+            instruments.push(instrument);
+            offsets.push(offset);
+        }
+        Ok((instruments, offsets))
+    }
@@ -0,0 +1,173 @@
+    use std::fmt;
+    use crate::module_reader::SongType;
+    use crate::song::CompatProfile;
+
+    /// How hard to try loading a module
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct LoadOptions {
+        /// Salvage broken modules instead of failing, e.g. play missing sample data as silence
+        pub lenient:    bool,
+        /// Load as this format instead of guessing
+        pub format:     Option<SongType>,
+        /// The zip member to load, instead of the first module in the archive
+        pub archive_member: Option<String>,
+        /// Play with the quirks of this tracker instead of the one the format came from
+        pub compat_profile: Option<CompatProfile>,
+        /// Play the song through once before it starts, to bring it to the same loudness as other songs
+        pub normalize_loudness: bool,
+    }
+
+    impl LoadOptions {
+        pub fn lenient() -> Self {
+            Self { lenient: true, ..Default::default() }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum LoadNote {
+        /// The module was broken and got patched up
+        Repair(String),
+        /// The module loaded as is, but won't play quite like the original
+        Warning(String),
+    }
+
+    /// Everything that was repaired or left out while loading a module
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct LoadReport {
+        notes: Vec<LoadNote>,
+    }
+
+    impl LoadReport {
+        pub(crate) fn repair(&mut self, message: String) {
+            self.notes.push(LoadNote::Repair(message));
+        }
+
+        pub(crate) fn warn(&mut self, message: String) {
+            self.notes.push(LoadNote::Warning(message));
+        }
+
+        pub fn notes(&self) -> &[LoadNote] { &self.notes }
+        pub fn is_empty(&self) -> bool { self.notes.is_empty() }
+
+        pub fn repairs(&self) -> impl Iterator<Item = &str> {
+            self.notes.iter().filter_map(|n| if let LoadNote::Repair(m) = n { Some(m.as_str()) } else { None })
+        }
+
+        pub fn warnings(&self) -> impl Iterator<Item = &str> {
+            self.notes.iter().filter_map(|n| if let LoadNote::Warning(m) = n { Some(m.as_str()) } else { None })
+        }
+    }
+
+    impl fmt::Display for LoadNote {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                LoadNote::Repair(message) => write!(f, "Repaired: {}", message),
+                LoadNote::Warning(message) => write!(f, "Warning: {}", message),
+            }
+        }
+    }
+
+    // one note per line
+    impl fmt::Display for LoadReport {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for note in &self.notes {
+                writeln!(f, "{}", note)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::module_reader::{open_module, open_module_with_options, LoadErrorKind, Section};
+
+        #[test]
+        fn test_lenient_truncated_sample_data() {
+            let mut data = std::fs::read("test_data/test.far").unwrap();
+            data.truncate(data.len() - 100);
+
+            assert_eq!(open_module(&data).err().unwrap().kind(), &LoadErrorKind::Truncated);
+
+            let song = open_module_with_options(&data, &LoadOptions::lenient()).unwrap();
+            let report = song.get_load_report();
+            assert_eq!(song.song_type, SongType::FAR);
+            assert_eq!(report.repairs().count(), 1);
+            assert!(report.repairs().next().unwrap().starts_with("Truncated sample data (FAR sample at offset 0x"));
+        }
+
+        fn u32_at(data: &[u8], pos: usize) -> usize {
+            u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize
+        }
+
+        // where the header of the first instrument starts
+        fn first_xm_instrument(data: &[u8]) -> usize {
+            let pattern_count = u16::from_le_bytes([data[70], data[71]]) as usize;
+            let mut pos = 60 + u32_at(data, 60);
+            for _ in 0..pattern_count {
+                pos += u32_at(data, pos) + u16::from_le_bytes([data[pos + 7], data[pos + 8]]) as usize;
+            }
+            pos
+        }
+
+        #[test]
+        fn test_lenient_bogus_sizes() {
+            let data = std::fs::read("test_data/test.xm").unwrap();
+            let instrument_pos = first_xm_instrument(&data);
+
+            // the first sample claims more than the whole file
+            let mut long_sample = data.clone();
+            let sample_pos = instrument_pos + u32_at(&data, instrument_pos);
+            long_sample[sample_pos..sample_pos + 4].copy_from_slice(&(data.len() as u32 * 4).to_le_bytes());
+            assert!(open_module(&long_sample).is_err());
+            let song = open_module_with_options(&long_sample, &LoadOptions::lenient()).unwrap();
+            let repair = song.get_load_report().repairs().next().unwrap();
+            assert!(repair.starts_with("Sample 0 of instrument 1 runs past the end of the file, cut to "), "{}", repair);
+            assert!(song.instruments[1].samples[0].length as usize <= data.len());
+
+            let mut long_header = data.clone();
+            long_header[instrument_pos..instrument_pos + 4].copy_from_slice(&0xFFFF_FF00u32.to_le_bytes());
+            assert!(open_module(&long_header).is_err());
+            let song = open_module_with_options(&long_header, &LoadOptions::lenient()).unwrap();
+            assert_eq!(song.get_load_report().repairs().next(), Some("Instrument 1 has a bogus header size of 4294967040, read as 263"));
+            assert_eq!(song.instruments.len(), open_module(&data).unwrap().instruments.len());
+        }
+
+        #[test]
+        fn test_lenient_loop_past_sample_end() {
+            let mut data = std::fs::read("test_data/test.xm").unwrap();
+            let instrument_pos = first_xm_instrument(&data);
+
+            // the first sample loops from its start to well past its end
+            let sample_pos = instrument_pos + u32_at(&data, instrument_pos);
+            let length = u32_at(&data, sample_pos) as u32;
+            data[sample_pos + 4..sample_pos + 8].copy_from_slice(&0u32.to_le_bytes());
+            data[sample_pos + 8..sample_pos + 12].copy_from_slice(&(length + 1000).to_le_bytes());
+            data[sample_pos + 14] = (data[sample_pos + 14] & !3) | 1;
+
+            let error = open_module(&data).err().unwrap();
+            assert_eq!(error.kind(), &LoadErrorKind::Invalid("Sample loops past its end".to_string()));
+            assert_eq!(error.section(), Some(Section::Sample));
+
+            let song = open_module_with_options(&data, &LoadOptions::lenient()).unwrap();
+            let sample = &song.instruments[1].samples[0];
+            // the loaded sample has 4 samples of padding in front
+            assert_eq!((sample.loop_start, sample.loop_end), (4, sample.length));
+            let repair = song.get_load_report().repairs().next().unwrap();
+            assert_eq!(repair, format!("Sample 0 of instrument 1 loops past its end, the loop is cut to 0-{}", sample.length - 4));
+        }
+
+        #[test]
+        fn test_order_past_last_pattern() {
+            let mut data = std::fs::read("test_data/test.xm").unwrap();
+            let song = open_module_with_options(&data, &LoadOptions::default()).unwrap();
+            assert!(song.get_load_report().is_empty());
+
+            data[80] = 200; // first order
+            let song = open_module_with_options(&data, &LoadOptions::default()).unwrap();
+            let report = song.get_load_report();
+            assert_eq!(song.pattern_order[0] as usize, song.patterns.len() - 1);
+            assert_eq!(report.notes(), &[LoadNote::Repair("Order 0 points past the last pattern (200), playing an empty pattern instead".to_string())]);
+            assert_eq!(report.to_string(), "Repaired: Order 0 points past the last pattern (200), playing an empty pattern instead\n");
+        }
+    }
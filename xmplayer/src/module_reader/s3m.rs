@@ -3,8 +3,9 @@
     use binary_reader_io::BinaryReader;
     use std::iter::FromIterator;
     use crate::pattern::Pattern;
-    use crate::instrument::{Instrument, Sample, LoopType};
-    use crate::{SimpleError, SimpleResult};
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::opl::AdlibPatch;
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, ModuleMetadata, bytes_left};
     use std::io;
     use crate::module_reader;
 
@@ -21,20 +22,20 @@
         }
     }
 
-    pub fn read_s3m<R: Read + Seek>(mut file: &mut R) -> SimpleResult<SongData> {
+    pub fn read_s3m<R: Read + Seek>(mut file: &mut R, lenient: bool) -> LoadResult<SongData> {
         file.seek(SeekFrom::Start(0))?;
 
         let file_len = file.seek(SeekFrom::End(0))?;
         file.seek(SeekFrom::Start(0))?;
 
         if file_len < 1084 {
-            return Err(SimpleError::new("File is too small!"));
+            return Err(LoadError::truncated().in_format(SongType::S3M).at(Section::Header, 0));
         }
 
-        read_s3m_header(&mut file)
+        read_s3m_header(&mut file, lenient).map_err(|e| e.in_format(SongType::S3M).at(Section::Header, 0))
     }
 
-    fn read_s3m_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
+    fn read_s3m_header<R: Read + Seek>(file: &mut R, lenient: bool) -> LoadResult<SongData>
     {
         let mut num_channels = 0;
 
@@ -43,7 +44,7 @@
         let id = file.read_bytes(4)?;
 
         if id != "SCRM".as_bytes() {
-            return Err(SimpleError::new("Unknown s3m format - signature"));
+            return Err(LoadError::wrong_format(SongType::S3M));
         }
 
         file.seek(SeekFrom::Start(0))?;
@@ -52,7 +53,7 @@
         let _sig = file.read_u8()?;
         let file_type = file.read_u8()?;
         if file_type != 16 {
-            return Err(SimpleError::new("Unknown s3m format"));
+            return Err(LoadError::invalid("Unknown s3m format").at(Section::Header, 29));
         }
 
         let _ = file.read_u16()?;
@@ -60,34 +61,37 @@
         let song_length = file.read_u16()?;
 
         if song_length > 256 {
-            return Err(SimpleError::new("Unknown s3m format - song length"));
+            return Err(LoadError::invalid("Unknown s3m format - song length"));
         }
 
         let instrument_count = file.read_u16()?;
 
         if instrument_count > 128 {
-            return Err(SimpleError::new("Unknown s3m format - instruments"));
+            return Err(LoadError::invalid("Unknown s3m format - instruments"));
         }
 
         let pattern_count = file.read_u16()?;
 
         if pattern_count > 256 {
-            return Err(SimpleError::new("Unknown s3m format - patterns"));
+            return Err(LoadError::invalid("Unknown s3m format - patterns"));
         }
 
-        let _flags = file.read_u16()?;
+        let flags = file.read_u16()?;
 
-        let _cwtv = file.read_u16()?;
+        let cwtv = file.read_u16()?;
+
+        // ST3 3.00 did its volume slides on every tick, later versions keep it with a flag
+        let fast_volume_slides = flags & 0x40 == 0x40 || cwtv == 0x1300;
 
         let _signed_samples = file.read_u16()?;
 
         let signature = file.read_string(4);
 
         if signature != "SCRM" {
-            return Err(SimpleError::new("Unknown s3m format - signature"));
+            return Err(LoadError::wrong_format(SongType::S3M));
         }
 
-        let _global_volume = file.read_u8()?;
+        let global_volume = file.read_u8()?;
 
         let speed = file.read_u8()?;
 
@@ -104,8 +108,9 @@
         let channel_data = file.read_bytes(32)?;
         let mut channel_map = [255u8; 32];
 
+        // 0-15 are the sample channels, 16-24 the AdLib melody channels and 25-29 the AdLib drums
         for i in 0..channel_data.len() {
-            if channel_data[i] < 16u8 {
+            if channel_data[i] < 30u8 {
                 channel_map[i] = num_channels;
                 num_channels += 1;
             }
@@ -118,7 +123,8 @@
         let pattern_ptrs = file.read_u16_vec(pattern_count as usize)?;
 
         // Now we should read the panning positions. Or not. Whatever. Maybe some other time.
-        let instruments = read_instruments(file, &instrument_ptrs)?;
+        let mut report = LoadReport::default();
+        let instruments = read_instruments(file, &instrument_ptrs, lenient, &mut report)?;
         let mut patterns = read_patterns(file, &pattern_ptrs, num_channels as usize, &channel_map)?;
 
         patterns.push(Patterns {
@@ -149,6 +155,58 @@
             pattern_order: Vec::from_iter(pattern_order.iter().cloned()),
             instruments,
             use_amiga: true,
+            global_volume: global_volume.min(64),
+            fast_volume_slides,
+            vblank: false,
+            song_message: "".to_string(),
+            report,
+        })
+    }
+
+    pub(crate) fn read_s3m_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(44))?;
+        if file.read_bytes(4)? != "SCRM".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::S3M));
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let title = file.read_string(28);
+        let _sig = file.read_u8()?;
+        if file.read_u8()? != 16 {
+            return Err(LoadError::invalid("Unknown s3m format").at(Section::Header, 29));
+        }
+        let _ = file.read_u16()?;
+        let song_length = file.read_u16()?;
+        let instrument_count = file.read_u16()?;
+        let pattern_count = file.read_u16()?;
+
+        if song_length > 256 || instrument_count > 128 || pattern_count > 256 {
+            return Err(LoadError::invalid("Unknown s3m format"));
+        }
+
+        file.seek(SeekFrom::Start(0x40))?;
+        let channel_count = file.read_bytes(32)?.iter().filter(|c| **c < 30).count();
+
+        let mut pattern_order = file.read_bytes(song_length as usize)?;
+        truncate_patterns(&mut pattern_order);
+        let instrument_ptrs = file.read_u16_vec(instrument_count as usize)?;
+
+        let mut names = vec![];
+        for instrument_ptr in instrument_ptrs {
+            let instrument_pos = instrument_ptr as u64 * 16;
+            file.seek(SeekFrom::Start(instrument_pos + 0x30))?;
+            names.push(file.read_string(28).trim().to_string());
+        }
+
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::S3M,
+            tracker_name: "Unknown".to_string(),
+            channel_count: channel_count as u16,
+            pattern_count,
+            order_count: pattern_order.len() as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
             song_message: "".to_string(),
         })
     }
@@ -167,75 +225,86 @@
         pattern_order.truncate(write_pos as usize);
     }
 
-    fn read_patterns<R: Read + Seek>(file: &mut R, pattern_ptrs: &Vec<u16>, channel_count: usize, channel_map: &[u8; 32]) -> SimpleResult<Vec<Patterns>> {
+    fn read_patterns<R: Read + Seek>(file: &mut R, pattern_ptrs: &Vec<u16>, channel_count: usize, channel_map: &[u8; 32]) -> LoadResult<Vec<Patterns>> {
         let pattern_count = pattern_ptrs.len();
         let mut patterns: Vec<Patterns> = vec![];
         patterns.reserve_exact(pattern_count);
-        let row_count = 64;
 
         for pattern_ptr in pattern_ptrs.iter().cloned() {
             if pattern_ptr == 0 {continue;}
-            file.seek(SeekFrom::Start((pattern_ptr as u64)  * 16))?;
+            let pattern_pos = (pattern_ptr as u64) * 16;
+            patterns.push(read_pattern(file, pattern_pos, channel_count, channel_map).map_err(|e| e.at(Section::Pattern, pattern_pos))?);
+        }
 
-            let mut pattern = Patterns::new(row_count, channel_count);
+        Ok(patterns)
+    }
 
-            let _size = file.read_u16()?;
+    fn read_pattern<R: Read + Seek>(file: &mut R, pattern_pos: u64, channel_count: usize, channel_map: &[u8; 32]) -> LoadResult<Patterns> {
+        let row_count = 64;
+        file.seek(SeekFrom::Start(pattern_pos))?;
 
-            let mut last_effect_param       = [0u8; 32];
-            let mut last_effect             = [0u8; 32];
-            let mut last_vibrato_param      = [0u8; 32];
-            let mut last_instrument = [0u8; 32];
+        let mut pattern = Patterns::new(row_count, channel_count);
 
-            for row in pattern.rows.iter_mut() {
-                let channels = &mut row.channels;
+        let _size = file.read_u16()?;
 
-                loop {
-                    let pattern_data = file.read_u8()?;
-                    if pattern_data == 0 { break; }
+        let mut last_effect_param       = [0u8; 32];
+        let mut last_effect             = [0u8; 32];
+        let mut last_vibrato_param      = [0u8; 32];
+        let mut last_instrument = [0u8; 32];
 
-                    let channel_num = pattern_data & 31;
-                    let channel_id = channel_map[channel_num as usize] as usize;
+        for row in pattern.rows.iter_mut() {
+            let channels = &mut row.channels;
 
-                    let mut note = 0u8;
-                    let mut instrument = 0u8;
-                    let mut volume = 0u8;
-                    let mut effect = 0u8;
-                    let mut effect_param = 0u8;
+            loop {
+                let pattern_data = file.read_u8()?;
+                if pattern_data == 0 { break; }
 
-                    if pattern_data & 32 == 32 {
-                        note = file.read_u8()?;
-                        instrument = file.read_u8()?;
+                let channel_num = pattern_data & 31;
+                let channel_id = channel_map[channel_num as usize] as usize;
 
-                        if note == 255 {
-                            note = 0;
-                        } else if note == 254 {
-                            note = 97;
-                        } else {
-                            note = 1 + (note >> 4) * 12 + (note & 0xF);
-                            if note > 96 {note = 0;}
-                        }
-                    }
+                let mut note = 0u8;
+                let mut instrument = 0u8;
+                let mut volume = 0u8;
+                let mut effect = 0u8;
+                let mut effect_param = 0u8;
 
-                    if pattern_data & 64 == 64 {
-                        volume = file.read_u8()?;
-                        if volume <= 64 {volume += 0x10} else { volume = 0;}
-                    }
+                if pattern_data & 32 == 32 {
+                    note = file.read_u8()?;
+                    instrument = file.read_u8()?;
 
-                    if pattern_data & 128 == 128 {
-                        effect = file.read_u8()?;
-                        effect_param = file.read_u8()?;
+                    if note == 255 {
+                        note = 0;
+                    } else if note == 254 {
+                        note = 97;
+                    } else {
+                        note = 1 + (note >> 4) * 12 + (note & 0xF);
+                        if note > 96 {note = 0;}
                     }
+                }
 
-                    if channel_num >= channel_count as u8 { continue; }
-                    let channel = &mut channels[channel_id];
+                if pattern_data & 64 == 64 {
+                    volume = file.read_u8()?;
+                    if volume <= 64 {volume += 0x10} else { volume = 0;}
+                }
 
-                    channel.note = note;
-                    channel.instrument = instrument;
-                    channel.volume = volume;
-                    channel.effect = effect;
-                    channel.effect_param = effect_param;
+                if pattern_data & 128 == 128 {
+                    effect = file.read_u8()?;
+                    effect_param = file.read_u8()?;
+                }
 
-                    if pattern_data & 128 == 128 {
+                if channel_num >= channel_count as u8 { continue; }
+                let channel = &mut channels[channel_id];
+
+                channel.note = note;
+                channel.instrument = instrument;
+                channel.volume = volume;
+                channel.effect = effect;
+                channel.effect_param = effect_param;
+
+                if pattern_data & 128 == 128 {
+                    if shares_memory(channel.effect) {
+                        channel.effect += SHARED_MEMORY_EFFECT;
+                    } else {
                         fix_effects(
                             channel,
                             &mut last_effect[channel_id],
@@ -244,24 +313,40 @@
                             &mut last_instrument[channel_id]
                         );
                     }
+                }
 
-                    if channel.instrument != 0 && channel.effect != 0x3 {
-                        last_instrument[channel_id] = channel.instrument;
-                    }
-
-                    if channel.effect > 35 {
-                        channel.effect = 0;
-                        channel.effect_param = 0;
-                    }
+                if channel.instrument != 0 && channel.effect != 0x3 {
+                    last_instrument[channel_id] = channel.instrument;
                 }
             }
-            patterns.push(pattern)
         }
+        Ok(pattern)
+    }
 
-        Ok(patterns)
+    // ST3 has a single effect memory for D, E, F, I, J, K, L, Q, R and S, and it follows the order the song plays in.
+    // These stay in the pattern as SHARED_MEMORY_EFFECT + their letter, and become the effect the player knows as they play
+    pub(crate) const SHARED_MEMORY_EFFECT: u8 = 0x40;
+
+    fn shares_memory(effect: u8) -> bool {
+        matches!(effect, 4..=6 | 9..=12 | 17..=19)
     }
 
-    fn fix_effects(pattern : &mut Pattern, last_effect: &mut u8, last_effect_param: &mut u8, last_vibrato_param: &mut u8,last_instrument: &mut u8) {
+    pub(crate) fn recall_shared_effect(pattern: &Pattern, memory: &mut u8) -> Pattern {
+        let mut result = *pattern;
+        if result.effect < SHARED_MEMORY_EFFECT {
+            return result;
+        }
+        result.effect -= SHARED_MEMORY_EFFECT;
+        if result.effect_param == 0 {
+            result.effect_param = *memory;
+        } else {
+            *memory = result.effect_param;
+        }
+        convert_effect(&mut result);
+        result
+    }
+
+    pub(crate) fn fix_effects(pattern : &mut Pattern, last_effect: &mut u8, last_effect_param: &mut u8, last_vibrato_param: &mut u8,last_instrument: &mut u8) {
         // lifted from FT2 - effect memory handling seems somewhat wrong - it should be handled during effect processing
         //                   Fixing it needs additional work in the player code - seems like this workaround will suffice for now
         if pattern.effect_param > 0 {
@@ -292,7 +377,16 @@
         if pattern.effect > 0 {
             *last_effect = pattern.effect;
         }
-        
+
+        // fix illegal slides (to new instruments)
+        if pattern.effect == 7 && pattern.instrument != 0 && pattern.instrument != *last_instrument {
+            pattern.instrument = *last_instrument;
+        }
+
+        convert_effect(pattern);
+    }
+
+    fn convert_effect(pattern: &mut Pattern) {
         match pattern.effect {
             1 => // A - Set speed - don't support speeds > 1F
                 {
@@ -307,12 +401,13 @@
             3 => pattern.effect = 0xD,  // C - Volume slide
             4 => // D
                 {
-                    if pattern.effect_param > 0xF0 { // fine slide up
-                        pattern.effect = 0xE;
-                        pattern.effect_param = 0xB0 | (pattern.effect_param & 0xF);
-                    } else if (pattern.effect_param & 0x0F) == 0x0F && (pattern.effect_param & 0xF0) > 0 { // fine slide down
+                    // DxF comes first, so DFF is a fine slide up
+                    if (pattern.effect_param & 0x0F) == 0x0F && (pattern.effect_param & 0xF0) > 0 { // fine slide up
                         pattern.effect = 0xE;
                         pattern.effect_param = 0xA0 | (pattern.effect_param >> 4);
+                    } else if pattern.effect_param > 0xF0 { // fine slide down
+                        pattern.effect = 0xE;
+                        pattern.effect_param = 0xB0 | (pattern.effect_param & 0xF);
                     } else {
                         pattern.effect = 0xA;
                         if (pattern.effect_param & 0x0F) != 0 { // on D/K (Volume slide/Vibrato + Volume slide), last nybble has first priority in ST3
@@ -344,27 +439,20 @@
                 }
             }
 
-            7 => { // G - Porta to note
-                pattern.effect = 0x03;
-
-                // fix illegal slides (to new instruments)
-                if pattern.instrument != 0 && pattern.instrument != *last_instrument {
-                    pattern.instrument = *last_instrument;
-                }
-            }
+            7 => { pattern.effect = 0x03; } // G - Porta to note
 
             11 => { // K - Vibrato + volume slide
-                if pattern.effect_param > 0xF0 { // fine slide up
+                if (pattern.effect_param & 0x0F) == 0x0F && (pattern.effect_param & 0xF0) > 0 { // fine slide up
                     pattern.effect = 0xE;
-                    pattern.effect_param = 0xB0 | (pattern.effect_param & 0xF);
+                    pattern.effect_param = 0xA0 | (pattern.effect_param >> 4);
 
                     // if volume column is unoccupied, set to vibrato
                     if pattern.volume == 0 {
                         pattern.volume = 0xB0;
                     }
-                } else if (pattern.effect_param & 0x0F) == 0x0F && (pattern.effect_param & 0xF0) > 0 { // fine slide down
+                } else if pattern.effect_param > 0xF0 { // fine slide down
                     pattern.effect = 0xE;
-                    pattern.effect_param = 0xA0 | (pattern.effect_param >> 4);
+                    pattern.effect_param = 0xB0 | (pattern.effect_param & 0xF);
 
                     // if volume column is unoccupied, set to vibrato
                     if pattern.volume == 0 {
@@ -392,9 +480,13 @@
                 match subcommand {
                     0x1 => { pattern.effect_param |= 0x30; } // Glissando
                     0x2 => { pattern.effect_param |= 0x50; } // Set finetune
-                    0x3 => { pattern.effect_param |= 0x40; } // Set Vibrato Waveform
-                    0x4 => { pattern.effect_param |= 0x70; } // Set Tremolo Waveform (Firelight S3M tutorial is wrong here)
-                    0xB => { pattern.effect_param |= 0x60; } // Channel pan position. ignore S8x because it's not compatible with FT2 panning
+                    0x3 | 0x4 => { // Set Vibrato/Tremolo Waveform, with the random one FT2 doesn't have (Firelight S3M tutorial is wrong here)
+                        pattern.effect = 0x1C;
+                        pattern.effect_param |= subcommand << 4;
+                    }
+                    0x8 => { pattern.effect_param |= 0x80; } // Channel pan position
+                    0xA => { pattern.effect_param = 0x80 | (pattern.effect_param ^ 0x8); } // Old stereo control, SA0-SA7 pan right and SA8-SAF left
+                    0xB => { pattern.effect_param |= 0x60; } // Pattern loop
                     0xC => {
                         pattern.effect_param |= 0xC0;
                         if pattern.effect_param == 0xC0 {
@@ -434,6 +526,7 @@
                     pattern.effect_param = 0;
                 }
             }
+            21 => { pattern.effect = 0x25; } // U - Fine vibrato, past the IT only effects
             22 => { // V - Set Global Volume
                 pattern.effect = 0x10;
                 if pattern.effect_param > 0x40 {
@@ -442,6 +535,18 @@
                     pattern.effect_param = 0;
                 }
             }
+            24 => { // X - Set panning, X00-X80 and XA4 for surround
+                if pattern.effect_param <= 0x80 {
+                    pattern.effect = 0x8;
+                    pattern.effect_param = (pattern.effect_param as u16 * 2).min(255) as u8;
+                } else if pattern.effect_param == 0xA4 {
+                    pattern.effect = 0x1C;
+                    pattern.effect_param = 0x91;
+                } else {
+                    pattern.effect = 0;
+                    pattern.effect_param = 0;
+                }
+            }
 
             _ => {
                 pattern.effect = 0;
@@ -450,7 +555,7 @@
         }
     }
 
-    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_ptrs: &Vec<u16>) -> SimpleResult<Vec<Instrument>> {
+    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_ptrs: &Vec<u16>, lenient: bool, report: &mut LoadReport) -> LoadResult<Vec<Instrument>> {
         let mut instruments: Vec<Instrument> = vec![];
         let instrument_count = instrument_ptrs.len();
 
@@ -460,53 +565,103 @@
         instruments.push(Instrument::new());
 
         for (instrument_idx, instrument_ptr) in instrument_ptrs.iter().cloned().enumerate() {
-            let mut instrument = Instrument::new();
-            file.seek(SeekFrom::Start((instrument_ptr as u64)  * 16))?;
-            let _type_ = file.read_u8()?;
-            let _dos_name = file.read_string(12);
-            let sample_ptr = file.read_u24_s3m()?;
-            let sample_len = file.read_u32()? & 0xFFFF;
-            let sample_loop_start = file.read_u32()? & 0xFFFF;
-            let sample_loop_end = file.read_u32()? & 0xFFFF;
-            let sample_volume = file.read_u8()?;
-            let _ = file.read_u8()?;
-            let sample_packing = file.read_u8()?;
-            if sample_packing != 0 {
-                return Err(SimpleError::new("Unknown file format"));
-            }
-            let sample_flags = file.read_u8()?;
-            let c2spd = file.read_u32()? & 0xFFFF;
-            let _ = file.read_bytes(12)?;
-            let sample_name = file.read_string(28);
-            let _sample_sig = file.read_string(4);
-
-            let (finetune, relative_note) = module_reader::c2spd_to_finetune_relnote(c2spd);
-
-            let mut sample = Sample{
-                length: sample_len,
-                loop_start: sample_loop_start,
-                loop_end: sample_loop_end,
-                loop_len: sample_loop_end - sample_loop_start,
-                volume: sample_volume,
-                finetune,
-                loop_type: if sample_flags & 1 == 1 {LoopType::ForwardLoop} else {LoopType::NoLoop},
-                bitness: 8,
-                panning: 128,
-                relative_note,
-                name: sample_name.clone().to_string(),
-                is_ping_pong: false,
-                original_loop_end: 0,
-                data: vec![]
-            };
-            sample.read_s3m_sample_data(file, sample_ptr)?;
-            instrument.name = sample.name.clone();
-            instrument.idx = instrument_idx as u8;
-            instrument.samples = vec![sample];
-            instruments.push(instrument);
+            let instrument_pos = (instrument_ptr as u64) * 16;
+            instruments.push(read_instrument(file, instrument_idx, instrument_pos, lenient, report).map_err(|e| e.at(Section::Instrument, instrument_pos))?);
         }
         Ok(instruments)
     }
 
+    fn read_instrument<R: Read + Seek>(file: &mut R, instrument_idx: usize, instrument_pos: u64, lenient: bool, report: &mut LoadReport) -> LoadResult<Instrument> {
+        let mut instrument = Instrument::new();
+        file.seek(SeekFrom::Start(instrument_pos))?;
+        let type_ = file.read_u8()?;
+        let _dos_name = file.read_string(12);
+        if type_ >= 2 {
+            return read_adlib_instrument(file, instrument_idx);
+        }
+        let sample_ptr = file.read_u24_s3m()?;
+        let sample_len = file.read_u32()? & 0xFFFF;
+        let sample_loop_start = file.read_u32()? & 0xFFFF;
+        let sample_loop_end = file.read_u32()? & 0xFFFF;
+        let sample_volume = file.read_u8()?;
+        let _ = file.read_u8()?;
+        let sample_packing = file.read_u8()?;
+        if sample_packing != 0 {
+            return Err(LoadError::invalid("Unknown file format"));
+        }
+        let sample_flags = file.read_u8()?;
+        let c2spd = file.read_u32()? & 0xFFFF;
+        let _ = file.read_bytes(12)?;
+        let sample_name = file.read_string(28);
+        let _sample_sig = file.read_string(4);
+
+        let (finetune, relative_note) = module_reader::c2spd_to_finetune_relnote(c2spd);
+
+        let mut sample = Sample{
+            length: sample_len,
+            loop_start: sample_loop_start,
+            loop_end: sample_loop_end,
+            loop_len: sample_loop_end.saturating_sub(sample_loop_start),
+            volume: sample_volume,
+            finetune,
+            loop_type: if sample_flags & 1 == 1 {LoopType::ForwardLoop} else {LoopType::NoLoop},
+            bitness: 8,
+            panning: 128,
+            relative_note,
+            name: sample_name.clone().to_string(),
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![]
+        };
+        if lenient {
+            file.seek(SeekFrom::Start(sample_ptr as u64 * 16))?;
+            if sample.fit_length(bytes_left(file)?) {
+                report.repair(format!("Sample of instrument {} runs past the end of the file, cut to {} samples", instrument_idx + 1, sample.length));
+            }
+            if sample.fit_loops() {
+                report.repair(format!("Sample of instrument {} loops past its end, the loop is cut to {}-{}", instrument_idx + 1, sample.loop_start, sample.loop_end));
+            }
+        } else if !sample.loops_fit() {
+            return Err(LoadError::invalid("Sample loops past its end").at(Section::Sample, sample_ptr as u64 * 16));
+        }
+        sample.read_s3m_sample_data(file, sample_ptr)?;
+        instrument.name = sample.name.clone();
+        instrument.idx = instrument_idx as u8;
+        instrument.samples = vec![sample];
+        Ok(instrument)
+    }
+
+    // Type 2 is a melodic AdLib instrument and 3-7 the drums, all of them the registers of the two operators.
+    // The sample only keeps the volume and the tuning, the OPL2 plays the notes
+    fn read_adlib_instrument<R: Read + Seek>(file: &mut R, instrument_idx: usize) -> LoadResult<Instrument> {
+        let mut instrument = Instrument::new();
+        let _ = file.read_bytes(3)?;
+        let registers = file.read_bytes(12)?;
+        let volume = file.read_u8()?;
+        let _ = file.read_bytes(3)?;
+        let c2spd = file.read_u32()? & 0xFFFF;
+        let _ = file.read_bytes(12)?;
+        let name = file.read_string(28);
+        let _sig = file.read_string(4);
+
+        let (finetune, relative_note) = module_reader::c2spd_to_finetune_relnote(if c2spd == 0 { 8363 } else { c2spd });
+
+        let mut patch = AdlibPatch { registers: [0; 11] };
+        patch.registers.copy_from_slice(&registers[..11]);
+        instrument.adlib = Some(patch);
+        instrument.name = name.clone();
+        instrument.idx = instrument_idx as u8;
+        instrument.samples = vec![Sample {
+            volume: volume.min(64),
+            finetune,
+            relative_note,
+            name,
+            ..Sample::new()
+        }];
+        Ok(instrument)
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -518,4 +673,113 @@
             let mut reader = Cursor::new(&data);
             assert_eq!(reader.read_u24_s3m().unwrap(), 0x010302);
         }
+
+        #[test]
+        fn test_adlib_instrument() {
+            let mut data = vec![0u8; 0x50];
+            data[0] = 2;
+            data[16..28].copy_from_slice(&[0x21, 0x01, 0x8F, 0x00, 0xF2, 0xF3, 0x13, 0x45, 0x01, 0x02, 0x0B, 0x00]);
+            data[28] = 50;
+            data[32..36].copy_from_slice(&8363u32.to_le_bytes());
+            data[48..53].copy_from_slice(b"organ");
+            data[76..80].copy_from_slice(b"SCRI");
+
+            let instrument = read_instrument(&mut Cursor::new(data), 1, 0, false, &mut LoadReport::default()).unwrap();
+            let patch = instrument.adlib.unwrap();
+            assert_eq!(patch.registers, [0x21, 0x01, 0x8F, 0x00, 0xF2, 0xF3, 0x13, 0x45, 0x01, 0x02, 0x0B]);
+            assert_eq!(instrument.name.trim(), "organ");
+            let sample = &instrument.samples[0];
+            assert_eq!((sample.length, sample.volume, sample.finetune, sample.relative_note), (0, 50, 0, 0));
+        }
+
+        fn s3m_effect(effect: u8, effect_param: u8) -> Pattern {
+            let mut pattern = Pattern::new();
+            pattern.effect = effect;
+            pattern.effect_param = effect_param;
+            convert_effect(&mut pattern);
+            pattern
+        }
+
+        #[test]
+        fn test_native_effects() {
+            let converted = |effect, effect_param| {
+                let pattern = s3m_effect(effect, effect_param);
+                (pattern.effect, pattern.effect_param)
+            };
+            assert_eq!(converted(21, 0x48), (0x25, 0x48));  // U48, fine vibrato
+            assert_eq!(converted(19, 0x33), (0x1C, 0x33));  // S33, random vibrato
+            assert_eq!(converted(19, 0x42), (0x1C, 0x42));  // S42, square tremolo
+            assert_eq!(converted(19, 0x8C), (0xE, 0x8C));   // S8C, panning
+            assert_eq!(converted(19, 0xA0), (0xE, 0x88));   // SA0, old stereo control
+            assert_eq!(converted(19, 0xA9), (0xE, 0x81));
+            assert_eq!(converted(24, 0x40), (0x8, 0x80));   // X40, middle
+            assert_eq!(converted(24, 0xA4), (0x1C, 0x91));  // XA4, surround
+            assert_eq!(converted(24, 0x90), (0, 0));
+            assert_eq!(converted(22, 0x20), (0x10, 0x20));  // V20, global volume
+            assert_eq!(converted(22, 0x41), (0, 0));
+        }
+
+        #[test]
+        fn test_volume_slide_quirks() {
+            let converted = |effect_param| {
+                let pattern = s3m_effect(4, effect_param);
+                (pattern.effect, pattern.effect_param)
+            };
+            assert_eq!(converted(0xFF), (0xE, 0xAF));   // DFF slides up
+            assert_eq!(converted(0xF3), (0xE, 0xB3));
+            assert_eq!(converted(0x3F), (0xE, 0xA3));
+            assert_eq!(converted(0x0F), (0xA, 0x0F));
+            assert_eq!(converted(0xF0), (0xA, 0xF0));
+            assert_eq!(converted(0x42), (0xA, 0x02));   // the low nybble wins
+        }
+
+        #[test]
+        fn test_pattern_keeps_shared_effects() {
+            // D04 on the first channel, then E00 and H00 on the next row
+            let mut data = vec![0, 0, 0x80, 4, 0x04, 0, 0x80, 5, 0x00, 0x81, 8, 0x00, 0];
+            data.resize(data.len() + 62, 0);
+            let mut channel_map = [255u8; 32];
+            channel_map[0] = 0;
+            channel_map[1] = 1;
+            let patterns = read_pattern(&mut Cursor::new(data), 0, 2, &channel_map).unwrap();
+            let effects = |row: usize, channel: usize| {
+                let pattern = &patterns.rows[row].channels[channel];
+                (pattern.effect, pattern.effect_param)
+            };
+            assert_eq!(effects(0, 0), (SHARED_MEMORY_EFFECT + 4, 0x04));
+            assert_eq!(effects(1, 0), (SHARED_MEMORY_EFFECT + 5, 0x00));
+            assert_eq!(effects(1, 1), (0x04, 0x00));
+
+            // and show as the effects they play as
+            assert_eq!(patterns.rows[0].channels[0].shown_effect(), (0xA, 0x04));
+            assert_eq!(patterns.rows[1].channels[0].shown_effect(), (0x2, 0x00));
+            assert_eq!(patterns.rows[1].channels[1].shown_effect(), (0x04, 0x00));
+        }
+
+        #[test]
+        fn test_shared_effect_memory() {
+            let shared = |effect, effect_param| {
+                let mut pattern = Pattern::new();
+                pattern.effect = effect + SHARED_MEMORY_EFFECT;
+                pattern.effect_param = effect_param;
+                pattern
+            };
+            let mut memory = 0;
+
+            // D04 sets the memory, E00 slides down with it and K00 slides the volume with it again
+            let slide = recall_shared_effect(&shared(4, 0x04), &mut memory);
+            assert_eq!((slide.effect, slide.effect_param, memory), (0xA, 0x04, 0x04));
+            let porta = recall_shared_effect(&shared(5, 0), &mut memory);
+            assert_eq!((porta.effect, porta.effect_param), (0x2, 0x04));
+            let vibrato_slide = recall_shared_effect(&shared(11, 0), &mut memory);
+            assert_eq!((vibrato_slide.effect, vibrato_slide.effect_param), (0x6, 0x04));
+
+            // the others don't touch it
+            let mut vibrato = Pattern::new();
+            vibrato.effect = 0x4;
+            vibrato.effect_param = 0x12;
+            let recalled = recall_shared_effect(&vibrato, &mut memory);
+            assert_eq!((recalled.effect, recalled.effect_param), (0x4, 0x12));
+            assert_eq!(memory, 0x04);
+        }
     }
\ No newline at end of file
@@ -1,11 +1,12 @@
     use crate::module_reader::{SongData, Patterns, Row, SongType, FrequencyType};
     use std::io::{Read, Seek, SeekFrom};
-    use binary_reader_io::BinaryReader;
+    use binary_reader_io::{BinaryReader, BinarySeek};
     use std::iter::FromIterator;
     use crate::pattern::Pattern;
-    use crate::instrument::{Instrument, Sample, LoopType};
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
     use crate::{module_reader};
-    use crate::{SimpleError, SimpleResult};
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+    use crate::module_reader::metadata::instrument_names;
     use std::cmp::min;
     use std::num::Wrapping;
     use crate::channel_state::channel_state::clamp;
@@ -13,24 +14,59 @@
     const STM_EFFECTS: [u8;16] = [0, 0, 11, 0, 10, 2, 1, 3, 4, 7, 0, 5, 6, 0, 0, 0];
 
 
-    pub fn read_stm<R: Read + Seek>(mut file: &mut R) -> SimpleResult<SongData> {
+    pub fn read_stm<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
         file.seek(SeekFrom::Start(0))?;
 
         let file_len = file.seek(SeekFrom::End(0))?;
         file.seek(SeekFrom::Start(0))?;
 
         if file_len < 0x3D0  {
-            return Err(SimpleError::new("File is too small!"));
+            return Err(LoadError::truncated().in_format(SongType::STM).at(Section::Header, 0));
         }
 
-        read_stm_header(&mut file)
+        read_stm_header(&mut file).map_err(|e| e.in_format(SongType::STM).at(Section::Header, 0))
+    }
+
+    // the tag says which tracker or converter wrote the file, so it makes a better tracker name than "Unknown"
+    pub(crate) fn read_stm_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(0))?;
+        let title = file.read_string(20);
+        let tracker_name = file.read_string(8);
+        if tracker_name != "!Scream!" && tracker_name != "BMOD2STM" &&
+           tracker_name != "WUZAMOD!" && tracker_name != "SWavePro" {
+            return Err(LoadError::wrong_format(SongType::STM));
+        }
+        if file.read_u8()? != 0x1A {
+            return Err(LoadError::wrong_format(SongType::STM));
+        }
+
+        file.seek(SeekFrom::Start(33))?;
+        let pattern_count = file.read_u8()?;
+
+        file.seek(SeekFrom::Start(48))?;
+        let instruments = read_instruments(file).map_err(|e| e.at(Section::Instrument, 48))?;
+        let pattern_order = file.read_bytes(128)?;
+        let song_length = pattern_order.iter().position(|x| *x >= 99).ok_or(LoadError::invalid("Unknown song length"))?;
+
+        let names = instrument_names(&instruments);
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::STM,
+            tracker_name,
+            channel_count: 4,
+            pattern_count: pattern_count as u16,
+            order_count: song_length as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message: "".to_string(),
+        })
     }
 
     fn to_bcd(num: u8) -> u8 {
         ((num / 10) << 4) + (num % 10)
     }
 
-    fn read_stm_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
+    fn read_stm_header<R: Read + Seek>(file: &mut R) -> LoadResult<SongData>
     {
         // Mostly lifted from ft2-clone. Docs are not reliable...
         let num_channels = 4;
@@ -39,12 +75,12 @@
         let tracker_name      = file.read_string(8);
         if tracker_name != "!Scream!" && tracker_name != "BMOD2STM" &&
            tracker_name != "WUZAMOD!" && tracker_name != "SWavePro" {
-            return Err(SimpleError::new("Unknown stm tracker_name"));
+            return Err(LoadError::wrong_format(SongType::STM));
         }
 
         let id                  = file.read_u8()?;
         if id != 0x1A {
-            return Err(SimpleError::new("Unknown stm signature"));
+            return Err(LoadError::wrong_format(SongType::STM));
         }
 
         let file_type = file.read_u8()?;
@@ -52,7 +88,7 @@
         let minor = file.read_u8()?;
 
         if file_type != 2 || minor == 0 {
-            return Err(SimpleError::new("Unknown stm version"));
+            return Err(LoadError::invalid("Unknown stm version"));
         }
 
         let tempo = file.read_u8()?;
@@ -73,24 +109,23 @@
 
         file.seek(SeekFrom::Current(13))?;
 
-        let mut instruments = read_instruments(file)?;
+        let instruments_pos = file.offset();
+        let mut instruments = read_instruments(file).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
 
         let mut pattern_order = file.read_bytes(128)?;
 
-        let song_length = pattern_order.iter().cloned().position(|x| {x >= 99}).ok_or(SimpleError::new("Unknown song length"))?;
+        let song_length = pattern_order.iter().cloned().position(|x| {x >= 99}).ok_or(LoadError::invalid("Unknown song length"))?;
 
         pattern_order.resize(song_length + 1, 0);
 
-        let mut patterns = read_patterns(file, pattern_count as usize, num_channels as usize, minor)?;
+        let patterns_pos = file.offset();
+        let mut patterns = read_patterns(file, pattern_count as usize, num_channels as usize, minor).map_err(|e| e.at(Section::Pattern, patterns_pos))?;
 
         read_sample_data(file, &mut instruments)?;
 
         // fix empty patterns at end
-        for idx in 0..pattern_order.len() {
-            if pattern_order[idx] >= patterns.len() as u8 {
-                pattern_order[idx] = patterns.len() as u8;
-            }
-        }
+        let mut report = LoadReport::default();
+        fix_pattern_order(&mut pattern_order, song_length, patterns.len(), &mut report);
 
         patterns.push(Patterns {
             rows: vec![Row {
@@ -121,7 +156,11 @@
             pattern_order: Vec::from_iter(pattern_order.iter().cloned()),
             instruments,
             use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
             song_message: "".to_string(),
+            report,
         })
     }
 
@@ -134,14 +173,15 @@
         clamp(bpm as u8, 32, 255) // result can be slightly off, but close enough...
     }
 
-    fn read_sample_data<R: Read + Seek>(f: &mut R, instruments: &mut Vec<Instrument>) -> SimpleResult<()> {
+    fn read_sample_data<R: Read + Seek>(f: &mut R, instruments: &mut Vec<Instrument>) -> LoadResult<()> {
         for i in 1..instruments.len() {
-            instruments[i].samples[0].read_non_packed_data(f)?;
+            let sample_pos = f.offset();
+            instruments[i].samples[0].read_non_packed_data(f).map_err(|e| e.at(Section::Sample, sample_pos))?;
         }
         Ok(())
     }
 
-    fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, channel_count: usize, minor: u8) -> SimpleResult<Vec<Patterns>> {
+    fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, channel_count: usize, minor: u8) -> LoadResult<Vec<Patterns>> {
         let mut patterns: Vec<Patterns> = vec![];
         patterns.reserve_exact(pattern_count as usize);
 
@@ -225,7 +265,7 @@
         Ok(patterns)
     }
 
-    fn read_instrument<R: Read>(file: &mut R) -> SimpleResult<Sample> {
+    fn read_instrument<R: Read>(file: &mut R) -> LoadResult<Sample> {
         let name          = file.read_string(12);
         let _id                  = file.read_u8()?;
         let _instrument_disk     = file.read_u8()?;  // yeah, whatever...?
@@ -271,11 +311,12 @@
             name,
             is_ping_pong: false,
             original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
             data: vec![],
         })
     }
 
-    fn read_instruments<R: Read + Seek>(file: &mut R) -> SimpleResult<Vec<Instrument>> {
+    fn read_instruments<R: Read + Seek>(file: &mut R) -> LoadResult<Vec<Instrument>> {
         let mut instruments: Vec<Instrument> = vec![];
         let instrument_count = 31;
 
@@ -0,0 +1,313 @@
+    use crate::module_reader::{SongData, Patterns, SongType, FrequencyType};
+    use std::io::{Read, Seek, SeekFrom};
+    use binary_reader_io::{BinaryReader, BinarySeek};
+    use crate::pattern::Pattern;
+    use crate::instrument::{Instrument, Sample, LoopType, SustainLoop};
+    use crate::channel_state::channel_state::clamp;
+    use crate::module_reader;
+    use crate::module_reader::{LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata};
+    use crate::module_reader::metadata::instrument_names;
+
+    const ROW_COUNT: usize = 64;
+    const RLE_MARKER: u8 = 0xFC;
+
+    pub fn read_ult<R: Read + Seek>(mut file: &mut R) -> LoadResult<SongData> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        if file_len < 48 {
+            return Err(LoadError::truncated().in_format(SongType::ULT).at(Section::Header, 0));
+        }
+
+        read_ult_header(&mut file).map_err(|e| e.in_format(SongType::ULT).at(Section::Header, 0))
+    }
+
+    fn read_ult_header<R: Read + Seek>(file: &mut R) -> LoadResult<SongData>
+    {
+        let id = file.read_bytes(14)?;
+        if id != "MAS_UTrack_V00".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::ULT));
+        }
+
+        let version = file.read_u8()?;
+        if !(b'1'..=b'4').contains(&version) {
+            return Err(LoadError::invalid("Unknown ULT version"));
+        }
+        let version = version - b'0';
+
+        let name = file.read_string(32);
+
+        // the song text is stored in lines of 32 characters
+        let line_count = file.read_u8()?;
+        let text = file.read_bytes(line_count as usize * 32)?;
+        let song_message = text.chunks(32)
+            .map(|line| String::from_utf8_lossy(line).replace('\0', " ").trim_end().to_string())
+            .collect::<Vec<String>>()
+            .join("\n").trim_end().to_string();
+
+        let sample_count = file.read_u8()? as usize;
+        let instruments_pos = file.offset();
+        let mut instruments = read_instruments(file, sample_count, version).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+
+        let orders = file.read_bytes(256)?;
+        let song_length = orders.iter().position(|x| *x == 0xFF).unwrap_or(orders.len());
+        let mut pattern_order = orders[0..song_length].to_vec();
+
+        let channel_count = file.read_u8()? as usize + 1;
+        let pattern_count = file.read_u8()? as usize + 1;
+
+        if song_length == 0 || channel_count > 32 {
+            return Err(LoadError::invalid("Unknown ULT format"));
+        }
+
+        if version >= 3 {
+            let _panning = file.read_bytes(channel_count)?; // no per-channel default panning in SongData (yet)
+        }
+
+        let patterns_pos = file.offset();
+        let mut patterns = read_patterns(file, pattern_count, channel_count).map_err(|e| e.at(Section::Pattern, patterns_pos))?;
+
+        read_sample_data(file, &mut instruments)?;
+
+        // fix empty patterns at end
+        let mut report = LoadReport::default();
+        fix_pattern_order(&mut pattern_order, song_length, patterns.len(), &mut report);
+
+        patterns.push(Patterns::new(64, channel_count));
+
+        Ok(SongData {
+            id: "MAS_UTrack".to_string(),
+            name: name.trim().to_string(),
+            song_type: SongType::ULT,
+            tracker_name: format!("UltraTracker (format {})", version),
+            song_length: song_length as u16,
+            restart_position: 0,
+            channel_count: channel_count as u16,
+            patterns,
+            instrument_count: instruments.len() as u16,
+            frequency_type: FrequencyType::AMIGA,
+            tempo: 6,
+            bpm: 125,
+            pattern_order,
+            instruments,
+            use_amiga: true,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
+            song_message,
+            report,
+        })
+    }
+
+    pub(crate) fn read_ult_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(0))?;
+        if file.read_bytes(14)? != "MAS_UTrack_V00".as_bytes() {
+            return Err(LoadError::wrong_format(SongType::ULT));
+        }
+
+        let version = file.read_u8()?;
+        if !(b'1'..=b'4').contains(&version) {
+            return Err(LoadError::invalid("Unknown ULT version"));
+        }
+        let version = version - b'0';
+
+        let title = file.read_string(32);
+
+        let line_count = file.read_u8()?;
+        let text = file.read_bytes(line_count as usize * 32)?;
+        let song_message = text.chunks(32)
+            .map(|line| String::from_utf8_lossy(line).replace('\0', " ").trim_end().to_string())
+            .collect::<Vec<String>>()
+            .join("\n").trim_end().to_string();
+
+        let sample_count = file.read_u8()? as usize;
+        let instruments_pos = file.offset();
+        let instruments = read_instruments(file, sample_count, version).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
+
+        let orders = file.read_bytes(256)?;
+        let song_length = orders.iter().position(|x| *x == 0xFF).unwrap_or(orders.len());
+        let channel_count = file.read_u8()? as u16 + 1;
+        let pattern_count = file.read_u8()? as u16 + 1;
+
+        if song_length == 0 || channel_count > 32 {
+            return Err(LoadError::invalid("Unknown ULT format"));
+        }
+
+        let names = instrument_names(&instruments);
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::ULT,
+            tracker_name: format!("UltraTracker (format {})", version),
+            channel_count,
+            pattern_count,
+            order_count: song_length as u16,
+            instrument_names: names.clone(),
+            sample_names: names,
+            song_message,
+        })
+    }
+
+    fn read_sample_data<R: Read + Seek>(f: &mut R, instruments: &mut [Instrument]) -> LoadResult<()> {
+        for instrument in instruments.iter_mut().skip(1) {
+            let sample_pos = f.offset();
+            instrument.samples[0].read_non_packed_data(f).map_err(|e| e.at(Section::Sample, sample_pos))?;
+        }
+        Ok(())
+    }
+
+    // Patterns are stored one channel at a time: every pattern of channel 0, then every pattern of channel 1 and so on
+    fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, channel_count: usize) -> LoadResult<Vec<Patterns>> {
+        let mut patterns = vec![Patterns::new(ROW_COUNT, channel_count); pattern_count];
+
+        for channel_idx in 0..channel_count {
+            for pattern in patterns.iter_mut() {
+                let mut row_idx = 0;
+                while row_idx < ROW_COUNT {
+                    let mut repeat = 1;
+                    let mut note = file.read_u8()?;
+                    if note == RLE_MARKER {
+                        repeat = file.read_u8()? as usize;
+                        note = file.read_u8()?;
+                    }
+                    let data = [note, file.read_u8()?, file.read_u8()?, file.read_u8()?, file.read_u8()?];
+                    let event = read_event(&data);
+
+                    for _ in 0..repeat.max(1) {
+                        if row_idx >= ROW_COUNT { break; }
+                        pattern.rows[row_idx].channels[channel_idx] = event;
+                        row_idx += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(patterns)
+    }
+
+    // note, instrument, two effect nibbles, the second effect's parameter, the first effect's parameter
+    fn read_event(data: &[u8; 5]) -> Pattern {
+        let mut pattern = Pattern::new();
+        if (1..=84).contains(&data[0]) {
+            pattern.note = data[0] + 12;
+        }
+        pattern.instrument = data[1];
+
+        let first = convert_effect(data[2] >> 4, data[4]);
+        let second = convert_effect(data[2] & 0xF, data[3]);
+
+        // there's one effect column and a volume column, a set volume or panning can go in the latter
+        for (effect, effect_param) in [first, second] {
+            if effect == 0 && effect_param == 0 { continue; }
+            if pattern.volume == 0 && effect == 0xC {
+                pattern.volume = 0x10 + effect_param;
+            } else if pattern.volume == 0 && effect == 0x8 {
+                pattern.volume = 0xC0 | (effect_param >> 4);
+            } else if pattern.effect == 0 && pattern.effect_param == 0 {
+                pattern.effect = effect;
+                pattern.effect_param = effect_param;
+            }
+        }
+        pattern
+    }
+
+    fn convert_effect(effect: u8, param: u8) -> (u8, u8) {
+        match effect {
+            0x0..=0x4 | 0x7 | 0xA | 0xD => (effect, param),        // as in ProTracker
+            0x9 => (0x9, param.saturating_mul(4)),                  // sample offset is in 1024 sample steps
+            0xB => (0x8, (param & 0xF) * 0x11),                     // balance
+            0xC => (0xC, param / 4),                                // volume is 0-255
+            0xE => match param >> 4 {
+                0x1 | 0x2 | 0x9 | 0xA | 0xB | 0xC | 0xD => (0xE, param),
+                _ => (0, 0),
+            },
+            0xF => (0xF, param),                                    // speed, or BPM from 0x20 up
+            _ => (0, 0),                                            // sample reverse / loop control
+        }
+    }
+
+    fn read_sample<R: Read>(file: &mut R, version: u8) -> LoadResult<Sample> {
+        let name = file.read_string(32);
+        let _file_name = file.read_string(12);
+        let mut loop_start = file.read_u32()?;
+        let mut loop_end = file.read_u32()?;
+        let size_start = file.read_u32()?;
+        let size_end = file.read_u32()?;
+        let volume = file.read_u8()?;
+        let flags = file.read_u8()?;
+        let c2spd = if version >= 4 { file.read_u16()? as u32 } else { 8363 };
+        let _finetune = file.read_u16()?;
+
+        let bitness = if flags & 4 != 0 { 16 } else { 8 };
+        let mut length = size_end.saturating_sub(size_start);
+        if bitness == 16 { // length is in bytes, the loop points aren't
+            length /= 2;
+        }
+
+        // fix overflown loop
+        if loop_end > length {
+            loop_end = length;
+        }
+
+        let mut loop_type = if flags & 8 == 0 { LoopType::NoLoop } else if flags & 16 == 0 { LoopType::ForwardLoop } else { LoopType::PingPongLoop };
+        if loop_end <= loop_start.saturating_add(2) {
+            loop_type = LoopType::NoLoop;
+        }
+        if loop_type == LoopType::NoLoop {
+            loop_start = 0;
+            loop_end = 0;
+        }
+
+        let (finetune, relative_note) = module_reader::c2spd_to_finetune_relnote(if c2spd == 0 { 8363 } else { c2spd });
+
+        Ok(Sample {
+            length,
+            loop_start,
+            loop_end,
+            loop_len: loop_end - loop_start,
+            volume: clamp(volume / 4, 0, 64),
+            finetune,
+            loop_type,
+            bitness,
+            panning: 128,
+            relative_note,
+            name,
+            is_ping_pong: false,
+            original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
+            data: vec![],
+        })
+    }
+
+    fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize, version: u8) -> LoadResult<Vec<Instrument>> {
+        let mut instruments: Vec<Instrument> = vec![];
+
+        // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
+        instruments.reserve_exact(instrument_count + 1);
+
+        instruments.push(Instrument::new());
+
+        for instrument_idx in 1..instrument_count + 1 {
+            let mut instrument = Instrument::new();
+            let sample = read_sample(file, version)?;
+            instrument.name = sample.name.trim().to_string();
+            instrument.idx = instrument_idx as u8;
+            instrument.samples = vec![sample];
+            instruments.push(instrument);
+        }
+        Ok(instruments)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_notes_past_b_7() {
+            assert_eq!(read_event(&[1, 1, 0, 0, 0]).note, 13);
+            assert_eq!(read_event(&[84, 1, 0, 0, 0]).note, 96);
+            assert_eq!(read_event(&[85, 1, 0, 0, 0]).note, 0);
+            assert_eq!(read_event(&[250, 1, 0, 0, 0]).note, 0);
+        }
+    }
@@ -0,0 +1,695 @@
+    use std::borrow::Cow;
+    use miniz_oxide::inflate::{decompress_to_vec_with_limit, TINFLStatus};
+    use crate::module_reader::{probe_format, LoadError, LoadResult, SongType};
+
+    // No module comes anywhere near this, a header claiming more is broken
+    const MAX_UNPACKED_SIZE: usize = 0x4000000;
+    // a gzipped zip of an MMCMP packed module is about as deep as it gets in the wild
+    const MAX_DEPTH: usize = 4;
+
+    /// The wrappers modules get distributed in
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Container {
+        Gzip,
+        Zip,
+        /// ziRCONia's MMCMP sample packer
+        Mmcmp,
+        /// PowerPacker PP20, mostly Amiga MODs
+        PowerPacker,
+    }
+
+    /// A file in a zip archive
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ArchiveMember {
+        pub name:       String,
+        pub size:       usize,
+        /// The format of the module inside, if it is one
+        pub format:     Option<SongType>,
+    }
+
+    pub fn detect_container(data: &[u8]) -> Option<Container> {
+        match data {
+            [0x1F, 0x8B, 8, ..] => Some(Container::Gzip),
+            [b'P', b'K', 3, 4, ..] => Some(Container::Zip),
+            _ if data.starts_with(b"ziRCONia") => Some(Container::Mmcmp),
+            _ if data.starts_with(b"PP20") && data.len() >= 12 => Some(Container::PowerPacker),
+            _ => None,
+        }
+    }
+
+    /// Takes the data out of its containers until what's left isn't one. Plain modules come back as they are.
+    /// Out of a zip comes the named member, or the first member that is a module.
+    pub fn unpack<'a>(data: &'a [u8], member: Option<&str>) -> LoadResult<Cow<'a, [u8]>> {
+        unpack_nested(data, member, 0)
+    }
+
+    /// Lists the files in a zip archive, with the format of those that are modules
+    pub fn list_archive(data: &[u8]) -> LoadResult<Vec<ArchiveMember>> {
+        zip_entries(data)?.iter().map(|entry| {
+            let format = extract_zip_entry(data, entry).ok()
+                .and_then(|member| unpack_nested(&member, None, 1).ok().and_then(|module| probe_format(&module).first().map(|(song_type, _)| *song_type)));
+            Ok(ArchiveMember { name: entry.name.clone(), size: entry.size, format })
+        }).collect()
+    }
+
+    fn unpack_nested<'a>(data: &'a [u8], member: Option<&str>, depth: usize) -> LoadResult<Cow<'a, [u8]>> {
+        let mut data = Cow::Borrowed(data);
+        for depth in depth..MAX_DEPTH {
+            let unpacked = match detect_container(&data) {
+                None => return Ok(data),
+                Some(Container::Gzip) => unpack_gzip(&data)?,
+                Some(Container::Zip) => unpack_zip(&data, member, depth)?,
+                Some(Container::Mmcmp) => unpack_mmcmp(&data)?,
+                Some(Container::PowerPacker) => unpack_pp20(&data)?,
+            };
+            data = Cow::Owned(unpacked);
+        }
+        Err(LoadError::invalid("Too many nested containers"))
+    }
+
+    fn u16_le(data: &[u8], offset: usize) -> LoadResult<u16> {
+        data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]])).ok_or_else(LoadError::truncated)
+    }
+
+    fn u32_le(data: &[u8], offset: usize) -> LoadResult<u32> {
+        data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(LoadError::truncated)
+    }
+
+    fn inflate(data: &[u8]) -> LoadResult<Vec<u8>> {
+        decompress_to_vec_with_limit(data, MAX_UNPACKED_SIZE).map_err(|e| match e.status {
+            TINFLStatus::FailedCannotMakeProgress => LoadError::truncated(),
+            TINFLStatus::HasMoreOutput => LoadError::invalid("Unpacked data too large"),
+            _ => LoadError::invalid("Broken deflate data"),
+        })
+    }
+
+    fn unpack_gzip(data: &[u8]) -> LoadResult<Vec<u8>> {
+        const FHCRC: u8 = 0x02;
+        const FEXTRA: u8 = 0x04;
+        const FNAME: u8 = 0x08;
+        const FCOMMENT: u8 = 0x10;
+
+        let flags = *data.get(3).ok_or_else(LoadError::truncated)?;
+        let mut pos = 10;
+        if flags & FEXTRA != 0 {
+            pos += 2 + u16_le(data, pos)? as usize;
+        }
+        for field in [FNAME, FCOMMENT] {
+            if flags & field != 0 {
+                pos += 1 + data.get(pos..).and_then(|rest| rest.iter().position(|&c| c == 0)).ok_or_else(LoadError::truncated)?;
+            }
+        }
+        if flags & FHCRC != 0 {
+            pos += 2;
+        }
+
+        // the stream is followed by its crc and size
+        if data.len() < pos + 8 {
+            return Err(LoadError::truncated());
+        }
+        let unpacked = inflate(&data[pos..data.len() - 8])?;
+        if unpacked.len() as u32 != u32_le(data, data.len() - 4)? {
+            return Err(LoadError::invalid("Broken gzip data"));
+        }
+        Ok(unpacked)
+    }
+
+    struct ZipEntry {
+        name:           String,
+        flags:          u16,
+        method:         u16,
+        packed_size:    usize,
+        size:           usize,
+        header_offset:  usize,
+    }
+
+    // The central directory at the end of the archive is the authority, the local headers can leave the sizes out
+    fn zip_entries(data: &[u8]) -> LoadResult<Vec<ZipEntry>> {
+        const END_OF_DIRECTORY_SIZE: usize = 22;
+
+        // the archive comment after the end of directory record is at most 64k
+        let last = data.len().saturating_sub(END_OF_DIRECTORY_SIZE);
+        let end = (last.saturating_sub(0xFFFF)..=last).rev()
+            .find(|&pos| data[pos..].starts_with(b"PK\x05\x06"))
+            .ok_or_else(LoadError::truncated)?;
+
+        let entry_count = u16_le(data, end + 10)?;
+        let mut pos = u32_le(data, end + 16)? as usize;
+        let mut entries = vec![];
+
+        for _ in 0..entry_count {
+            if data.get(pos..pos + 4) != Some(b"PK\x01\x02") {
+                return Err(LoadError::invalid("Broken zip directory"));
+            }
+
+            let name_length = u16_le(data, pos + 28)? as usize;
+            let name = data.get(pos + 46..pos + 46 + name_length).ok_or_else(LoadError::truncated)?;
+            let entry = ZipEntry {
+                name:           String::from_utf8_lossy(name).to_string(),
+                flags:          u16_le(data, pos + 8)?,
+                method:         u16_le(data, pos + 10)?,
+                packed_size:    u32_le(data, pos + 20)? as usize,
+                size:           u32_le(data, pos + 24)? as usize,
+                header_offset:  u32_le(data, pos + 42)? as usize,
+            };
+            pos += 46 + name_length + u16_le(data, pos + 30)? as usize + u16_le(data, pos + 32)? as usize;
+
+            if !entry.name.ends_with('/') {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn extract_zip_entry(data: &[u8], entry: &ZipEntry) -> LoadResult<Vec<u8>> {
+        const ENCRYPTED: u16 = 0x01;
+        const STORED: u16 = 0;
+        const DEFLATED: u16 = 8;
+
+        let header = entry.header_offset;
+        if data.get(header..header + 4) != Some(b"PK\x03\x04") {
+            return Err(LoadError::invalid("Broken zip member header"));
+        }
+        let start = header + 30 + u16_le(data, header + 26)? as usize + u16_le(data, header + 28)? as usize;
+        let packed = data.get(start..start + entry.packed_size).ok_or_else(LoadError::truncated)?;
+
+        if entry.flags & ENCRYPTED != 0 {
+            return Err(LoadError::invalid(&format!("{} is encrypted", entry.name)));
+        }
+
+        match entry.method {
+            STORED => Ok(packed.to_vec()),
+            DEFLATED => inflate(packed),
+            method => Err(LoadError::invalid(&format!("{} uses unsupported zip compression method {}", entry.name, method))),
+        }
+    }
+
+    fn unpack_zip(data: &[u8], member: Option<&str>, depth: usize) -> LoadResult<Vec<u8>> {
+        let entries = zip_entries(data)?;
+
+        if let Some(name) = member {
+            let entry = entries.iter().find(|entry| entry.name == name).ok_or_else(|| LoadError::invalid(&format!("No {} in the zip archive", name)))?;
+            return extract_zip_entry(data, entry);
+        }
+
+        for entry in &entries {
+            let Ok(unpacked) = extract_zip_entry(data, entry) else { continue };
+            if let Ok(module) = unpack_nested(&unpacked, None, depth + 1)
+                && !probe_format(&module).is_empty() {
+                return Ok(module.into_owned());
+            }
+        }
+        Err(LoadError::invalid("No module in the zip archive"))
+    }
+
+    /// Reads the lowest bits first, for MMCMP and IT sample compression
+    pub(crate) struct BitReader<'a> {
+        data:       &'a [u8],
+        pos:        usize,
+        buffer:     u32,
+        count:      u32,
+    }
+
+    impl<'a> BitReader<'a> {
+        pub(crate) fn new(data: &'a [u8]) -> Self {
+            Self { data, pos: 0, buffer: 0, count: 0 }
+        }
+
+        pub(crate) fn read(&mut self, bits: u32) -> LoadResult<u32> {
+            while self.count < bits {
+                let byte = *self.data.get(self.pos).ok_or_else(LoadError::truncated)?;
+                self.buffer |= (byte as u32) << self.count;
+                self.count += 8;
+                self.pos += 1;
+            }
+            let value = self.buffer & ((1 << bits) - 1);
+            self.buffer >>= bits;
+            self.count -= bits;
+            Ok(value)
+        }
+    }
+
+    // MMCMP packs a module in blocks, and each block unpacks into one or more places in the output.
+    // Compressed blocks are a variable bit length code, where the values at the top of each bit length
+    // switch to another bit length.
+    fn unpack_mmcmp(data: &[u8]) -> LoadResult<Vec<u8>> {
+        const COMPRESSED: u16 = 0x01;
+        const DELTA: u16 = 0x02;
+        const SIXTEEN_BIT: u16 = 0x04;
+        const ABS16: u16 = 0x10;
+        const BIG_ENDIAN: u16 = 0x20;
+
+        const BLOCK_HEADER_SIZE: usize = 20;
+        const SUB_BLOCK_SIZE: usize = 8;
+
+        if u16_le(data, 8)? != 14 {
+            return Err(LoadError::invalid("Unsupported MMCMP header"));
+        }
+        let block_count = u16_le(data, 12)? as usize;
+        let size = u32_le(data, 14)? as usize;
+        let block_table = u32_le(data, 18)? as usize;
+        if size > MAX_UNPACKED_SIZE {
+            return Err(LoadError::invalid("Unpacked data too large"));
+        }
+
+        let mut unpacked = vec![0u8; size];
+
+        for block_idx in 0..block_count {
+            let block = u32_le(data, block_table + block_idx * 4)? as usize;
+            let packed_size = u32_le(data, block + 4)? as usize;
+            let sub_block_count = u16_le(data, block + 12)? as usize;
+            let flags = u16_le(data, block + 14)?;
+            let table_size = u16_le(data, block + 16)? as usize;
+            let mut bits = u16_le(data, block + 18)? as usize;
+
+            let mut sub_blocks = vec![];
+            for sub_block_idx in 0..sub_block_count {
+                let pos = block + BLOCK_HEADER_SIZE + sub_block_idx * SUB_BLOCK_SIZE;
+                let (start, length) = (u32_le(data, pos)? as usize, u32_le(data, pos + 4)? as usize);
+                if start + length > size {
+                    return Err(LoadError::invalid("Broken MMCMP block"));
+                }
+                sub_blocks.push((start, length));
+            }
+
+            let pos = block + BLOCK_HEADER_SIZE + sub_block_count * SUB_BLOCK_SIZE;
+
+            if flags & COMPRESSED == 0 {
+                let mut pos = pos;
+                for (start, length) in sub_blocks {
+                    let src = data.get(pos..pos + length).ok_or_else(LoadError::truncated)?;
+                    unpacked[start..start + length].copy_from_slice(src);
+                    pos += length;
+                }
+                continue;
+            }
+
+            let end = (pos + packed_size).min(data.len());
+            let table = data.get(pos..pos + table_size).ok_or_else(LoadError::truncated)?;
+            let mut reader = BitReader::new(data.get(pos + table_size..end).ok_or_else(LoadError::truncated)?);
+
+            let step = if flags & SIXTEEN_BIT != 0 { 2 } else { 1 };
+            let mut targets = sub_blocks.iter().flat_map(|&(start, length)| (start..start + length / step * step).step_by(step));
+            let mut target = targets.next();
+
+            if flags & SIXTEEN_BIT != 0 {
+                const COMMANDS: [u32; 16] = [0x01, 0x03, 0x07, 0x0F, 0x1E, 0x3C, 0x78, 0xF0, 0x1F0, 0x3F0, 0x7F0, 0xFF0, 0x1FF0, 0x3FF0, 0x7FF0, 0xFFF0];
+                const FETCH: [u32; 16] = [4, 4, 4, 4, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+                if bits >= 16 {
+                    return Err(LoadError::invalid("Broken MMCMP block"));
+                }
+
+                let mut old = 0u16;
+                while let Some(dst) = target {
+                    let mut code = reader.read(bits as u32 + 1)?;
+                    if code >= COMMANDS[bits] {
+                        let fetch = FETCH[bits];
+                        let new_bits = (reader.read(fetch)? + ((code - COMMANDS[bits]) << fetch)) as usize;
+                        if new_bits != bits {
+                            bits = new_bits & 0x0F;
+                            continue;
+                        }
+                        code = reader.read(4)?;
+                        if code == 0x0F {
+                            if reader.read(1)? != 0 { break; }
+                            code = 0xFFFF;
+                        } else {
+                            code += 0xFFF0;
+                        }
+                    }
+
+                    // odd codes are negative
+                    let mut value = if code & 1 != 0 { (-(((code + 1) >> 1) as i32)) as u16 } else { (code >> 1) as u16 };
+                    if flags & DELTA != 0 {
+                        value = value.wrapping_add(old);
+                        old = value;
+                    } else if flags & ABS16 == 0 {
+                        value ^= 0x8000;
+                    }
+
+                    let bytes = if flags & BIG_ENDIAN != 0 { value.to_be_bytes() } else { value.to_le_bytes() };
+                    unpacked[dst..dst + 2].copy_from_slice(&bytes);
+                    target = targets.next();
+                }
+            } else {
+                const COMMANDS: [u32; 8] = [0x01, 0x03, 0x07, 0x0F, 0x1E, 0x3C, 0x78, 0xF8];
+                const FETCH: [u32; 8] = [3, 3, 3, 3, 2, 1, 0, 0];
+
+                if bits >= 8 {
+                    return Err(LoadError::invalid("Broken MMCMP block"));
+                }
+
+                let mut old = 0u8;
+                while let Some(dst) = target {
+                    let mut code = reader.read(bits as u32 + 1)?;
+                    if code >= COMMANDS[bits] {
+                        let fetch = FETCH[bits];
+                        let new_bits = (reader.read(fetch)? + ((code - COMMANDS[bits]) << fetch)) as usize;
+                        if new_bits != bits {
+                            bits = new_bits & 0x07;
+                            continue;
+                        }
+                        code = reader.read(3)?;
+                        if code == 0x07 {
+                            if reader.read(1)? != 0 { break; }
+                            code = 0xFF;
+                        } else {
+                            code += 0xF8;
+                        }
+                    }
+
+                    // the codes go through a translation table
+                    let mut value = *table.get(code as usize).ok_or_else(|| LoadError::invalid("Broken MMCMP block"))?;
+                    if flags & DELTA != 0 {
+                        value = value.wrapping_add(old);
+                        old = value;
+                    }
+
+                    unpacked[dst] = value;
+                    target = targets.next();
+                }
+            }
+        }
+
+        Ok(unpacked)
+    }
+
+    // PowerPacker streams are read from the end, and each byte from its lowest bit up
+    struct BackwardBitReader<'a> {
+        data:       &'a [u8],
+        pos:        usize,
+        buffer:     u8,
+        count:      u32,
+    }
+
+    impl<'a> BackwardBitReader<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Self { data, pos: data.len(), buffer: 0, count: 0 }
+        }
+
+        fn read(&mut self, bits: u32) -> LoadResult<u32> {
+            let mut value = 0;
+            for _ in 0..bits {
+                if self.count == 0 {
+                    if self.pos == 0 {
+                        return Err(LoadError::truncated());
+                    }
+                    self.pos -= 1;
+                    self.buffer = self.data[self.pos];
+                    self.count = 8;
+                }
+                value = (value << 1) | (self.buffer & 1) as u32;
+                self.buffer >>= 1;
+                self.count -= 1;
+            }
+            Ok(value)
+        }
+    }
+
+    // "PP20", the offset bit lengths of the four match lengths, the crunched stream, then the unpacked size
+    // and the number of unused bits at the end of the stream. The output is written back to front, alternating
+    // literal runs and matches.
+    fn unpack_pp20(data: &[u8]) -> LoadResult<Vec<u8>> {
+        let offset_bits = &data[4..8];
+        if offset_bits.iter().any(|bits| !(9..=15).contains(bits)) {
+            return Err(LoadError::invalid("Broken PowerPacker header"));
+        }
+
+        let footer = &data[data.len() - 4..];
+        let size = u32::from_be_bytes([0, footer[0], footer[1], footer[2]]) as usize;
+        if size == 0 {
+            return Err(LoadError::invalid("Broken PowerPacker header"));
+        }
+
+        let mut reader = BackwardBitReader::new(&data[8..data.len() - 4]);
+        reader.read(footer[3] as u32)?;
+
+        let mut unpacked = vec![0u8; size];
+        let mut left = size;
+
+        while left > 0 {
+            if reader.read(1)? == 0 {
+                let mut length = 1;
+                while length < left {
+                    let code = reader.read(2)? as usize;
+                    length += code;
+                    if code != 3 { break; }
+                }
+                if length > left {
+                    return Err(LoadError::invalid("Broken PowerPacker data"));
+                }
+                for _ in 0..length {
+                    left -= 1;
+                    unpacked[left] = reader.read(8)? as u8;
+                }
+                if left == 0 { break; }
+            }
+
+            let mut length = reader.read(2)? as usize + 1;
+            let bits = offset_bits[length - 1] as u32;
+            let offset = if length == 4 {
+                let long = reader.read(1)? != 0;
+                let offset = reader.read(if long { bits } else { 7 })?;
+                while length < left {
+                    let code = reader.read(3)? as usize;
+                    length += code;
+                    if code != 7 { break; }
+                }
+                offset
+            } else {
+                reader.read(bits)?
+            } as usize;
+
+            // a match copies length + 1 bytes, anything before the start of the output is silence
+            for _ in 0..=length {
+                unpacked[left - 1] = unpacked.get(left + offset).copied().unwrap_or(0);
+                left -= 1;
+                if left == 0 { break; }
+            }
+        }
+
+        Ok(unpacked)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use miniz_oxide::deflate::compress_to_vec;
+        use crate::module_reader::{open_module, LoadErrorKind};
+
+        fn gzip(data: &[u8]) -> Vec<u8> {
+            let mut gz = vec![0x1F, 0x8B, 8, 0x08, 0, 0, 0, 0, 0, 3];
+            gz.extend_from_slice(b"test.mod\0");
+            gz.extend_from_slice(&compress_to_vec(data, 6));
+            gz.extend_from_slice(&0u32.to_le_bytes());                  // crc, not checked
+            gz.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            gz
+        }
+
+        fn zip(members: &[(&str, &[u8], bool)]) -> Vec<u8> {
+            let mut data = vec![];
+            let mut directory = vec![];
+            for (name, content, deflate) in members {
+                let packed = if *deflate { compress_to_vec(content, 6) } else { content.to_vec() };
+                let method: u16 = if *deflate { 8 } else { 0 };
+
+                let mut header = vec![0u8; 46];
+                header[0..4].copy_from_slice(b"PK\x01\x02");
+                header[10..12].copy_from_slice(&method.to_le_bytes());
+                header[20..24].copy_from_slice(&(packed.len() as u32).to_le_bytes());
+                header[24..28].copy_from_slice(&(content.len() as u32).to_le_bytes());
+                header[28..30].copy_from_slice(&(name.len() as u16).to_le_bytes());
+                header[42..46].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                directory.extend_from_slice(&header);
+                directory.extend_from_slice(name.as_bytes());
+
+                // sizes left out of the local header, like streaming zippers do
+                let mut local = vec![0u8; 30];
+                local[0..4].copy_from_slice(b"PK\x03\x04");
+                local[8..10].copy_from_slice(&method.to_le_bytes());
+                local[26..28].copy_from_slice(&(name.len() as u16).to_le_bytes());
+                data.extend_from_slice(&local);
+                data.extend_from_slice(name.as_bytes());
+                data.extend_from_slice(&packed);
+            }
+
+            let mut end = vec![0u8; 22];
+            end[0..4].copy_from_slice(b"PK\x05\x06");
+            end[10..12].copy_from_slice(&(members.len() as u16).to_le_bytes());
+            end[12..16].copy_from_slice(&(directory.len() as u32).to_le_bytes());
+            end[16..20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            data.extend_from_slice(&directory);
+            data.extend_from_slice(&end);
+            data
+        }
+
+        // Bits are collected in the order the unpackers read them
+        struct BitWriter {
+            bits:   Vec<bool>,
+        }
+
+        impl BitWriter {
+            fn write(&mut self, value: u32, count: u32) {
+                for bit in (0..count).rev() {
+                    self.bits.push(value >> bit & 1 != 0);
+                }
+            }
+
+            // lowest bits first
+            fn write_lsb(&mut self, value: u32, count: u32) {
+                for bit in 0..count {
+                    self.bits.push(value >> bit & 1 != 0);
+                }
+            }
+
+            fn bytes(&self) -> Vec<u8> {
+                self.bits.chunks(8).map(|chunk| chunk.iter().enumerate().fold(0u8, |byte, (idx, &bit)| byte | (bit as u8) << idx)).collect()
+            }
+        }
+
+        // A single 8 bit block at the widest bit length, with the top codes escaped
+        fn mmcmp(data: &[u8], compressed: bool) -> Vec<u8> {
+            let mut packed = vec![];
+            if compressed {
+                packed.extend((0..=255u8).collect::<Vec<u8>>());
+                let mut writer = BitWriter { bits: vec![] };
+                for &byte in data {
+                    match byte {
+                        0..=0xF7 => writer.write_lsb(byte as u32, 8),
+                        0xFF => { writer.write_lsb(0xFF, 8); writer.write_lsb(7, 3); writer.write_lsb(0, 1); }
+                        _ => { writer.write_lsb(0xFF, 8); writer.write_lsb((byte - 0xF8) as u32, 3); }
+                    }
+                }
+                packed.extend(writer.bytes());
+            } else {
+                packed.extend_from_slice(data);
+            }
+
+            let mut file = b"ziRCONia".to_vec();
+            file.extend_from_slice(&14u16.to_le_bytes());
+            file.extend_from_slice(&0x1310u16.to_le_bytes());          // version
+            file.extend_from_slice(&1u16.to_le_bytes());               // blocks
+            file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(&24u32.to_le_bytes());              // block table
+            file.extend_from_slice(&[0, 0]);
+            file.extend_from_slice(&28u32.to_le_bytes());
+
+            file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(&(packed.len() as u32).to_le_bytes());
+            file.extend_from_slice(&0u32.to_le_bytes());               // checksum
+            file.extend_from_slice(&1u16.to_le_bytes());               // sub blocks
+            file.extend_from_slice(&(compressed as u16).to_le_bytes());
+            file.extend_from_slice(&(if compressed { 256u16 } else { 0 }).to_le_bytes());
+            file.extend_from_slice(&7u16.to_le_bytes());               // bits - 1
+            file.extend_from_slice(&0u32.to_le_bytes());
+            file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(&packed);
+            file
+        }
+
+        // Crunches back to front into two byte matches within the reach of the shortest offset, or literals
+        fn pp20(data: &[u8]) -> Vec<u8> {
+            const OFFSET_BITS: [u8; 4] = [9, 10, 11, 11];
+            let mut writer = BitWriter { bits: vec![] };
+            let mut left = data.len();
+
+            while left > 0 {
+                let find_match = |left: usize| (left..(left + (1 << OFFSET_BITS[0]) - 1).min(data.len() - 1))
+                    .find(|&pos| left >= 2 && data[pos] == data[left - 2] && data[pos + 1] == data[left - 1]);
+
+                let mut literals = 0;
+                while left - literals > 0 && (literals == 0 || find_match(left - literals).is_none()) {
+                    literals += 1;
+                }
+
+                writer.write(0, 1);
+                let mut length = 1;
+                while length < left {
+                    let code = (literals - length).min(3);
+                    writer.write(code as u32, 2);
+                    length += code;
+                    if code != 3 { break; }
+                }
+                for _ in 0..literals {
+                    left -= 1;
+                    writer.write(data[left] as u32, 8);
+                }
+
+                if left > 0 {
+                    let pos = find_match(left).unwrap();
+                    writer.write(0, 2);
+                    writer.write((pos - (left - 1)) as u32, OFFSET_BITS[0] as u32);
+                    left -= 2;
+                }
+            }
+
+            let mut bits = writer.bits.clone();
+            let skip = (8 - bits.len() % 8) % 8;
+            bits.splice(0..0, std::iter::repeat_n(false, skip));
+            let mut stream: Vec<u8> = bits.chunks(8).map(|chunk| chunk.iter().enumerate().fold(0u8, |byte, (idx, &bit)| byte | (bit as u8) << idx)).collect();
+            stream.reverse();
+
+            let mut file = b"PP20".to_vec();
+            file.extend_from_slice(&OFFSET_BITS);
+            file.extend_from_slice(&stream);
+            file.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+            file.push(skip as u8);
+            file
+        }
+
+        #[test]
+        fn test_gzip() {
+            let module = std::fs::read("test_data/test.mod").unwrap();
+            let packed = gzip(&module);
+            assert_eq!(detect_container(&packed), Some(Container::Gzip));
+            assert_eq!(unpack(&packed, None).unwrap().as_ref(), module.as_slice());
+            assert_eq!(open_module(&packed).unwrap().name, open_module(&module).unwrap().name);
+
+            let truncated = &packed[..packed.len() / 2];
+            assert_eq!(unpack(truncated, None).err().unwrap().kind(), &LoadErrorKind::Truncated);
+        }
+
+        #[test]
+        fn test_zip() {
+            let xm = std::fs::read("test_data/test.xm").unwrap();
+            let module = std::fs::read("test_data/test.mod").unwrap();
+            let archive = zip(&[("readme.txt", b"hello", false), ("dir/song.xm", &xm, true), ("test.mod.gz", &gzip(&module), false)]);
+
+            let members = list_archive(&archive).unwrap();
+            assert_eq!(members.iter().map(|m| (m.name.as_str(), m.format)).collect::<Vec<_>>(),
+                       vec![("readme.txt", None), ("dir/song.xm", Some(SongType::XM)), ("test.mod.gz", Some(SongType::MOD))]);
+            assert_eq!(members[1].size, xm.len());
+
+            assert_eq!(unpack(&archive, None).unwrap().as_ref(), xm.as_slice());
+            assert_eq!(unpack(&archive, Some("test.mod.gz")).unwrap().as_ref(), module.as_slice());
+            assert!(unpack(&archive, Some("missing.it")).is_err());
+            assert!(unpack(&zip(&[("readme.txt", b"hello", false)]), None).is_err());
+        }
+
+        #[test]
+        fn test_mmcmp() {
+            let module = std::fs::read("test_data/test.mod").unwrap();
+            for compressed in [false, true] {
+                let packed = mmcmp(&module, compressed);
+                assert_eq!(detect_container(&packed), Some(Container::Mmcmp));
+                assert_eq!(unpack(&packed, None).unwrap().as_ref(), module.as_slice());
+            }
+        }
+
+        #[test]
+        fn test_pp20() {
+            let module = std::fs::read("test_data/test.mod").unwrap();
+            let packed = pp20(&module);
+            assert!(packed.len() < module.len());
+            assert_eq!(detect_container(&packed), Some(Container::PowerPacker));
+            assert_eq!(unpack(&packed, None).unwrap().as_ref(), module.as_slice());
+            assert_eq!(open_module(&packed).unwrap().song_type, SongType::MOD);
+        }
+
+        #[test]
+        fn test_plain_module_is_not_copied() {
+            let module = std::fs::read("test_data/test.xm").unwrap();
+            assert!(matches!(unpack(&module, None).unwrap(), Cow::Borrowed(_)));
+        }
+    }
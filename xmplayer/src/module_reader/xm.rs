@@ -1,114 +1,137 @@
 use std::io::{Read, Seek, SeekFrom};
-use crate::module_reader::{Patterns, Row, SongData, SongType, FrequencyType};
-use binary_reader_io::BinaryReader;
+use crate::module_reader::{Patterns, Row, SongData, SongType, FrequencyType, LoadError, LoadResult, Section, LoadReport, fix_pattern_order, ModuleMetadata, bytes_left};
+use binary_reader_io::{BinaryReader, BinarySeek};
 use crate::pattern::Pattern;
-use crate::envelope::{EnvelopePoints, EnvelopePoint, Envelope};
-use crate::instrument::{Sample, LoopType, Instrument, VibratoEnvelope};
+use crate::envelope::{EnvelopePoints, EnvelopePoint, Envelope, MAX_ENVELOPE_POINTS};
+use crate::instrument::{Sample, LoopType, SustainLoop, Instrument, VibratoEnvelope, NewNoteAction, DuplicateCheckType, DuplicateCheckAction};
 use std::iter::FromIterator;
-use crate::{SimpleResult, SimpleError};
-use std::io;
 
-fn read_patterns<R: Read>(file: &mut R, pattern_count: usize, channel_count: usize) -> SimpleResult<Vec<Patterns>> {
+fn read_patterns<R: Read + Seek>(file: &mut R, pattern_count: usize, channel_count: usize) -> LoadResult<Vec<Patterns>> {
     let mut patterns: Vec<Patterns> = vec![];
     patterns.reserve_exact(pattern_count as usize);
 
     for _pattern_idx in 0..pattern_count {
-        let _pattern_header_size = file.read_u32()?;
-        let _pattern_type = file.read_u8()?;
-        let row_count = file.read_u16()?;
-        let pattern_size = file.read_u16()?;
-
-        let mut pos = 0usize;
-        if pattern_size == 0 {
-            patterns.push(Patterns {
-                rows: vec![Row {
-                    channels: vec![Pattern {
-                        note: 0,
-                        instrument: 0,
-                        volume: 0,
-                        effect: 0,
-                        effect_param: 0
-                    }; channel_count]
-                }; 64]
-            });
-            continue;
-        }
+        let pattern_pos = file.offset();
+        patterns.push(read_pattern(file, channel_count).map_err(|e| e.at(Section::Pattern, pattern_pos))?);
+    }
+
+    Ok(patterns)
+}
+
+fn read_pattern<R: Read>(file: &mut R, channel_count: usize) -> LoadResult<Patterns> {
+    let _pattern_header_size = file.read_u32()?;
+    let _pattern_type = file.read_u8()?;
+    let row_count = file.read_u16()?;
+    let pattern_size = file.read_u16()?;
+
+    let mut pos = 0usize;
+    if pattern_size == 0 {
+        return Ok(Patterns {
+            rows: vec![Row {
+                channels: vec![Pattern {
+                    note: 0,
+                    instrument: 0,
+                    volume: 0,
+                    effect: 0,
+                    effect_param: 0
+                }; channel_count]
+            }; 64]
+        });
+    }
 
-        let mut rows: Vec<Row> = vec![];
-        rows.reserve_exact(row_count as usize);
-        for _row_idx in 0..row_count {
-            let mut channels: Vec<Pattern> = vec![];
-            channels.reserve_exact(channel_count);
-            for _channel_idx in 0..channel_count {
-                let flags = file.read_u8()?;
-                channels.push(if flags & 0x80 == 0x80 {
+    let mut rows: Vec<Row> = vec![];
+    rows.reserve_exact(row_count as usize);
+    for _row_idx in 0..row_count {
+        let mut channels: Vec<Pattern> = vec![];
+        channels.reserve_exact(channel_count);
+        for _channel_idx in 0..channel_count {
+            let flags = file.read_u8()?;
+            channels.push(if flags & 0x80 == 0x80 {
+                pos += 1;
+                let note = if flags & 1 == 1 {
                     pos += 1;
-                    let note = if flags & 1 == 1 {
-                        pos += 1;
-                        file.read_u8()?
-                    } else { 0 };
-                    let instrument = if flags & 2 == 2 {
-                        pos += 1;
-                        file.read_u8()?
-                    } else { 0 };
-                    let volume = if flags & 4 == 4 {
-                        pos += 1;
-                        file.read_u8()?
-                    } else { 0 };
-                    let effect = if flags & 8 == 8 {
-                        pos += 1;
-                        file.read_u8()?
-                    } else { 0 };
-                    let effect_param = if flags & 16 == 16 {
-                        pos += 1;
-                        file.read_u8()?
-                    } else { 0 };
-                    Pattern {
-                        note,
-                        instrument,
-                        volume,
-                        effect,
-                        effect_param
-                    }
-                } else {
-                    let note = flags;
-                    let instrument = file.read_u8()?;
-                    let volume = file.read_u8()?;
-                    let effect = file.read_u8()?;
-                    let effect_param = file.read_u8()?;
-                    pos += 5;
-
-                    Pattern {
-                        note,
-                        instrument,
-                        volume,
-                        effect,
-                        effect_param
-                    }
-                });
-            }
-            rows.push(Row { channels });
-        }
-        if pattern_size as usize != pos {
-            return Err(SimpleError::from(format!("size {} != pos {}", pattern_size, pos)));
+                    file.read_u8()?
+                } else { 0 };
+                let instrument = if flags & 2 == 2 {
+                    pos += 1;
+                    file.read_u8()?
+                } else { 0 };
+                let volume = if flags & 4 == 4 {
+                    pos += 1;
+                    file.read_u8()?
+                } else { 0 };
+                let effect = if flags & 8 == 8 {
+                    pos += 1;
+                    file.read_u8()?
+                } else { 0 };
+                let effect_param = if flags & 16 == 16 {
+                    pos += 1;
+                    file.read_u8()?
+                } else { 0 };
+                Pattern {
+                    note,
+                    instrument,
+                    volume,
+                    effect,
+                    effect_param
+                }
+            } else {
+                let note = flags;
+                let instrument = file.read_u8()?;
+                let volume = file.read_u8()?;
+                let effect = file.read_u8()?;
+                let effect_param = file.read_u8()?;
+                pos += 5;
+
+                Pattern {
+                    note,
+                    instrument,
+                    volume,
+                    effect,
+                    effect_param
+                }
+            });
         }
-        patterns.push(Patterns { rows })
+        rows.push(Row { channels });
     }
-
-    Ok(patterns)
+    if pattern_size as usize != pos {
+        return Err(LoadError::invalid(&format!("Pattern size {} doesn't match its data ({} bytes)", pattern_size, pos)));
+    }
+    Ok(Patterns { rows })
 }
 
-fn read_envelope<R: Read>(file: &mut R) -> SimpleResult<EnvelopePoints> {
-    let mut result = [EnvelopePoint::new(); 12];
+fn read_envelope<R: Read>(file: &mut R) -> LoadResult<EnvelopePoints> {
+    let mut result = [EnvelopePoint::new(); MAX_ENVELOPE_POINTS];
 
-    for point in &mut result {
+    for point in &mut result[..12] {
         point.frame = file.read_u16()?;
         point.value = file.read_u16()?;
     }
     Ok(result)
 }
 
-fn read_samples<R: Read>(file: &mut R, sample_count: usize) -> SimpleResult<Vec<Sample>> {
+fn read_samples<R: Read + Seek>(file: &mut R, sample_count: usize, instrument_idx: usize, lenient: bool, report: &mut LoadReport) -> LoadResult<Vec<Sample>> {
+    let mut samples = read_sample_headers(file, sample_count)?;
+
+    for (sample_idx, sample) in samples.iter_mut().enumerate() {
+        let sample_pos = file.offset();
+        if lenient {
+            if sample.fit_length(bytes_left(file)?) {
+                report.repair(format!("Sample {} of instrument {} runs past the end of the file, cut to {} samples", sample_idx, instrument_idx, sample.length));
+            }
+            if sample.fit_loops() {
+                report.repair(format!("Sample {} of instrument {} loops past its end, the loop is cut to {}-{}", sample_idx, instrument_idx, sample.loop_start, sample.loop_end));
+            }
+        } else if !sample.loops_fit() {
+            return Err(LoadError::invalid("Sample loops past its end").at(Section::Sample, sample_pos));
+        }
+        sample.read_data(file).map_err(|e| e.at(Section::Sample, sample_pos))?;
+    }
+
+    Ok(samples)
+}
+
+fn read_sample_headers<R: Read>(file: &mut R, sample_count: usize) -> LoadResult<Vec<Sample>> {
     let mut samples: Vec<Sample> = vec![];
     samples.reserve_exact(sample_count as usize);
 
@@ -143,7 +166,7 @@ fn read_samples<R: Read>(file: &mut R, sample_count: usize) -> SimpleResult<Vec<
         samples.push(Sample {
             length,
             loop_start,
-            loop_end: loop_start + loop_len,
+            loop_end: loop_start.saturating_add(loop_len),
             loop_len,
             volume,
             finetune,
@@ -154,18 +177,27 @@ fn read_samples<R: Read>(file: &mut R, sample_count: usize) -> SimpleResult<Vec<
             name,
             is_ping_pong: false,
             original_loop_end: 0,
+            sustain_loop: SustainLoop::new(),
             data: vec![],
         })
     }
 
-    for sample in &mut samples {
-        sample.read_data(file)?;
-    }
-
     Ok(samples)
 }
 
-fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize) -> SimpleResult<Vec<Instrument>> {
+// FT2 writes 263 byte instrument headers, or 29 bytes without samples. A lenient load falls back on those when the
+// header size is too small for what's been read, or runs past the end of the file.
+fn fit_header_size<R: Read + Seek>(file: &mut R, instrument_idx: usize, instrument_pos: u64, header_size: u32, sample_count: u16, report: &mut LoadReport) -> LoadResult<u32> {
+    let header_read = file.offset() - instrument_pos;
+    if header_size as u64 >= header_read && header_size as u64 - header_read <= bytes_left(file)? {
+        return Ok(header_size);
+    }
+    let standard_size = if sample_count > 0 { 263 } else { 29 };
+    report.repair(format!("Instrument {} has a bogus header size of {}, read as {}", instrument_idx, header_size, standard_size));
+    Ok(standard_size)
+}
+
+fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize, lenient: bool, report: &mut LoadReport) -> LoadResult<Vec<Instrument>> {
     let mut instruments: Vec<Instrument> = vec![];
 
     // Instruments are one based, go figure. We'll add an empty instrument as sample 0.
@@ -175,7 +207,7 @@ fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize) -> Si
 
     for instrument_idx in 0..instrument_count {
         let instrument_pos = file.seek(SeekFrom::Current(0))?;
-        let header_size = file.read_u32()?;
+        let mut header_size = file.read_u32()?;
         let name = file.read_string(22);
         let _instrument_type = file.read_u8()?;
         let sample_count = file.read_u16()?;
@@ -203,30 +235,54 @@ fn read_instruments<R: Read + Seek>(file: &mut R, instrument_count: usize) -> Si
             let volume_fadeout = file.read_u16()?;
             let _reserved = file.read_u16()?;
 
+            if lenient {
+                header_size = fit_header_size(file, instrument_idx + 1, instrument_pos, header_size, sample_count, report)?;
+            }
             file.seek(SeekFrom::Start(instrument_pos + header_size as u64))?;
             instruments.push(Instrument {
                 name,
                 idx: (instrument_idx + 1) as u8,
                 sample_indexes,
+                note_map: vec![],
                 volume_envelope: Envelope::create(volume_envelope, volume_points, volume_sustain_point, volume_loop_start_point, volume_loop_end_point, volume_type),
                 panning_envelope: Envelope::create(panning_envelope,panning_points, panning_sustain_point, panning_loop_start_point, panning_loop_end_point,panning_type),
                 vibrato_envelope: VibratoEnvelope::create(vibrato_type, vibrato_sweep, vibrato_depth, vibrato_rate),
+                pitch_envelope: Envelope::new(),
+                filter_envelope: false,
+                filter_cutoff: None,
+                filter_resonance: None,
                 volume_fadeout,
-                samples: read_samples(file, sample_count as usize)?
+                new_note_action: NewNoteAction::Cut,
+                duplicate_check_type: DuplicateCheckType::Off,
+                duplicate_check_action: DuplicateCheckAction::Cut,
+                global_volume: 128,
+                pitch_pan_separation: 0,
+                pitch_pan_center: 0,
+                random_volume: 0,
+                random_panning: 0,
+                adlib: None,
+                samples: read_samples(file, sample_count as usize, instrument_idx + 1, lenient, report).map_err(|e| e.at(Section::Instrument, instrument_pos))?
             });
         } else {
+            // no samples, but the name can still be part of a message
+            if lenient {
+                header_size = fit_header_size(file, instrument_idx + 1, instrument_pos, header_size, sample_count, report)?;
+            }
             file.seek(SeekFrom::Start(instrument_pos + header_size as u64))?;
-            instruments.push(Instrument::new());
+            let mut instrument = Instrument::new();
+            instrument.name = name;
+            instrument.idx = (instrument_idx + 1) as u8;
+            instruments.push(instrument);
         }
     }
     Ok(instruments)
 }
 
-fn read_xm_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
+fn read_xm_header<R: Read + Seek>(file: &mut R, lenient: bool) -> LoadResult<SongData>
 {
     let id = file.read_string(17);
     if id != "Extended Module: " {
-        return Err(SimpleError::from(io::Error::new(io::ErrorKind::Other, "Not an XM module")));
+        return Err(LoadError::wrong_format(SongType::XM));
     }
 
     dbg!(&id);
@@ -234,7 +290,7 @@ fn read_xm_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
     dbg!(&name);
     let sig = file.read_u8()?;
     if sig != 0x1a {
-        return Err(SimpleError::from(io::Error::new(io::ErrorKind::Other, "Not an XM module")));
+        return Err(LoadError::wrong_format(SongType::XM));
     }
 
     let tracker_name = file.read_string(20);
@@ -279,14 +335,11 @@ fn read_xm_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
         let mut patterns = read_patterns(file, pattern_count as usize, channel_count as usize)?;
 
         // fix empty patterns at end
-        for idx in 0..pattern_order.len() {
-            if pattern_order[idx] >= patterns.len() as u8 {
-                pattern_order[idx] = patterns.len() as u8;
-            }
-        }
+        let mut report = LoadReport::default();
+        fix_pattern_order(&mut pattern_order, song_length as usize, patterns.len(), &mut report);
         if song_length > pattern_order.len() as u16 {
+            report.repair(format!("Song length {} is past the end of the order list, trimmed to {}", song_length, pattern_order.len()));
             song_length = pattern_order.len() as u16;
-            dbg!("Trimming song legth to {}", song_length);
         }
         // dbg!(&pattern_order);
 
@@ -302,7 +355,8 @@ fn read_xm_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
             }; 64]
         });
 
-        let instruments = read_instruments(file, instrument_count as usize)?;
+        let instruments_pos = file.offset();
+        let instruments = read_instruments(file, instrument_count as usize, lenient, &mut report).map_err(|e| e.at(Section::Instrument, instruments_pos))?;
 
         Ok(SongData {
             id: id.trim().to_string(),
@@ -320,19 +374,84 @@ fn read_xm_header<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData>
             pattern_order: Vec::from_iter(pattern_order.iter().cloned()),
             instruments,
             use_amiga: (flags & 1) != 1,
+            global_volume: 64,
+            fast_volume_slides: false,
+            vblank: false,
             song_message: "".to_string(),
+            report,
         })
     }
 
-    pub fn read_xm<R: Read + Seek>(file: &mut R) -> SimpleResult<SongData> {
+    pub fn read_xm<R: Read + Seek>(file: &mut R, lenient: bool) -> LoadResult<SongData> {
         file.seek(SeekFrom::Start(0))?;
 
         let file_len = file.seek(SeekFrom::End(0))?;
         file.seek(SeekFrom::Start(0))?;
 
         if file_len < 60 {
-            return Err(SimpleError::new("File is too small!"));
+            return Err(LoadError::truncated().in_format(SongType::XM).at(Section::Header, 0));
+        }
+
+        read_xm_header(file, lenient).map_err(|e| e.in_format(SongType::XM).at(Section::Header, 0))
+    }
+
+    // Patterns and sample data say how long they are, so everything but the headers gets skipped
+    pub(crate) fn read_xm_metadata<R: Read + Seek>(file: &mut R) -> LoadResult<ModuleMetadata> {
+        file.seek(SeekFrom::Start(0))?;
+
+        if file.read_string(17) != "Extended Module: " {
+            return Err(LoadError::wrong_format(SongType::XM));
+        }
+        let title = file.read_string(20);
+        if file.read_u8()? != 0x1a {
+            return Err(LoadError::wrong_format(SongType::XM));
+        }
+        let tracker_name = file.read_string(20);
+        let _ver = file.read_u16()?;
+        let header_size = file.read_u32()?;
+        let song_length = file.read_u16()?;
+        let _restart_position = file.read_u16()?;
+        let channel_count = file.read_u16()?;
+        let pattern_count = file.read_u16()?;
+        let instrument_count = file.read_u16()?;
+
+        file.seek(SeekFrom::Start(60 + header_size as u64))?;
+        for _ in 0..pattern_count {
+            let pattern_pos = file.offset();
+            let _pattern_header_size = file.read_u32()?;
+            let _pattern_type = file.read_u8()?;
+            let _row_count = file.read_u16()?;
+            let pattern_size = file.read_u16()?;
+            file.seek(SeekFrom::Start(pattern_pos + 9 + pattern_size as u64))?;
         }
 
-        read_xm_header(file)
+        let mut instrument_names = vec![];
+        let mut sample_names = vec![];
+        for _ in 0..instrument_count {
+            let instrument_pos = file.offset();
+            let header_size = file.read_u32()?;
+            instrument_names.push(file.read_string(22).trim().to_string());
+            let _instrument_type = file.read_u8()?;
+            let sample_count = file.read_u16()?;
+            file.seek(SeekFrom::Start(instrument_pos + header_size as u64))?;
+
+            if sample_count > 0 {
+                let samples = read_sample_headers(file, sample_count as usize).map_err(|e| e.at(Section::Instrument, instrument_pos))?;
+                let data_len: u64 = samples.iter().map(|s| s.length as u64 * s.bitness as u64 / 8).sum();
+                sample_names.extend(samples.iter().map(|s| s.name.trim().to_string()));
+                file.seek(SeekFrom::Current(data_len as i64))?;
+            }
+        }
+
+        Ok(ModuleMetadata {
+            title: title.trim().to_string(),
+            format: SongType::XM,
+            tracker_name: tracker_name.trim().to_string(),
+            channel_count,
+            pattern_count,
+            order_count: song_length,
+            instrument_names,
+            sample_names,
+            song_message: "".to_string(),
+        })
     }
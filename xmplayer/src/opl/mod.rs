@@ -0,0 +1,387 @@
+// A software YM3812 (OPL2), as much of it as the AdLib instruments of S3M need: two operators on every channel,
+// the four waveforms, ADSR envelopes with key scaling, feedback, and FM or additive connection. Rhythm mode is
+// left out, ST3's drum instruments play as melodic ones. The chip steps at 49716 Hz, this one works out phases
+// and envelopes at the output rate instead.
+
+use std::f32::consts::PI;
+
+const CHIP_RATE: f32 = 49716.0;
+const SILENCE_DB: f32 = 96.0;
+
+// Key scale level attenuation in the top octave, by the top 4 bits of the frequency number, for 3 dB per octave
+const KEY_SCALE_LEVELS: [f32; 16] = [
+    0.0, 9.0, 12.0, 13.875, 15.0, 16.125, 16.875, 17.625, 18.0, 18.75, 19.125, 19.5, 19.875, 20.25, 20.625, 21.0
+];
+
+// 0, 3, 1.5 and 6 dB per octave
+const KEY_SCALE_LEVEL_FACTORS: [f32; 4] = [0.0, 1.0, 0.5, 2.0];
+
+// the multiplier register goes 1/2, 1, 2 ... 10, and then 10, 12, 12, 15, 15
+const MULTIPLIERS: [f32; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+
+// The LFOs are fixed: tremolo swings 1 dB at 3.7 Hz, and vibrato 7 cents at 6.1 Hz
+const TREMOLO_RATE: f32 = 3.7;
+const TREMOLO_DB: f32 = 1.0;
+const VIBRATO_RATE: f32 = 6.1;
+const VIBRATO_CENTS: f32 = 7.0;
+
+/// The OPL2 registers of an AdLib instrument, as S3M stores them: 0x20, 0x40, 0x60, 0x80 and 0xE0 of the
+/// modulator and the carrier in turn, and then 0xC0 for feedback and connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdlibPatch {
+    pub registers: [u8; 11],
+}
+
+impl AdlibPatch {
+    fn operator(&self, carrier: bool) -> [u8; 5] {
+        let offset = carrier as usize;
+        [0, 2, 4, 6, 8].map(|register| self.registers[register + offset])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Operator {
+    tremolo:            bool,
+    vibrato:            bool,
+    // holds the sustain level until key off, otherwise the release follows the decay
+    sustaining:         bool,
+    key_scale_rate:     bool,
+    multiplier:         f32,
+    key_scale_level:    u8,
+    total_level:        u8,
+    attack:             u8,
+    decay:              u8,
+    sustain_level:      u8,
+    release:            u8,
+    waveform:           u8,
+
+    phase:              f32,
+    stage:              EnvelopeStage,
+    // in dB
+    attenuation:        f32,
+}
+
+impl Operator {
+    fn new() -> Self {
+        Self {
+            tremolo: false,
+            vibrato: false,
+            sustaining: false,
+            key_scale_rate: false,
+            multiplier: 1.0,
+            key_scale_level: 0,
+            total_level: 63,
+            attack: 0,
+            decay: 0,
+            sustain_level: 0,
+            release: 0,
+            waveform: 0,
+            phase: 0.0,
+            stage: EnvelopeStage::Off,
+            attenuation: SILENCE_DB,
+        }
+    }
+
+    fn load(&mut self, registers: [u8; 5]) {
+        let [characteristic, levels, attack_decay, sustain_release, waveform] = registers;
+        self.tremolo = characteristic & 0x80 != 0;
+        self.vibrato = characteristic & 0x40 != 0;
+        self.sustaining = characteristic & 0x20 != 0;
+        self.key_scale_rate = characteristic & 0x10 != 0;
+        self.multiplier = MULTIPLIERS[(characteristic & 0xF) as usize];
+        self.key_scale_level = levels >> 6;
+        self.total_level = levels & 0x3F;
+        self.attack = attack_decay >> 4;
+        self.decay = attack_decay & 0xF;
+        self.sustain_level = sustain_release >> 4;
+        self.release = sustain_release & 0xF;
+        self.waveform = waveform & 3;
+    }
+
+    fn key_on(&mut self) {
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != EnvelopeStage::Off {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    // Each rate step is 4 times the register, plus the key scale, and every 4 steps halve the time
+    fn rate_time(&self, rate: u8, key_scale: u8, time_ms: f32) -> Option<f32> {
+        if rate == 0 {
+            return None;
+        }
+        let offset = if self.key_scale_rate { key_scale } else { key_scale >> 2 };
+        let rate = (rate * 4 + offset).min(63);
+        Some(time_ms / 1000.0 / 2.0f32.powf((rate as f32 - 4.0) / 4.0))
+    }
+
+    fn sustain_db(&self) -> f32 {
+        if self.sustain_level == 15 { 93.0 } else { self.sustain_level as f32 * 3.0 }
+    }
+
+    fn update_envelope(&mut self, key_scale: u8, rate: f32) {
+        match self.stage {
+            EnvelopeStage::Attack => {
+                let offset = if self.key_scale_rate { key_scale } else { key_scale >> 2 };
+                if self.attack != 0 && self.attack * 4 + offset >= 60 {
+                    self.attenuation = 0.0;
+                } else if let Some(time) = self.rate_time(self.attack, key_scale, 2826.24) {
+                    // the attack is exponential, ln(97) gets it from silence to the top
+                    self.attenuation -= (self.attenuation + 1.0) * 4.575 / (time * rate);
+                }
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                if let Some(time) = self.rate_time(self.decay, key_scale, 39280.64) {
+                    self.attenuation += SILENCE_DB / (time * rate);
+                }
+                if self.attenuation >= self.sustain_db() {
+                    self.attenuation = self.sustain_db();
+                    self.stage = if self.sustaining { EnvelopeStage::Sustain } else { EnvelopeStage::Release };
+                }
+            }
+            EnvelopeStage::Sustain => {}
+            EnvelopeStage::Release => {
+                if let Some(time) = self.rate_time(self.release, key_scale, 39280.64) {
+                    self.attenuation += SILENCE_DB / (time * rate);
+                }
+                if self.attenuation >= SILENCE_DB {
+                    self.attenuation = SILENCE_DB;
+                    self.stage = EnvelopeStage::Off;
+                }
+            }
+            EnvelopeStage::Off => {}
+        }
+    }
+
+    fn wave(&self, phase: f32) -> f32 {
+        let sin = (phase * 2.0 * PI).sin();
+        match self.waveform {
+            0 => sin,
+            1 => sin.max(0.0),
+            2 => sin.abs(),
+            _ => if phase.fract() % 0.5 < 0.25 { sin.abs() } else { 0.0 },
+        }
+    }
+
+    // modulation is in cycles, the output goes from -1 to 1
+    fn output(&mut self, increment: f32, modulation: f32, key_scale_level: f32, tremolo: f32, vibrato: f32) -> f32 {
+        let mut attenuation = self.attenuation + self.total_level as f32 * 0.75 +
+            key_scale_level * KEY_SCALE_LEVEL_FACTORS[self.key_scale_level as usize];
+        if self.tremolo { attenuation += tremolo; }
+
+        let output = if attenuation >= SILENCE_DB { 0.0 } else {
+            self.wave((self.phase + modulation).rem_euclid(1.0)) * 10.0f32.powf(-attenuation / 20.0)
+        };
+
+        let increment = increment * self.multiplier * if self.vibrato { vibrato } else { 1.0 };
+        self.phase = (self.phase + increment).fract();
+        output
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct OplChannel {
+    modulator:          Operator,
+    carrier:            Operator,
+    feedback:           u8,
+    additive:           bool,
+    feedback_history:   [f32; 2],
+    block:              u8,
+    frequency_number:   u16,
+    // the note keyed on last, from the channel's note count
+    note:               u32,
+    keyed:              bool,
+    // what the note plays at, kept through the release
+    volume:             f32,
+    lfo_time:           f32,
+}
+
+impl OplChannel {
+    fn new() -> Self {
+        Self {
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: 0,
+            additive: false,
+            feedback_history: [0.0; 2],
+            block: 0,
+            frequency_number: 0,
+            note: 0,
+            keyed: false,
+            volume: 0.0,
+            lfo_time: 0.0,
+        }
+    }
+
+    fn load(&mut self, patch: &AdlibPatch) {
+        self.modulator.load(patch.operator(false));
+        self.carrier.load(patch.operator(true));
+        self.feedback = (patch.registers[10] >> 1) & 7;
+        self.additive = patch.registers[10] & 1 != 0;
+    }
+
+    // The chip plays frequency number * 49716 / 2^(20 - block), with the smallest block the number fits 10 bits in
+    fn set_frequency(&mut self, frequency: f32) {
+        let mut block = 0;
+        while block < 7 && frequency * (1 << (20 - block)) as f32 / CHIP_RATE > 1023.0 {
+            block += 1;
+        }
+        self.block = block;
+        self.frequency_number = (frequency * (1 << (20 - block)) as f32 / CHIP_RATE).round().min(1023.0) as u16;
+    }
+
+    fn key_scale(&self) -> u8 {
+        self.block * 2 + ((self.frequency_number >> 9) & 1) as u8
+    }
+
+    fn key_scale_level(&self) -> f32 {
+        let level = KEY_SCALE_LEVELS[(self.frequency_number >> 6) as usize] - 3.0 * (7 - self.block) as f32;
+        level.max(0.0)
+    }
+}
+
+/// An OPL2 with a channel for every channel of the song
+#[derive(Clone, Debug)]
+pub(crate) struct Opl {
+    channels: Vec<OplChannel>,
+}
+
+impl Opl {
+    pub(crate) fn new(channel_count: usize) -> Self {
+        Self { channels: vec![OplChannel::new(); channel_count] }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        for channel in self.channels.iter_mut() {
+            *channel = OplChannel::new();
+        }
+    }
+
+    /// Keys on a note whenever the channel starts a new one, keys off when it's let go, and follows the
+    /// channel's pitch and volume while the note is held. frequency is the tone, in Hz.
+    pub(crate) fn play(&mut self, channel: usize, patch: &AdlibPatch, note: u32, key_on: bool, frequency: f32, volume: f32) {
+        let channel = &mut self.channels[channel];
+        if note != channel.note {
+            channel.note = note;
+            channel.load(patch);
+            channel.keyed = false;
+        }
+
+        if key_on && !channel.keyed {
+            channel.modulator.key_on();
+            channel.carrier.key_on();
+        } else if !key_on && channel.keyed {
+            channel.modulator.key_off();
+            channel.carrier.key_off();
+        }
+        channel.keyed = key_on;
+
+        if key_on {
+            channel.set_frequency(frequency);
+            channel.volume = volume;
+        }
+    }
+
+    pub(crate) fn is_silent(&self, channel: usize) -> bool {
+        self.channels[channel].carrier.stage == EnvelopeStage::Off &&
+            (!self.channels[channel].additive || self.channels[channel].modulator.stage == EnvelopeStage::Off)
+    }
+
+    /// The next sample of a channel. increment is how much of a cycle of the tone passes in a sample, which
+    /// follows the playback speed like the samples do.
+    pub(crate) fn next_sample(&mut self, channel: usize, increment: f32, rate: f32) -> f32 {
+        let channel = &mut self.channels[channel];
+        let key_scale = channel.key_scale();
+        let key_scale_level = channel.key_scale_level();
+
+        channel.lfo_time += 1.0 / rate;
+        let tremolo = TREMOLO_DB * (1.0 - ((channel.lfo_time * TREMOLO_RATE).fract() * 2.0 - 1.0).abs());
+        let vibrato = 2.0f32.powf(VIBRATO_CENTS / 1200.0 * (channel.lfo_time * VIBRATO_RATE * 2.0 * PI).sin());
+
+        channel.modulator.update_envelope(key_scale, rate);
+        channel.carrier.update_envelope(key_scale, rate);
+
+        let feedback = if channel.feedback == 0 { 0.0 } else {
+            (channel.feedback_history[0] + channel.feedback_history[1]) / 2.0 * 2.0f32.powi(channel.feedback as i32 - 6)
+        };
+        let modulator = channel.modulator.output(increment, feedback, key_scale_level, tremolo, vibrato);
+        channel.feedback_history = [channel.feedback_history[1], modulator];
+
+        let output = if channel.additive {
+            modulator + channel.carrier.output(increment, 0.0, key_scale_level, tremolo, vibrato)
+        } else {
+            // a modulator at full level swings the carrier 4 cycles either way
+            channel.carrier.output(increment, modulator * 4.0, key_scale_level, tremolo, vibrato)
+        };
+        output * channel.volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 44100.0;
+
+    // an organ like patch: a quiet modulator, a carrier that attacks at once and holds full level
+    fn patch(connection: u8) -> AdlibPatch {
+        AdlibPatch { registers: [0x21, 0x21, 0x3F, 0x00, 0xF0, 0xF0, 0x0F, 0x05, 0x00, 0x00, connection] }
+    }
+
+    fn render(opl: &mut Opl, count: usize) -> Vec<f32> {
+        (0..count).map(|_| opl.next_sample(0, 440.0 / RATE, RATE)).collect()
+    }
+
+    #[test]
+    fn test_frequency_number() {
+        let mut channel = OplChannel::new();
+        channel.set_frequency(261.63);
+        assert_eq!((channel.block, channel.frequency_number), (3, 690));
+        channel.set_frequency(440.0);
+        assert_eq!((channel.block, channel.frequency_number), (4, 580));
+    }
+
+    #[test]
+    fn test_carrier_plays_the_tone() {
+        let mut opl = Opl::new(1);
+        opl.play(0, &patch(1), 1, true, 440.0, 1.0);
+        let output = render(&mut opl, 4410);
+
+        // additive, with the modulator at the bottom of its level the carrier is a plain 440 Hz sine
+        let peak = output.iter().fold(0.0f32, |a, b| a.max(b.abs()));
+        assert!(peak > 0.99 && peak <= 1.01, "peak {}", peak);
+        let rising = output.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((43..=45).contains(&rising), "{} cycles", rising);
+    }
+
+    #[test]
+    fn test_key_off_releases() {
+        let mut opl = Opl::new(1);
+        opl.play(0, &patch(0), 1, true, 440.0, 1.0);
+        render(&mut opl, 441);
+        assert!(!opl.is_silent(0));
+
+        opl.play(0, &patch(0), 1, false, 440.0, 0.0);
+        // release rate 5 takes under 2 seconds from the top to silence, the volume stays what it was on key off
+        let released = render(&mut opl, 4410);
+        assert!(released.iter().any(|s| s.abs() > 0.1));
+        render(&mut opl, 2 * RATE as usize);
+        assert!(opl.is_silent(0));
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt;
 use std::string::ToString;
+use crate::module_reader::recall_shared_effect;
 
 #[derive(Copy, Clone)]
 pub struct Pattern {
@@ -13,6 +14,9 @@ pub struct Pattern {
 
 impl Pattern {
     pub const NOTES: [&'static str; 12] = ["C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-"];
+    /// Impulse Tracker's note cut and note fade, they stop the note on the channel right away or let it fade out
+    pub const NOTE_CUT: u8 = 254;
+    pub const NOTE_FADE: u8 = 253;
 
     pub(crate) fn new() -> Self {
         Self {
@@ -25,11 +29,19 @@ impl Pattern {
     }
     
     fn get_note(&self) -> String {
-        if self.note == 97 {"OFF". to_string() } else if self.note == 0 { "   ".to_string() } else {
+        if self.note == 97 {"OFF". to_string() } else if self.note == 0 { "   ".to_string() }
+        else if self.note == Pattern::NOTE_CUT { "CUT".to_string() } else if self.note == Pattern::NOTE_FADE { "FAD".to_string() } else {
             format!("{}{}", Pattern::NOTES[((self.note - 1) % 12) as usize], (((self.note - 1) / 12) + '0' as u8) as char)
         }
     }
 
+    /// The effect and its parameter the way the player knows them, for showing. S3M effects that share ST3's effect
+    /// memory stay in the pattern under their own letter until they play
+    pub fn shown_effect(&self) -> (u8, u8) {
+        let shown = recall_shared_effect(self, &mut 0);
+        (shown.effect, shown.effect_param)
+    }
+
     pub(crate) fn is_porta_to_note(&self) -> bool {
         self.effect == 0x3
     }
@@ -38,7 +50,7 @@ impl Pattern {
         self.effect == 0xe && self.get_x() == 0xd
     }
 
-    pub(crate) fn has_vibrato(&self) -> bool { self.get_volume_effect() == 0xb || self.effect == 0x4 || self.effect == 0x6 }
+    pub(crate) fn has_vibrato(&self) -> bool { self.get_volume_effect() == 0xb || self.effect == 0x4 || self.effect == 0x6 || self.effect == 0x25 }
 
     pub(crate) fn has_tremolo(&self) -> bool { self.effect == 0x7 }
 
@@ -67,6 +79,7 @@ impl Pattern {
 
 impl fmt::Display for Pattern {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {:2} {:2x} {:2x} {:2x}", self.get_note(), self.instrument, self.volume, self.effect, self.effect_param)
+        let (effect, effect_param) = self.shown_effect();
+        write!(f, "{} {:2} {:2x} {:2x} {:2x}", self.get_note(), self.instrument, self.volume, effect, effect_param)
     }
 }
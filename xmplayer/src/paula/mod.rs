@@ -0,0 +1,211 @@
+// Paula, the Amiga's sound chip. It doesn't interpolate: every channel holds a sample until its period runs out and
+// then steps to the next, so the output is a staircase. The steps are added here as minimum phase band-limited
+// steps (minBLEP), which keeps the edges of the staircase without the aliasing. After the DAC, the Amiga
+// has a one pole RC low-pass, steep on the A500 and out of hearing on the A1200, and a one pole high-pass that
+// blocks DC. The LED filter comes after those, and is ProTracker's to turn on and off with E0x.
+
+use std::f32::consts::PI;
+use rustfft::{FftPlanner, num_complex::Complex};
+use serde::Serialize;
+
+// zero crossings of the windowed sinc on each side, and table entries between two of them
+const BLEP_ZERO_CROSSINGS: usize = 16;
+const BLEP_OVERSAMPLING: usize = 16;
+// output samples a step takes to settle
+const BLEP_LENGTH: usize = BLEP_ZERO_CROSSINGS;
+const BLEP_BUFFER_MASK: usize = 31;
+
+const HIGH_PASS_CUTOFF: f32 = 5.2;
+
+/// Which Amiga's output filters Paula plays through
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum AmigaModel {
+    A500,
+    A1200,
+}
+
+impl AmigaModel {
+    // the cutoff of the RC low-pass after the DAC
+    fn low_pass_cutoff(&self) -> f32 {
+        match self {
+            AmigaModel::A500 => 4420.97,
+            AmigaModel::A1200 => 34419.32,
+        }
+    }
+}
+
+impl std::fmt::Display for AmigaModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AmigaModel::A500 => write!(f, "A500"),
+            AmigaModel::A1200 => write!(f, "A1200"),
+        }
+    }
+}
+
+// How far a step still is from the value it steps to, BLEP_OVERSAMPLING entries for every output sample after it
+fn minblep_table() -> Vec<f32> {
+    let taps = 2 * BLEP_ZERO_CROSSINGS * BLEP_OVERSAMPLING + 1;
+    let size = 8192;
+
+    // a Blackman windowed sinc, band-limited at the output's Nyquist frequency
+    let mut buffer: Vec<Complex<f64>> = (0..size).map(|i| {
+        if i >= taps { return Complex::new(0.0, 0.0); }
+        let x = (i as f64 - (taps / 2) as f64) / BLEP_OVERSAMPLING as f64;
+        let sinc = if x == 0.0 { 1.0 } else { (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x) };
+        let w = 2.0 * std::f64::consts::PI * i as f64 / (taps - 1) as f64;
+        Complex::new(sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos()), 0.0)
+    }).collect();
+
+    // to minimum phase through the real cepstrum
+    let mut planner = FftPlanner::<f64>::new();
+    let forward = planner.plan_fft_forward(size);
+    let inverse = planner.plan_fft_inverse(size);
+    forward.process(&mut buffer);
+    for value in buffer.iter_mut() {
+        *value = Complex::new(value.norm().max(1e-20).ln(), 0.0);
+    }
+    inverse.process(&mut buffer);
+    for (i, value) in buffer.iter_mut().enumerate() {
+        let fold = match i {
+            0 => 1.0,
+            i if i < size / 2 => 2.0,
+            i if i == size / 2 => 1.0,
+            _ => 0.0,
+        };
+        *value = Complex::new(value.re * fold / size as f64, 0.0);
+    }
+    forward.process(&mut buffer);
+    for value in buffer.iter_mut() {
+        *value = value.exp();
+    }
+    inverse.process(&mut buffer);
+
+    // the integral of the impulse is the step
+    let mut step: Vec<f64> = buffer[..taps].iter().scan(0.0, |sum, value| {
+        *sum += value.re / size as f64;
+        Some(*sum)
+    }).collect();
+    let last = *step.last().unwrap();
+    step.iter_mut().for_each(|value| *value /= last);
+
+    (0..=(BLEP_LENGTH + 1) * BLEP_OVERSAMPLING).map(|i| (1.0 - step[i]) as f32).collect()
+}
+
+lazy_static! {
+static ref MINBLEP: Vec<f32> = minblep_table();
+}
+
+/// The steps of one channel that are still settling
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Blep {
+    buffer: [f32; BLEP_BUFFER_MASK + 1],
+    index:  usize,
+    last:   f32,
+}
+
+impl Blep {
+    pub(crate) fn new() -> Self {
+        Self { buffer: [0.0; BLEP_BUFFER_MASK + 1], index: 0, last: 0.0 }
+    }
+
+    // The sample Paula holds now, and how long ago in output samples it stepped to it if it's a new one
+    pub(crate) fn output(&mut self, value: f32, since_step: f32) -> f32 {
+        if value != self.last {
+            self.add(since_step.clamp(0.0, 1.0), self.last - value);
+            self.last = value;
+        }
+        let result = value + self.buffer[self.index];
+        self.buffer[self.index] = 0.0;
+        self.index = (self.index + 1) & BLEP_BUFFER_MASK;
+        result
+    }
+
+    fn add(&mut self, offset: f32, amplitude: f32) {
+        let position = offset * BLEP_OVERSAMPLING as f32;
+        let (start, fraction) = (position as usize, position.fract());
+        for i in 0..BLEP_LENGTH {
+            let entry = start + i * BLEP_OVERSAMPLING;
+            let residual = MINBLEP[entry] + (MINBLEP[entry + 1] - MINBLEP[entry]) * fraction;
+            self.buffer[(self.index + i) & BLEP_BUFFER_MASK] += amplitude * residual;
+        }
+    }
+}
+
+// One pole, from the RC values of the Amiga's output stage
+#[derive(Clone, Copy, Debug)]
+struct OnePole {
+    coefficient:    f32,
+    state:          [f32; 2],
+}
+
+impl OnePole {
+    fn new(cutoff: f32, rate: f32) -> Self {
+        Self { coefficient: 1.0 - (-2.0 * PI * cutoff / rate).exp(), state: [0.0; 2] }
+    }
+
+    fn low_pass(&mut self, side: usize, input: f32) -> f32 {
+        self.state[side] += self.coefficient * (input - self.state[side]);
+        self.state[side]
+    }
+}
+
+/// The output stage of an A500 or an A1200, on the mix of all of Paula's channels
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AmigaFilter {
+    low_pass:   OnePole,
+    high_pass:  OnePole,
+}
+
+impl AmigaFilter {
+    pub(crate) fn new(model: AmigaModel, rate: f32) -> Self {
+        Self {
+            low_pass: OnePole::new(model.low_pass_cutoff(), rate),
+            high_pass: OnePole::new(HIGH_PASS_CUTOFF, rate),
+        }
+    }
+
+    pub(crate) fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let mut output = [left, right];
+        for (side, value) in output.iter_mut().enumerate() {
+            let low_passed = self.low_pass.low_pass(side, *value);
+            *value = low_passed - self.high_pass.low_pass(side, low_passed);
+        }
+        (output[0], output[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minblep_settles() {
+        // a step starts where it came from, and is all the way there by the end of the table
+        assert!((MINBLEP[0] - 1.0).abs() < 0.01, "{}", MINBLEP[0]);
+        assert!(MINBLEP.last().unwrap().abs() < 0.01, "{}", MINBLEP.last().unwrap());
+
+        let mut blep = Blep::new();
+        let output: Vec<f32> = (0..BLEP_LENGTH + 4).map(|_| blep.output(1.0, 0.0)).collect();
+        assert!(output[0] < 0.5, "{:?}", output);
+        assert!(output[BLEP_LENGTH..].iter().all(|value| (value - 1.0).abs() < 0.01), "{:?}", output);
+    }
+
+    #[test]
+    fn test_a500_is_darker_than_a1200() {
+        let rate = 48000.0;
+        let square = |filter: &mut AmigaFilter| (0..4800).map(|i| {
+            let value = if (i / 2) % 2 == 0 { 0.5 } else { -0.5 };
+            filter.process(value, value).0.abs()
+        }).skip(2400).fold(0.0f32, f32::max);
+
+        let a500 = square(&mut AmigaFilter::new(AmigaModel::A500, rate));
+        let a1200 = square(&mut AmigaFilter::new(AmigaModel::A1200, rate));
+        assert!(a500 < 0.3 && a1200 > 0.45, "{} {}", a500, a1200);
+
+        // DC goes away
+        let mut filter = AmigaFilter::new(AmigaModel::A1200, rate);
+        let dc = (0..rate as usize).map(|_| filter.process(0.5, 0.5).0).last().unwrap();
+        assert!(dc.abs() < 0.01, "{}", dc);
+    }
+}
@@ -0,0 +1,126 @@
+use serde::Serialize;
+use crate::module_reader::SongType;
+
+/// Whose bugs and quirks the player plays along with. Every format gets the tracker it came from, and the formats
+/// whose trackers aren't emulated play without any of them, like OpenMPT does
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum CompatProfile {
+    FT2,
+    ProTracker,
+    ST3,
+    IT,
+    Modern,
+}
+
+impl CompatProfile {
+    pub fn for_song_type(song_type: SongType) -> Self {
+        match song_type {
+            SongType::XM => CompatProfile::FT2,
+            SongType::MOD => CompatProfile::ProTracker,
+            SongType::S3M | SongType::STM => CompatProfile::ST3,
+            SongType::IT => CompatProfile::IT,
+            _ => CompatProfile::Modern,
+        }
+    }
+
+    // one effect memory for D, E, F, I, J, K, L, Q, R and S
+    pub(crate) fn shares_effect_memory(&self) -> bool {
+        *self == CompatProfile::ST3
+    }
+
+    // an offset past the loop end folds back into the loop, instead of stopping the note
+    pub(crate) fn wraps_offset_into_loop(&self) -> bool {
+        *self == CompatProfile::ST3
+    }
+
+    // the volume slides of ST3 3.00 slide on the first tick too
+    pub(crate) fn has_fast_volume_slides(&self) -> bool {
+        *self == CompatProfile::ST3
+    }
+
+    // a panning slide left with a speed of 0 pans all the way left
+    pub(crate) fn resets_panning_on_empty_slide(&self) -> bool {
+        *self == CompatProfile::FT2
+    }
+
+    // Lxx only moves the panning envelope when the volume envelope has a sustain point
+    pub(crate) fn ties_panning_envelope_to_volume_sustain(&self) -> bool {
+        *self == CompatProfile::FT2
+    }
+
+    // The highest and lowest periods a slide goes to. ProTracker keeps them within its three octaves, FT2 compares
+    // them as signed 16 bits, so only the ends it checks for hold
+    pub(crate) fn period_limits(&self) -> (u16, u16) {
+        match self {
+            CompatProfile::ProTracker => (113 * 4, 856 * 4),
+            _ => (1, 31999),
+        }
+    }
+}
+
+impl std::fmt::Display for CompatProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompatProfile::FT2 => write!(f, "FT2"),
+            CompatProfile::ProTracker => write!(f, "ProTracker"),
+            CompatProfile::ST3 => write!(f, "ST3"),
+            CompatProfile::IT => write!(f, "IT"),
+            CompatProfile::Modern => write!(f, "Modern"),
+        }
+    }
+}
+
+impl std::str::FromStr for CompatProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ft2" => Ok(CompatProfile::FT2),
+            "protracker" | "pt" => Ok(CompatProfile::ProTracker),
+            "st3" => Ok(CompatProfile::ST3),
+            "it" => Ok(CompatProfile::IT),
+            "modern" => Ok(CompatProfile::Modern),
+            _ => Err(format!("unknown profile '{}', expected ft2, protracker, st3, it or modern", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_state::ChannelState;
+    use crate::tables::AMIGA_TABLES;
+
+    #[test]
+    fn test_for_song_type() {
+        assert_eq!(CompatProfile::for_song_type(SongType::XM), CompatProfile::FT2);
+        assert_eq!(CompatProfile::for_song_type(SongType::MOD), CompatProfile::ProTracker);
+        assert_eq!(CompatProfile::for_song_type(SongType::STM), CompatProfile::ST3);
+        assert_eq!(CompatProfile::for_song_type(SongType::IT), CompatProfile::IT);
+        assert_eq!(CompatProfile::for_song_type(SongType::MTM), CompatProfile::Modern);
+    }
+
+    #[test]
+    fn test_period_limits() {
+        let slide = |compat: CompatProfile, up: bool| {
+            let mut channel = ChannelState::new();
+            channel.note.period = 428 * 4;
+            for tick in 0..32 {
+                if up {
+                    channel.porta_up(tick == 0, 0xFF, 48000.0, &AMIGA_TABLES, compat);
+                } else {
+                    channel.porta_down(tick == 0, 0xFF, 48000.0, &AMIGA_TABLES, compat);
+                }
+            }
+            channel.note.period
+        };
+
+        // ProTracker stops at B-3 and C-1, the others at 1 and 31999
+        assert_eq!(slide(CompatProfile::ProTracker, true), 113 * 4);
+        assert_eq!(slide(CompatProfile::ProTracker, false), 856 * 4);
+        assert_eq!(slide(CompatProfile::FT2, true), 1);
+        assert_eq!(slide(CompatProfile::Modern, false), 31999);
+        // FT2 stops at 31999 once, and the next slide takes it past 32767 where the check misses it
+        assert_eq!(slide(CompatProfile::FT2, false), 31999 + 0xFF * 4);
+    }
+}
@@ -0,0 +1,122 @@
+// The last stage of the output: the master gain, the gain that evens out the loudness of songs in a playlist, and a
+// soft-knee limiter that bends peaks under full scale instead of letting them clip. The loudness is the mean square
+// of 400 ms blocks, leaving out the blocks quiet enough to be silence, like the gating of EBU R128 without its
+// frequency weighting.
+
+// where the limiter starts bending the output
+const LIMITER_KNEE: f32 = 0.7;
+// the loudness songs are brought to, and below which a block counts as silence
+const TARGET_LOUDNESS_DB: f32 = -18.0;
+const SILENCE_DB: f32 = -70.0;
+const BLOCK_MS: f32 = 400.0;
+
+pub(crate) fn db_to_gain(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+pub(crate) fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+// Leaves everything under the knee alone and bends what's above it towards full scale, never past it
+pub(crate) fn limit(value: f32) -> f32 {
+    let magnitude = value.abs();
+    if magnitude <= LIMITER_KNEE {
+        return value;
+    }
+    let headroom = 1.0 - LIMITER_KNEE;
+    (LIMITER_KNEE + headroom * ((magnitude - LIMITER_KNEE) / headroom).tanh()).copysign(value)
+}
+
+/// The peak and loudness of a song, measured by playing it once
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Loudness {
+    block_length:   usize,
+    block_frames:   usize,
+    block_sum:      f64,
+    // of the blocks that weren't silence
+    loud_sum:       f64,
+    loud_blocks:    usize,
+    peak:           f32,
+}
+
+impl Loudness {
+    pub(crate) fn new(rate: f32) -> Self {
+        Self {
+            block_length: (rate * BLOCK_MS / 1000.0) as usize,
+            block_frames: 0,
+            block_sum: 0.0,
+            loud_sum: 0.0,
+            loud_blocks: 0,
+            peak: 0.0,
+        }
+    }
+
+    pub(crate) fn add(&mut self, left: f32, right: f32) {
+        self.peak = self.peak.max(left.abs()).max(right.abs());
+        self.block_sum += ((left * left + right * right) / 2.0) as f64;
+        self.block_frames += 1;
+        if self.block_frames == self.block_length {
+            let mean_square = self.block_sum / self.block_length as f64;
+            if mean_square > 10.0f64.powf(SILENCE_DB as f64 / 10.0) {
+                self.loud_sum += mean_square;
+                self.loud_blocks += 1;
+            }
+            self.block_frames = 0;
+            self.block_sum = 0.0;
+        }
+    }
+
+    // in dB of full scale, None for a silent song
+    pub(crate) fn loudness_db(&self) -> Option<f32> {
+        if self.loud_blocks == 0 {
+            return None;
+        }
+        Some(10.0 * (self.loud_sum / self.loud_blocks as f64).log10() as f32)
+    }
+
+    // The gain that brings the song to the target loudness, as long as its peak stays under full scale
+    pub(crate) fn normalizing_gain(&self) -> f32 {
+        let Some(loudness) = self.loudness_db() else { return 1.0; };
+        let gain = db_to_gain(TARGET_LOUDNESS_DB - loudness);
+        if self.peak > 0.0 { gain.min(1.0 / self.peak) } else { gain }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        assert_eq!(limit(0.5), 0.5);
+        assert_eq!(limit(-0.7), -0.7);
+        assert!(limit(1.5) < 1.0 && limit(1.5) > limit(1.0));
+        assert!(limit(-8.0) >= -1.0 && limit(-8.0) < -0.99);
+        // no corner at the knee
+        assert!((limit(0.71) - 0.71).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_loudness() {
+        let rate = 48000.0;
+        let mut loudness = Loudness::new(rate);
+        // three blocks of a sine at -20 dB RMS, then a second of silence that doesn't count
+        let amplitude = db_to_gain(-20.0) * std::f32::consts::SQRT_2;
+        for i in 0..(rate * 1.2) as usize {
+            let value = amplitude * (i as f32 * 2.0 * std::f32::consts::PI * 441.0 / rate).sin();
+            loudness.add(value, value);
+        }
+        (0..rate as usize).for_each(|_| loudness.add(0.0, 0.0));
+
+        let measured = loudness.loudness_db().unwrap();
+        assert!((measured + 20.0).abs() < 0.1, "{}", measured);
+        assert!((gain_to_db(loudness.normalizing_gain()) - 2.0).abs() < 0.1);
+
+        // a loud peak holds the gain back
+        loudness.add(1.0, 0.0);
+        assert!((loudness.normalizing_gain() - 1.0).abs() < 1e-6);
+
+        assert_eq!(Loudness::new(rate).normalizing_gain(), 1.0);
+    }
+}
@@ -7,17 +7,36 @@ use std::sync::mpsc::Receiver;
 #[cfg(not(target_arch = "wasm32"))]
 use std::time::Instant;
 
-use crate::channel_state::{ChannelState, Voice};
-use crate::channel_state::channel_state::{EnvelopeState, Note, PortaToNoteState, TremoloState, VibratoState, WaveControl, Panning, clamp, VibratoEnvelopeState};
-use crate::instrument::{LoopType, Instrument};
-use crate::module_reader::{SongData, SongType, is_note_valid, Patterns};
+use crate::channel_state::ChannelState;
+use crate::channel_state::channel_state::{it_slide, WaveControl};
+use crate::instrument::{LoopType, Instrument, NewNoteAction, Sample};
+use crate::pattern::Pattern;
+use crate::module_reader::{SongData, SongType, is_note_valid, recall_shared_effect, Patterns};
+use crate::opl::{AdlibPatch, Opl};
+use crate::paula::{AmigaFilter, AmigaModel};
 #[cfg(test)]
 #[allow(unused_imports)]
 use crate::tables::{TableType, AMIGA_PERIODS, LINEAR_PERIODS};
-use crate::tables::{PANNING_TAB, AudioTables};
+use crate::tables::AudioTables;
 use shared_sync_primitives::TripleBufferWriter;
 use std::collections::HashMap;
 use std::num::Wrapping;
+use voice_pool::{duplicate_action, VoicePool};
+use protracker::ProTracker;
+use stereo::StereoImage;
+use loudness::Loudness;
+pub use compat::CompatProfile;
+pub use stereo::PanLaw;
+pub use voice_pool::DEFAULT_MAX_VOICES;
+
+/// How long a volume or panning change takes, like FT2's ramping
+pub const DEFAULT_VOLUME_RAMP_MS: f32 = 5.0;
+
+mod voice_pool;
+mod protracker;
+mod compat;
+mod stereo;
+mod loudness;
 
 #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
 use core::arch::wasm32::*;
@@ -288,6 +307,14 @@ impl BPM {
         self.tick_duration_in_ms = 2500.0 / self.bpm as f32;
         self.tick_duration_in_frames = (self.tick_duration_in_ms / 1000.0 * rate) as usize;
     }
+
+    // IT's T0x and T1x slide the tempo down and up by x on every tick but the first
+    fn slide(&mut self, first_tick: bool, param: u8, rate: f32) {
+        if first_tick { return; }
+        let step = (param & 0xF) as i32;
+        let bpm = if param >> 4 == 0 { self.bpm as i32 - step } else { self.bpm as i32 + step };
+        self.update(bpm.clamp(32, 255) as u32, rate);
+    }
 }
 
 struct PatternChange {
@@ -297,6 +324,8 @@ struct PatternChange {
     row:            u8,
     pattern:        u8,
     pattern_delay:  u8,
+    // S6x, extra ticks for the row
+    fine_pattern_delay: u8,
 }
 
 impl PatternChange {
@@ -308,6 +337,7 @@ impl PatternChange {
             row: 0,
             pattern: 0,
             pattern_delay: 0,
+            fine_pattern_delay: 0,
         }
     }
     fn reset(&mut self) {
@@ -346,12 +376,26 @@ pub fn fill<T>(arr: &mut [T], value: T)
 
 struct GlobalVolume {
     volume:                     u32,
+    // IT's global volume goes up to 128, everything else to 64
+    max:                        u32,
     last_volume_slide:          u8,
 }
 
 impl GlobalVolume {
-    pub fn new() -> Self {
-        GlobalVolume { volume: 64, last_volume_slide: 0 }
+    pub fn new(song_data: &SongData) -> Self {
+        let max = if song_data.song_type == SongType::IT { 128 } else { 64 };
+        GlobalVolume { volume: min(song_data.global_volume as u32, max), max, last_volume_slide: 0 }
+    }
+
+    fn scale(&self) -> f32 {
+        self.volume as f32 / self.max as f32
+    }
+
+    // IT's Wxy, with the fine slides of the other IT slides
+    fn it_volume_slide(&mut self, first_tick: bool, param: u8) {
+        if param != 0 { self.last_volume_slide = param; }
+        let slide = it_slide(first_tick, self.last_volume_slide);
+        self.volume = (self.volume as i32 + slide).clamp(0, self.max as i32) as u32;
     }
 
     fn volume_slide(&mut self, first_tick: bool, param: u8) {
@@ -386,7 +430,7 @@ impl GlobalVolume {
 
     fn set_volume(&mut self, first_tick: bool, volume: u8) {
         if first_tick {
-            self.volume = if volume <= 0x40 { volume } else { 0x40 } as u32;
+            self.volume = min(volume as u32, self.max);
         }
     }
 
@@ -408,8 +452,22 @@ pub enum PlaybackCmd {
     LinearTable,
     PauseToggle,
     FilterToggle,
+    SetFilter(FilterType),
     DisplayToggle,
     SetDisplay(bool),
+    /// Most voices mixed at once, pattern channels and background voices of the new note actions together
+    SetMaxVoices(usize),
+    /// Milliseconds volume and panning changes ramp over, 0 changes them at once for bit-exact output
+    SetVolumeRamp(f32),
+    /// Stereo separation in percent, 0 is mono, 100 the song's own panning and 200 twice as wide
+    SetStereoSeparation(u32),
+    SetPanLaw(PanLaw),
+    /// Leaks the low end of each side into the other, for listening on headphones
+    SetCrossfeed(bool),
+    /// Master gain in dB
+    SetMasterGain(f32),
+    /// Bends peaks under full scale instead of letting them clip
+    SetLimiter(bool),
     ChannelToggle(u8),
     ChannelSolo(u8),
     ChannelUnmuteAll,
@@ -482,6 +540,8 @@ pub enum FilterType {
     Linear,
     Cubic,
     Sinc,
+    /// No interpolation, band-limited steps and the output filters of an Amiga
+    Paula(AmigaModel),
 }
 
 impl std::fmt::Display for FilterType {
@@ -491,10 +551,36 @@ impl std::fmt::Display for FilterType {
             FilterType::Linear => write!(f, "Linear"),
             FilterType::Cubic => write!(f, "Cubic"),
             FilterType::Sinc => write!(f, "Sinc"),
+            FilterType::Paula(model) => write!(f, "Paula {}", model),
         }
     }
 }
 
+impl std::str::FromStr for FilterType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(FilterType::None),
+            "linear" => Ok(FilterType::Linear),
+            "cubic" => Ok(FilterType::Cubic),
+            "sinc" => Ok(FilterType::Sinc),
+            "a500" | "paula" => Ok(FilterType::Paula(AmigaModel::A500)),
+            "a1200" => Ok(FilterType::Paula(AmigaModel::A1200)),
+            _ => Err(format!("unknown filter '{}', expected none, linear, cubic, sinc, a500 or a1200", s)),
+        }
+    }
+}
+
+/// An effect the player skipped. `effect` is in the player's XM numbering, 0x0-0xF and then 0x10 for G onwards,
+/// with the IT S commands XM has nothing like under 0x1C
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct UnsupportedEffect {
+    pub channel:                            usize,
+    pub effect:                             u8,
+    pub param:                              u8,
+}
+
 #[derive(Clone, Serialize)]
 pub struct PlayData {
     pub name:                               String,
@@ -513,6 +599,8 @@ pub struct PlayData {
     pub channel_status:                     Vec<ChannelStatus>,
     pub filter:                             FilterType,
     pub song_message:                       String,
+    pub load_report:                        String,
+    pub unsupported_effects:                Vec<UnsupportedEffect>,
     pub visualizer_enabled:                 bool,
     pub scopes_enabled:                     bool,
     pub visualizer_mode:                    u32,
@@ -543,6 +631,8 @@ impl Default for PlayData {
             channel_status: vec![],
             filter: FilterType::Sinc,
             song_message: "".to_string(),
+            load_report: "".to_string(),
+            unsupported_effects: vec![],
             visualizer_enabled: true,
             scopes_enabled: true,
             visualizer_mode: 0,
@@ -580,6 +670,8 @@ pub trait BufferAdapter {
     fn len(&mut self) -> usize;
     fn num_frames(&mut self) -> usize;
     fn post_process(&mut self);
+    // runs every frame from pos on through a stereo effect
+    fn process_frames(&mut self, pos: usize, count: usize, process: &mut dyn FnMut(f32, f32) -> (f32, f32));
 }
 
 pub struct InterleavedBufferAdaptar<'a> {
@@ -612,6 +704,12 @@ impl BufferAdapter for InterleavedBufferAdaptar<'_> {
     }
 
     fn post_process(&mut self) {}
+
+    fn process_frames(&mut self, pos: usize, count: usize, process: &mut dyn FnMut(f32, f32) -> (f32, f32)) {
+        for frame in self.buf[pos * 2..(pos + count) * 2].chunks_exact_mut(2) {
+            (frame[0], frame[1]) = process(frame[0], frame[1]);
+        }
+    }
 }
 
 pub struct PlanarBufferAdaptar<'a> {
@@ -647,6 +745,12 @@ impl BufferAdapter for PlanarBufferAdaptar<'_> {
         Self::normalize_array(self.buf[0]);
         Self::normalize_array(self.buf[1]);
     }
+
+    fn process_frames(&mut self, pos: usize, count: usize, process: &mut dyn FnMut(f32, f32) -> (f32, f32)) {
+        for i in pos..pos + count {
+            (self.buf[0][i], self.buf[1][i]) = process(self.buf[0][i], self.buf[1][i]);
+        }
+    }
 }
 
 impl<'a> PlanarBufferAdaptar<'a> {
@@ -676,12 +780,36 @@ pub struct Song {
     global_volume:              GlobalVolume,
     song_data:                  SongData,
     channels:                   Vec<ChannelState>,
+    voice_pool:                 VoicePool,
+    volume_ramp_ms:             f32,
+    // notes that were cut or made way for a new one, ramping down to silence, and their channels
+    declicking:                 Vec<(usize, ChannelState)>,
+    // plays the AdLib instruments of S3M, a chip channel for every channel
+    opl:                        Opl,
+    // whose quirks the song plays with
+    compat:                     CompatProfile,
+    // what ProTracker 2.3 does to MODs, with its profile
+    protracker:                 Option<ProTracker>,
+    // every effect the song used that the player skipped, once for each effect and parameter
+    unsupported_effects:        Vec<UnsupportedEffect>,
+    // what process_tick collects on the way through the channels, kept to not allocate on every tick
+    tick_unsupported:           Vec<UnsupportedEffect>,
+    tick_funk:                  Vec<usize>,
     pattern_change:             PatternChange,
     total_duration_ms:          f32,
     bpm:                        BPM,
     loop_pattern:               bool,
     pause:                      bool,
     filter:                     FilterType,
+    // the Amiga's output stage, for the Paula filter
+    amiga_filter:               AmigaFilter,
+    pan_law:                    PanLaw,
+    // separation and crossfeed of the whole mix
+    stereo_image:               StereoImage,
+    master_gain_db:             f32,
+    // what the loudness pre-scan found the song needs, 1 when it wasn't run
+    normalizing_gain:           f32,
+    limiter_on:                 bool,
     display:                    bool,
     frequency_tables:           Box<AudioTables>,
     is_fast_forwarding:         bool,
@@ -734,6 +862,7 @@ impl Song {
 
     pub fn new(song_data: &SongData, triple_buffer_writer: TripleBufferWriter<PlayData>, sample_rate: f32) -> Self {
         let use_amiga = if song_data.use_amiga {AudioTables::calc_tables_amiga()} else {AudioTables::calc_tables_linear()};
+        let compat = CompatProfile::for_song_type(song_data.song_type);
         let mut result = Self {
             name: song_data.name.clone(),
             song_position: 0,
@@ -744,60 +873,29 @@ impl Song {
             speed: song_data.tempo as u32,
             total_duration_ms: 0.0,
             bpm: BPM::new(song_data.bpm as u32, sample_rate as f32),
-            global_volume: GlobalVolume::new(),
+            global_volume: GlobalVolume::new(song_data),
             song_message: "".to_string(),
             song_data: song_data.clone(),
-            channels: vec![ChannelState {
-                // instrument: &song_data.instruments[0],
-                // sample: &song_data.instruments[0].samples[0],
-                voice: Voice::new(),
-                note: Note::new(),
-                frequency: 0.0,
-                // du: 0.0,
-                // volume: Volume::new(),
-                // sample_position: 0.0,
-                // loop_started: false,
-                // ping: true,
-                volume_envelope_state: EnvelopeState::new(),
-                panning_envelope_state: EnvelopeState::new(),
-                // sustained: false,
-                vibrato_envelope_state: VibratoEnvelopeState::new(),
-                vibrato_state: VibratoState::new(),
-                tremolo_state: TremoloState::new(),
-                frequency_shift: 0.0,
-                period_shift: 0,
-                on: false,
-                last_porta_up: 0,
-                last_porta_down: 0,
-                last_fine_porta_up: 0,
-                last_fine_porta_down: 0,
-                last_volume_slide: 0,
-                last_fine_volume_slide_up: 0,
-                last_fine_volume_slide_down: 0,
-                porta_to_note: PortaToNoteState::new(),
-                last_sample_offset: 0,
-                last_panning_speed: 0,
-                panning: Panning::new(),
-                force_off: false,
-                glissando: false,
-                // last_sample: 0,
-                // last_sample_pos: 0.0,
-                vibrato_control: 0,
-                tremolo_control: 0,
-                tremor: 0,
-                tremor_count: 0,
-                multi_retrig_count: 0,
-                multi_retrig_volume: 0,
-                last_played_note: 0,
-                last_samples: [0.0; 512],
-                last_samples_pos: 0,
-                loop_row: 0,
-                loop_count: 0,
-            }; song_data.channel_count as usize],
+            channels: vec![ChannelState::new(); song_data.channel_count as usize],
+            voice_pool: VoicePool::new(DEFAULT_MAX_VOICES),
+            volume_ramp_ms: DEFAULT_VOLUME_RAMP_MS,
+            declicking: Vec::with_capacity(song_data.channel_count as usize),
+            opl: Opl::new(song_data.channel_count as usize),
+            compat,
+            protracker: if compat == CompatProfile::ProTracker { Some(ProTracker::new(sample_rate)) } else { None },
+            unsupported_effects: vec![],
+            tick_unsupported: Vec::with_capacity(song_data.channel_count as usize * 2),
+            tick_funk: Vec::with_capacity(song_data.channel_count as usize),
             loop_pattern: false,
             pattern_change: PatternChange::new(),
             pause: false,
             filter: FilterType::Sinc,
+            amiga_filter: AmigaFilter::new(AmigaModel::A500, sample_rate),
+            pan_law: PanLaw::FT2,
+            stereo_image: StereoImage::new(sample_rate),
+            master_gain_db: 0.0,
+            normalizing_gain: 1.0,
+            limiter_on: false,
             display: true,
             frequency_tables: use_amiga,
             is_fast_forwarding: false,
@@ -880,7 +978,7 @@ impl Song {
         self.tick = 0;
         self.speed = self.song_data.tempo as u32;
         self.bpm = BPM::new(self.song_data.bpm as u32, self.rate);
-        self.global_volume = GlobalVolume::new();
+        self.global_volume = GlobalVolume::new(&self.song_data);
         self.pattern_change = PatternChange::new();
         self.total_samples = 0;
         self.last_fps_sample = 0;
@@ -894,43 +992,14 @@ impl Song {
 
         // Reset all channels to blank slate
         for ch in self.channels.iter_mut() {
-            *ch = ChannelState {
-                voice: Voice::new(),
-                note: Note::new(),
-                frequency: 0.0,
-                volume_envelope_state: EnvelopeState::new(),
-                panning_envelope_state: EnvelopeState::new(),
-                vibrato_envelope_state: VibratoEnvelopeState::new(),
-                vibrato_state: VibratoState::new(),
-                tremolo_state: TremoloState::new(),
-                frequency_shift: 0.0,
-                period_shift: 0,
-                on: false,
-                last_porta_up: 0,
-                last_porta_down: 0,
-                last_fine_porta_up: 0,
-                last_fine_porta_down: 0,
-                last_volume_slide: 0,
-                last_fine_volume_slide_up: 0,
-                last_fine_volume_slide_down: 0,
-                porta_to_note: PortaToNoteState::new(),
-                last_sample_offset: 0,
-                last_panning_speed: 0,
-                panning: Panning::new(),
-                force_off: false, // Keep force_off or reset? We should preserve muting state if it was requested
-                glissando: false,
-                vibrato_control: 0,
-                tremolo_control: 0,
-                tremor: 0,
-                tremor_count: 0,
-                multi_retrig_count: 0,
-                multi_retrig_volume: 0,
-                last_played_note: 0,
-                last_samples: [0.0; 512],
-                last_samples_pos: 0,
-                loop_row: 0,
-                loop_count: 0,
-            };
+            *ch = ChannelState::new();
+        }
+        self.voice_pool.clear();
+        self.declicking.clear();
+        self.opl.reset();
+        if let Some(protracker) = &mut self.protracker {
+            protracker.led_filter_on = false;
+            protracker.restore(&mut self.song_data.instruments);
         }
     }
 
@@ -1014,7 +1083,7 @@ impl Song {
         play_data.name                      = self.name.clone();
         play_data.total_duration_ms         = self.total_duration_ms;
         play_data.current_duration_ms       = (self.total_samples as f32 / self.rate) * 1000.0;
-        play_data.global_volume             = self.global_volume.volume;
+        play_data.global_volume             = self.global_volume.volume * 64 / self.global_volume.max;
         play_data.tick_duration_in_frames   = self.bpm.tick_duration_in_frames;
         play_data.tick_duration_in_ms       = self.bpm.tick_duration_in_ms;
         play_data.tick                      = self.tick;
@@ -1030,6 +1099,8 @@ impl Song {
         play_data.bpm                       = self.bpm.bpm;
         play_data.speed                     = self.speed;
         play_data.song_message              = self.song_data.song_message.clone();
+        play_data.load_report               = self.song_data.report.to_string();
+        play_data.unsupported_effects.clone_from(&self.unsupported_effects);
 
         // --- INSTANT UI FEEDBACK (Always update user-controllable state) ---
         play_data.theme_id         = self.theme_id;
@@ -1102,7 +1173,7 @@ impl Song {
             if channel.voice.instrument < self.song_data.instruments.len() {
                 let inst = &self.song_data.instruments[channel.voice.instrument];
                 if channel.voice.sample < inst.samples.len() {
-                    let sample = &inst.samples[channel.voice.sample];
+                    let sample = inst.samples[channel.voice.sample].playing(channel.voice.sustained);
                     if sample.is_ping_pong && sample_position >= sample.original_loop_end as f32 {
                         let over = sample_position - sample.original_loop_end as f32;
                         sample_position = (sample.original_loop_end as f32 - 1.0) - over;
@@ -1222,9 +1293,36 @@ impl Song {
     pub fn set_sample_rate(&mut self, sample_rate: f32) {
         self.rate = sample_rate;
         self.original_rate = sample_rate;
+        if let Some(protracker) = &mut self.protracker {
+            protracker.set_rate(sample_rate);
+        }
+        self.stereo_image.set_rate(sample_rate);
+        self.set_filter(self.filter);
         self.recalculate_bin_map();
     }
 
+    /// Plays with the quirks of another tracker than the one the format came from, from the start of the song
+    pub fn set_compat_profile(&mut self, compat: CompatProfile) {
+        if let Some(protracker) = &mut self.protracker {
+            protracker.restore(&mut self.song_data.instruments);
+        }
+        self.compat = compat;
+        self.protracker = if compat == CompatProfile::ProTracker { Some(ProTracker::new(self.original_rate)) } else { None };
+        self.reset();
+        self.total_duration_ms = self.compute_total_duration();
+    }
+
+    pub fn compat_profile(&self) -> CompatProfile {
+        self.compat
+    }
+
+    fn set_filter(&mut self, filter: FilterType) {
+        self.filter = filter;
+        if let FilterType::Paula(model) = filter {
+            self.amiga_filter = AmigaFilter::new(model, self.original_rate);
+        }
+    }
+
     pub fn get_instruments(&self) -> Vec<Instrument>{
         self.song_data.instruments.clone()
     }
@@ -1233,11 +1331,60 @@ impl Song {
         self.song_data.patterns.clone()
     }
 
+    pub fn unsupported_effects(&self) -> &[UnsupportedEffect] {
+        &self.unsupported_effects
+    }
+
     pub fn get_order(&self) -> Vec<u8> {
         self.song_data.pattern_order.clone()
     }
 
     pub fn get_next_tick(&mut self, buf: &mut impl BufferAdapter, rx: &mut Receiver<PlaybackCmd>) -> CallbackState {
+        let state = self.fill_buffer(buf, rx);
+        self.master_output(buf);
+        state
+    }
+
+    // The gain and the limiter, on everything the buffer got
+    fn master_output(&mut self, buf: &mut impl BufferAdapter) {
+        let gain = loudness::db_to_gain(self.master_gain_db) * self.normalizing_gain;
+        if self.is_fast_forwarding || (gain == 1.0 && !self.limiter_on) {
+            return;
+        }
+        let (limiter_on, num_frames) = (self.limiter_on, buf.num_frames());
+        buf.process_frames(0, num_frames, &mut |left, right| {
+            let (left, right) = (left * gain, right * gain);
+            if limiter_on { (loudness::limit(left), loudness::limit(right)) } else { (left, right) }
+        });
+    }
+
+    /// Plays the song through once and sets the gain that brings it to the same loudness as other songs, without
+    /// letting its peak go over full scale. Starts the song over, and returns the gain in dB
+    pub fn normalize_loudness(&mut self) -> f32 {
+        let current_display = self.display;
+        self.display = false;
+        self.reset();
+
+        let mut loudness = Loudness::new(self.original_rate);
+        let mut buffer = vec![0.0; 8192];
+        let mut rx = std::sync::mpsc::channel().1;
+        let max_frames = (self.total_duration_ms / 1000.0 * self.original_rate) as u64;
+        while self.total_samples < max_frames {
+            let mut adapter = InterleavedBufferAdaptar { buf: &mut buffer };
+            let state = self.fill_buffer(&mut adapter, &mut rx);
+            buffer.chunks(2).for_each(|frame| loudness.add(frame[0], frame[1]));
+            if let CallbackState::Complete = state {
+                break;
+            }
+        }
+
+        self.reset();
+        self.display = current_display;
+        self.normalizing_gain = loudness.normalizing_gain();
+        loudness::gain_to_db(self.normalizing_gain)
+    }
+
+    fn fill_buffer(&mut self, buf: &mut impl BufferAdapter, rx: &mut Receiver<PlaybackCmd>) -> CallbackState {
         buf.clear();
         self.bpm.update(self.bpm.bpm, self.rate);
         loop { // loop1
@@ -1314,15 +1461,25 @@ impl Song {
                     PlaybackCmd::LoopPattern => {self.loop_pattern = !self.loop_pattern;}
                     PlaybackCmd::PauseToggle => {self.pause = !self.pause;}
                     PlaybackCmd::FilterToggle => {
-                        self.filter = match self.filter {
+                        self.set_filter(match self.filter {
                             FilterType::None => FilterType::Linear,
                             FilterType::Linear => FilterType::Cubic,
                             FilterType::Cubic => FilterType::Sinc,
-                            FilterType::Sinc => FilterType::None,
-                        }
+                            FilterType::Sinc => FilterType::Paula(AmigaModel::A500),
+                            FilterType::Paula(AmigaModel::A500) => FilterType::Paula(AmigaModel::A1200),
+                            FilterType::Paula(AmigaModel::A1200) => FilterType::None,
+                        });
                     }
+                    PlaybackCmd::SetFilter(filter) => {self.set_filter(filter);}
                     PlaybackCmd::DisplayToggle => {self.display = !self.display;}
                     PlaybackCmd::SetDisplay(on) => {self.display = on;}
+                    PlaybackCmd::SetMaxVoices(max_voices) => {self.voice_pool.set_max_voices(max_voices, self.channels.len());}
+                    PlaybackCmd::SetVolumeRamp(ms) => {self.volume_ramp_ms = ms.max(0.0);}
+                    PlaybackCmd::SetStereoSeparation(percent) => {self.stereo_image.set_separation(percent);}
+                    PlaybackCmd::SetPanLaw(pan_law) => {self.pan_law = pan_law;}
+                    PlaybackCmd::SetCrossfeed(on) => {self.stereo_image.set_crossfeed(on);}
+                    PlaybackCmd::SetMasterGain(db) => {self.master_gain_db = db;}
+                    PlaybackCmd::SetLimiter(on) => {self.limiter_on = on;}
                     PlaybackCmd::ChannelToggle(channel) => {
                         if (channel as usize) < self.channels.len() {
                             self.channels[channel as usize].force_off = !self.channels[channel as usize].force_off;
@@ -1391,6 +1548,9 @@ impl Song {
                             channel.on = false;
                             channel.voice.volume.set_volume(0);
                         }
+                        self.voice_pool.clear();
+                        self.declicking.clear();
+                        self.opl.reset();
                         self.pattern_change.pattern = order as u8;
                         self.pattern_change.pattern_jump = true;
                         self.pattern_change.row = 0;
@@ -1438,7 +1598,7 @@ impl Song {
         }
 
         self.tick += 1;
-        if self.tick >= self.speed {
+        if self.tick >= self.speed + self.pattern_change.fine_pattern_delay as u32 {
             // Handle Pattern Delay (EEx)
             if self.pattern_change.pattern_delay > 0 {
                 self.pattern_change.pattern_delay -= 1;
@@ -1492,13 +1652,24 @@ impl Song {
         // Hyper-optimization for duration calculation:
         // Skip all expensive effect processing and only handle flow control.
         if self.is_calculating_duration {
-            for pattern in &row.channels {
+            let is_s3m = self.song_data.song_type == SongType::S3M;
+            let shares_effect_memory = self.compat.shares_effect_memory();
+            for (i, pattern) in row.channels.iter().enumerate() {
+                let recalled;
+                let pattern = if is_s3m {
+                    let mut own_memory = 0;
+                    let memory = if shares_effect_memory { &mut self.channels[i].shared_effect_memory } else { &mut own_memory };
+                    recalled = recall_shared_effect(pattern, memory);
+                    &recalled
+                } else {
+                    pattern
+                };
                 match pattern.effect {
                     0xB => { self.pattern_change.set_jump(first_tick, pattern.effect_param); }
                     0xD => { self.pattern_change.set_break(first_tick, pattern.get_x() * 10 + pattern.get_y()); }
                     0xF => { 
                         if first_tick && pattern.effect_param > 0 {
-                            if pattern.effect_param <= 0x1f { self.speed = pattern.effect_param as u32; }
+                            if pattern.effect_param <= 0x1f || self.song_data.vblank { self.speed = pattern.effect_param as u32; }
                             else { self.bpm.update(pattern.effect_param as u32, self.rate); }
                         }
                     }
@@ -1526,23 +1697,49 @@ impl Song {
                             _ => {}
                         }
                     }
+                    0x1C if first_tick && pattern.get_x() == 0x6 => {
+                        // Fine Pattern Delay (S6x)
+                        self.pattern_change.fine_pattern_delay = pattern.get_y();
+                    }
+                    0x24 => {
+                        let channel = &mut self.channels[i];
+                        if pattern.effect_param != 0 { channel.last_tempo_slide = pattern.effect_param; }
+                        self.bpm.slide(first_tick, channel.last_tempo_slide, self.rate);
+                    }
                     _ => {}
                 }
             }
             return;
         }
 
-        let mut missing = String::new();
+        self.voice_pool.update(instruments, self.global_volume.scale(), self.rate, &self.frequency_tables);
+
+        let channel_count = self.channels.len();
+        let mut unsupported = std::mem::take(&mut self.tick_unsupported);
+        let is_s3m = self.song_data.song_type == SongType::S3M;
+        // only the IT reader puts pitch slides in the volume column, FT2 ignores these bytes
+        let has_volume_pitch_slides = self.song_data.song_type == SongType::IT;
+        let shares_effect_memory = self.compat.shares_effect_memory();
+        let is_protracker = self.protracker.is_some();
+        let declick = self.volume_ramp_ms > 0.0 && !self.is_fast_forwarding;
+        let mut funk = std::mem::take(&mut self.tick_funk);
         for (i, pattern) in row.channels.iter().enumerate() {
             let channel = &mut self.channels[i];
+            let recalled;
+            let pattern = if is_s3m {
+                // without ST3's shared memory, the effects remember their own parameters
+                let mut own_memory = 0;
+                let memory = if shares_effect_memory { &mut channel.shared_effect_memory } else { &mut own_memory };
+                recalled = recall_shared_effect(pattern, memory);
+                &recalled
+            } else {
+                pattern
+            };
             let note_delay_first_tick = if pattern.is_note_delay() { self.tick == pattern.get_y() as u32 } else {first_tick};
 
-            if !channel.voice.sustained {
-                if channel.voice.volume.fadeout_vol - channel.voice.volume.fadeout_speed * 2 < 0 {
-                    channel.voice.volume.fadeout_vol = 0;
-                } else {
-                    channel.voice.volume.fadeout_vol -= channel.voice.volume.fadeout_speed * 2;
-                }
+            channel.voice.fade_out();
+            if first_tick {
+                channel.panning.offset = 0;
             }
 
             if first_tick && pattern.is_porta_to_note() && pattern.instrument != 0 {
@@ -1561,9 +1758,34 @@ impl Song {
                 ((pattern.is_note_delay() && self.tick == pattern.get_y() as u32) ||
                     (!pattern.is_note_delay() && first_tick)) { // new row, set instruments
 
+                // the note playing now moves to the background unless the new note cuts it
+                let mut past_note = None;
+                if is_note_valid(note) {
+                    let action = channel.new_note_action.take().unwrap_or(instruments[channel.voice.instrument].new_note_action);
+                    if channel.on && action != NewNoteAction::Cut {
+                        past_note = Some((*channel, action));
+                    } else if channel.on && declick {
+                        self.declicking.push((i, channel.fading_out()));
+                    }
+                }
+
                 let mut reset_envelope = false;
-                if pattern.instrument != 0 {
-                    let instrument = if pattern.instrument < instruments.len() as u8 {pattern.instrument as usize} else {0};
+                let mut instrument_number = pattern.instrument;
+                if is_protracker {
+                    if instrument_number != 0 {
+                        channel.sample_start = 0;
+                    } else if is_note_valid(note) {
+                        instrument_number = channel.queued_instrument.take().unwrap_or(0) as u8;
+                    }
+                }
+                let instrument = if instrument_number < instruments.len() as u8 {instrument_number as usize} else {0};
+                if is_protracker && instrument_number != 0 && !is_note_valid(note) && channel.on && instrument != channel.voice.instrument {
+                    // PT2.3 waits for the sample playing to end or loop to swap it, only the volume changes now
+                    channel.queued_instrument = Some(instrument);
+                    channel.voice.volume.retrig(instruments[instrument].samples.first().map_or(0, |s| s.volume) as i32);
+                } else if instrument_number != 0 {
+                    channel.queued_instrument = None;
+                    channel.funk_position = 0;
                     channel.voice.instrument = instrument;
                     if is_note_valid(note) {
                         channel.voice.sample = instruments[instrument].sample_indexes[(note - 1)  as usize] as usize;
@@ -1582,6 +1804,15 @@ impl Song {
                         // continue;
                     }
                 }
+                if note == Pattern::NOTE_CUT {
+                    if channel.on && declick {
+                        self.declicking.push((i, channel.fading_out()));
+                    }
+                    channel.apply_note_action(instruments, NewNoteAction::Cut);
+                }
+                if note == Pattern::NOTE_FADE {
+                    channel.apply_note_action(instruments, NewNoteAction::NoteFade);
+                }
 
                 channel.frequency_shift = 0.0;
                 channel.period_shift = 0;
@@ -1590,6 +1821,9 @@ impl Song {
                 if reset_envelope {
                     channel.voice.volume.retrig(self.song_data.get_sample(channel).volume as i32);
                     channel.reset_envelopes(instruments);
+                    if is_note_valid(note) {
+                        channel.apply_instrument_variation(&instruments[channel.voice.instrument], note);
+                    }
                 }
 
                 if pattern.is_note_delay() {
@@ -1597,20 +1831,47 @@ impl Song {
                 }
 
                 channel.trigger_note(instruments, note, self.rate, &self.frequency_tables);
+
+                if is_protracker && is_note_valid(note) {
+                    // PT2.3 takes 9xx in before the note starts and again after it, the next note without an
+                    // instrument number starts at twice the offset
+                    let offset = pattern.effect == 0x9;
+                    if offset { channel.protracker_offset(pattern.effect_param); }
+                    channel.start_protracker_sample(&instruments[channel.voice.instrument].samples[channel.voice.sample]);
+                    if offset { channel.protracker_offset(pattern.effect_param); }
+                }
+
+                if let Some((mut state, action)) = past_note {
+                    // IT checks the note it moves to the background for duplicates too
+                    let action = duplicate_action(instruments, &state, channel.voice.instrument, channel.voice.sample, note).unwrap_or(action);
+                    if action == NewNoteAction::Cut {
+                        if declick {
+                            self.declicking.push((i, state.fading_out()));
+                        }
+                    } else {
+                        state.apply_note_action(instruments, action);
+                        self.voice_pool.push(i, state, channel_count);
+                    }
+                }
+                if is_note_valid(note) {
+                    self.voice_pool.duplicate_check(i, instruments, channel.voice.instrument, channel.voice.sample, note);
+                }
             }
 
             // handle vibrato
             if !first_tick && pattern.has_vibrato() { // vibrate
-                channel.frequency_shift = channel.vibrato_state.get_frequency_shift(WaveControl::from(channel.vibrato_control)) as f32;
+                channel.update_vibrato();
                 channel.update_frequency(self.rate, false, &self.frequency_tables);
             }
 
             // handle tremolo (not really need to do it here, but oh, well)
             if !first_tick && pattern.has_tremolo() { // tremolate
-                channel.voice.volume.volume_shift = channel.tremolo_state.get_volume_shift(WaveControl::from(channel.tremolo_control));
+                channel.update_tremolo();
             }
 
             match pattern.volume {
+                0x01..=0x0a if has_volume_pitch_slides => { channel.porta_down(first_tick, (pattern.volume - 0x01) * 4, self.rate, &self.frequency_tables, self.compat); } // IT's pitch slide down
+                0x51..=0x5a if has_volume_pitch_slides => { channel.porta_up(first_tick, (pattern.volume - 0x51) * 4, self.rate, &self.frequency_tables, self.compat); }   // IT's pitch slide up
                 0x10..=0x50 => { channel.set_volume(note_delay_first_tick, pattern.volume - 0x10); }       // set volume
                 0x60..=0x6f => { channel.volume_slide(note_delay_first_tick, -(pattern.get_volume_param() as i8)); }       // Volume slide down
                 0x70..=0x7f => { channel.volume_slide(note_delay_first_tick, pattern.get_volume_param() as i8); }    // Volume slide up
@@ -1621,7 +1882,7 @@ impl Song {
                 0xc0..=0xcf => { channel.panning.set_panning((pattern.get_volume_param() as i32) * 16);}// Set panning
                 0xd0..=0xdf => { // Panning slide left
                     let pan = channel.panning.panning as i16 - pattern.get_volume_param() as i16;
-                    if pattern.get_volume_param() == 0 || pan < 0 {
+                    if (pattern.get_volume_param() == 0 && self.compat.resets_panning_on_empty_slide()) || pan < 0 {
                         channel.panning.set_panning(0); // FT2 bug: param 0 = pan gets set to 0
                     } else {
                         channel.panning.set_panning(pan as i32);
@@ -1657,15 +1918,19 @@ impl Song {
                         channel.update_frequency(self.rate, true, &self.frequency_tables);
                     }
                 }
-                0x1 => { channel.porta_up(first_tick, pattern.effect_param, self.rate, &self.frequency_tables); } // Porta up
-                0x2 => { channel.porta_down(first_tick, pattern.effect_param, self.rate, &self.frequency_tables); } // Porta down
+                0x1 => { channel.porta_up(first_tick, pattern.effect_param, self.rate, &self.frequency_tables, self.compat); } // Porta up
+                0x2 => { channel.porta_down(first_tick, pattern.effect_param, self.rate, &self.frequency_tables, self.compat); } // Porta down
                 0x3 => { channel.porta_to_note(instruments, first_tick, pattern.effect_param, pattern.note, self.rate, &self.frequency_tables); } // Porta to note
-                0x4 => { channel.vibrato(first_tick, pattern.get_x() * 4, pattern.get_y()); } // vibrato
+                0x4 => { // vibrato
+                    channel.vibrato_state.fine = false;
+                    channel.vibrato(first_tick, pattern.get_x() * 4, pattern.get_y());
+                }
                 0x5 => { // porta to note + volume slide
                     channel.porta_to_note(instruments, first_tick, 0, 0, self.rate, &self.frequency_tables);
                     channel.volume_slide_main(first_tick, pattern.effect_param);
                 }
                 0x6 => { // vibrato + volume slide
+                    channel.vibrato_state.fine = false;
                     channel.vibrato(first_tick, 0, 0);
                     channel.volume_slide_main(first_tick, pattern.effect_param);
                 }
@@ -1674,13 +1939,19 @@ impl Song {
                 }
                 0x8 => { // panning
                     channel.panning.set_panning(pattern.effect_param as i32);
+                    channel.surround = false;
+                }
+                0x9 if is_protracker => { // sample offset, started with the note already
+                    if first_tick && !is_note_valid(pattern.note) {
+                        channel.protracker_offset(pattern.effect_param);
+                    }
                 }
                 0x9 => { // sample offset
                     if first_tick && is_note_valid(channel.last_played_note) {
                         if pattern.effect_param != 0 {
                             channel.last_sample_offset = pattern.effect_param as u32 * 256;
                         }
-                        let mut offset = channel.last_sample_offset;
+                        let mut offset = channel.last_sample_offset + channel.high_offset * 0x10000;
                         let sample = self.song_data.get_sample(channel);
                         // sample.length / loop_start / loop_end include the 4-sample sinc prefix.
                         // Subtract 4 to get the logical (original) coordinates.
@@ -1690,9 +1961,7 @@ impl Song {
                         let is_looped = sample.loop_type != crate::instrument::LoopType::NoLoop
                             && orig_loop_end > orig_loop_start;
 
-                        let is_s3m = matches!(self.song_data.song_type, SongType::S3M | SongType::STM);
-
-                        if is_s3m && is_looped && offset >= orig_loop_end {
+                        if self.compat.wraps_offset_into_loop() && is_looped && offset >= orig_loop_end {
                             // S3M loop wrap-around: offset folds back into the loop region
                             let loop_len = orig_loop_end - orig_loop_start;
                             offset = (offset - orig_loop_start) % loop_len + orig_loop_start;
@@ -1720,7 +1989,8 @@ impl Song {
                 0xE => {} // handled separately
                 0xF => { // set speed
                     if first_tick && pattern.effect_param > 0 {
-                        if pattern.effect_param <= 0x1f {
+                        // on a VBlank player every speed is ticks
+                        if pattern.effect_param <= 0x1f || self.song_data.vblank {
                             self.speed = pattern.effect_param as u32;
                         } else {
                             self.bpm.update(pattern.effect_param as u32, self.rate);
@@ -1730,6 +2000,14 @@ impl Song {
                 0x10 => { // set global volume
                     self.global_volume.set_volume(note_delay_first_tick, pattern.effect_param);
                 }
+                0x16 => { // M, set channel volume
+                    if first_tick && pattern.effect_param <= 0x40 {
+                        channel.channel_volume = pattern.effect_param;
+                    }
+                }
+                0x17 => { // N, channel volume slide
+                    channel.channel_volume_slide(first_tick, pattern.effect_param);
+                }
                 0x11 => { // global volume slide
                     self.global_volume.volume_slide(note_delay_first_tick, pattern.effect_param);
                 }
@@ -1742,10 +2020,15 @@ impl Song {
                     let instrument = self.song_data.get_instrument(channel);
                     if instrument.volume_envelope.on { channel.volume_envelope_state.set_position(&instrument.volume_envelope, pattern.effect_param);}
                     // FT2 bug - only set panning position if volume sustain is set
-                    if instrument.volume_envelope.sustain { channel.panning_envelope_state.set_position(&instrument.panning_envelope, pattern.effect_param);}
+                    let panning_envelope = if self.compat.ties_panning_envelope_to_volume_sustain() { instrument.volume_envelope.sustain } else { instrument.panning_envelope.on };
+                    if panning_envelope { channel.panning_envelope_state.set_position(&instrument.panning_envelope, pattern.effect_param);}
                 }
                 0x19 => {
-                    channel.panning_slide(first_tick, pattern.effect_param);
+                    if self.song_data.song_type == SongType::IT {
+                        channel.it_panning_slide(first_tick, pattern.effect_param);
+                    } else {
+                        channel.panning_slide(first_tick, pattern.effect_param);
+                    }
                 }
                 0x1b => {
                     channel.multi_retrig(instruments, first_tick, self.tick, pattern.effect_param, note, self.rate, &self.frequency_tables);
@@ -1753,15 +2036,81 @@ impl Song {
                 0x1d => {
                     channel.tremor(self.tick, pattern.effect_param);
                 }
-                _ => {missing.push_str(format!("channel: {}, eff: {:x},", i, pattern.effect).as_ref());}
+                0x20 => { // W, global volume slide in IT
+                    self.global_volume.it_volume_slide(first_tick, pattern.effect_param);
+                }
+                0x22 => { // Y, panbrello
+                    channel.panbrello(first_tick, pattern.get_x(), pattern.get_y());
+                }
+                0x23 => { // Z, the default filter macro sets the cutoff with Z00-Z7F
+                    if first_tick && pattern.effect_param < 0x80 {
+                        channel.filter.cutoff = pattern.effect_param;
+                    }
+                }
+                0x24 => { // T0x/T1x, tempo slide
+                    if pattern.effect_param != 0 { channel.last_tempo_slide = pattern.effect_param; }
+                    self.bpm.slide(first_tick, channel.last_tempo_slide, self.rate);
+                }
+                0x25 => { // U, fine vibrato
+                    channel.vibrato_state.fine = true;
+                    channel.vibrato(first_tick, pattern.get_x() * 4, pattern.get_y());
+                }
+                0x1c => { // the S commands of IT that XM has nothing like
+                    match (pattern.get_x(), pattern.get_y()) {
+                        (0x7, 0x0) => { if first_tick { self.voice_pool.past_note_action(i, instruments, NewNoteAction::Cut); } }
+                        (0x7, 0x1) => { if first_tick { self.voice_pool.past_note_action(i, instruments, NewNoteAction::NoteOff); } }
+                        (0x7, 0x2) => { if first_tick { self.voice_pool.past_note_action(i, instruments, NewNoteAction::NoteFade); } }
+                        (0x7, 0x3..=0x6) => { channel.new_note_action = Some(NewNoteAction::from_it(pattern.get_y() - 3)); }
+                        (0x3, control) => { channel.vibrato_control = control; }
+                        (0x4, control) => { channel.tremolo_control = control; }
+                        (0x5, waveform) => { channel.panbrello_state.waveform = waveform; }
+                        (0x6, ticks) => { if first_tick { self.pattern_change.fine_pattern_delay = ticks; } }
+                        (0x9, 0x0) => { channel.surround = false; }
+                        (0x9, 0x1) => { channel.surround = true; }
+                        (0x9, 0xE) => { channel.voice.reverse = false; }
+                        (0x9, 0xF) => {
+                            if first_tick && !channel.voice.reverse {
+                                channel.voice.reverse = true;
+                                // a note that just started plays from its end
+                                if channel.voice.sample_position <= 4.0 {
+                                    let sample = self.song_data.get_sample(channel).playing(channel.voice.sustained);
+                                    if sample.loop_type == LoopType::NoLoop {
+                                        channel.voice.sample_position = sample.length.saturating_sub(1) as f32;
+                                    } else {
+                                        channel.voice.sample_position = sample.loop_end.saturating_sub(1) as f32;
+                                        channel.voice.loop_started = true;
+                                    }
+                                }
+                            }
+                        }
+                        (0xA, high) => { channel.high_offset = high as u32; }
+                        (0xF, 0x0) => {} // the default macro, the only one there is
+                        _ => { unsupported.push(UnsupportedEffect { channel: i, effect: pattern.effect, param: pattern.effect_param }); }
+                    }
+                }
+                _ => { unsupported.push(UnsupportedEffect { channel: i, effect: pattern.effect, param: pattern.effect_param }); }
+            }
+
+            // ST3 3.00 slides on the first tick too
+            if self.compat.has_fast_volume_slides() && self.song_data.fast_volume_slides && first_tick && matches!(pattern.effect, 0x5 | 0x6 | 0xA) {
+                channel.volume_slide_main(false, 0);
+            }
+
+            if is_protracker && !first_tick && channel.update_funk() {
+                funk.push(i);
             }
 
             if pattern.effect == 0xe {
                 match pattern.get_x() {
-                    0x1 => { channel.fine_porta_up(first_tick, pattern.get_y(), self.rate, &self.frequency_tables); } // Porta up
-                    0x2 => { channel.fine_porta_down(first_tick, pattern.get_y(), self.rate, &self.frequency_tables); } // Porta down
+                    0x0 if is_protracker => { // LED filter, E00 on and E01 off
+                        if let (true, Some(protracker)) = (first_tick, &mut self.protracker) {
+                            protracker.led_filter_on = pattern.get_y() & 1 == 0;
+                        }
+                    }
+                    0x1 => { channel.fine_porta_up(first_tick, pattern.get_y(), self.rate, &self.frequency_tables, self.compat); } // Porta up
+                    0x2 => { channel.fine_porta_down(first_tick, pattern.get_y(), self.rate, &self.frequency_tables, self.compat); } // Porta down
                     0x3 => { channel.glissando = pattern.get_y() == 1; }
-                    0x4 => { channel.vibrato_control = pattern.get_y();}
+                    0x4 => { channel.vibrato_control = WaveControl::ft2_control(pattern.get_y());}
                     0x6 => { // Pattern Loop
                         let param = pattern.get_y();
                         if first_tick {
@@ -1782,8 +2131,11 @@ impl Song {
                             }
                         }
                     }
-                    0x7 => { channel.tremolo_control = pattern.get_y();}
-                    0x8 => { channel.panning.set_panning((pattern.get_y() * 17) as i32);}
+                    0x7 => { channel.tremolo_control = WaveControl::ft2_control(pattern.get_y());}
+                    0x8 => {
+                        channel.panning.set_panning((pattern.get_y() * 17) as i32);
+                        channel.surround = false;
+                    }
                     0x9 => { channel.retrig_note(instruments, first_tick, self.tick, pattern.get_y(), pattern.note, self.rate, &self.frequency_tables);}
                     0xa => { channel.fine_volume_slide_up(note_delay_first_tick, pattern.get_y());} // volume slide up
                     0xb => { channel.fine_volume_slide_down(note_delay_first_tick, pattern.get_y());} // volume slide up
@@ -1794,29 +2146,38 @@ impl Song {
                             self.pattern_change.pattern_delay = pattern.get_y();
                         }
                     }
-                    _ => {missing.push_str(format!("channel_state: {}, eff: 0xe{:x},", i, pattern.get_x()).as_ref());}
+                    0xf if is_protracker => { // Invert loop
+                        if first_tick {
+                            channel.funk_speed = pattern.get_y();
+                            if channel.update_funk() {
+                                funk.push(i);
+                            }
+                        }
+                    }
+                    _ => { unsupported.push(UnsupportedEffect { channel: i, effect: pattern.effect, param: pattern.effect_param }); }
                 }
             }
 
             let instrument = self.song_data.get_instrument(channel);
-
-            let envelope_volume = channel.volume_envelope_state.handle(&instrument.volume_envelope, channel.voice.sustained, 64, false);
-
-            let mut envelope_panning = channel.panning_envelope_state.handle(&instrument.panning_envelope, channel.voice.sustained, 32, true);
-            // let scale = 0.9;
-            envelope_panning = clamp(envelope_panning, 0, 64 * 256);
-
-
-            channel.panning.update_envelope_panning(envelope_panning);
-            // FinalVol = (FadeOutVol/65536)*(EnvelopeVol/64)*(GlobalVol/64)*(Vol/64)*Scale;
+            channel.update_pitch_envelope(instrument, self.rate);
             channel.update_frequency(self.rate, channel.glissando, &self.frequency_tables);
+            channel.update_output_volume(instrument, self.global_volume.scale());
+        }
 
-            let global_volume = self.global_volume.volume as f32 / 64.0 ;
-            channel.voice.volume.envelope_vol = envelope_volume as i32;
-            channel.voice.volume.global_vol = self.global_volume.volume as i32;
-            channel.voice.volume.output_volume = (channel.voice.volume.fadeout_vol as f32 / 65536.0) * (envelope_volume as f32 / 16384.0) * (channel.voice.volume.get_volume() as f32 / 64.0) * global_volume;
-            
+        if let Some(protracker) = &mut self.protracker {
+            for &i in &funk {
+                protracker.invert_loop(&mut self.song_data.instruments, &mut self.channels[i]);
+            }
+        }
+
+        for effect in unsupported.drain(..) {
+            if !self.unsupported_effects.iter().any(|e| e.effect == effect.effect && e.param == effect.param) {
+                self.unsupported_effects.push(effect);
+            }
         }
+        funk.clear();
+        self.tick_unsupported = unsupported;
+        self.tick_funk = funk;
 //            row
     }
 
@@ -1843,181 +2204,307 @@ impl Song {
             return;
         }
 
-        for channel in &mut self.channels {
-            if !channel.on || channel.force_off {
+        let mut mixer = Mixer {
+            filter: self.filter,
+            ramp_length: (self.volume_ramp_ms * self.original_rate / 1000.0) as usize,
+            pan_law: self.pan_law,
+            frequency_tables: &self.frequency_tables,
+            master_samples: &mut self.master_samples,
+            master_samples_pos: &mut self.master_samples_pos,
+        };
+
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            if channel.force_off {
+                continue;
+            }
+            if let Some(patch) = &self.song_data.get_instrument(channel).adlib {
+                mixer.mix_adlib(channel, &mut self.opl, i, patch, self.rate, current_buf_position, buf, ticks_to_generate);
                 continue;
             }
+            // a queued instrument takes over from the one playing where it ends or loops
+            let mut mixed = 0;
+            while channel.on && mixed < ticks_to_generate {
+                let sample = self.song_data.get_sample(channel);
+                mixed += mixer.mix(channel, sample, current_buf_position + mixed, buf, ticks_to_generate - mixed);
+                channel.swap_instrument(&self.song_data.instruments);
+            }
+        }
 
-            let sample = self.song_data.get_sample(channel);
+        // background voices go quiet with their channel
+        for voice in self.voice_pool.voices_mut() {
+            if !voice.state.on || self.channels[voice.channel].force_off {
+                continue;
+            }
+            let sample = self.song_data.get_sample(&voice.state);
+            mixer.mix(&mut voice.state, sample, current_buf_position, buf, ticks_to_generate);
+        }
 
-            let vol_right = PANNING_TAB[      channel.panning.final_panning as usize] as f32 / 65536.0;
-            let vol_left  = PANNING_TAB[256 - channel.panning.final_panning as usize] as f32 / 65536.0;
-            
-            let mut i = 0;
-            
-            // Fast Path: 4-sample SIMD Block
-            while i + 4 <= ticks_to_generate {
-                let pos = channel.voice.sample_position;
-                let du = channel.voice.du;
-                
-                // Check if any of the 4 samples will cross a loop or end boundary
-                // We also check channel.on in case the scalar loop turned it off (not possible here but good for safety)
-                if pos + (4.0 * du) >= sample.length as f32 || 
-                   (sample.loop_type != LoopType::NoLoop && pos + (4.0 * du) >= sample.loop_end as f32) {
-                    break;
-                }
+        for (channel, state) in self.declicking.iter_mut() {
+            if !self.channels[*channel].force_off {
+                let sample = self.song_data.get_sample(state);
+                mixer.mix(state, sample, current_buf_position, buf, ticks_to_generate);
+            }
+        }
+        self.declicking.retain(|(channel, state)| state.on && !state.ramp.is_silent() && !self.channels[*channel].force_off);
 
-                let mut out_samples = [0.0f32; 4];
-                
-                match self.filter {
-                    FilterType::Linear => {
-                        let mut lo = [0.0f32; 4];
-                        let mut hi = [0.0f32; 4];
-                        let mut t  = [0.0f32; 4];
-                        
-                        for j in 0..4 {
-                            let p = pos + (j as f32 * du);
-                            let idx = p as usize;
-                            lo[j] = sample.data[idx];
-                            hi[j] = sample.data[idx+1];
-                            t[j]  = p.fract();
-                        }
-                        out_samples = lerp_simd(lo, hi, t);
-                    },
-                    FilterType::Cubic => {
-                        let mut p0 = [0.0f32; 4];
-                        let mut p1 = [0.0f32; 4];
-                        let mut p2 = [0.0f32; 4];
-                        let mut p3 = [0.0f32; 4];
-                        let mut t  = [0.0f32; 4];
-                        
-                        for j in 0..4 {
-                            let p = pos + (j as f32 * du);
-                            let idx = p as usize;
-                            p0[j] = sample.data[idx-1];
-                            p1[j] = sample.data[idx];
-                            p2[j] = sample.data[idx+1];
-                            p3[j] = sample.data[idx+2];
-                            t[j]  = p.fract();
-                        }
-                        out_samples = cubic_simd(p0, p1, p2, p3, t);
-                    },
-                    FilterType::Sinc => {
-                        for j in 0..4 {
-                            let p = pos + (j as f32 * du);
-                            let idx = p as usize;
-                            let phase = (p.fract() * 512.0) as usize;
-                            let table = &self.frequency_tables.resampling.sinc_table[phase];
-                            out_samples[j] = sinc_dot_product(&sample.data[idx - 3..], table);
-                        }
-                    },
-                    FilterType::None => {
-                        for j in 0..4 {
-                            let p = pos + (j as f32 * du);
-                            out_samples[j] = sample.data[p as usize];
-                        }
-                    }
-                }
+        if let FilterType::Paula(_) = self.filter {
+            let amiga_filter = &mut self.amiga_filter;
+            buf.process_frames(current_buf_position, ticks_to_generate, &mut |left, right| amiga_filter.process(left, right));
+        }
+        if let Some(protracker) = &mut self.protracker {
+            buf.process_frames(current_buf_position, ticks_to_generate, &mut |left, right| protracker.filter(left, right));
+        }
+        if !self.stereo_image.is_neutral() {
+            let stereo_image = &mut self.stereo_image;
+            buf.process_frames(current_buf_position, ticks_to_generate, &mut |left, right| stereo_image.process(left, right));
+        }
+    }
+}
 
-                // Volume and Panning
-                let mut left_samples  = [0.0f32; 4];
-                let mut right_samples = [0.0f32; 4];
-                
-                let output_vol = channel.voice.volume.output_volume / 4.0;
-                
-                for j in 0..4 {
-                    let final_sample = out_samples[j] * output_vol;
-                    
-                    channel.last_samples[channel.last_samples_pos] = final_sample;
-                    channel.last_samples_pos = (channel.last_samples_pos + 1) % 512;
-                    
-                    left_samples[j]  = final_sample * vol_left;
-                    right_samples[j] = final_sample * vol_right;
-                    
-                    self.master_samples[self.master_samples_pos] = (left_samples[j] + right_samples[j]) / 2.0;
-                    self.master_samples_pos = (self.master_samples_pos + 1) % 8192;
-                }
+struct Mixer<'a> {
+    filter:             FilterType,
+    // in frames, 0 when ramping is off
+    ramp_length:        usize,
+    pan_law:            PanLaw,
+    frequency_tables:   &'a AudioTables,
+    master_samples:     &'a mut [f32; 8192],
+    master_samples_pos: &'a mut usize,
+}
 
-                buf.mix_samples(0, &left_samples,  current_buf_position + i);
-                buf.mix_samples(1, &right_samples, current_buf_position + i);
+impl Mixer<'_> {
+    // The chip keys on with every new note and off when the channel lets go of it. ST3 plays an AdLib C-4 at the
+    // instrument's C2SPD / 32 Hz, so the tone is the sample frequency the note would have, over 32
+    #[allow(clippy::too_many_arguments)]
+    fn mix_adlib(&mut self, channel: &mut ChannelState, opl: &mut Opl, opl_channel: usize, patch: &AdlibPatch, rate: f32, current_buf_position: usize, buf: &mut impl BufferAdapter, ticks_to_generate: usize) {
+        let key_on = channel.on && channel.voice.sustained;
+        opl.play(opl_channel, patch, channel.voice.note_count, key_on, channel.voice.frequency / 32.0, channel.voice.volume.output_volume);
+        if opl.is_silent(opl_channel) {
+            return;
+        }
 
-                channel.voice.sample_position += 4.0 * du;
-                i += 4;
-            }
+        let (vol_left, vol_right) = self.pan_law.gains(channel.panning.final_panning);
+        let vol_right = if channel.surround { -vol_right } else { vol_right };
 
-            // Path 2: Scalar Fallback
-            while i < ticks_to_generate {
+        let increment = channel.voice.du / 32.0;
+        for i in 0..ticks_to_generate {
+            let final_sample = opl.next_sample(opl_channel, increment, rate) / 4.0;
 
-                if channel.voice.sample_position as u32 >= sample.length {
-                    channel.on = false;
-                    break;
-                }
+            channel.last_samples[channel.last_samples_pos] = final_sample;
+            channel.last_samples_pos = (channel.last_samples_pos + 1) % 512;
 
-                let out_sample: f32 = match self.filter {
-                    FilterType::Linear => {
-                        let pos = channel.voice.sample_position as usize;
-                        Self::lerp(channel.voice.sample_position, sample.data[pos], sample.data[pos+1])
-                    },
-                    FilterType::Cubic => {
-                        let pos = channel.voice.sample_position as usize;
-                        channel.voice.spline_data.p0 = sample.data[pos-1];
-                        channel.voice.spline_data.p1 = sample.data[pos];
-                        channel.voice.spline_data.p2 = sample.data[pos+1];
-                        channel.voice.spline_data.p3 = sample.data[pos+2];
-                        
-                        channel.voice.spline_data.interpolate(channel.voice.sample_position.fract())
-                    },
-                    FilterType::Sinc => {
-                        let pos = channel.voice.sample_position as usize;
-                        let phase = (channel.voice.sample_position.fract() * 512.0) as usize;
-                        let table = &self.frequency_tables.resampling.sinc_table[phase];
-                        sinc_dot_product(&sample.data[pos - 3..], table)
+            let l = final_sample * vol_left;
+            let r = final_sample * vol_right;
 
-                    },
-                    FilterType::None => {
-                        sample.data[channel.voice.sample_position as usize]
+            self.master_samples[*self.master_samples_pos] = (l + r) / 2.0;
+            *self.master_samples_pos = (*self.master_samples_pos + 1) % 8192;
+
+            buf.mix_sample(0, l, current_buf_position + i);
+            buf.mix_sample(1, r, current_buf_position + i);
+        }
+    }
+
+    // Returns how many frames it mixed, less than asked for when the channel stops or its queued instrument takes over
+    fn mix(&mut self, channel: &mut ChannelState, sample: &Sample, current_buf_position: usize, buf: &mut impl BufferAdapter, ticks_to_generate: usize) -> usize {
+        let sample = sample.playing(channel.voice.sustained);
+
+        let (vol_left, vol_right) = self.pan_law.gains(channel.panning.final_panning);
+        // surround inverts the phase of the right side
+        let vol_right = if channel.surround { -vol_right } else { vol_right };
+        let output_vol = channel.voice.volume.output_volume / 4.0;
+        channel.ramp.set_target([output_vol * vol_left, output_vol * vol_right], channel.voice.note_count, self.ramp_length);
+        
+        let mut i = 0;
+        let paula = matches!(self.filter, FilterType::Paula(_));
+        
+        // Fast Path: 4-sample SIMD Block, forward only
+        while !channel.voice.reverse && !paula && i + 4 <= ticks_to_generate {
+            let pos = channel.voice.sample_position;
+            let du = channel.voice.du;
+            
+            // Check if any of the 4 samples will cross a loop or end boundary
+            // We also check channel.on in case the scalar loop turned it off (not possible here but good for safety)
+            if pos + (4.0 * du) >= sample.length as f32 || 
+               (sample.loop_type != LoopType::NoLoop && pos + (4.0 * du) >= sample.loop_end as f32) {
+                break;
+            }
+
+            let mut out_samples = [0.0f32; 4];
+            
+            match self.filter {
+                FilterType::Linear => {
+                    let mut lo = [0.0f32; 4];
+                    let mut hi = [0.0f32; 4];
+                    let mut t  = [0.0f32; 4];
+                    
+                    for j in 0..4 {
+                        let p = pos + (j as f32 * du);
+                        let idx = p as usize;
+                        lo[j] = sample.data[idx];
+                        hi[j] = sample.data[idx+1];
+                        t[j]  = p.fract();
                     }
-                };
+                    out_samples = lerp_simd(lo, hi, t);
+                },
+                FilterType::Cubic => {
+                    let mut p0 = [0.0f32; 4];
+                    let mut p1 = [0.0f32; 4];
+                    let mut p2 = [0.0f32; 4];
+                    let mut p3 = [0.0f32; 4];
+                    let mut t  = [0.0f32; 4];
+                    
+                    for j in 0..4 {
+                        let p = pos + (j as f32 * du);
+                        let idx = p as usize;
+                        p0[j] = sample.data[idx-1];
+                        p1[j] = sample.data[idx];
+                        p2[j] = sample.data[idx+1];
+                        p3[j] = sample.data[idx+2];
+                        t[j]  = p.fract();
+                    }
+                    out_samples = cubic_simd(p0, p1, p2, p3, t);
+                },
+                FilterType::Sinc => {
+                    for j in 0..4 {
+                        let p = pos + (j as f32 * du);
+                        let idx = p as usize;
+                        let phase = (p.fract() * 512.0) as usize;
+                        let table = &self.frequency_tables.resampling.sinc_table[phase];
+                        out_samples[j] = sinc_dot_product(&sample.data[idx - 3..], table);
+                    }
+                },
+                FilterType::None | FilterType::Paula(_) => {
+                    for j in 0..4 {
+                        let p = pos + (j as f32 * du);
+                        out_samples[j] = sample.data[p as usize];
+                    }
+                }
+            }
+
+            // Volume and Panning
+            let mut left_samples  = [0.0f32; 4];
+            let mut right_samples = [0.0f32; 4];
+            
+            for out_sample in out_samples.iter_mut() {
+                *out_sample = channel.filter.process(*out_sample);
+            }
 
-                let final_sample = out_sample / 4.0 * channel.voice.volume.output_volume;
+            for j in 0..4 {
+                let final_sample = out_samples[j] * output_vol;
                 
                 channel.last_samples[channel.last_samples_pos] = final_sample;
                 channel.last_samples_pos = (channel.last_samples_pos + 1) % 512;
+                
+                let [gain_left, gain_right] = channel.ramp.next();
+                left_samples[j]  = out_samples[j] * gain_left;
+                right_samples[j] = out_samples[j] * gain_right;
+                
+                self.master_samples[*self.master_samples_pos] = (left_samples[j] + right_samples[j]) / 2.0;
+                *self.master_samples_pos = (*self.master_samples_pos + 1) % 8192;
+            }
 
-                let l = final_sample * vol_left;
-                let r = final_sample * vol_right;
+            buf.mix_samples(0, &left_samples,  current_buf_position + i);
+            buf.mix_samples(1, &right_samples, current_buf_position + i);
 
+            channel.voice.sample_position += 4.0 * du;
+            i += 4;
+        }
 
-                self.master_samples[self.master_samples_pos] = (l + r) / 2.0;
+        // Path 2: Scalar Fallback
+        while i < ticks_to_generate {
 
-                self.master_samples_pos = (self.master_samples_pos + 1) % 8192;
+            if channel.voice.sample_position as u32 >= sample.length {
+                channel.on = false;
+                break;
+            }
 
-                buf.mix_sample(0, l, current_buf_position + i);
-                buf.mix_sample(1, r, current_buf_position + i);
+            let out_sample: f32 = match self.filter {
+                FilterType::Linear => {
+                    let pos = channel.voice.sample_position as usize;
+                    Song::lerp(channel.voice.sample_position, sample.data[pos], sample.data[pos+1])
+                },
+                FilterType::Cubic => {
+                    let pos = channel.voice.sample_position as usize;
+                    channel.voice.spline_data.p0 = sample.data[pos-1];
+                    channel.voice.spline_data.p1 = sample.data[pos];
+                    channel.voice.spline_data.p2 = sample.data[pos+1];
+                    channel.voice.spline_data.p3 = sample.data[pos+2];
+                    
+                    channel.voice.spline_data.interpolate(channel.voice.sample_position.fract())
+                },
+                FilterType::Sinc => {
+                    let pos = channel.voice.sample_position as usize;
+                    let phase = (channel.voice.sample_position.fract() * 512.0) as usize;
+                    let table = &self.frequency_tables.resampling.sinc_table[phase];
+                    sinc_dot_product(&sample.data[pos - 3..], table)
+
+                },
+                FilterType::None => {
+                    sample.data[channel.voice.sample_position as usize]
+                }
+                FilterType::Paula(_) => {
+                    let since_step = channel.voice.sample_position.fract() / channel.voice.du;
+                    channel.blep.output(sample.data[channel.voice.sample_position as usize], since_step)
+                }
+            };
 
-                channel.voice.sample_position += channel.voice.du;
+            let filtered = channel.filter.process(out_sample);
+            let final_sample = filtered * output_vol;
+            
+            channel.last_samples[channel.last_samples_pos] = final_sample;
+            channel.last_samples_pos = (channel.last_samples_pos + 1) % 512;
 
-                if channel.voice.sample_position as u32 >= sample.length ||
-                    (sample.loop_type != LoopType::NoLoop && channel.voice.sample_position >= sample.loop_end as f32) {
-                    channel.voice.loop_started = true;
-                    match sample.loop_type {
-                        LoopType::NoLoop => {
-                            channel.on = false;
-                            channel.voice.volume.set_volume(0);
-                            break;
-                        }
-                        LoopType::ForwardLoop | LoopType::PingPongLoop => {
-                            channel.voice.sample_position = (channel.voice.sample_position - sample.loop_end as f32) + sample.loop_start as f32;
-                        }
+            let [gain_left, gain_right] = channel.ramp.next();
+            let l = filtered * gain_left;
+            let r = filtered * gain_right;
+
+
+            self.master_samples[*self.master_samples_pos] = (l + r) / 2.0;
+
+            *self.master_samples_pos = (*self.master_samples_pos + 1) % 8192;
+
+            buf.mix_sample(0, l, current_buf_position + i);
+            buf.mix_sample(1, r, current_buf_position + i);
+
+            if channel.voice.reverse {
+                channel.voice.sample_position -= channel.voice.du;
+                if sample.loop_type != LoopType::NoLoop && channel.voice.loop_started {
+                    if channel.voice.sample_position < sample.loop_start as f32 {
+                        channel.voice.sample_position += (sample.loop_end - sample.loop_start) as f32;
                     }
+                } else if channel.voice.sample_position < 4.0 {
+                    channel.on = false;
+                    break;
                 }
+                i += 1;
+                continue;
+            }
 
-                if channel.voice.loop_started && channel.voice.sample_position < sample.loop_start as f32 {
-                    channel.voice.sample_position = sample.loop_start as f32 + (sample.loop_start as f32 - channel.voice.sample_position) as f32;
+            channel.voice.sample_position += channel.voice.du;
+
+            if channel.voice.sample_position as u32 >= sample.length ||
+                (sample.loop_type != LoopType::NoLoop && channel.voice.sample_position >= sample.loop_end as f32) {
+                channel.voice.loop_started = true;
+                if channel.queued_instrument.is_some() {
+                    return i + 1;
+                }
+                match sample.loop_type {
+                    LoopType::NoLoop => {
+                        channel.on = false;
+                        channel.voice.volume.set_volume(0);
+                        break;
+                    }
+                    LoopType::ForwardLoop | LoopType::PingPongLoop => {
+                        channel.voice.sample_position = (channel.voice.sample_position - sample.loop_end as f32) + sample.loop_start as f32;
+                    }
                 }
-                
-                i += 1;
             }
+
+            if channel.voice.loop_started && channel.voice.sample_position < sample.loop_start as f32 {
+                channel.voice.sample_position = sample.loop_start as f32 + (sample.loop_start as f32 - channel.voice.sample_position) as f32;
+            }
+            
+            i += 1;
         }
+        i
     }
 }
@@ -0,0 +1,190 @@
+// What MODs get from ProTracker 2.3 that the FT2 style playback doesn't do: the LED filter of E0x, and EFx
+// inverting the sample loop a byte at a time. EFx writes to the sample data itself, like it did on the Amiga,
+// so the data of every sample it touches is kept to put it back when the song restarts.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use crate::channel_state::ChannelState;
+use crate::instrument::{Instruments, LoopType};
+
+// The A500's LED filter, a 2 pole low-pass at 3090 Hz
+const LED_CUTOFF: f32 = 3090.5;
+const LED_Q: f32 = 0.660;
+
+#[derive(Clone, Copy, Debug)]
+struct LedFilter {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    // the last two inputs and outputs of each side
+    history: [[f32; 4]; 2],
+}
+
+impl LedFilter {
+    fn new(rate: f32) -> Self {
+        let w0 = 2.0 * PI * LED_CUTOFF.min(rate * 0.45) / rate;
+        let alpha = w0.sin() / (2.0 * LED_Q);
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 - w0.cos()) / 2.0 / a0,
+            b1: (1.0 - w0.cos()) / a0,
+            b2: (1.0 - w0.cos()) / 2.0 / a0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+            history: [[0.0; 4]; 2],
+        }
+    }
+
+    fn process(&mut self, side: usize, input: f32) -> f32 {
+        let [x1, x2, y1, y2] = self.history[side];
+        let output = self.b0 * input + self.b1 * x1 + self.b2 * x2 - self.a1 * y1 - self.a2 * y2;
+        self.history[side] = [input, x1, output, y1];
+        output
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ProTracker {
+    // E00 turns it on and E01 off
+    pub(crate) led_filter_on:   bool,
+    led_filter:                 LedFilter,
+    // the data of every sample EFx inverted, by instrument and sample, from before its first inversion
+    originals:                  HashMap<(usize, usize), Vec<f32>>,
+}
+
+impl ProTracker {
+    pub(crate) fn new(rate: f32) -> Self {
+        Self { led_filter_on: false, led_filter: LedFilter::new(rate), originals: HashMap::new() }
+    }
+
+    pub(crate) fn set_rate(&mut self, rate: f32) {
+        self.led_filter = LedFilter::new(rate);
+    }
+
+    // the filter keeps running while it's off, so turning it on doesn't click
+    pub(crate) fn filter(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let filtered = (self.led_filter.process(0, left), self.led_filter.process(1, right));
+        if self.led_filter_on { filtered } else { (left, right) }
+    }
+
+    // Inverts the byte after the last one in the loop of the channel's sample, starting over at the loop start
+    pub(crate) fn invert_loop(&mut self, instruments: &mut Instruments, channel: &mut ChannelState) {
+        let sample = &mut instruments[channel.voice.instrument].samples[channel.voice.sample];
+        if sample.loop_type == LoopType::NoLoop || sample.loop_len == 0 {
+            return;
+        }
+        channel.funk_position = (channel.funk_position + 1) % sample.loop_len;
+        let position = (sample.loop_start + channel.funk_position) as usize;
+        self.originals.entry((channel.voice.instrument, channel.voice.sample)).or_insert_with(|| sample.data.clone());
+        sample.invert(position);
+    }
+
+    pub(crate) fn restore(&mut self, instruments: &mut Instruments) {
+        for ((instrument, sample), data) in self.originals.drain() {
+            instruments[instrument].samples[sample].data = data;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::{Instrument, Sample};
+
+    fn looped_instruments() -> Instruments {
+        let mut sample = Sample::new();
+        sample.data = (0..16).map(|i| i as f32 / 32.0).collect();
+        sample.length = 16;
+        sample.loop_start = 8;
+        sample.loop_end = 16;
+        sample.loop_len = 8;
+        sample.loop_type = LoopType::ForwardLoop;
+        sample.setup_loops_and_padding();
+        let mut instrument = Instrument::new();
+        instrument.samples = vec![sample];
+        vec![Instrument::new(), instrument]
+    }
+
+    #[test]
+    fn test_invert_loop() {
+        let mut instruments = looped_instruments();
+        let original = instruments[1].samples[0].data.clone();
+        let mut channel = ChannelState::new();
+        channel.voice.instrument = 1;
+        let mut protracker = ProTracker::new(48000.0);
+
+        // the byte after the loop start goes first, and the padding copies of the loop go along
+        protracker.invert_loop(&mut instruments, &mut channel);
+        let data = &instruments[1].samples[0].data;
+        assert_eq!(data[13], -original[13] - 1.0 / 32768.0);
+        assert_eq!(data[21], data[13]);
+
+        for _ in 0..8 {
+            protracker.invert_loop(&mut instruments, &mut channel);
+        }
+        let data = &instruments[1].samples[0].data;
+        assert_eq!(data[0..4], data[16..20]);
+        assert_eq!(data[12..16], data[20..24]);
+
+        protracker.restore(&mut instruments);
+        assert_eq!(instruments[1].samples[0].data, original);
+    }
+
+    #[test]
+    fn test_long_funk_keeps_one_copy() {
+        let mut instruments = looped_instruments();
+        let original = instruments[1].samples[0].data.clone();
+        let mut channel = ChannelState::new();
+        channel.voice.instrument = 1;
+        let mut protracker = ProTracker::new(48000.0);
+
+        // an EFx left running for a whole song doesn't pile up what it inverted
+        for _ in 0..100_001 {
+            protracker.invert_loop(&mut instruments, &mut channel);
+        }
+        assert_eq!(protracker.originals.len(), 1);
+        assert_ne!(instruments[1].samples[0].data, original);
+
+        protracker.restore(&mut instruments);
+        assert_eq!(instruments[1].samples[0].data, original);
+        assert!(protracker.originals.is_empty());
+    }
+
+    #[test]
+    fn test_funk_speed() {
+        let mut channel = ChannelState::new();
+        assert!(!channel.update_funk());
+
+        // EFF inverts every tick, EF8 every 8th
+        channel.funk_speed = 0xF;
+        assert!(channel.update_funk() && channel.update_funk());
+        channel.funk_speed = 0x8;
+        let inverted: Vec<bool> = (0..16).map(|_| channel.update_funk()).collect();
+        assert_eq!(inverted.iter().filter(|&&inverted| inverted).count(), 2);
+        assert!(inverted[7] && inverted[15]);
+    }
+
+    #[test]
+    fn test_offset_past_the_end_plays_the_loop() {
+        let instruments = looped_instruments();
+        let sample = &instruments[1].samples[0];
+        let mut channel = ChannelState::new();
+        channel.on = true;
+
+        channel.protracker_offset(0);
+        channel.start_protracker_sample(sample);
+        assert!(channel.on);
+
+        // an offset of 0 repeats the last one, they add up until the next instrument number
+        channel.last_sample_offset = 8;
+        channel.protracker_offset(0);
+        channel.start_protracker_sample(sample);
+        assert_eq!(channel.voice.sample_position, 12.0);
+        channel.protracker_offset(0);
+        channel.start_protracker_sample(sample);
+        assert_eq!(channel.voice.sample_position, sample.loop_start as f32);
+        assert!(channel.voice.loop_started);
+    }
+}
@@ -0,0 +1,163 @@
+// The stereo image of the whole mix. The pan law turns a channel's panning into the gains of both sides, the
+// separation narrows the mix down to mono or widens it past what the song asked for, and the crossfeed leaks the
+// low end of each side into the other, so hard LRRL panning doesn't sit on one ear only on headphones.
+
+use std::f32::consts::{FRAC_PI_2, PI};
+use serde::Serialize;
+use crate::tables::PANNING_TAB;
+
+// where the crossfeed starts to roll off, and how much of the other side it mixes in
+const CROSSFEED_CUTOFF: f32 = 700.0;
+const CROSSFEED_LEVEL: f32 = 0.3;
+
+/// How a panning turns into the gains of the left and right side
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum PanLaw {
+    /// FT2's square root table, bit-exact
+    FT2,
+    /// Gains that add up to 1, 6 dB down in the middle
+    Linear,
+    /// Sine and cosine gains whose powers add up to 1, 3 dB down in the middle
+    ConstantPower,
+}
+
+impl PanLaw {
+    // the gains of the left and the right side, for a panning from 0 (left) to 256 (right)
+    pub(crate) fn gains(&self, panning: u8) -> (f32, f32) {
+        let panning = panning as usize;
+        match self {
+            PanLaw::FT2 => (PANNING_TAB[256 - panning] as f32 / 65536.0, PANNING_TAB[panning] as f32 / 65536.0),
+            PanLaw::Linear => ((256 - panning) as f32 / 256.0, panning as f32 / 256.0),
+            PanLaw::ConstantPower => {
+                let angle = panning as f32 / 256.0 * FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PanLaw {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PanLaw::FT2 => write!(f, "FT2"),
+            PanLaw::Linear => write!(f, "Linear"),
+            PanLaw::ConstantPower => write!(f, "Constant power"),
+        }
+    }
+}
+
+impl std::str::FromStr for PanLaw {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ft2" => Ok(PanLaw::FT2),
+            "linear" => Ok(PanLaw::Linear),
+            "power" | "constant-power" => Ok(PanLaw::ConstantPower),
+            _ => Err(format!("unknown pan law '{}', expected ft2, linear or power", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StereoImage {
+    // 0 is mono, 1 the song's own panning and 2 twice as wide
+    separation:     f32,
+    crossfeed_on:   bool,
+    coefficient:    f32,
+    // the low-passed left and right side
+    low_passed:     [f32; 2],
+}
+
+impl StereoImage {
+    pub(crate) fn new(rate: f32) -> Self {
+        let mut image = Self { separation: 1.0, crossfeed_on: false, coefficient: 0.0, low_passed: [0.0; 2] };
+        image.set_rate(rate);
+        image
+    }
+
+    pub(crate) fn set_rate(&mut self, rate: f32) {
+        self.coefficient = 1.0 - (-2.0 * PI * CROSSFEED_CUTOFF / rate).exp();
+    }
+
+    // in percent, from 0 to 200
+    pub(crate) fn set_separation(&mut self, percent: u32) {
+        self.separation = percent.min(200) as f32 / 100.0;
+    }
+
+    pub(crate) fn set_crossfeed(&mut self, on: bool) {
+        self.crossfeed_on = on;
+        self.low_passed = [0.0; 2];
+    }
+
+    // the song's own panning without crossfeed leaves the mix as it is
+    pub(crate) fn is_neutral(&self) -> bool {
+        self.separation == 1.0 && !self.crossfeed_on
+    }
+
+    pub(crate) fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let mid = (left + right) / 2.0;
+        let side = (left - right) / 2.0 * self.separation;
+        let (left, right) = (mid + side, mid - side);
+        if !self.crossfeed_on {
+            return (left, right);
+        }
+
+        self.low_passed[0] += self.coefficient * (left - self.low_passed[0]);
+        self.low_passed[1] += self.coefficient * (right - self.low_passed[1]);
+        // a sound on both sides comes out as loud as it went in
+        ((left + CROSSFEED_LEVEL * self.low_passed[1]) / (1.0 + CROSSFEED_LEVEL),
+         (right + CROSSFEED_LEVEL * self.low_passed[0]) / (1.0 + CROSSFEED_LEVEL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    #[test]
+    fn test_pan_laws() {
+        for law in [PanLaw::FT2, PanLaw::Linear, PanLaw::ConstantPower] {
+            assert_eq!(law.gains(0), (1.0, 0.0), "{}", law);
+        }
+        assert_eq!(PanLaw::Linear.gains(128), (0.5, 0.5));
+        let (left, right) = PanLaw::ConstantPower.gains(128);
+        assert!((left - right).abs() < 1e-6 && (left * left + right * right - 1.0).abs() < 1e-6);
+        // FT2's table is a square root law, as loud in the middle as the constant power one
+        let (left, right) = PanLaw::FT2.gains(128);
+        assert!((left - FRAC_1_SQRT_2).abs() < 1e-3 && (right - FRAC_1_SQRT_2).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_separation() {
+        let mut image = StereoImage::new(48000.0);
+        assert!(image.is_neutral());
+        assert_eq!(image.process(1.0, 0.0), (1.0, 0.0));
+
+        image.set_separation(0);
+        assert_eq!(image.process(1.0, 0.0), (0.5, 0.5));
+        image.set_separation(200);
+        assert_eq!(image.process(0.75, 0.25), (1.0, 0.0));
+        image.set_separation(500);
+        assert_eq!(image.process(0.75, 0.25), (1.0, 0.0));
+    }
+
+    #[test]
+    fn test_crossfeed() {
+        let rate = 48000.0;
+        let leak = |period: usize| {
+            let mut image = StereoImage::new(rate);
+            image.set_crossfeed(true);
+            (0..4800).map(|i| {
+                let value = if (i / period).is_multiple_of(2) { 1.0 } else { -1.0 };
+                image.process(value, 0.0).1.abs()
+            }).skip(2400).fold(0.0f32, f32::max)
+        };
+
+        // the bass of a hard panned channel reaches the other side, the treble hardly does
+        let bass = leak(480);
+        let treble = leak(2);
+        assert!(bass > 0.2 && treble < 0.05, "{} {}", bass, treble);
+    }
+}
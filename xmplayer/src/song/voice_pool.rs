@@ -0,0 +1,201 @@
+use crate::channel_state::ChannelState;
+use crate::instrument::{DuplicateCheckType, Instruments, NewNoteAction};
+use crate::tables::AudioTables;
+
+/// Impulse Tracker mixes up to 256 voices
+pub const DEFAULT_MAX_VOICES: usize = 256;
+
+/// A note a new note moved off its pattern channel. It plays on with its own envelopes and fade out, the pattern only
+/// gets to it through the past note actions of the channel.
+#[derive(Clone, Copy)]
+pub(crate) struct BackgroundVoice {
+    pub(crate) channel: usize,
+    pub(crate) state:   ChannelState,
+}
+
+/// The duplicate check action of a new note's instrument, if the note playing duplicates the new one. It goes for the
+/// note the new one moves to the background too, in place of the new note action.
+pub(crate) fn duplicate_action(instruments: &Instruments, state: &ChannelState, instrument: usize, sample: usize, note: u8) -> Option<NewNoteAction> {
+    if state.voice.instrument != instrument {
+        return None;
+    }
+    let duplicate = match instruments[instrument].duplicate_check_type {
+        DuplicateCheckType::Off => false,
+        DuplicateCheckType::Note => state.note.original_note() == note,
+        DuplicateCheckType::Sample => state.voice.sample == sample,
+        DuplicateCheckType::Instrument => true,
+    };
+    duplicate.then(|| NewNoteAction::from(instruments[instrument].duplicate_check_action))
+}
+
+/// The voices behind the pattern channels. The pattern channels count towards the maximum, when it's reached the
+/// quietest background voice makes room for the new one.
+pub(crate) struct VoicePool {
+    voices:     Vec<BackgroundVoice>,
+    max_voices: usize,
+}
+
+impl VoicePool {
+    pub(crate) fn new(max_voices: usize) -> Self {
+        Self { voices: Vec::with_capacity(max_voices), max_voices }
+    }
+
+    pub(crate) fn set_max_voices(&mut self, max_voices: usize, channel_count: usize) {
+        self.max_voices = max_voices;
+        self.voices.reserve(max_voices.saturating_sub(self.voices.len()));
+        while self.voices.len() > self.capacity(channel_count) {
+            self.drop_quietest();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.voices.clear();
+    }
+
+    pub(crate) fn voices_mut(&mut self) -> impl Iterator<Item = &mut BackgroundVoice> {
+        self.voices.iter_mut()
+    }
+
+    fn capacity(&self, channel_count: usize) -> usize {
+        self.max_voices.saturating_sub(channel_count)
+    }
+
+    pub(crate) fn push(&mut self, channel: usize, state: ChannelState, channel_count: usize) {
+        let capacity = self.capacity(channel_count);
+        if capacity == 0 {
+            return;
+        }
+        while self.voices.len() >= capacity {
+            self.drop_quietest();
+        }
+        self.voices.push(BackgroundVoice { channel, state });
+    }
+
+    fn drop_quietest(&mut self) {
+        let quietest = self.voices.iter().enumerate()
+            .min_by(|(_, a), (_, b)| a.state.voice.volume.output_volume.total_cmp(&b.state.voice.volume.output_volume))
+            .map(|(idx, _)| idx);
+        if let Some(idx) = quietest {
+            self.voices.swap_remove(idx);
+        }
+    }
+
+    /// S70-S72, cut, release or fade the background notes of the channel
+    pub(crate) fn past_note_action(&mut self, channel: usize, instruments: &Instruments, action: NewNoteAction) {
+        for voice in self.voices.iter_mut().filter(|voice| voice.channel == channel) {
+            voice.state.apply_note_action(instruments, action);
+        }
+        self.voices.retain(|voice| voice.state.on);
+    }
+
+    /// The background notes of the channel that duplicate a new note of the same instrument get its duplicate check action
+    pub(crate) fn duplicate_check(&mut self, channel: usize, instruments: &Instruments, instrument: usize, sample: usize, note: u8) {
+        if instruments[instrument].duplicate_check_type == DuplicateCheckType::Off {
+            return;
+        }
+
+        for voice in self.voices.iter_mut().filter(|voice| voice.channel == channel) {
+            if let Some(action) = duplicate_action(instruments, &voice.state, instrument, sample, note) {
+                voice.state.apply_note_action(instruments, action);
+            }
+        }
+        self.voices.retain(|voice| voice.state.on);
+    }
+    /// Runs a tick of fade out, envelopes and filter, and lets go of the notes that ended or faded out
+    pub(crate) fn update(&mut self, instruments: &Instruments, global_volume: f32, rate: f32, frequency_tables: &AudioTables) {
+        for voice in self.voices.iter_mut() {
+            let state = &mut voice.state;
+            let instrument = &instruments[state.voice.instrument];
+            state.voice.fade_out();
+            state.update_pitch_envelope(instrument, rate);
+            state.update_frequency(rate, false, frequency_tables);
+            state.update_output_volume(instrument, global_volume);
+        }
+        self.voices.retain(|voice| voice.state.on && voice.state.voice.volume.fadeout_vol > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_state::Voice;
+    use crate::instrument::{DuplicateCheckAction, Instrument};
+    use crate::tables::LINEAR_TABLES;
+
+    fn playing(instrument: usize, volume: f32) -> ChannelState {
+        let mut voice = Voice::new();
+        voice.instrument = instrument;
+        voice.volume.output_volume = volume;
+        voice.volume.fadeout_vol = 65536;
+        voice.sustained = true;
+        let mut state = ChannelState::new();
+        state.voice = voice;
+        state.on = true;
+        state
+    }
+
+    fn instruments() -> Instruments {
+        let mut instrument = Instrument::new();
+        instrument.volume_fadeout = 0x2000;
+        instrument.duplicate_check_type = DuplicateCheckType::Instrument;
+        instrument.duplicate_check_action = DuplicateCheckAction::NoteFade;
+        vec![Instrument::new(), instrument, Instrument::new()]
+    }
+
+    #[test]
+    fn test_quietest_voice_makes_room() {
+        let mut pool = VoicePool::new(6);
+        for volume in [0.5, 0.1, 0.9] {
+            pool.push(0, playing(1, volume), 4);
+        }
+        assert_eq!(pool.voices_mut().count(), 2);
+        let volumes: Vec<f32> = pool.voices_mut().map(|voice| voice.state.voice.volume.output_volume).collect();
+        assert_eq!(volumes, vec![0.5, 0.9]);
+
+        pool.set_max_voices(4, 4);
+        assert_eq!(pool.voices_mut().count(), 0);
+        pool.push(0, playing(1, 1.0), 4);
+        assert_eq!(pool.voices_mut().count(), 0);
+    }
+
+    #[test]
+    fn test_past_note_actions_stay_on_their_channel() {
+        let instruments = instruments();
+        let mut pool = VoicePool::new(DEFAULT_MAX_VOICES);
+        pool.push(0, playing(1, 1.0), 2);
+        pool.push(1, playing(1, 1.0), 2);
+
+        pool.past_note_action(1, &instruments, NewNoteAction::Cut);
+        assert_eq!(pool.voices_mut().map(|voice| voice.channel).collect::<Vec<usize>>(), vec![0]);
+
+        // a fade out of 0x2000 takes a quarter of the volume every tick
+        pool.past_note_action(0, &instruments, NewNoteAction::NoteFade);
+        for _ in 0..3 {
+            pool.update(&instruments, 1.0, 48000.0, &LINEAR_TABLES);
+        }
+        assert_eq!(pool.voices_mut().count(), 1);
+        pool.update(&instruments, 1.0, 48000.0, &LINEAR_TABLES);
+        assert_eq!(pool.voices_mut().count(), 0);
+    }
+
+    #[test]
+    fn test_duplicate_check() {
+        let instruments = instruments();
+        let mut pool = VoicePool::new(DEFAULT_MAX_VOICES);
+        pool.push(0, playing(1, 1.0), 1);
+        pool.push(0, playing(2, 1.0), 1);
+
+        pool.duplicate_check(0, &instruments, 1, 0, 49);
+        let fading: Vec<bool> = pool.voices_mut().map(|voice| voice.state.voice.fading).collect();
+        assert_eq!(fading, vec![true, false]);
+
+        // instrument 2 doesn't check for duplicates
+        pool.duplicate_check(0, &instruments, 2, 0, 49);
+        assert!(!pool.voices_mut().nth(1).unwrap().state.voice.fading);
+
+        // nor does a note of another instrument duplicate one of instrument 1
+        assert_eq!(duplicate_action(&instruments, &playing(1, 1.0), 1, 0, 49), Some(NewNoteAction::NoteFade));
+        assert_eq!(duplicate_action(&instruments, &playing(2, 1.0), 1, 0, 49), None);
+        assert_eq!(duplicate_action(&instruments, &playing(2, 1.0), 2, 0, 49), None);
+    }
+}
@@ -4,7 +4,7 @@ mod leak;
 
 use core::sync::atomic::{AtomicBool, Ordering};
 use crate::song::{PlayData, Song, PlaybackCmd, CallbackState};
-use crate::module_reader::{SongData, read_module};
+use crate::module_reader::{SongData, LoadOptions, LoadReport, read_module_with_options};
 use shared_sync_primitives::{ProducerConsumerQueue};
 use std::sync::{mpsc, Mutex, Arc};
 use core::option::Option::None;
@@ -66,11 +66,22 @@ pub struct SongState {
 impl SongState {
 
     pub fn new(path: &str) -> SimpleResult<(SongHandle, AudioConsumer)> {
-        let song_data = read_module(path)?;
+        Self::new_with_options(path, &LoadOptions::default())
+    }
+
+    pub fn new_with_options(path: &str, options: &LoadOptions) -> SimpleResult<(SongHandle, AudioConsumer)> {
+        let song_data = read_module_with_options(path, options)?;
 
         let triple_buffer = TripleBuffer::<PlayData>::new_with_signal();
         let (triple_buffer_reader, triple_buffer_writer) = triple_buffer.split();
-        let song = Arc::new(Mutex::new(Song::new(&song_data, triple_buffer_writer, 48000.0)));
+        let mut song = Song::new(&song_data, triple_buffer_writer, 48000.0);
+        if let Some(compat) = options.compat_profile {
+            song.set_compat_profile(compat);
+        }
+        if options.normalize_loudness {
+            song.normalize_loudness();
+        }
+        let song = Arc::new(Mutex::new(song));
         let (tx, rx): (Sender<PlaybackCmd>, Receiver<PlaybackCmd>) = mpsc::channel();
         let stopped = Arc::new(AtomicBool::from(false));
 
@@ -90,6 +101,11 @@ impl SongState {
         Ok((sh, consumer))
     }
 
+    /// What was repaired or left out while loading the module
+    pub fn get_load_report(&self) -> &LoadReport {
+        self.song_data.get_load_report()
+    }
+
     pub fn set_order(&self, order: u32) {
         if let Ok(_) = self.tx.send(PlaybackCmd::SetPosition(order)) {}
     }
@@ -102,6 +118,12 @@ impl SongState {
         if let Ok(_) = self.tx.send(PlaybackCmd::SetDisplay(on)) {}
     }
 
+    /// Caps the voices mixed at once, the pattern channels included. Impulse Tracker modules keep notes playing in the
+    /// background when new ones start, the quietest of them make room when there are too many.
+    pub fn set_max_voices(&self, max_voices: usize) {
+        let _ = self.tx.send(PlaybackCmd::SetMaxVoices(max_voices));
+    }
+
     fn callback(&self) {
         let mut song = self.song.lock().unwrap();
         let mut rx = self.rx.lock().unwrap();
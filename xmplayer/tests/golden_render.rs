@@ -1,14 +1,44 @@
 use xmplayer::song_state::SongState;
 use xmplayer::song::InterleavedBufferAdaptar;
 use xmplayer::song::CallbackState;
+use xmplayer::song::{CompatProfile, FilterType, PanLaw, PlaybackCmd};
+use xmplayer::paula::AmigaModel;
+use xmplayer::module_reader::LoadOptions;
 
 fn render_test_file(path: &str, num_frames: usize) -> f64 {
-    let (song_handle, _consumer) = match SongState::new(path) {
+    render_test_file_with(path, num_frames, vec![])
+}
+
+fn rms(audio_buffer: &[f32]) -> f64 {
+    let sum_sq: f64 = audio_buffer.iter().map(|&x| (x as f64) * (x as f64)).sum();
+    (sum_sq / audio_buffer.len() as f64).sqrt()
+}
+
+// the biggest jump from one frame to the next, on either side
+fn largest_step(audio_buffer: &[f32]) -> f32 {
+    audio_buffer.windows(3).step_by(2).map(|w| (w[2] - w[0]).abs()).fold(0.0, f32::max)
+}
+
+// the commands go in before the first tick
+fn render_test_file_with(path: &str, num_frames: usize, commands: Vec<PlaybackCmd>) -> f64 {
+    rms(&render(path, num_frames, commands))
+}
+
+fn render(path: &str, num_frames: usize, commands: Vec<PlaybackCmd>) -> Vec<f32> {
+    render_with_options(path, num_frames, commands, &LoadOptions::default())
+}
+
+fn render_with_options(path: &str, num_frames: usize, commands: Vec<PlaybackCmd>, options: &LoadOptions) -> Vec<f32> {
+    let (song_handle, _consumer) = match SongState::new_with_options(path, options) {
         Ok(s) => s,
         Err(e) => panic!("Failed to load test file: {}", e),
     };
 
     let mut audio_buffer = vec![0.0f32; num_frames * 2];
+    let (tx, mut rx) = std::sync::mpsc::channel();
+    for command in commands {
+        tx.send(command).unwrap();
+    }
     
     // Process frames in chunks
     let chunk_size = 512;
@@ -21,7 +51,6 @@ fn render_test_file(path: &str, num_frames: usize) -> f64 {
         };
         
         let mut song = song_handle.get_song().lock().unwrap();
-        let (_tx, mut rx): (std::sync::mpsc::Sender<xmplayer::song::PlaybackCmd>, std::sync::mpsc::Receiver<xmplayer::song::PlaybackCmd>) = std::sync::mpsc::channel();
         if let CallbackState::Complete = song.get_next_tick(&mut adapter, &mut rx) {
             break;
         }
@@ -38,9 +67,7 @@ fn render_test_file(path: &str, num_frames: usize) -> f64 {
     // Compute RMSE locally (against zero for now just to generate a fingerprint sum)
     // A true golden test would check against a pre-computed array, but for now we
     // just return the sum or RMS to easily verify stability across refactors.
-    let sum_sq: f64 = audio_buffer.iter().map(|&x| (x as f64) * (x as f64)).sum();
-    
-    (sum_sq / audio_buffer.len() as f64).sqrt()
+    audio_buffer
 }
 
 #[test]
@@ -57,9 +84,274 @@ fn test_openmpt_spacedeb() {
     assert!(rms > 0.0);
 }
 
+#[test]
+fn test_paula() {
+    let sinc = render_test_file("test_data/spacedeb.mod", 44100 * 2);
+    let a1200 = render_test_file_with("test_data/spacedeb.mod", 44100 * 2, vec![PlaybackCmd::SetFilter(FilterType::Paula(AmigaModel::A1200))]);
+    let a500 = render_test_file_with("test_data/spacedeb.mod", 44100 * 2, vec![PlaybackCmd::SetFilter(FilterType::Paula(AmigaModel::A500))]);
+    println!("spacedeb.mod RMS = {}, Paula A1200 = {}, A500 = {}", sinc, a1200, a500);
+    // the same song, the A500 takes the top off
+    assert!((a1200 / sinc - 1.0).abs() < 0.1);
+    assert!(a500 < a1200);
+}
+
+#[test]
+fn test_volume_ramp() {
+    let ramped = render("test_data/milky.xm", 44100 * 2, vec![]);
+    let stepped = render("test_data/milky.xm", 44100 * 2, vec![PlaybackCmd::SetVolumeRamp(0.0)]);
+    println!("milky.xm largest step ramped = {}, without ramping = {}", largest_step(&ramped), largest_step(&stepped));
+    assert!(largest_step(&ramped) < largest_step(&stepped));
+    assert!((rms(&ramped) / rms(&stepped) - 1.0).abs() < 0.1);
+}
+
+#[test]
+fn test_stereo_image() {
+    let mono = render("test_data/milky.xm", 44100 * 4, vec![PlaybackCmd::SetStereoSeparation(0)]);
+    assert!(mono.chunks(2).all(|frame| (frame[0] - frame[1]).abs() < 1e-6));
+
+    // the energy of the difference between the sides
+    let side = |commands: Vec<PlaybackCmd>| {
+        let audio = render("test_data/milky.xm", 44100 * 4, commands);
+        audio.chunks(2).map(|frame| ((frame[0] - frame[1]) as f64).powi(2)).sum::<f64>()
+    };
+    let normal = side(vec![]);
+    let doubled = side(vec![PlaybackCmd::SetStereoSeparation(200)]);
+    let crossfed = side(vec![PlaybackCmd::SetCrossfeed(true)]);
+    let linear = side(vec![PlaybackCmd::SetPanLaw(PanLaw::Linear)]);
+    println!("milky.xm side energy {} doubled {} crossfed {} linear {}", normal, doubled, crossfed, linear);
+    assert!(normal > 0.0);
+    assert!((doubled / normal - 4.0).abs() < 0.01);
+    assert!(crossfed < normal);
+    assert!(linear != normal);
+}
+
+#[test]
+fn test_master_gain_and_limiter() {
+    let plain = render("test_data/milky.xm", 44100, vec![]);
+    let louder = render("test_data/milky.xm", 44100, vec![PlaybackCmd::SetMasterGain(6.0)]);
+    assert!((rms(&louder) / rms(&plain) - 1.995).abs() < 0.01);
+
+    let limited = render("test_data/milky.xm", 44100, vec![PlaybackCmd::SetMasterGain(40.0), PlaybackCmd::SetLimiter(true)]);
+    assert!(limited.iter().all(|value| value.abs() <= 1.0));
+    assert!(limited.iter().any(|value| value.abs() > 0.9));
+}
+
+#[test]
+fn test_normalize_loudness() {
+    // milky.xm is a few dB quieter than the target, with the headroom to come up to it
+    let options = LoadOptions { normalize_loudness: true, ..Default::default() };
+    let plain = render("test_data/milky.xm", 44100 * 2, vec![]);
+    let normalized = render_with_options("test_data/milky.xm", 44100 * 2, vec![], &options);
+    let gain = 20.0 * (rms(&normalized) / rms(&plain)).log10();
+    println!("milky.xm normalized by {} dB", gain);
+    assert!(gain > 1.0 && gain < 6.0);
+    assert!(normalized.iter().all(|value| value.abs() <= 1.0));
+}
+
 #[test]
 fn test_openmpt_amiga_limits() {
     let rms = render_test_file("test_data/AmigaLimitsFinetune.mod", 44100 * 2);
     println!("AmigaLimitsFinetune.mod RMS = {}", rms);
     assert!(rms > 0.0);
 }
+
+#[test]
+fn test_ptm() {
+    let rms = render_test_file("test_data/test.ptm", 44100 * 2);
+    println!("test.ptm RMS = {}", rms);
+    assert!(rms > 0.0);
+}
+
+#[test]
+fn test_ult() {
+    let rms = render_test_file("test_data/test.ult", 44100 * 2);
+    println!("test.ult RMS = {}", rms);
+    assert!(rms > 0.0);
+}
+
+#[test]
+fn test_far() {
+    let rms = render_test_file("test_data/test.far", 44100 * 2);
+    println!("test.far RMS = {}", rms);
+    assert!(rms > 0.0);
+}
+
+// The S3M test modules play a looped sine on the first channel at C-4, six ticks a row at 125 BPM. They are written by
+// make_s3m_tests.py.
+const S3M_ROW: usize = 48000 * 6 * 5 / 2 / 125;
+
+fn render_s3m(name: &str, options: &LoadOptions) -> Vec<f32> {
+    render_with_options(&format!("test_data/{}", name), S3M_ROW * 64, vec![], options)
+}
+
+// the frames of the rows from the first to the last, without the last
+fn s3m_rows(audio: &[f32], first: usize, last: usize) -> &[f32] {
+    &audio[first * S3M_ROW * 2..last * S3M_ROW * 2]
+}
+
+// Where the left side crosses zero going up, between two frames
+fn rising_edges(audio: &[f32]) -> Vec<f32> {
+    let left: Vec<f32> = audio.chunks(2).map(|frame| frame[0]).collect();
+    left.windows(2).enumerate()
+        .filter(|(_, w)| w[0] < 0.0 && w[1] >= 0.0)
+        .map(|(i, w)| i as f32 + w[0] / (w[0] - w[1]))
+        .collect()
+}
+
+// the frequency of every cycle of the sine
+fn cycle_frequencies(audio: &[f32]) -> Vec<f32> {
+    rising_edges(audio).windows(2).map(|w| 48000.0 / (w[1] - w[0])).collect()
+}
+
+// the highest value of every cycle of the sine
+fn cycle_peaks(audio: &[f32]) -> Vec<f32> {
+    let edges = rising_edges(audio);
+    edges.windows(2).map(|w| {
+        audio[w[0] as usize * 2..w[1] as usize * 2].iter().step_by(2).fold(0.0f32, |a, &b| a.max(b))
+    }).collect()
+}
+
+fn spread(values: &[f32]) -> f32 {
+    values.iter().fold(f32::MIN, |a, &b| a.max(b)) - values.iter().fold(f32::MAX, |a, &b| a.min(b))
+}
+
+// how many of the values are in the middle half between the lowest and the highest, a good part of a sine's and
+// hardly any of a square's
+fn in_the_middle(values: &[f32]) -> f32 {
+    let (low, high) = (values.iter().fold(f32::MAX, |a, &b| a.min(b)), values.iter().fold(f32::MIN, |a, &b| a.max(b)));
+    let quarter = (high - low) / 4.0;
+    values.iter().filter(|&&v| v > low + quarter && v < high - quarter).count() as f32 / values.len() as f32
+}
+
+fn side_rms(audio: &[f32], side: usize) -> f64 {
+    rms(&audio.iter().skip(side).step_by(2).cloned().collect::<Vec<f32>>())
+}
+
+const S3M_TICK: usize = S3M_ROW / 6;
+
+const S3M_TEST_MODULES: [&str; 7] = ["s3m_global_volume.s3m", "s3m_fine_vibrato.s3m", "s3m_vibrato_waveform.s3m",
+    "s3m_tremolo_waveform.s3m", "s3m_panning.s3m", "s3m_shared_memory.s3m", "s3m_fast_volume_slides.s3m"];
+
+// The RMS of each side and the mean frequency of the sine, for every tick
+fn s3m_ticks(audio: &[f32]) -> Vec<[f64; 3]> {
+    audio.chunks(S3M_TICK * 2).map(|tick| {
+        // the sine is on one side or both, in phase
+        let mid: Vec<f32> = tick.chunks(2).flat_map(|frame| [frame[0] + frame[1], 0.0]).collect();
+        let frequencies = cycle_frequencies(&mid);
+        let frequency = if frequencies.is_empty() { 0.0 } else { frequencies.iter().map(|&f| f as f64).sum::<f64>() / frequencies.len() as f64 };
+        [side_rms(tick, 0), side_rms(tick, 1), frequency]
+    }).collect()
+}
+
+// Compares a render tick by tick with its reference in test_data. UPDATE_REFERENCES=1 writes the references from the
+// renders instead, for a change that's meant to move them.
+fn assert_matches_reference(name: &str, audio: &[f32]) {
+    let path = format!("test_data/{}.ref", name.trim_end_matches(".s3m"));
+    let ticks = s3m_ticks(audio);
+    if std::env::var_os("UPDATE_REFERENCES").is_some() {
+        let text: String = ticks.iter().map(|[left, right, frequency]| format!("{:.6} {:.6} {:.3}\n", left, right, frequency)).collect();
+        std::fs::write(&path, text).unwrap();
+        return;
+    }
+
+    let reference: Vec<Vec<f64>> = std::fs::read_to_string(&path).unwrap().lines()
+        .map(|line| line.split(' ').map(|value| value.parse().unwrap()).collect())
+        .collect();
+    assert_eq!(ticks.len(), reference.len(), "{}", name);
+    for (tick, (rendered, expected)) in ticks.iter().zip(&reference).enumerate() {
+        let matches = rendered.iter().zip(expected).all(|(value, expected)| (value - expected).abs() <= expected.abs() * 0.005 + 1e-4);
+        assert!(matches, "{} row {} tick {}: {:?} where the reference has {:?}", name, tick / 6, tick % 6, rendered, expected);
+    }
+}
+
+#[test]
+fn test_s3m_reference_renders() {
+    for name in S3M_TEST_MODULES {
+        assert_matches_reference(name, &render_s3m(name, &LoadOptions::default()));
+    }
+
+    // C-4 plays the sample at its C2SPD of 8363 Hz, 32 frames a cycle
+    let audio = render_s3m("s3m_global_volume.s3m", &LoadOptions::default());
+    let frequencies = cycle_frequencies(s3m_rows(&audio, 1, 32));
+    let frequency = frequencies.iter().sum::<f32>() / frequencies.len() as f32;
+    assert!((frequency / (8363.0 / 32.0) - 1.0).abs() < 0.001, "{}", frequency);
+}
+
+#[test]
+fn test_s3m_global_volume() {
+    let audio = render_s3m("s3m_global_volume.s3m", &LoadOptions::default());
+    let full = rms(s3m_rows(&audio, 4, 32));
+    let half = rms(s3m_rows(&audio, 36, 64));
+    println!("s3m V20 {} {}", full, half);
+    assert!((half / full - 0.5).abs() < 0.02);
+}
+
+#[test]
+fn test_s3m_fine_vibrato() {
+    let audio = render_s3m("s3m_fine_vibrato.s3m", &LoadOptions::default());
+    let vibrato = spread(&cycle_frequencies(s3m_rows(&audio, 4, 32)));
+    let fine = spread(&cycle_frequencies(s3m_rows(&audio, 36, 64)));
+    println!("s3m H48 {} U48 {}", vibrato, fine);
+    assert!((vibrato / fine - 4.0).abs() < 0.8);
+}
+
+#[test]
+fn test_s3m_vibrato_waveform() {
+    let audio = render_s3m("s3m_vibrato_waveform.s3m", &LoadOptions::default());
+    let sine = in_the_middle(&cycle_frequencies(s3m_rows(&audio, 4, 32)));
+    let square = in_the_middle(&cycle_frequencies(s3m_rows(&audio, 36, 64)));
+    println!("s3m S30 {} S32 {}", sine, square);
+    // the first tick of every row plays without the vibrato, in the middle of the square too
+    assert!(sine > 0.4 && square < 0.3);
+}
+
+#[test]
+fn test_s3m_tremolo_waveform() {
+    let audio = render_s3m("s3m_tremolo_waveform.s3m", &LoadOptions::default());
+    let sine = in_the_middle(&cycle_peaks(s3m_rows(&audio, 4, 32)));
+    let square = in_the_middle(&cycle_peaks(s3m_rows(&audio, 36, 64)));
+    println!("s3m S40 {} S42 {}", sine, square);
+    assert!(sine > 0.2 && square < 0.1);
+}
+
+#[test]
+fn test_s3m_panning() {
+    let audio = render_s3m("s3m_panning.s3m", &LoadOptions::default());
+    let sides = |first: usize| {
+        let rows = s3m_rows(&audio, first + 2, first + 16);
+        (side_rms(rows, 0), side_rms(rows, 1))
+    };
+    let (s80, x80, x40, sa8) = (sides(0), sides(16), sides(32), sides(48));
+    println!("s3m S80 {:?} X80 {:?} X40 {:?} SA8 {:?}", s80, x80, x40, sa8);
+    assert!(s80.1 < s80.0 * 0.01);
+    // FT2's panning table leaves a little on the left even at the far right
+    assert!(x80.0 < x80.1 * 0.1);
+    assert!((x40.0 / x40.1 - 1.0).abs() < 0.05);
+    assert!(sa8.1 < sa8.0 * 0.01);
+}
+
+#[test]
+fn test_s3m_shared_memory() {
+    // E00 slides down with what D01 left in the memory, where every effect keeps its own it has nothing to slide with
+    let slide = |options: &LoadOptions| {
+        let audio = render_s3m("s3m_shared_memory.s3m", options);
+        let before = cycle_frequencies(s3m_rows(&audio, 4, 8));
+        let after = cycle_frequencies(s3m_rows(&audio, 26, 32));
+        before.iter().sum::<f32>() / before.len() as f32 - after.iter().sum::<f32>() / after.len() as f32
+    };
+    let shared = slide(&LoadOptions::default());
+    let own = slide(&LoadOptions { compat_profile: Some(CompatProfile::Modern), ..Default::default() });
+    println!("s3m shared memory slide {} own memory slide {}", shared, own);
+    assert!(shared > 10.0);
+    assert!(own.abs() < 0.5);
+}
+
+#[test]
+fn test_s3m_fast_volume_slides() {
+    // eight rows of D01 from 64, 48 steps down where ST3 3.00 slides on the first tick too, 40 elsewhere
+    let level = |options: &LoadOptions| rms(s3m_rows(&render_s3m("s3m_fast_volume_slides.s3m", options), 10, 16));
+    let fast = level(&LoadOptions::default());
+    let slow = level(&LoadOptions { compat_profile: Some(CompatProfile::Modern), ..Default::default() });
+    println!("s3m fast volume slides {} without {}", fast, slow);
+    assert!((fast / slow - 16.0 / 24.0).abs() < 0.03);
+}
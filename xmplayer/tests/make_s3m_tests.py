@@ -0,0 +1,115 @@
+#!/usr/bin/env python3
+# Writes the s3m_*.s3m test modules of tests/golden_render.rs: a looped 32 frame sine on the first channel at C-4,
+# six ticks a row at 125 BPM, and the effect under test. Run from the repository root:
+#   python3 xmplayer/tests/make_s3m_tests.py xmplayer/test_data
+
+import math, struct, sys
+
+def pad16(b):
+    return b + b'\0' * (-len(b) % 16)
+
+def s3m(title, rows, flags=0):
+    """rows: dict row -> (note, vol, effect_letter, param) for channel 0; note None = no note"""
+    header = bytearray(0x60)
+    header[0:len(title)] = title.encode()
+    header[0x1C] = 0x1A
+    header[0x1D] = 16
+    orders = bytes([0, 0xFF])
+    struct.pack_into('<HHHHHH', header, 0x20, len(orders), 1, 1, flags, 0x1320, 2)
+    header[0x2C:0x30] = b'SCRM'
+    header[0x30] = 64      # global volume
+    header[0x31] = 6       # speed
+    header[0x32] = 125     # tempo
+    header[0x33] = 0xB0    # stereo
+    header[0x40:0x60] = bytes([0] + [255] * 31)
+
+    # a sine, 32 samples a cycle, unsigned
+    sample = bytes(int(round(128 + 127 * math.sin(2 * math.pi * i / 32))) & 0xFF for i in range(1024))
+
+    pointers_at = 0x60 + len(orders)
+    instrument_at = (pointers_at + 4 + 15) // 16 * 16
+    sample_at = instrument_at + 0x50
+    sample_at = (sample_at + 15) // 16 * 16
+    pattern_at = (sample_at + len(sample) + 15) // 16 * 16
+
+    instrument = bytearray(0x50)
+    instrument[0] = 1
+    instrument[1:9] = b'SINE.SMP'
+    para = sample_at // 16
+    instrument[0x0D] = para >> 16
+    struct.pack_into('<H', instrument, 0x0E, para & 0xFFFF)
+    struct.pack_into('<III', instrument, 0x10, len(sample), 0, len(sample))
+    instrument[0x1C] = 64
+    instrument[0x1F] = 1
+    struct.pack_into('<I', instrument, 0x20, 8363)
+    instrument[0x30:0x34] = b'sine'
+    instrument[0x4C:0x50] = b'SCRS'
+
+    packed = bytearray()
+    for row in range(64):
+        if row in rows:
+            note, vol, effect, param = rows[row]
+            what = 0
+            data = bytearray()
+            if note is not None:
+                what |= 32
+                data += bytes([note, 1])
+            if vol is not None:
+                what |= 64
+                data += bytes([vol])
+            if effect is not None:
+                what |= 128
+                data += bytes([ord(effect) - ord('A') + 1, param])
+            packed += bytes([what]) + data
+        packed += b'\0'
+    pattern = struct.pack('<H', len(packed) + 2) + packed
+
+    out = bytearray(header) + orders + struct.pack('<HH', instrument_at // 16, pattern_at // 16)
+    out += b'\0' * (instrument_at - len(out))
+    out += instrument
+    out += b'\0' * (sample_at - len(out))
+    out += sample
+    out += b'\0' * (pattern_at - len(out))
+    out += pattern
+    return bytes(out)
+
+C4 = 0x40
+out = sys.argv[1]
+
+def write(name, data):
+    open(f'{out}/{name}', 'wb').write(data)
+
+# Vxx: the first half at full global volume, the second at V20
+write('s3m_global_volume.s3m', s3m('Vxx global volume', {0: (C4, 64, None, 0), 32: (None, None, 'V', 0x20)}))
+
+# Uxy is Hxy at a quarter of the depth
+rows = {r: (None, None, 'H', 0x48) for r in range(32)}
+rows.update({r: (None, None, 'U', 0x48) for r in range(32, 64)})
+rows[0] = (C4, 64, 'H', 0x48)
+write('s3m_fine_vibrato.s3m', s3m('Uxy fine vibrato', rows))
+
+# S3x: sine vibrato, then square from S32
+rows = {r: (None, None, 'H', 0x48) for r in range(64)}
+rows[0] = (C4, 64, 'H', 0x48)
+rows[32] = (None, None, 'S', 0x32)
+write('s3m_vibrato_waveform.s3m', s3m('S3x vibrato waveform', rows))
+
+# S4x: sine tremolo, then square from S42
+rows = {r: (None, None, 'R', 0x48) for r in range(64)}
+rows[0] = (C4, 64, 'R', 0x48)
+rows[32] = (None, None, 'S', 0x42)
+write('s3m_tremolo_waveform.s3m', s3m('S4x tremolo waveform', rows))
+
+# S8x, Xxx and SAx, 16 rows each: S80 left, X80 right, X40 middle, SA8 left
+write('s3m_panning.s3m', s3m('S8x Xxx SAx panning', {
+    0: (C4, 64, 'S', 0x80), 16: (None, None, 'X', 0x80), 32: (None, None, 'X', 0x40), 48: (None, None, 'S', 0xA8)}))
+
+# D01 puts 01 in the shared memory, E00 on the rows after it slides down with it
+rows = {0: (C4, 64, None, 0), 8: (None, None, 'D', 0x01)}
+rows.update({r: (None, None, 'E', 0x00) for r in range(16, 24)})
+write('s3m_shared_memory.s3m', s3m('shared effect memory', rows))
+
+# D01 for 8 rows with the fast volume slides flag, 6 steps a row instead of 5
+rows = {r: (None, None, 'D', 0x01) for r in range(8)}
+rows[0] = (C4, 64, 'D', 0x01)
+write('s3m_fast_volume_slides.s3m', s3m('fast volume slides', rows, flags=0x40))